@@ -0,0 +1,767 @@
+/*
+ * Parseable Server (C) 2022 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use actix_web::rt::time::sleep;
+use arrow::json::writer::array_to_json_array;
+use arrow::record_batch::RecordBatch;
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::metadata::STREAM_INFO;
+use crate::option::CONFIG;
+use crate::Error;
+
+/// Number of times delivery of an alert to a target is retried
+const MAX_NOTIFY_RETRIES: u32 = 3;
+/// Delay before the first retry, it doubles with every retry after that
+const NOTIFY_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+lazy_static::lazy_static! {
+    pub static ref COOLDOWNS: Cooldowns = Cooldowns::default();
+    static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(CONFIG.parseable.alert_timeout))
+        .build()
+        .expect("http client can be built");
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Alerts {
+    pub alerts: Vec<Alert>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Alert {
+    pub name: String,
+    pub message: String,
+    /// Alerts set before rules could be grouped have a single `rule`
+    #[serde(alias = "rule", deserialize_with = "one_or_many_rules")]
+    pub rules: Vec<Rule>,
+    #[serde(default)]
+    pub grouping: Grouping,
+    /// Seconds after firing that the alert doesn't fire again, the server's
+    /// alert cooldown if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cooldown: Option<u64>,
+    pub target: Vec<Target>,
+}
+
+fn one_or_many_rules<'de, D>(deserializer: D) -> Result<Vec<Rule>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Value::deserialize(deserializer)?;
+    let rules = if value.is_array() {
+        serde_json::from_value(value)
+    } else {
+        serde_json::from_value(value).map(|rule| vec![rule])
+    };
+    rules.map_err(serde::de::Error::custom)
+}
+
+/// How the rules of an alert are combined, the alert fires when all of them
+/// match the events or when any of them does
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Grouping {
+    #[default]
+    AllOf,
+    AnyOf,
+}
+
+impl Alert {
+    /// Returns the rules that matched, if the rules match this record batch as
+    /// grouped. Evaluation stops at the first rule that doesn't match for all-of,
+    /// and at the first rule that matches for any-of.
+    pub fn matches(&self, rb: &RecordBatch) -> Option<Vec<MatchedRule>> {
+        if self.rules.is_empty() {
+            return None;
+        }
+
+        let mut matched = self.rules.iter().enumerate().map(|(index, rule)| {
+            rule.matches(rb).map(|value| MatchedRule {
+                rule: index,
+                field: rule.field.clone(),
+                value,
+            })
+        });
+        match self.grouping {
+            Grouping::AllOf => matched.collect(),
+            Grouping::AnyOf => matched.find_map(|rule| rule).map(|rule| vec![rule]),
+        }
+    }
+}
+
+/// A rule of an alert that matched, with the first value of its field that did
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchedRule {
+    /// Index of the rule in the rules of the alert
+    pub rule: usize,
+    pub field: String,
+    pub value: Value,
+}
+
+impl fmt::Display for MatchedRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.value)
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Rule {
+    pub field: String,
+    /// Alerts set before operators were introduced only have `contains`
+    #[serde(default)]
+    pub operator: Operator,
+    #[serde(alias = "contains")]
+    pub value: Value,
+    pub repeats: u32,
+    pub within: String,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Operator {
+    Equal,
+    NotEqual,
+    GreaterThan,
+    GreaterThanEquals,
+    LessThan,
+    LessThanEquals,
+    #[default]
+    Contains,
+}
+
+impl Operator {
+    const ALL: [Operator; 7] = [
+        Operator::Equal,
+        Operator::NotEqual,
+        Operator::GreaterThan,
+        Operator::GreaterThanEquals,
+        Operator::LessThan,
+        Operator::LessThanEquals,
+        Operator::Contains,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operator::Equal => "=",
+            Operator::NotEqual => "!=",
+            Operator::GreaterThan => ">",
+            Operator::GreaterThanEquals => ">=",
+            Operator::LessThan => "<",
+            Operator::LessThanEquals => "<=",
+            Operator::Contains => "contains",
+        }
+    }
+
+    /// Operators that compare the field with a numeric value
+    pub fn is_numeric(&self) -> bool {
+        !matches!(
+            self,
+            Operator::Equal | Operator::NotEqual | Operator::Contains
+        )
+    }
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl TryFrom<String> for Operator {
+    type Error = String;
+
+    fn try_from(operator: String) -> Result<Self, Self::Error> {
+        Operator::ALL
+            .into_iter()
+            .find(|op| op.as_str() == operator)
+            .ok_or_else(|| {
+                let known = Operator::ALL.map(|op| op.as_str()).join(", ");
+                format!("unknown operator {}, expected one of {}", operator, known)
+            })
+    }
+}
+
+impl From<Operator> for String {
+    fn from(operator: Operator) -> Self {
+        operator.as_str().to_owned()
+    }
+}
+
+impl Rule {
+    /// Returns the first value of the rule's field that matches the rule, if at least
+    /// `repeats` values of the field in this record batch match. Record batches
+    /// without the field never match.
+    pub fn matches(&self, rb: &RecordBatch) -> Option<Value> {
+        let (index, _) = rb.schema().column_with_name(&self.field)?;
+        let mut matched = array_to_json_array(rb.column(index))
+            .into_iter()
+            .filter(|value| self.matches_value(value));
+
+        let first = matched.next()?;
+        if 1 + matched.count() as u32 >= self.repeats {
+            Some(first)
+        } else {
+            None
+        }
+    }
+
+    fn matches_value(&self, value: &Value) -> bool {
+        match self.operator {
+            Operator::Contains => match (value.as_str(), self.value.as_str()) {
+                (Some(value), Some(pattern)) => value.contains(pattern),
+                _ => false,
+            },
+            Operator::Equal => values_equal(value, &self.value),
+            Operator::NotEqual => !value.is_null() && !values_equal(value, &self.value),
+            op => match (value.as_f64(), self.value.as_f64()) {
+                (Some(value), Some(threshold)) => match op {
+                    Operator::GreaterThan => value > threshold,
+                    Operator::GreaterThanEquals => value >= threshold,
+                    Operator::LessThan => value < threshold,
+                    Operator::LessThanEquals => value <= threshold,
+                    _ => unreachable!("non numeric operators are handled above"),
+                },
+                _ => false,
+            },
+        }
+    }
+}
+
+// numbers are compared by value, so that 500 in a rule matches 500.0 in a float column
+fn values_equal(left: &Value, right: &Value) -> bool {
+    match (left.as_f64(), right.as_f64()) {
+        (Some(left), Some(right)) => left == right,
+        _ => left == right,
+    }
+}
+
+/// An alert whose rules matched events sent to a log stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TriggeredAlert {
+    pub alert: Alert,
+    pub matched: Vec<MatchedRule>,
+}
+
+/// Evaluate all alerts set for the log stream against the events in the record batch.
+pub fn evaluate(stream_name: &str, rb: &RecordBatch) -> Result<Vec<TriggeredAlert>, Error> {
+    let alerts = STREAM_INFO.alert(stream_name)?;
+
+    Ok(evaluate_alerts(alerts, rb))
+}
+
+fn evaluate_alerts(alerts: Vec<Alert>, rb: &RecordBatch) -> Vec<TriggeredAlert> {
+    alerts
+        .into_iter()
+        .filter_map(|alert| {
+            let matched = alert.matches(rb)?;
+            Some(TriggeredAlert { alert, matched })
+        })
+        .collect()
+}
+
+/// When each alert last fired, by log stream and alert name, and the number of times
+/// it matched since without firing. Kept in memory only, a restart lets alerts fire again.
+#[derive(Debug, Default)]
+pub struct Cooldowns {
+    fired: Mutex<HashMap<(String, String), Fired>>,
+}
+
+#[derive(Debug)]
+struct Fired {
+    at: Instant,
+    suppressed: u64,
+}
+
+impl Cooldowns {
+    /// Returns the alerts that may fire at `now`, each with the number of matches
+    /// suppressed since it last fired. Alerts still cooling down are left out and
+    /// counted, `default_cooldown` applies to alerts that don't set their own.
+    pub fn throttle(
+        &self,
+        stream_name: &str,
+        triggered: Vec<TriggeredAlert>,
+        default_cooldown: Duration,
+        now: Instant,
+    ) -> Vec<(TriggeredAlert, u64)> {
+        let mut fired = self.fired.lock().unwrap();
+        triggered
+            .into_iter()
+            .filter_map(|triggered| {
+                let cooldown = triggered
+                    .alert
+                    .cooldown
+                    .map_or(default_cooldown, Duration::from_secs);
+                let key = (stream_name.to_string(), triggered.alert.name.clone());
+                match fired.get_mut(&key) {
+                    Some(last) if now.duration_since(last.at) < cooldown => {
+                        last.suppressed += 1;
+                        None
+                    }
+                    _ => {
+                        let last = fired.insert(
+                            key,
+                            Fired {
+                                at: now,
+                                suppressed: 0,
+                            },
+                        );
+                        Some((triggered, last.map_or(0, |last| last.suppressed)))
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Forget the alerts of a deleted log stream
+    pub fn remove_stream(&self, stream_name: &str) {
+        self.fired
+            .lock()
+            .unwrap()
+            .retain(|(stream, _), _| stream != stream_name);
+    }
+}
+
+/// Context of the events that triggered an alert
+#[derive(Debug, Clone)]
+pub struct EventContext {
+    pub stream_name: String,
+    pub matched: Vec<MatchedRule>,
+    /// Matches of the alert while it cooled down since it last fired
+    pub suppressed: u64,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AlertPayload<'a> {
+    stream_name: &'a str,
+    alert_name: &'a str,
+    message: &'a str,
+    /// Value matched by the first matched rule, as sent before rules were grouped
+    matched_value: &'a Value,
+    matched_rules: &'a [MatchedRule],
+    additional_matches: u64,
+    timestamp: DateTime<Utc>,
+}
+
+/// Deliver a triggered alert to all of its targets. Delivery failures are
+/// only logged, as they must not affect ingestion of the events.
+pub async fn notify(alert: &Alert, context: &EventContext) {
+    let payload = AlertPayload {
+        stream_name: &context.stream_name,
+        alert_name: &alert.name,
+        message: &alert.message,
+        matched_value: context
+            .matched
+            .first()
+            .map_or(&Value::Null, |matched| &matched.value),
+        matched_rules: &context.matched,
+        additional_matches: context.suppressed,
+        timestamp: context.timestamp,
+    };
+
+    for target in &alert.target {
+        if let Err(e) = target.send(&payload).await {
+            warn!(
+                "failed to deliver alert {} for log stream {} to target {}. {}",
+                alert.name, context.stream_name, target.name, e
+            );
+        }
+    }
+}
+
+// exponential backoff, 500ms, 1s, 2s ...
+fn retry_delay(retry: u32) -> Duration {
+    NOTIFY_RETRY_DELAY * 2u32.pow(retry)
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Target {
+    pub name: String,
+    #[serde(rename = "server_url")]
+    pub server_url: String,
+    #[serde(rename = "api_key")]
+    pub api_key: String,
+}
+
+impl Target {
+    async fn send(&self, payload: &AlertPayload<'_>) -> Result<(), reqwest::Error> {
+        let mut retry = 0;
+        loop {
+            let mut request = HTTP_CLIENT.post(&self.server_url).json(payload);
+            if !self.api_key.is_empty() {
+                request = request.bearer_auth(&self.api_key);
+            }
+
+            match request
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status())
+            {
+                Ok(_) => return Ok(()),
+                Err(e) if retry < MAX_NOTIFY_RETRIES && is_retryable(&e) => {
+                    sleep(retry_delay(retry)).await;
+                    retry += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+// server errors and timeouts are worth retrying, client errors are not
+fn is_retryable(e: &reqwest::Error) -> bool {
+    match e.status() {
+        Some(status) => status.is_server_error(),
+        None => e.is_timeout() || e.is_connect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{Float64Array, Int64Array, StringArray};
+    use arrow::datatypes::{DataType, Field, Schema};
+    use rstest::*;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn record_batch() -> RecordBatch {
+        let schema = Schema::new(vec![
+            Field::new("code", DataType::Int64, true),
+            Field::new("latency", DataType::Float64, true),
+            Field::new("msg", DataType::Utf8, true),
+        ]);
+        RecordBatch::try_new(
+            Arc::new(schema),
+            vec![
+                Arc::new(Int64Array::from(vec![200, 500, 503])),
+                Arc::new(Float64Array::from(vec![0.5, 1.5, 2.5])),
+                Arc::new(StringArray::from(vec!["ok", "server error", "unavailable"])),
+            ],
+        )
+        .unwrap()
+    }
+
+    fn rule(field: &str, operator: Operator, value: Value, repeats: u32) -> Rule {
+        Rule {
+            field: field.to_string(),
+            operator,
+            value,
+            repeats,
+            within: "1m".to_string(),
+        }
+    }
+
+    fn alert(field: &str, operator: Operator, value: Value, repeats: u32) -> Alert {
+        Alert {
+            name: format!("{} {} {}", field, operator, value),
+            rules: vec![rule(field, operator, value, repeats)],
+            ..Default::default()
+        }
+    }
+
+    fn matched(rule: usize, field: &str, value: Value) -> MatchedRule {
+        MatchedRule {
+            rule,
+            field: field.to_string(),
+            value,
+        }
+    }
+
+    #[rstest]
+    #[case::greater_than_fires("code", Operator::GreaterThan, json!(499), 1, Some(json!(500)))]
+    #[case::greater_than_repeats("code", Operator::GreaterThan, json!(499), 2, Some(json!(500)))]
+    #[case::greater_than_not_enough_repeats("code", Operator::GreaterThan, json!(499), 3, None)]
+    #[case::greater_than_does_not_fire("code", Operator::GreaterThan, json!(503), 1, None)]
+    #[case::less_than_float("latency", Operator::LessThanEquals, json!(1.5), 2, Some(json!(0.5)))]
+    #[case::equal_int_float("latency", Operator::Equal, json!(2.5), 1, Some(json!(2.5)))]
+    #[case::not_equal("code", Operator::NotEqual, json!(200), 2, Some(json!(500)))]
+    #[case::contains("msg", Operator::Contains, json!("error"), 1, Some(json!("server error")))]
+    #[case::missing_column("status", Operator::GreaterThan, json!(499), 1, None)]
+    fn evaluate_rule(
+        #[case] field: &str,
+        #[case] operator: Operator,
+        #[case] value: Value,
+        #[case] repeats: u32,
+        #[case] matched: Option<Value>,
+    ) {
+        let alert = alert(field, operator, value, repeats);
+        let triggered = evaluate_alerts(vec![alert.clone()], &record_batch());
+        let right = matched
+            .map(|value| {
+                vec![TriggeredAlert {
+                    alert,
+                    matched: vec![self::matched(0, field, value)],
+                }]
+            })
+            .unwrap_or_default();
+        assert_eq!(triggered, right);
+    }
+
+    #[rstest]
+    #[case::all_of_fires(
+        Grouping::AllOf,
+        vec![
+            rule("code", Operator::GreaterThanEquals, json!(500), 1),
+            rule("latency", Operator::GreaterThan, json!(1), 1),
+        ],
+        Some(vec![matched(0, "code", json!(500)), matched(1, "latency", json!(1.5))])
+    )]
+    #[case::all_of_partial_match(
+        Grouping::AllOf,
+        vec![
+            rule("code", Operator::GreaterThanEquals, json!(500), 1),
+            rule("msg", Operator::Contains, json!("timeout"), 1),
+        ],
+        None
+    )]
+    #[case::all_of_same_field(
+        Grouping::AllOf,
+        vec![
+            rule("latency", Operator::GreaterThan, json!(1), 1),
+            rule("latency", Operator::LessThan, json!(2), 1),
+        ],
+        Some(vec![matched(0, "latency", json!(1.5)), matched(1, "latency", json!(0.5))])
+    )]
+    #[case::any_of_partial_match(
+        Grouping::AnyOf,
+        vec![
+            rule("code", Operator::GreaterThan, json!(503), 1),
+            rule("latency", Operator::GreaterThan, json!(2), 1),
+        ],
+        Some(vec![matched(1, "latency", json!(2.5))])
+    )]
+    #[case::any_of_stops_at_first_match(
+        Grouping::AnyOf,
+        vec![
+            rule("code", Operator::Equal, json!(503), 1),
+            rule("msg", Operator::Contains, json!("error"), 1),
+        ],
+        Some(vec![matched(0, "code", json!(503))])
+    )]
+    #[case::any_of_does_not_fire(
+        Grouping::AnyOf,
+        vec![
+            rule("code", Operator::GreaterThan, json!(503), 1),
+            rule("status", Operator::Equal, json!(1), 1),
+        ],
+        None
+    )]
+    fn evaluate_grouped_rules(
+        #[case] grouping: Grouping,
+        #[case] rules: Vec<Rule>,
+        #[case] matched: Option<Vec<MatchedRule>>,
+    ) {
+        let alert = Alert {
+            rules,
+            grouping,
+            ..Default::default()
+        };
+        assert_eq!(alert.matches(&record_batch()), matched);
+    }
+
+    #[test]
+    fn alert_without_rules_does_not_fire() {
+        assert_eq!(Alert::default().matches(&record_batch()), None);
+    }
+
+    #[test]
+    fn parse_legacy_alert() {
+        let alert = r#"{
+            "name": "server errors",
+            "message": "too many server errors",
+            "rule": { "field": "msg", "contains": "error", "repeats": 1, "within": "1m" },
+            "target": []
+        }"#;
+        let alert: Alert = serde_json::from_str(alert).unwrap();
+        assert_eq!(alert.rules.len(), 1);
+        assert_eq!(alert.grouping, Grouping::AllOf);
+
+        // grouped rules are put in place of the single rule
+        let json = serde_json::to_value(&alert).unwrap();
+        assert_eq!(json["rules"][0]["field"], "msg");
+        assert_eq!(json["grouping"], "allOf");
+        assert!(json.get("rule").is_none());
+        assert_eq!(serde_json::from_value::<Alert>(json).unwrap(), alert);
+    }
+
+    #[test]
+    fn parse_grouped_alert_bad_rule() {
+        let alert = r#"{
+            "name": "errors",
+            "message": "errors",
+            "rules": [{ "field": "code", "operator": "~", "value": 1, "repeats": 1, "within": "1m" }],
+            "grouping": "anyOf",
+            "target": []
+        }"#;
+        let err = serde_json::from_str::<Alert>(alert).unwrap_err();
+        assert!(err.to_string().contains("unknown operator ~"));
+    }
+
+    #[rstest]
+    #[case::greater_than(
+        r#"{"field": "code", "operator": ">", "value": 499, "repeats": 1, "within": "1m"}"#,
+        Operator::GreaterThan
+    )]
+    #[case::legacy_contains(
+        r#"{"field": "msg", "contains": "error", "repeats": 1, "within": "1m"}"#,
+        Operator::Contains
+    )]
+    fn parse_rule(#[case] rule: &str, #[case] operator: Operator) {
+        let rule: Rule = serde_json::from_str(rule).unwrap();
+        assert_eq!(rule.operator, operator);
+    }
+
+    #[test]
+    fn parse_rule_unknown_operator() {
+        let rule =
+            r#"{"field": "code", "operator": "~", "value": 1, "repeats": 1, "within": "1m"}"#;
+        let err = serde_json::from_str::<Rule>(rule).unwrap_err();
+        assert!(err.to_string().contains("unknown operator ~"));
+    }
+
+    fn triggered(name: &str, cooldown: Option<u64>) -> Vec<TriggeredAlert> {
+        vec![TriggeredAlert {
+            alert: Alert {
+                name: name.to_string(),
+                cooldown,
+                ..Default::default()
+            },
+            matched: vec![matched(0, "code", json!(500))],
+        }]
+    }
+
+    fn fired(alerts: Vec<(TriggeredAlert, u64)>) -> Vec<(String, u64)> {
+        alerts
+            .into_iter()
+            .map(|(triggered, suppressed)| (triggered.alert.name, suppressed))
+            .collect()
+    }
+
+    #[test]
+    fn burst_fires_once_per_cooldown() {
+        let cooldowns = Cooldowns::default();
+        let cooldown = Duration::from_secs(60);
+        let start = Instant::now();
+
+        let mut notifications = Vec::new();
+        for i in 0..100 {
+            let now = start + Duration::from_millis(i * 10);
+            notifications.extend(fired(cooldowns.throttle(
+                "stream",
+                triggered("errors", None),
+                cooldown,
+                now,
+            )));
+        }
+        assert_eq!(notifications, vec![("errors".to_string(), 0)]);
+
+        // after the window the alert fires again, with the matches suppressed meanwhile
+        let after = start + cooldown;
+        let notifications =
+            cooldowns.throttle("stream", triggered("errors", None), cooldown, after);
+        assert_eq!(fired(notifications), vec![("errors".to_string(), 99)]);
+        let notifications =
+            cooldowns.throttle("stream", triggered("errors", None), cooldown, after);
+        assert!(notifications.is_empty());
+    }
+
+    #[test]
+    fn cooldowns_are_per_stream_and_alert() {
+        let cooldowns = Cooldowns::default();
+        let cooldown = Duration::from_secs(60);
+        let now = Instant::now();
+
+        for (stream_name, name) in [
+            ("stream", "errors"),
+            ("stream", "latency"),
+            ("other", "errors"),
+        ] {
+            let notifications =
+                cooldowns.throttle(stream_name, triggered(name, None), cooldown, now);
+            assert_eq!(fired(notifications), vec![(name.to_string(), 0)]);
+        }
+
+        // a deleted stream's alerts fire right away when the stream is created again
+        cooldowns.remove_stream("stream");
+        let notifications = cooldowns.throttle("stream", triggered("errors", None), cooldown, now);
+        assert_eq!(fired(notifications), vec![("errors".to_string(), 0)]);
+    }
+
+    #[test]
+    fn alert_sets_own_cooldown() {
+        let cooldowns = Cooldowns::default();
+        let start = Instant::now();
+        let default_cooldown = Duration::from_secs(60);
+
+        let first = cooldowns.throttle(
+            "stream",
+            triggered("errors", Some(1)),
+            default_cooldown,
+            start,
+        );
+        let later = start + Duration::from_secs(1);
+        let second = cooldowns.throttle(
+            "stream",
+            triggered("errors", Some(1)),
+            default_cooldown,
+            later,
+        );
+        assert_eq!(fired(first), vec![("errors".to_string(), 0)]);
+        assert_eq!(fired(second), vec![("errors".to_string(), 0)]);
+
+        // no cooldown, every match fires
+        let no_cooldown = Duration::ZERO;
+        for _ in 0..2 {
+            let notifications =
+                cooldowns.throttle("stream", triggered("latency", None), no_cooldown, start);
+            assert_eq!(notifications.len(), 1);
+        }
+    }
+
+    #[test]
+    fn notify_retry_delay() {
+        let delays = (0..MAX_NOTIFY_RETRIES).map(retry_delay).collect::<Vec<_>>();
+        assert_eq!(
+            delays,
+            [500, 1000, 2000].map(Duration::from_millis).to_vec()
+        );
+    }
+
+    #[test]
+    fn operator_roundtrip() {
+        for operator in Operator::ALL {
+            let json = serde_json::to_string(&operator).unwrap();
+            assert_eq!(serde_json::from_str::<Operator>(&json).unwrap(), operator);
+        }
+    }
+}
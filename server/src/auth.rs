@@ -0,0 +1,409 @@
+/*
+ * Parseable Server (C) 2022 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::Header;
+use actix_web::http::Method;
+use actix_web::HttpRequest;
+use actix_web_httpauth::extractors::AuthExtractor;
+use actix_web_httpauth::headers::authorization::{Authorization, Basic, Bearer};
+use futures::future::{ready, Ready};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Credentials a request to the API carries in its `Authorization` header
+#[derive(Debug, PartialEq, Eq)]
+pub enum Credentials {
+    Basic {
+        user_id: String,
+        password: Option<String>,
+    },
+    /// `Bearer <key>`, with one of the API keys of the server
+    Bearer(String),
+    None,
+}
+
+impl Credentials {
+    fn from_request(req: &ServiceRequest) -> Self {
+        if let Ok(auth) = Authorization::<Basic>::parse(req) {
+            let basic = auth.into_scheme();
+            return Credentials::Basic {
+                user_id: basic.user_id().to_string(),
+                password: basic.password().map(|password| password.to_string()),
+            };
+        }
+        if let Ok(auth) = Authorization::<Bearer>::parse(req) {
+            return Credentials::Bearer(auth.into_scheme().token().to_string());
+        }
+
+        Credentials::None
+    }
+}
+
+// Requests without valid credentials are still extracted, so that the
+// validator can let through requests to paths that need none.
+impl AuthExtractor for Credentials {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_service_request(req: &ServiceRequest) -> Self::Future {
+        ready(Ok(Credentials::from_request(req)))
+    }
+}
+
+/// What a request does to log streams
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Read,
+    Write,
+}
+
+/// Log streams and actions an API key is limited to
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Permission {
+    pub streams: Vec<String>,
+    pub actions: Vec<Action>,
+}
+
+impl Permission {
+    /// Whether the key may take `action`, on `stream` if the request is for one
+    pub fn allows(&self, action: Action, stream: Option<&str>) -> bool {
+        self.actions.contains(&action)
+            && stream.map_or(true, |stream| self.streams.iter().any(|s| s == stream))
+    }
+}
+
+/// Permissions of API keys, read from a JSON object mapping keys to their
+/// permission. Keys without one may take any action on any stream.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Permissions(HashMap<String, Permission>);
+
+impl Permissions {
+    pub fn get(&self, api_key: &str) -> Option<&Permission> {
+        self.0.get(api_key)
+    }
+
+    /// Streams named in any of the permissions
+    pub fn streams(&self) -> impl Iterator<Item = &str> {
+        self.0
+            .values()
+            .flat_map(|permission| permission.streams.iter().map(String::as_str))
+    }
+}
+
+impl FromStr for Permissions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s)
+            .map(Permissions)
+            .map_err(|e| format!("invalid API key permissions. {}", e))
+    }
+}
+
+/// Action a request takes and the stream it is for, from its method and its
+/// path under the API base path. Follows the routes in `configure_routes`.
+pub fn request_scope<'a>(method: &Method, path: &'a str) -> (Action, Option<&'a str>) {
+    let by_method = if method == Method::GET || method == Method::HEAD {
+        Action::Read
+    } else {
+        Action::Write
+    };
+    let segments = path.trim_start_matches('/').split('/').collect::<Vec<_>>();
+
+    match segments[..] {
+        // stats of all streams added up
+        ["logstream", "stats"] if method == Method::GET => (Action::Read, None),
+        // queries are POSTs that only read
+        ["logstream", stream, "query"] => (Action::Read, Some(stream)),
+        // inferring a schema ingests nothing
+        ["logstream", stream, "schema", "infer"] => (Action::Read, Some(stream)),
+        // an export hands over all data of the stream, it takes what a delete does
+        ["logstream", stream, "export"] => (Action::Write, Some(stream)),
+        ["logstream", stream, ..] if !stream.is_empty() => (by_method, Some(stream)),
+        ["query"] => (Action::Read, None),
+        _ => (by_method, None),
+    }
+}
+
+/// Whether the API key of the request may take `action` on a stream named in the
+/// request body rather than the path, which only handlers know of.
+pub fn permits(req: &HttpRequest, action: Action, stream_name: &str) -> bool {
+    req.extensions()
+        .get::<Permission>()
+        .map_or(true, |permission| {
+            permission.allows(action, Some(stream_name))
+        })
+}
+
+/// Whether the API key of the request is limited to some streams, so that it
+/// can't take actions on all streams at once.
+pub fn is_scoped(req: &HttpRequest) -> bool {
+    req.extensions().get::<Permission>().is_some()
+}
+
+/// Why a request is turned down
+#[derive(Debug, PartialEq, Eq)]
+pub enum Denied {
+    /// No or wrong credentials, answered with 401
+    Unauthorized,
+    /// A valid API key without permission for the request, answered with 403
+    Forbidden,
+}
+
+/// Who may call the API: the user with the username and password
+/// and anyone with one of the API keys, within its permission.
+pub struct Access<'a> {
+    pub username: &'a str,
+    pub password: &'a str,
+    pub api_keys: &'a [String],
+    pub permissions: &'a Permissions,
+    /// Paths reachable without credentials, like health checks
+    pub exempt_paths: &'a [String],
+}
+
+impl Access<'_> {
+    /// Check the request taking `action` on `stream` is allowed. Returns the
+    /// permission of its API key if it has one, for handlers to check streams
+    /// the path doesn't name.
+    pub fn authorize(
+        &self,
+        path: &str,
+        action: Action,
+        stream: Option<&str>,
+        credentials: &Credentials,
+    ) -> Result<Option<Permission>, Denied> {
+        if self.exempt_paths.iter().any(|exempt| exempt == path) {
+            return Ok(None);
+        }
+
+        match credentials {
+            Credentials::Basic { user_id, password }
+                if user_id.trim() == self.username
+                    && password.as_deref().map(str::trim) == Some(self.password) =>
+            {
+                Ok(None)
+            }
+            Credentials::Bearer(key) => {
+                let api_key = self
+                    .api_keys
+                    .iter()
+                    .find(|api_key| constant_time_eq(api_key.as_bytes(), key.as_bytes()))
+                    .ok_or(Denied::Unauthorized)?;
+
+                match self.permissions.get(api_key) {
+                    None => Ok(None),
+                    Some(permission) if permission.allows(action, stream) => {
+                        Ok(Some(permission.clone()))
+                    }
+                    Some(_) => Err(Denied::Forbidden),
+                }
+            }
+            _ => Err(Denied::Unauthorized),
+        }
+    }
+}
+
+// Compares all bytes of equally long keys, so the time taken doesn't
+// tell how much of a guessed key is right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::dev::{Service, ServiceRequest};
+    use actix_web::http::header::AUTHORIZATION;
+    use actix_web::http::Method;
+    use actix_web::{test, web, App, HttpMessage, HttpRequest, HttpResponse};
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use rstest::*;
+
+    use super::{permits, request_scope, Access, Action, Credentials, Denied, Permissions};
+
+    async fn validator(
+        req: ServiceRequest,
+        credentials: Credentials,
+    ) -> Result<ServiceRequest, actix_web::Error> {
+        let api_keys = [
+            "first-key".to_string(),
+            "second-key".to_string(),
+            "read-key".to_string(),
+            "write-key".to_string(),
+        ];
+        let permissions = r#"{
+            "read-key": {"streams": ["app"], "actions": ["read"]},
+            "write-key": {"streams": ["app"], "actions": ["write"]}
+        }"#
+        .parse::<Permissions>()
+        .unwrap();
+        let exempt_paths = ["/api/v1/liveness".to_string()];
+        let access = Access {
+            username: "admin",
+            password: "admin",
+            api_keys: &api_keys,
+            permissions: &permissions,
+            exempt_paths: &exempt_paths,
+        };
+
+        let path = req.path().to_string();
+        let (action, stream) = request_scope(req.method(), path.trim_start_matches("/api/v1"));
+        match access.authorize(&path, action, stream, &credentials) {
+            Ok(Some(permission)) => {
+                req.extensions_mut().insert(permission);
+                Ok(req)
+            }
+            Ok(None) => Ok(req),
+            Err(Denied::Unauthorized) => Err(actix_web::error::ErrorUnauthorized("Unauthorized")),
+            Err(Denied::Forbidden) => Err(actix_web::error::ErrorForbidden("Forbidden")),
+        }
+    }
+
+    // stands in for the query handler, which takes the stream from the body
+    async fn query(req: HttpRequest, stream_name: String) -> HttpResponse {
+        if permits(&req, Action::Read, &stream_name) {
+            HttpResponse::Ok().finish()
+        } else {
+            HttpResponse::Forbidden().finish()
+        }
+    }
+
+    #[actix_web::test]
+    async fn authorize_requests() {
+        let app = test::init_service(
+            App::new().service(
+                web::scope("/api/v1")
+                    .route("/query", web::post().to(query))
+                    .route(
+                        "/logstream/{logstream}",
+                        web::post().to(|| async { HttpResponse::Ok().finish() }),
+                    )
+                    .route(
+                        "/logstream/{logstream}/query",
+                        web::post().to(|| async { HttpResponse::Ok().finish() }),
+                    )
+                    .route(
+                        "/liveness",
+                        web::get().to(|| async { HttpResponse::Ok().finish() }),
+                    )
+                    .wrap(HttpAuthentication::with_fn(validator)),
+            ),
+        )
+        .await;
+
+        let cases = [
+            // valid, missing, wrong and partial API keys
+            ("/api/v1/query", "app", Some("Bearer second-key"), 200),
+            ("/api/v1/query", "app", None, 401),
+            ("/api/v1/query", "app", Some("Bearer third-key"), 401),
+            ("/api/v1/query", "app", Some("Bearer first"), 401),
+            // basic auth with the right and a wrong password
+            ("/api/v1/query", "app", Some("Basic YWRtaW46YWRtaW4="), 200),
+            ("/api/v1/query", "app", Some("Basic YWRtaW46cm9vdA=="), 401),
+            ("/api/v1/liveness", "", None, 200),
+            // a write only key can ingest to its stream, but not query it
+            ("/api/v1/logstream/app", "", Some("Bearer write-key"), 200),
+            (
+                "/api/v1/logstream/app/query",
+                "",
+                Some("Bearer write-key"),
+                403,
+            ),
+            ("/api/v1/query", "app", Some("Bearer write-key"), 403),
+            // nor ingest to other streams
+            ("/api/v1/logstream/other", "", Some("Bearer write-key"), 403),
+            // a read only key can query its stream, but not ingest to it
+            (
+                "/api/v1/logstream/app/query",
+                "",
+                Some("Bearer read-key"),
+                200,
+            ),
+            ("/api/v1/query", "app", Some("Bearer read-key"), 200),
+            ("/api/v1/logstream/app", "", Some("Bearer read-key"), 403),
+            // nor query other streams, named in the path or in the body
+            (
+                "/api/v1/logstream/other/query",
+                "",
+                Some("Bearer read-key"),
+                403,
+            ),
+            ("/api/v1/query", "other", Some("Bearer read-key"), 403),
+        ];
+        for (path, body, authorization, status) in cases {
+            let mut req = if path.ends_with("liveness") {
+                test::TestRequest::get()
+            } else {
+                test::TestRequest::post()
+            }
+            .uri(path)
+            .set_payload(body);
+            if let Some(authorization) = authorization {
+                req = req.insert_header((AUTHORIZATION, authorization));
+            }
+            // rejected requests fail in the middleware, before a response is made
+            let actual = match app.call(req.to_request()).await {
+                Ok(resp) => resp.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+
+            assert_eq!(
+                actual.as_u16(),
+                status,
+                "{} {} {:?}",
+                path,
+                body,
+                authorization
+            );
+        }
+    }
+
+    #[rstest]
+    #[case(Method::GET, "/logstream/stats", Action::Read, None)]
+    #[case(Method::POST, "/logstream/stats", Action::Write, Some("stats"))]
+    #[case(Method::POST, "/logstream/app", Action::Write, Some("app"))]
+    #[case(Method::HEAD, "/logstream/app", Action::Read, Some("app"))]
+    #[case(Method::PUT, "/logstream/app/retention", Action::Write, Some("app"))]
+    #[case(Method::POST, "/logstream/app/query", Action::Read, Some("app"))]
+    #[case(Method::POST, "/logstream/query", Action::Write, Some("query"))]
+    #[case(Method::POST, "/logstream/app/schema/infer", Action::Read, Some("app"))]
+    #[case(Method::GET, "/logstream/app/export", Action::Write, Some("app"))]
+    #[case(Method::DELETE, "/logstream/app", Action::Write, Some("app"))]
+    #[case(Method::POST, "/query", Action::Read, None)]
+    #[case(Method::GET, "/logstream", Action::Read, None)]
+    #[case(Method::POST, "/refresh", Action::Write, None)]
+    fn scope_of_requests(
+        #[case] method: Method,
+        #[case] path: &str,
+        #[case] action: Action,
+        #[case] stream: Option<&str>,
+    ) {
+        assert_eq!(request_scope(&method, path), (action, stream));
+    }
+
+    #[rstest]
+    #[case(r#"{"key": {"streams": ["app"], "actions": ["delete"]}}"#)]
+    #[case(r#"{"key": {"streams": ["app"], "action": ["read"]}}"#)]
+    #[case(r#"{"key": ["app"]}"#)]
+    fn invalid_permissions(#[case] permissions: &str) {
+        assert!(permissions.parse::<Permissions>().is_err());
+    }
+}
@@ -0,0 +1,1256 @@
+/*
+ * Parseable Server (C) 2022 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use arrow::datatypes::Schema;
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use datafusion::arrow::record_batch::RecordBatch;
+use hmac::{Hmac, Mac};
+use reqwest::header::{
+    HeaderName, HeaderValue, AUTHORIZATION, CONTENT_LENGTH, ETAG, IF_MATCH, IF_NONE_MATCH,
+};
+use reqwest::{Client, Method, Request, RequestBuilder, Response, StatusCode, Url};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use structopt::StructOpt;
+use tokio::sync::Mutex;
+
+use crate::alerts::Alerts;
+use crate::metadata::{
+    Compression, Flatten, Limits, PartitionGranularity, Stats, StreamTimestamps,
+};
+use crate::migration::MetadataDocument;
+use crate::option::{StorageOpt, CONFIG};
+use crate::query::Query;
+use crate::retention::Retention;
+use crate::storage::{
+    DeletedObjects, LogStream, ObjectMeta, ObjectStorage, ObjectStorageError, ParquetFile,
+    StoredObjects,
+};
+use crate::utils;
+
+const AZURE_STORAGE_VERSION: &str = "2020-10-02";
+const AZURE_ENDPOINT_SUFFIX: &str = "core.windows.net";
+const AZURE_STORAGE_RESOURCE: &str = "https://storage.azure.com/";
+/// Token endpoint of the instance metadata service, available on Azure VMs and AKS nodes
+const IMDS_TOKEN_URL: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+/// Managed identity tokens are renewed this long before they expire
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(300);
+
+lazy_static::lazy_static! {
+    #[derive(Debug)]
+    pub static ref AZURE_CONFIG: Arc<AzureConfig> = Arc::new(AzureConfig::from_args());
+
+    static ref AZURE_ACCOUNT: AzureAccount = AzureAccount::from_config(&AZURE_CONFIG)
+        .unwrap_or_else(|e| panic!("Invalid Azure Blob Storage configuration. {}", e));
+
+    /// Shared by all storage clients, they are created for each use
+    static ref MANAGED_IDENTITY_TOKEN: Mutex<Option<AccessToken>> = Mutex::new(None);
+}
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(name = "Azure config", about = "configuration for Azure Blob Storage")]
+pub struct AzureConfig {
+    /// The Azure storage account name, required unless a connection string is given
+    #[structopt(long, env = "P_AZR_ACCOUNT")]
+    pub azr_account: Option<String>,
+
+    /// The access key of the Azure storage account. Without an access key
+    /// or connection string the managed identity of the host is used
+    #[structopt(long, env = "P_AZR_ACCESS_KEY")]
+    pub azr_access_key: Option<String>,
+
+    /// The client id of the user assigned managed identity to use,
+    /// when the host has more than one
+    #[structopt(long, env = "P_AZR_CLIENT_ID")]
+    pub azr_client_id: Option<String>,
+
+    /// The connection string of the Azure storage account,
+    /// used instead of the account name and access key
+    #[structopt(long, env = "P_AZR_CONNECTION_STRING")]
+    pub azr_connection_string: Option<String>,
+
+    /// The Azure Blob Storage container to be used for storage
+    #[structopt(long, env = "P_AZR_CONTAINER")]
+    pub azr_container: String,
+}
+
+impl StorageOpt for AzureConfig {
+    fn bucket_name(&self) -> &str {
+        &self.azr_container
+    }
+
+    fn endpoint_url(&self) -> &str {
+        AZURE_ACCOUNT.endpoint.as_str()
+    }
+
+    fn is_default_url(&self) -> bool {
+        false
+    }
+
+    fn warning(&self) {}
+
+    fn object_storage(&self) -> Box<dyn ObjectStorage> {
+        Box::new(AzureBlobStorage::new())
+    }
+}
+
+struct AzureAccount {
+    name: String,
+    credential: Credential,
+    /// Blob service endpoint, always a base URL that container paths are added to
+    endpoint: Url,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Credential {
+    /// Access key of the account, requests are signed with it
+    SharedKey(Vec<u8>),
+    /// Requests carry a token of the managed identity of the host,
+    /// optionally a user assigned one
+    ManagedIdentity { client_id: Option<String> },
+}
+
+impl AzureAccount {
+    fn from_config(config: &AzureConfig) -> Result<Self, String> {
+        match (
+            &config.azr_connection_string,
+            &config.azr_account,
+            &config.azr_access_key,
+        ) {
+            (Some(connection_string), _, _) => Self::from_connection_string(connection_string),
+            (None, Some(name), Some(key)) => Self::new(name.clone(), key, None),
+            (None, Some(name), None) => Self::with_credential(
+                name.clone(),
+                Credential::ManagedIdentity {
+                    client_id: config.azr_client_id.clone(),
+                },
+                None,
+            ),
+            _ => Err("either a connection string or an account name is required".to_string()),
+        }
+    }
+
+    fn new(name: String, key: &str, endpoint: Option<String>) -> Result<Self, String> {
+        let key = base64::decode(key).map_err(|e| format!("invalid access key. {}", e))?;
+        Self::with_credential(name, Credential::SharedKey(key), endpoint)
+    }
+
+    fn with_credential(
+        name: String,
+        credential: Credential,
+        endpoint: Option<String>,
+    ) -> Result<Self, String> {
+        let endpoint =
+            endpoint.unwrap_or_else(|| format!("https://{}.blob.{}", name, AZURE_ENDPOINT_SUFFIX));
+        let endpoint = Url::parse(endpoint.trim_end_matches('/'))
+            .map_err(|e| format!("invalid blob endpoint {}. {}", endpoint, e))?;
+        if endpoint.cannot_be_a_base() {
+            return Err(format!("invalid blob endpoint {}", endpoint));
+        }
+
+        Ok(Self {
+            name,
+            credential,
+            endpoint,
+        })
+    }
+
+    /// Parse a connection string of the form
+    /// `DefaultEndpointsProtocol=https;AccountName=..;AccountKey=..;EndpointSuffix=..`
+    fn from_connection_string(connection_string: &str) -> Result<Self, String> {
+        let mut protocol = "https";
+        let mut suffix = AZURE_ENDPOINT_SUFFIX;
+        let (mut name, mut key, mut blob_endpoint) = (None, None, None);
+
+        for pair in connection_string.split(';').filter(|pair| !pair.is_empty()) {
+            let (k, v) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("invalid connection string entry {}", pair))?;
+            match k {
+                "DefaultEndpointsProtocol" => protocol = v,
+                "AccountName" => name = Some(v),
+                "AccountKey" => key = Some(v),
+                "EndpointSuffix" => suffix = v,
+                "BlobEndpoint" => blob_endpoint = Some(v.to_string()),
+                _ => (),
+            }
+        }
+
+        let name = name.ok_or("connection string is missing AccountName")?;
+        let key = key.ok_or("connection string is missing AccountKey")?;
+        let endpoint =
+            blob_endpoint.unwrap_or_else(|| format!("{}://{}.blob.{}", protocol, name, suffix));
+
+        Self::new(name.to_string(), key, Some(endpoint))
+    }
+}
+
+/// Token response of the instance metadata service
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    /// Unix time in seconds, as a string
+    expires_on: String,
+}
+
+struct AccessToken {
+    token: String,
+    expires_on: DateTime<Utc>,
+}
+
+impl TryFrom<TokenResponse> for AccessToken {
+    type Error = std::num::ParseIntError;
+
+    fn try_from(response: TokenResponse) -> Result<Self, Self::Error> {
+        let expires_on = UNIX_EPOCH + Duration::from_secs(response.expires_on.parse()?);
+        Ok(Self {
+            token: response.access_token,
+            expires_on: expires_on.into(),
+        })
+    }
+}
+
+impl AccessToken {
+    fn is_fresh(&self) -> bool {
+        let margin = chrono::Duration::from_std(TOKEN_REFRESH_MARGIN).unwrap();
+        self.expires_on - margin > Utc::now()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct EnumerationResults {
+    #[serde(default)]
+    blobs: BlobList,
+    next_marker: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+struct BlobList {
+    #[serde(rename = "Blob", default)]
+    blobs: Vec<BlobItem>,
+    #[serde(rename = "BlobPrefix", default)]
+    prefixes: Vec<BlobItem>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct BlobItem {
+    name: String,
+    /// not present on blob prefixes
+    properties: Option<BlobProperties>,
+}
+
+#[derive(Deserialize)]
+struct BlobProperties {
+    #[serde(rename = "Content-Length", default)]
+    content_length: u64,
+}
+
+pub struct AzureBlobStorage {
+    client: Client,
+}
+
+impl AzureBlobStorage {
+    pub fn new() -> Self {
+        Self {
+            client: Client::new(),
+        }
+    }
+
+    fn container_url(&self) -> Url {
+        let mut url = AZURE_ACCOUNT.endpoint.clone();
+        url.path_segments_mut()
+            .expect("endpoint is a base URL")
+            .push(&AZURE_CONFIG.azr_container);
+        url
+    }
+
+    fn blob_url(&self, key: &str) -> Url {
+        let mut url = self.container_url();
+        url.path_segments_mut()
+            .expect("endpoint is a base URL")
+            .extend(key.split('/'));
+        url
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        url: Url,
+        body: Option<Vec<u8>>,
+    ) -> Result<Response, ObjectStorageError> {
+        let mut builder = self.client.request(method, url);
+        if let Some(body) = body {
+            builder = builder.header("x-ms-blob-type", "BlockBlob").body(body);
+        }
+
+        self.execute(builder).await
+    }
+
+    /// Authorize the request, by signing it with the account access key or with a
+    /// token of the managed identity, and send it.
+    /// See https://learn.microsoft.com/en-us/rest/api/storageservices/authorize-with-shared-key
+    async fn execute(&self, builder: RequestBuilder) -> Result<Response, ObjectStorageError> {
+        let mut request = builder
+            .header(
+                "x-ms-date",
+                Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+            )
+            .header("x-ms-version", AZURE_STORAGE_VERSION)
+            .build()?;
+        let authorization = match &AZURE_ACCOUNT.credential {
+            Credential::SharedKey(key) => {
+                format!("SharedKey {}:{}", AZURE_ACCOUNT.name, sign(&request, key))
+            }
+            Credential::ManagedIdentity { client_id } => {
+                format!("Bearer {}", self.access_token(client_id.as_deref()).await?)
+            }
+        };
+        request.headers_mut().insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&authorization)
+                .map_err(|e| ObjectStorageError::UnhandledError(e.into()))?,
+        );
+
+        Ok(self.client.execute(request).await?)
+    }
+
+    /// Token of the managed identity for the storage service, fetched from the
+    /// instance metadata service and reused until shortly before it expires.
+    /// See https://learn.microsoft.com/en-us/azure/active-directory/managed-identities-azure-resources/how-to-use-vm-token
+    async fn access_token(&self, client_id: Option<&str>) -> Result<String, ObjectStorageError> {
+        let mut cached = MANAGED_IDENTITY_TOKEN.lock().await;
+        if let Some(token) = cached.as_ref().filter(|token| token.is_fresh()) {
+            return Ok(token.token.clone());
+        }
+
+        let mut url = Url::parse(IMDS_TOKEN_URL).unwrap();
+        {
+            let mut query = url.query_pairs_mut();
+            query
+                .append_pair("api-version", "2018-02-01")
+                .append_pair("resource", AZURE_STORAGE_RESOURCE);
+            if let Some(client_id) = client_id {
+                query.append_pair("client_id", client_id);
+            }
+        }
+
+        let response: TokenResponse = self
+            .client
+            .get(url)
+            .header("Metadata", "true")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let token = AccessToken::try_from(response)
+            .map_err(|e| ObjectStorageError::UnhandledError(e.into()))?;
+        let value = token.token.clone();
+        *cached = Some(token);
+
+        Ok(value)
+    }
+
+    async fn _get(&self, key: &str) -> Result<Bytes, ObjectStorageError> {
+        let resp = self.send(Method::GET, self.blob_url(key), None).await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Err(ObjectStorageError::NoSuchKey(key.to_string()));
+        }
+
+        Ok(resp.error_for_status()?.bytes().await?)
+    }
+
+    async fn _put(&self, key: &str, body: Vec<u8>) -> Result<(), ObjectStorageError> {
+        self.send(Method::PUT, self.blob_url(key), Some(body))
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    /// Copy the blob within the container. Copies within a storage account
+    /// complete before the response is sent.
+    async fn _copy(&self, from: &str, to: &str) -> Result<(), ObjectStorageError> {
+        let builder = self
+            .client
+            .put(self.blob_url(to))
+            .header("x-ms-copy-source", self.blob_url(from).as_str())
+            .body(Vec::new());
+
+        self.execute(builder).await?.error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn _delete(&self, key: &str) -> Result<(), ObjectStorageError> {
+        let resp = self.send(Method::DELETE, self.blob_url(key), None).await?;
+        // blob is already gone
+        if resp.status() != StatusCode::NOT_FOUND {
+            resp.error_for_status()?;
+        }
+
+        Ok(())
+    }
+
+    /// List all blobs under `prefix`. With a `delimiter`, blobs nested deeper
+    /// are not listed and their common prefixes are returned instead.
+    async fn _list(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+    ) -> Result<(Vec<BlobItem>, Vec<String>), ObjectStorageError> {
+        let mut blobs = Vec::new();
+        let mut prefixes = Vec::new();
+        let mut marker: Option<String> = None;
+
+        loop {
+            let page = self
+                ._list_page(prefix, delimiter, marker.as_deref())
+                .await?;
+
+            blobs.extend(page.blobs.blobs);
+            prefixes.extend(page.blobs.prefixes.into_iter().map(|prefix| prefix.name));
+
+            match page.next_marker.filter(|marker| !marker.is_empty()) {
+                Some(next) => marker = Some(next),
+                None => break,
+            }
+        }
+
+        Ok((blobs, prefixes))
+    }
+
+    async fn _list_page(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+        marker: Option<&str>,
+    ) -> Result<EnumerationResults, ObjectStorageError> {
+        let mut url = self.container_url();
+        {
+            let mut query = url.query_pairs_mut();
+            query
+                .append_pair("restype", "container")
+                .append_pair("comp", "list")
+                .append_pair("prefix", prefix);
+            if let Some(delimiter) = delimiter {
+                query.append_pair("delimiter", delimiter);
+            }
+            if let Some(marker) = marker {
+                query.append_pair("marker", marker);
+            }
+        }
+
+        let body = self
+            .send(Method::GET, url, None)
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        quick_xml::de::from_str(&body).map_err(|e| ObjectStorageError::UnhandledError(e.into()))
+    }
+
+    async fn query_in_dir(
+        &self,
+        query: &Query,
+        dir: &Path,
+        results: &mut Vec<RecordBatch>,
+    ) -> Result<(), ObjectStorageError> {
+        let mut downloaded = 0;
+        for prefix in self
+            .query_partitions(&query.stream_name, query.start, query.end)
+            .await?
+        {
+            let (blobs, _) = self._list(&prefix, None).await?;
+            for blob in blobs.iter().filter(|blob| blob.name.ends_with(".parquet")) {
+                let body = self._get(&blob.name).await?;
+                fs::write(dir.join(blob.name.replace('/', ".")), body)?;
+                downloaded += 1;
+            }
+        }
+
+        if downloaded > 0 {
+            query
+                .execute_on_dir(&dir.display().to_string(), results)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Signature of the request for Shared Key authorization
+fn sign(request: &Request, key: &[u8]) -> String {
+    let headers = request.headers();
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+    };
+
+    let content_length = request
+        .body()
+        .and_then(|body| body.as_bytes())
+        .map(|body| body.len())
+        .filter(|len| *len > 0)
+        .map(|len| len.to_string())
+        .unwrap_or_default();
+
+    let mut ms_headers = headers
+        .iter()
+        .filter(|(name, _)| name.as_str().starts_with("x-ms-"))
+        .map(|(name, value)| {
+            format!(
+                "{}:{}\n",
+                name.as_str(),
+                value.to_str().unwrap_or_default().trim()
+            )
+        })
+        .collect::<Vec<_>>();
+    ms_headers.sort();
+
+    let url = request.url();
+    let mut resource = format!("/{}{}", AZURE_ACCOUNT.name, url.path());
+    let mut params = url
+        .query_pairs()
+        .map(|(k, v)| (k.to_lowercase(), v.into_owned()))
+        .collect::<Vec<_>>();
+    params.sort();
+    for (k, v) in params {
+        resource.push_str(&format!("\n{}:{}", k, v));
+    }
+
+    let string_to_sign = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}\n\n{}\n{}\n{}\n{}\n{}\n{}{}",
+        request.method().as_str(),
+        header("content-encoding"),
+        header("content-language"),
+        content_length,
+        header("content-md5"),
+        header("content-type"),
+        header("if-modified-since"),
+        header("if-match"),
+        header("if-none-match"),
+        header("if-unmodified-since"),
+        header("range"),
+        ms_headers.concat(),
+        resource
+    );
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC can take key of any size");
+    mac.update(string_to_sign.as_bytes());
+    base64::encode(mac.finalize().into_bytes())
+}
+
+#[async_trait]
+impl ObjectStorage for AzureBlobStorage {
+    async fn check(&self) -> Result<(), ObjectStorageError> {
+        let mut url = self.container_url();
+        url.query_pairs_mut().append_pair("restype", "container");
+
+        let resp = self.send(Method::GET, url, None).await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Err(ObjectStorageError::NoSuchBucket(
+                AZURE_CONFIG.azr_container.clone(),
+            ));
+        }
+        if matches!(
+            resp.status(),
+            StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED
+        ) {
+            return Err(ObjectStorageError::AccessDenied(
+                AZURE_CONFIG.azr_container.clone(),
+            ));
+        }
+        resp.error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn put_schema(
+        &self,
+        stream_name: String,
+        schema: &Schema,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(schema)?;
+        self._put(&format!("{}/.schema", stream_name), body).await
+    }
+
+    async fn create_stream(&self, stream_name: &str) -> Result<(), ObjectStorageError> {
+        self._put(&format!("{}/.schema", stream_name), Vec::new())
+            .await?;
+        // Prefix created on Azure, now create the directory in
+        // the local storage as well
+        let _res = fs::create_dir_all(CONFIG.parseable.local_stream_data_path(stream_name));
+
+        Ok(())
+    }
+
+    async fn create_alert(
+        &self,
+        stream_name: &str,
+        alerts: &Alerts,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(alerts)?;
+        self._put(&format!("{}/.alert.json", stream_name), body)
+            .await
+    }
+
+    async fn get_schema(&self, stream_name: &str) -> Result<Bytes, ObjectStorageError> {
+        self._get(&format!("{}/.schema", stream_name)).await
+    }
+
+    async fn get_schema_tagged(
+        &self,
+        stream_name: &str,
+    ) -> Result<Option<(Bytes, String)>, ObjectStorageError> {
+        let key = format!("{}/.schema", stream_name);
+        let resp = self.send(Method::GET, self.blob_url(&key), None).await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let resp = resp.error_for_status()?;
+        let etag = resp
+            .headers()
+            .get(ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+
+        Ok(Some((resp.bytes().await?, etag)))
+    }
+
+    // See https://learn.microsoft.com/en-us/rest/api/storageservices/specifying-conditional-headers-for-blob-service-operations
+    async fn put_schema_if(
+        &self,
+        stream_name: &str,
+        schema: &Schema,
+        tag: Option<&str>,
+    ) -> Result<(), ObjectStorageError> {
+        let key = format!("{}/.schema", stream_name);
+        let body = serde_json::to_vec(schema)?;
+        let builder = self
+            .client
+            .put(self.blob_url(&key))
+            .header("x-ms-blob-type", "BlockBlob")
+            .body(body);
+        let builder = match tag {
+            Some(tag) => builder.header(IF_MATCH, tag),
+            None => builder.header(IF_NONE_MATCH, "*"),
+        };
+
+        let resp = self.execute(builder).await?;
+        // a blob that exists fails If-None-Match with a conflict
+        if matches!(
+            resp.status(),
+            StatusCode::PRECONDITION_FAILED | StatusCode::CONFLICT
+        ) {
+            return Err(ObjectStorageError::PreconditionFailed(key));
+        }
+        resp.error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn get_alert(&self, stream_name: &str) -> Result<Bytes, ObjectStorageError> {
+        self._get(&format!("{}/.alert.json", stream_name)).await
+    }
+
+    async fn get_stats(&self, stream_name: &str) -> Result<Stats, ObjectStorageError> {
+        let body = self._get(&format!("{}/.stats.json", stream_name)).await?;
+        let stats = serde_json::from_slice(&body)?;
+
+        Ok(stats)
+    }
+
+    async fn put_stats(&self, stream_name: &str, stats: &Stats) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(stats)?;
+        self._put(&format!("{}/.stats.json", stream_name), body)
+            .await
+    }
+
+    async fn put_retention(
+        &self,
+        stream_name: &str,
+        retention: &Retention,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(retention)?;
+        self._put(&format!("{}/.retention.json", stream_name), body)
+            .await
+    }
+
+    async fn get_retention(&self, stream_name: &str) -> Result<Retention, ObjectStorageError> {
+        let body = self
+            ._get(&format!("{}/.retention.json", stream_name))
+            .await?;
+        let retention = serde_json::from_slice(&body)?;
+
+        Ok(retention)
+    }
+
+    async fn put_tags(
+        &self,
+        stream_name: &str,
+        tags: &HashMap<String, String>,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(tags)?;
+        self._put(&format!("{}/.tags.json", stream_name), body)
+            .await
+    }
+
+    async fn get_tags(
+        &self,
+        stream_name: &str,
+    ) -> Result<HashMap<String, String>, ObjectStorageError> {
+        let body = self._get(&format!("{}/.tags.json", stream_name)).await?;
+        let tags = serde_json::from_slice(&body)?;
+
+        Ok(tags)
+    }
+
+    async fn put_static_schema(
+        &self,
+        stream_name: &str,
+        static_schema: bool,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(&static_schema)?;
+        self._put(&format!("{}/.static_schema.json", stream_name), body)
+            .await
+    }
+
+    async fn get_static_schema(&self, stream_name: &str) -> Result<bool, ObjectStorageError> {
+        let body = self
+            ._get(&format!("{}/.static_schema.json", stream_name))
+            .await?;
+        let static_schema = serde_json::from_slice(&body)?;
+
+        Ok(static_schema)
+    }
+
+    async fn put_deleted_at(
+        &self,
+        stream_name: &str,
+        deleted_at: Option<DateTime<Utc>>,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(&deleted_at)?;
+        self._put(&format!("{}/.deleted.json", stream_name), body)
+            .await
+    }
+
+    async fn get_deleted_at(
+        &self,
+        stream_name: &str,
+    ) -> Result<Option<DateTime<Utc>>, ObjectStorageError> {
+        let body = self._get(&format!("{}/.deleted.json", stream_name)).await?;
+        let deleted_at = serde_json::from_slice(&body)?;
+
+        Ok(deleted_at)
+    }
+
+    async fn put_renamed_from(
+        &self,
+        stream_name: &str,
+        renamed_from: &str,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(renamed_from)?;
+        self._put(&format!("{}/.renamed_from.json", stream_name), body)
+            .await
+    }
+
+    async fn get_renamed_from(&self, stream_name: &str) -> Result<String, ObjectStorageError> {
+        let body = self
+            ._get(&format!("{}/.renamed_from.json", stream_name))
+            .await?;
+        let renamed_from = serde_json::from_slice(&body)?;
+
+        Ok(renamed_from)
+    }
+
+    async fn put_time_field(
+        &self,
+        stream_name: &str,
+        time_field: &str,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(time_field)?;
+        self._put(&format!("{}/.time_field.json", stream_name), body)
+            .await
+    }
+
+    async fn get_time_field(&self, stream_name: &str) -> Result<String, ObjectStorageError> {
+        let body = self
+            ._get(&format!("{}/.time_field.json", stream_name))
+            .await?;
+        let time_field = serde_json::from_slice(&body)?;
+
+        Ok(time_field)
+    }
+
+    async fn put_limits(
+        &self,
+        stream_name: &str,
+        limits: &Limits,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(limits)?;
+        self._put(&format!("{}/.limits.json", stream_name), body)
+            .await
+    }
+
+    async fn get_limits(&self, stream_name: &str) -> Result<Limits, ObjectStorageError> {
+        let body = self._get(&format!("{}/.limits.json", stream_name)).await?;
+        let limits = serde_json::from_slice(&body)?;
+
+        Ok(limits)
+    }
+
+    async fn put_compression(
+        &self,
+        stream_name: &str,
+        compression: Compression,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(&compression)?;
+        self._put(&format!("{}/.compression.json", stream_name), body)
+            .await
+    }
+
+    async fn get_compression(&self, stream_name: &str) -> Result<Compression, ObjectStorageError> {
+        let body = self
+            ._get(&format!("{}/.compression.json", stream_name))
+            .await?;
+        let compression = serde_json::from_slice(&body)?;
+
+        Ok(compression)
+    }
+
+    async fn put_flatten(
+        &self,
+        stream_name: &str,
+        flatten: Flatten,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(&flatten)?;
+        self._put(&format!("{}/.flatten.json", stream_name), body)
+            .await
+    }
+
+    async fn get_flatten(&self, stream_name: &str) -> Result<Flatten, ObjectStorageError> {
+        let body = self._get(&format!("{}/.flatten.json", stream_name)).await?;
+        let flatten = serde_json::from_slice(&body)?;
+
+        Ok(flatten)
+    }
+
+    async fn put_partition(
+        &self,
+        stream_name: &str,
+        partition: PartitionGranularity,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(&partition)?;
+        self._put(&format!("{}/.partition.json", stream_name), body)
+            .await
+    }
+
+    async fn get_partition(
+        &self,
+        stream_name: &str,
+    ) -> Result<PartitionGranularity, ObjectStorageError> {
+        let body = self
+            ._get(&format!("{}/.partition.json", stream_name))
+            .await?;
+        let partition = serde_json::from_slice(&body)?;
+
+        Ok(partition)
+    }
+
+    async fn put_metadata(
+        &self,
+        stream_name: &str,
+        document: &MetadataDocument,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(document)?;
+        self._put(&format!("{}/.metadata.json", stream_name), body)
+            .await
+    }
+
+    async fn get_metadata(
+        &self,
+        stream_name: &str,
+    ) -> Result<MetadataDocument, ObjectStorageError> {
+        let body = self
+            ._get(&format!("{}/.metadata.json", stream_name))
+            .await?;
+        let document = serde_json::from_slice(&body)?;
+
+        Ok(document)
+    }
+
+    async fn put_timestamps(
+        &self,
+        stream_name: &str,
+        timestamps: &StreamTimestamps,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(timestamps)?;
+        self._put(&format!("{}/.timestamps.json", stream_name), body)
+            .await
+    }
+
+    async fn get_timestamps(
+        &self,
+        stream_name: &str,
+    ) -> Result<StreamTimestamps, ObjectStorageError> {
+        let body = self
+            ._get(&format!("{}/.timestamps.json", stream_name))
+            .await?;
+        let timestamps = serde_json::from_slice(&body)?;
+
+        Ok(timestamps)
+    }
+
+    async fn list_streams(&self) -> Result<Vec<LogStream>, ObjectStorageError> {
+        let (_, prefixes) = self._list("", Some("/")).await?;
+        let streams = prefixes
+            .into_iter()
+            .map(|prefix| LogStream {
+                name: prefix.trim_end_matches('/').to_string(),
+            })
+            .collect();
+
+        Ok(streams)
+    }
+
+    async fn list_dirs(&self, prefix: &str) -> Result<Vec<String>, ObjectStorageError> {
+        let (_, prefixes) = self._list(prefix, Some("/")).await?;
+        let dirs = prefixes
+            .iter()
+            .map(|dir| {
+                dir.trim_start_matches(prefix)
+                    .trim_end_matches('/')
+                    .to_string()
+            })
+            .collect();
+
+        Ok(dirs)
+    }
+
+    async fn put_object(&self, key: &str, body: Bytes) -> Result<(), ObjectStorageError> {
+        let resp = self
+            .send(Method::PUT, self.blob_url(key), Some(body.to_vec()))
+            .await?;
+        if matches!(
+            resp.status(),
+            StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED
+        ) {
+            return Err(ObjectStorageError::AccessDenied(
+                AZURE_CONFIG.azr_container.clone(),
+            ));
+        }
+        resp.error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn get_object(&self, key: &str) -> Result<Bytes, ObjectStorageError> {
+        self._get(key).await
+    }
+
+    async fn upload_file(&self, key: &str, path: &str) -> Result<(), ObjectStorageError> {
+        let body = fs::read(path)?;
+        self._put(key, body).await
+    }
+
+    async fn object_meta(&self, key: &str) -> Result<ObjectMeta, ObjectStorageError> {
+        let resp = self.send(Method::HEAD, self.blob_url(key), None).await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Err(ObjectStorageError::NoSuchKey(key.to_string()));
+        }
+        let resp = resp.error_for_status()?;
+        let header = |name: HeaderName| {
+            resp.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+
+        // Content-MD5 is base64 encoded, blobs put in blocks have none
+        Ok(ObjectMeta {
+            size: header(CONTENT_LENGTH)
+                .and_then(|len| len.parse().ok())
+                .unwrap_or_default(),
+            md5: header(HeaderName::from_static("content-md5"))
+                .and_then(|md5| base64::decode(md5).ok())
+                .and_then(|md5| md5.try_into().ok()),
+        })
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> Result<DeletedObjects, ObjectStorageError> {
+        let (blobs, _) = self._list(prefix, None).await?;
+
+        let mut deleted = DeletedObjects::default();
+        for blob in blobs {
+            // carry on with the rest, so that as little as possible is left behind
+            match self._delete(&blob.name).await {
+                Ok(()) => {
+                    deleted.objects += 1;
+                    deleted.size += blob.properties.map_or(0, |props| props.content_length);
+                }
+                Err(e) => {
+                    log::warn!("failed to delete object {}. {:?}", blob.name, e);
+                    deleted.failed += 1;
+                }
+            }
+        }
+
+        deleted.complete()
+    }
+
+    async fn copy_prefix(&self, from: &str, to: &str) -> Result<u64, ObjectStorageError> {
+        let mut copied = 0;
+        let mut marker: Option<String> = None;
+
+        loop {
+            let page = self._list_page(from, None, marker.as_deref()).await?;
+            for blob in page.blobs.blobs {
+                let target = format!(
+                    "{}{}",
+                    to,
+                    blob.name.strip_prefix(from).unwrap_or(&blob.name)
+                );
+                self._copy(&blob.name, &target).await?;
+                copied += 1;
+            }
+
+            match page.next_marker.filter(|marker| !marker.is_empty()) {
+                Some(next) => marker = Some(next),
+                None => break,
+            }
+        }
+
+        Ok(copied)
+    }
+
+    async fn count_objects(&self, prefix: &str) -> Result<u64, ObjectStorageError> {
+        let (blobs, _) = self._list(prefix, None).await?;
+
+        Ok(blobs.len() as u64)
+    }
+
+    async fn parquet_objects(&self, prefix: &str) -> Result<StoredObjects, ObjectStorageError> {
+        let mut stored = StoredObjects::default();
+        let mut marker: Option<String> = None;
+
+        // summed up page by page, a stream can have a lot of blobs
+        loop {
+            let page = self._list_page(prefix, None, marker.as_deref()).await?;
+            for blob in page.blobs.blobs {
+                if blob.name.ends_with(".parquet") {
+                    stored.objects += 1;
+                    stored.size += blob.properties.map_or(0, |props| props.content_length);
+                }
+            }
+
+            match page.next_marker.filter(|marker| !marker.is_empty()) {
+                Some(next) => marker = Some(next),
+                None => break,
+            }
+        }
+
+        Ok(stored)
+    }
+
+    async fn list_parquet_files(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<ParquetFile>, ObjectStorageError> {
+        let mut files = Vec::new();
+        let mut marker: Option<String> = None;
+
+        loop {
+            let page = self._list_page(prefix, None, marker.as_deref()).await?;
+            for blob in page.blobs.blobs {
+                if blob.name.ends_with(".parquet") {
+                    files.push(ParquetFile {
+                        size: blob.properties.map_or(0, |props| props.content_length),
+                        key: blob.name,
+                    });
+                }
+            }
+
+            match page.next_marker.filter(|marker| !marker.is_empty()) {
+                Some(next) => marker = Some(next),
+                None => break,
+            }
+        }
+
+        Ok(files)
+    }
+
+    async fn query(
+        &self,
+        query: &Query,
+        results: &mut Vec<RecordBatch>,
+    ) -> Result<(), ObjectStorageError> {
+        // DataFusion can't read from Azure directly, so parquet files of the
+        // queried prefixes are downloaded to a local directory first.
+        let dir = std::env::temp_dir().join(format!("parseable-{}", utils::random_string()));
+        fs::create_dir_all(&dir)?;
+
+        let result = self.query_in_dir(query, &dir, results).await;
+        if let Err(e) = fs::remove_dir_all(&dir) {
+            log::warn!("failed to remove query directory {}. {}", dir.display(), e);
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::*;
+
+    use super::*;
+
+    #[test]
+    fn parse_connection_string() {
+        let account = AzureAccount::from_connection_string(
+            "DefaultEndpointsProtocol=https;AccountName=parseable;AccountKey=a2V5;EndpointSuffix=core.windows.net",
+        )
+        .unwrap();
+
+        assert_eq!(account.name, "parseable");
+        assert_eq!(account.credential, Credential::SharedKey(b"key".to_vec()));
+        assert_eq!(
+            account.endpoint.as_str(),
+            "https://parseable.blob.core.windows.net/"
+        );
+    }
+
+    #[test]
+    fn parse_connection_string_with_blob_endpoint() {
+        let account = AzureAccount::from_connection_string(
+            "AccountName=devstoreaccount1;AccountKey=a2V5;BlobEndpoint=http://127.0.0.1:10000/devstoreaccount1/",
+        )
+        .unwrap();
+
+        assert_eq!(
+            account.endpoint.as_str(),
+            "http://127.0.0.1:10000/devstoreaccount1"
+        );
+    }
+
+    #[rstest]
+    #[case::not_a_url("BlobEndpoint=devstoreaccount1")]
+    #[case::not_a_base("BlobEndpoint=mailto:devstoreaccount1")]
+    fn parse_connection_string_with_invalid_blob_endpoint(#[case] blob_endpoint: &str) {
+        let connection_string = format!(
+            "AccountName=devstoreaccount1;AccountKey=a2V5;{}",
+            blob_endpoint
+        );
+
+        assert!(AzureAccount::from_connection_string(&connection_string).is_err());
+    }
+
+    #[test]
+    fn parse_connection_string_without_key() {
+        assert!(AzureAccount::from_connection_string("AccountName=parseable").is_err());
+    }
+
+    #[test]
+    fn account_without_key_uses_managed_identity() {
+        let config = AzureConfig {
+            azr_account: Some("parseable".to_string()),
+            azr_access_key: None,
+            azr_client_id: Some("client".to_string()),
+            azr_connection_string: None,
+            azr_container: "logs".to_string(),
+        };
+        let account = AzureAccount::from_config(&config).unwrap();
+
+        assert_eq!(
+            account.credential,
+            Credential::ManagedIdentity {
+                client_id: Some("client".to_string())
+            }
+        );
+        assert_eq!(
+            account.endpoint.as_str(),
+            "https://parseable.blob.core.windows.net/"
+        );
+    }
+
+    #[test]
+    fn parse_token_response() {
+        let body = r#"{
+            "access_token": "token",
+            "expires_in": "86399",
+            "expires_on": "1665831000",
+            "resource": "https://storage.azure.com/",
+            "token_type": "Bearer"
+        }"#;
+
+        let response: TokenResponse = serde_json::from_str(body).unwrap();
+        let token = AccessToken::try_from(response).unwrap();
+
+        assert_eq!(token.token, "token");
+        assert_eq!(token.expires_on.to_rfc3339(), "2022-10-15T10:50:00+00:00");
+        assert!(!token.is_fresh());
+    }
+
+    #[test]
+    fn parse_list_blobs() {
+        let body = r#"<?xml version="1.0" encoding="utf-8"?>
+            <EnumerationResults ServiceEndpoint="https://parseable.blob.core.windows.net/" ContainerName="logs">
+                <Prefix>stream/</Prefix>
+                <Delimiter>/</Delimiter>
+                <Blobs>
+                    <Blob><Name>stream/.schema</Name><Properties /></Blob>
+                    <BlobPrefix><Name>stream/date=2022-10-15/</Name></BlobPrefix>
+                    <Blob><Name>stream/.stats.json</Name><Properties><Content-Length>42</Content-Length></Properties></Blob>
+                </Blobs>
+                <NextMarker />
+            </EnumerationResults>"#;
+
+        let page: EnumerationResults = quick_xml::de::from_str(body).unwrap();
+        let blobs = page
+            .blobs
+            .blobs
+            .iter()
+            .map(|blob| blob.name.as_str())
+            .collect::<Vec<_>>();
+
+        assert_eq!(blobs, vec!["stream/.schema", "stream/.stats.json"]);
+        assert_eq!(
+            page.blobs.blobs[1]
+                .properties
+                .as_ref()
+                .unwrap()
+                .content_length,
+            42
+        );
+        assert_eq!(page.blobs.prefixes[0].name, "stream/date=2022-10-15/");
+        assert!(page.next_marker.unwrap_or_default().is_empty());
+    }
+}
@@ -0,0 +1,192 @@
+/*
+ * Parseable Server (C) 2022 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use arrow::datatypes::SchemaRef;
+use arrow::record_batch::RecordBatch;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    pub static ref EVENT_BUFFER: EventBuffer = EventBuffer::default();
+}
+
+/// Events of a log stream, or of a partition of it, read into record batches
+/// but not written to its data file yet.
+#[derive(Debug)]
+pub struct BufferedEvents {
+    pub stream_name: String,
+    pub partition: Option<String>,
+    /// Schema of the latest record batch, earlier ones are adapted to it when written
+    pub schema: SchemaRef,
+    pub batches: Vec<RecordBatch>,
+    /// Size in bytes of the events as received
+    pub size: u64,
+    pub events: u64,
+    since: Instant,
+}
+
+/// Buffers events in memory, so that they are written to parquet in fewer, larger writes.
+#[derive(Debug, Default)]
+pub struct EventBuffer {
+    buffers: Mutex<HashMap<(String, Option<String>), BufferedEvents>>,
+}
+
+impl EventBuffer {
+    /// Add `events` events of `size` bytes, read into the record batch, to the buffer of
+    /// the stream or its partition. Returns the buffered events once they reach
+    /// `flush_size` bytes, they are to be written right away then.
+    #[allow(clippy::too_many_arguments)]
+    pub fn push(
+        &self,
+        stream_name: &str,
+        partition: Option<&str>,
+        schema: SchemaRef,
+        rb: RecordBatch,
+        size: u64,
+        events: u64,
+        flush_size: u64,
+    ) -> Option<BufferedEvents> {
+        let mut buffers = self.buffers.lock().unwrap();
+        let key = (stream_name.to_owned(), partition.map(str::to_owned));
+        let buffered = buffers
+            .entry(key.clone())
+            .or_insert_with(|| BufferedEvents {
+                stream_name: stream_name.to_owned(),
+                partition: partition.map(str::to_owned),
+                schema: schema.clone(),
+                batches: Vec::new(),
+                size: 0,
+                events: 0,
+                since: Instant::now(),
+            });
+
+        buffered.schema = schema;
+        buffered.batches.push(rb);
+        buffered.size += size;
+        buffered.events += events;
+
+        if buffered.size >= flush_size {
+            return buffers.remove(&key);
+        }
+
+        None
+    }
+
+    /// Take the buffers holding events for `max_age` or longer.
+    pub fn take_expired(&self, max_age: Duration) -> Vec<BufferedEvents> {
+        let mut buffers = self.buffers.lock().unwrap();
+        let expired = buffers
+            .iter()
+            .filter(|(_, buffered)| buffered.since.elapsed() >= max_age)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+
+        expired
+            .iter()
+            .filter_map(|key| buffers.remove(key))
+            .collect()
+    }
+
+    /// Take the buffers of the stream, of all its partitions.
+    pub fn take_stream(&self, stream_name: &str) -> Vec<BufferedEvents> {
+        let mut buffers = self.buffers.lock().unwrap();
+        let keys = buffers
+            .keys()
+            .filter(|(name, _)| name == stream_name)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        keys.iter().filter_map(|key| buffers.remove(key)).collect()
+    }
+
+    /// Take all buffers, e.g. to write them before the server shuts down.
+    pub fn take_all(&self) -> Vec<BufferedEvents> {
+        self.buffers
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, buffered)| buffered)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::Int64Array;
+    use arrow::datatypes::{DataType, Field, Schema};
+    use std::sync::Arc;
+
+    fn record_batch(rows: i64) -> (SchemaRef, RecordBatch) {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int64, true)]));
+        let column = Int64Array::from((0..rows).collect::<Vec<_>>());
+        let rb = RecordBatch::try_new(schema.clone(), vec![Arc::new(column)]).unwrap();
+        (schema, rb)
+    }
+
+    #[test]
+    fn flush_once_size_is_reached() {
+        let buffer = EventBuffer::default();
+        let (schema, rb) = record_batch(2);
+
+        for _ in 0..2 {
+            let flushed = buffer.push("stream", None, schema.clone(), rb.clone(), 400, 2, 1000);
+            assert!(flushed.is_none());
+        }
+        let flushed = buffer
+            .push("stream", None, schema, rb, 400, 2, 1000)
+            .unwrap();
+
+        assert_eq!(flushed.batches.len(), 3);
+        assert_eq!(flushed.size, 1200);
+        assert_eq!(flushed.events, 6);
+        assert!(buffer.take_all().is_empty());
+    }
+
+    #[test]
+    fn partitions_are_buffered_apart() {
+        let buffer = EventBuffer::default();
+        let (schema, rb) = record_batch(1);
+        let partition = Some("date=2022-10-15.hour=10.minute=30.");
+
+        buffer.push("stream", None, schema.clone(), rb.clone(), 10, 1, 1000);
+        buffer.push("stream", partition, schema.clone(), rb.clone(), 10, 1, 1000);
+        buffer.push("other", None, schema, rb, 10, 1, 1000);
+
+        let mut taken = buffer.take_stream("stream");
+        taken.sort_by(|a, b| a.partition.cmp(&b.partition));
+        assert_eq!(taken.len(), 2);
+        assert_eq!(taken[1].partition.as_deref(), partition);
+        assert_eq!(buffer.take_all().len(), 1);
+    }
+
+    #[test]
+    fn take_expired_buffers() {
+        let buffer = EventBuffer::default();
+        let (schema, rb) = record_batch(1);
+        buffer.push("stream", None, schema, rb, 10, 1, 1000);
+
+        assert!(buffer.take_expired(Duration::from_secs(60)).is_empty());
+        let expired = buffer.take_expired(Duration::ZERO);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].stream_name, "stream");
+        assert!(buffer.take_all().is_empty());
+    }
+}
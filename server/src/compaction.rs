@@ -0,0 +1,584 @@
+/*
+ * Parseable Server (C) 2022 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use arrow::datatypes::SchemaRef;
+use chrono::{DateTime, Duration, Timelike, Utc};
+use log::{info, warn};
+use parquet::arrow::arrow_writer::ArrowWriter;
+use parquet::arrow::{ArrowReader, ParquetFileArrowReader};
+use parquet::file::properties::WriterProperties;
+use parquet::file::reader::SerializedFileReader;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::event;
+use crate::manifest::{self, ManifestFile};
+use crate::metadata::{Compression, STREAM_INFO};
+use crate::storage::{self, FileDigest, ObjectStorage, ObjectStorageError, ParquetFile};
+use crate::utils;
+use crate::Error;
+
+/// Days of partitions looked at by a run, counting today. Partitions of earlier days
+/// were compacted by earlier runs.
+const COMPACTION_DAYS: i64 = 2;
+
+/// Record of a compaction in progress, put next to the stream before the merged
+/// object is uploaded and deleted once its sources are.
+///
+/// From the upload of the merged object until its sources are deleted, queries of the
+/// partition read the rows of the sources twice. Rows are never missed, the sources are
+/// only deleted once the merged object is verified. A compaction interrupted by a
+/// restart is completed from the manifest by the next run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompactionManifest {
+    /// Key of the merged object
+    pub merged: String,
+    /// Size and hash of the merged object, one that doesn't match wasn't uploaded completely
+    pub digest: FileDigest,
+    /// Rows of the merged object, for the manifest of the stream
+    #[serde(default)]
+    pub rows: u64,
+    /// Keys of the objects merged
+    pub sources: Vec<String>,
+    pub started_at: DateTime<Utc>,
+}
+
+/// Merges small parquet files of log streams into files of up to `target_size` bytes.
+/// Queries pick the files to read by minute partition, so files are only merged with
+/// files of the same partition.
+pub struct Compactor {
+    pub target_size: u64,
+    /// Local directory files are downloaded to and merged in
+    pub staging_dir: PathBuf,
+    /// Codec of merged files of streams that don't set their own
+    pub compression: Compression,
+}
+
+impl Compactor {
+    /// Compact partitions of every stream older than the current hour, files are still
+    /// synced to the partitions of the current hour.
+    pub async fn run(&self, storage: &dyn ObjectStorage, now: DateTime<Utc>) {
+        for (stream_name, schema) in STREAM_INFO.compactable_streams() {
+            if let Err(e) = self
+                .compact_stream(storage, &stream_name, schema, now)
+                .await
+            {
+                warn!("failed to compact log stream {}. {:?}", stream_name, e);
+            }
+        }
+    }
+
+    pub async fn compact_stream(
+        &self,
+        storage: &dyn ObjectStorage,
+        stream_name: &str,
+        schema: SchemaRef,
+        now: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        resume(storage, stream_name).await?;
+
+        let current_hour = now.date().and_hms_opt(now.hour(), 0, 0).unwrap_or(now);
+
+        let mut merged = 0;
+        for days_ago in (0..COMPACTION_DAYS).rev() {
+            let date = (now - Duration::days(days_ago)).date();
+            let prefix = format!("{}/{}", stream_name, utils::date_to_prefix(date));
+            let files = storage.list_parquet_files(&prefix).await?;
+
+            for (partition, files) in partitions(stream_name, files) {
+                if !matches!(partition_time(stream_name, &partition), Some(time) if time < current_hour)
+                {
+                    continue;
+                }
+                for group in groups(files, self.target_size) {
+                    self.merge(
+                        storage,
+                        stream_name,
+                        &partition,
+                        &group,
+                        schema.clone(),
+                        now,
+                    )
+                    .await?;
+                    merged += group.len();
+                }
+            }
+        }
+
+        if merged > 0 {
+            info!(
+                "merged {} parquet files of log stream {}",
+                merged, stream_name
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Merge the files of a partition into a single object, then delete them
+    async fn merge(
+        &self,
+        storage: &dyn ObjectStorage,
+        stream_name: &str,
+        partition: &str,
+        sources: &[ParquetFile],
+        schema: SchemaRef,
+        now: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        fs::create_dir_all(&self.staging_dir)?;
+        let mut staged = Staged::default();
+
+        for source in sources {
+            let body = storage.get_object(&source.key).await?;
+            let path = staged.add(&self.staging_dir);
+            fs::write(path, &body)?;
+        }
+
+        let compression = STREAM_INFO
+            .compression(stream_name)
+            .ok()
+            .flatten()
+            .unwrap_or(self.compression);
+        let path = staged.add(&self.staging_dir);
+        let rows = write_merged(&staged.0[..sources.len()], schema, &path, compression)?;
+
+        let manifest = CompactionManifest {
+            merged: format!("{}/compacted-{}.parquet", partition, utils::random_string()),
+            digest: FileDigest::of_file(&path)?,
+            rows,
+            sources: sources.iter().map(|source| source.key.clone()).collect(),
+            started_at: now,
+        };
+        let manifest_key = manifest_key(stream_name);
+        storage
+            .put_object(&manifest_key, serde_json::to_vec(&manifest)?.into())
+            .await?;
+
+        let uploaded = storage::upload_verified(
+            storage,
+            stream_name,
+            &manifest.merged,
+            &path.to_string_lossy(),
+        )
+        .await
+        .map_err(|e| e.to_string());
+        if let Err(e) = uploaded {
+            // the sources are all still there, only the merged object goes
+            storage.delete_prefix(&manifest.merged).await?;
+            storage.delete_prefix(&manifest_key).await?;
+            return Err(ObjectStorageError::UnhandledError(e.into()).into());
+        }
+
+        finish(storage, stream_name, &manifest).await?;
+
+        let source_size = sources.iter().map(|source| source.size).sum();
+        if let Err(e) = STREAM_INFO.record_compaction(
+            stream_name,
+            sources.len() as u64,
+            source_size,
+            manifest.digest.size,
+            now,
+        ) {
+            warn!(
+                "failed to record compaction of log stream {}. {:?}",
+                stream_name, e
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Local files of a compaction, removed when dropped whatever the outcome
+#[derive(Default)]
+struct Staged(Vec<PathBuf>);
+
+impl Staged {
+    fn add(&mut self, dir: &Path) -> PathBuf {
+        let path = dir.join(format!("{}.parquet", utils::random_string()));
+        self.0.push(path.clone());
+        path
+    }
+}
+
+impl Drop for Staged {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn manifest_key(stream_name: &str) -> String {
+    format!("{}/.compaction.json", stream_name)
+}
+
+/// Complete a compaction of the stream interrupted by a restart. Sources are deleted
+/// if the merged object was uploaded completely, the merged object otherwise.
+async fn resume(storage: &dyn ObjectStorage, stream_name: &str) -> Result<(), Error> {
+    let manifest_key = manifest_key(stream_name);
+    let manifest: CompactionManifest = match storage.get_object(&manifest_key).await {
+        Ok(body) => serde_json::from_slice(&body)?,
+        Err(ObjectStorageError::NoSuchKey(_)) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let uploaded = match storage.object_meta(&manifest.merged).await {
+        Ok(meta) => manifest.digest.verify(&manifest.merged, &meta).is_ok(),
+        Err(ObjectStorageError::NoSuchKey(_)) => false,
+        Err(e) => return Err(e.into()),
+    };
+
+    if uploaded {
+        finish(storage, stream_name, &manifest).await?;
+    } else {
+        storage.delete_prefix(&manifest.merged).await?;
+        storage.delete_prefix(&manifest_key).await?;
+    }
+    info!(
+        "resumed compaction of log stream {} started at {}",
+        stream_name, manifest.started_at
+    );
+
+    Ok(())
+}
+
+/// Delete the sources of a merged object and swap them for it in the manifest of the
+/// stream, then delete the compaction manifest. Deleting a source that is already gone
+/// succeeds, so this is repeated as is when resuming.
+async fn finish(
+    storage: &dyn ObjectStorage,
+    stream_name: &str,
+    compaction: &CompactionManifest,
+) -> Result<(), ObjectStorageError> {
+    for source in &compaction.sources {
+        storage.delete_prefix(source).await?;
+    }
+
+    let prefix = format!("{}/", stream_name);
+    let relative = |key: &str| key.strip_prefix(&prefix).unwrap_or(key).to_string();
+    let sources: Vec<String> = compaction.sources.iter().map(|key| relative(key)).collect();
+    let merged = ManifestFile::new(
+        &relative(&compaction.merged),
+        compaction.digest.size,
+        compaction.rows,
+    );
+    manifest::update(storage, stream_name, |manifest| {
+        manifest.files.retain(|file| !sources.contains(&file.key));
+        manifest.add(merged.into_iter().collect());
+    })
+    .await;
+
+    storage.delete_prefix(&manifest_key(stream_name)).await?;
+
+    Ok(())
+}
+
+/// Files by the partition they are in, i.e. the key without the file name
+fn partitions(stream_name: &str, files: Vec<ParquetFile>) -> BTreeMap<String, Vec<ParquetFile>> {
+    let mut partitions: BTreeMap<String, Vec<ParquetFile>> = BTreeMap::new();
+    for file in files {
+        if let Some((partition, _)) = file.key.rsplit_once('/') {
+            if partition.starts_with(stream_name) {
+                partitions
+                    .entry(partition.to_string())
+                    .or_default()
+                    .push(file);
+            }
+        }
+    }
+
+    partitions
+}
+
+fn partition_time(stream_name: &str, partition: &str) -> Option<DateTime<Utc>> {
+    let dirs: Vec<String> = partition
+        .strip_prefix(stream_name)?
+        .split('/')
+        .filter(|dir| !dir.is_empty())
+        .map(str::to_string)
+        .collect();
+
+    utils::partition_to_time(&dirs)
+}
+
+/// Group files smaller than `target_size`, in key order, into groups of up to
+/// `target_size` bytes. Groups of a single file are left out, there's nothing to merge.
+fn groups(mut files: Vec<ParquetFile>, target_size: u64) -> Vec<Vec<ParquetFile>> {
+    files.retain(|file| file.size < target_size);
+    files.sort_by(|a, b| a.key.cmp(&b.key));
+
+    let mut groups = vec![];
+    let mut group: Vec<ParquetFile> = vec![];
+    let mut size = 0;
+    for file in files {
+        if !group.is_empty() && size + file.size > target_size {
+            groups.push(std::mem::take(&mut group));
+            size = 0;
+        }
+        size += file.size;
+        group.push(file);
+    }
+    groups.push(group);
+    groups.retain(|group| group.len() > 1);
+
+    groups
+}
+
+/// Write the rows of the parquet files at `sources`, adapted to the current schema of
+/// the stream, to a single parquet file at `path`. Returns the number of rows written.
+fn write_merged(
+    sources: &[PathBuf],
+    schema: SchemaRef,
+    path: &Path,
+    compression: Compression,
+) -> Result<u64, Error> {
+    let props = WriterProperties::builder()
+        .set_compression(compression.into())
+        .build();
+    let mut writer = ArrowWriter::try_new(fs::File::create(path)?, schema.clone(), Some(props))?;
+
+    for source in sources {
+        let mut reader = ParquetFileArrowReader::new(Arc::new(SerializedFileReader::new(
+            fs::File::open(source)?,
+        )?));
+        for rb in reader.get_record_reader(2048)? {
+            writer.write(&event::adapt_record_batch(rb?, schema.clone())?)?;
+        }
+    }
+    let metadata = writer.close()?;
+
+    Ok(metadata.num_rows.max(0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{Int64Array, StringArray};
+    use arrow::datatypes::{DataType, Field, Schema};
+    use arrow::record_batch::RecordBatch;
+    use chrono::TimeZone;
+    use rstest::*;
+    use serial_test::serial;
+
+    use crate::storage::mock::MockStorage;
+
+    fn schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("level", DataType::Utf8, true),
+            Field::new("code", DataType::Int64, true),
+        ]))
+    }
+
+    fn compactor() -> Compactor {
+        Compactor {
+            target_size: 1024 * 1024,
+            staging_dir: std::env::temp_dir()
+                .join(format!("parseable-test-{}", utils::random_string())),
+            compression: Compression::Snappy,
+        }
+    }
+
+    /// Body of a parquet file with `rows` rows
+    fn parquet_body(rows: usize) -> Vec<u8> {
+        let rb = RecordBatch::try_new(
+            schema(),
+            vec![
+                Arc::new(StringArray::from(vec!["info"; rows])),
+                Arc::new(Int64Array::from(vec![200; rows])),
+            ],
+        )
+        .unwrap();
+
+        let path = std::env::temp_dir().join(format!("parseable-test-{}", utils::random_string()));
+        let mut writer =
+            ArrowWriter::try_new(fs::File::create(&path).unwrap(), schema(), None).unwrap();
+        writer.write(&rb).unwrap();
+        writer.close().unwrap();
+        let body = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        body
+    }
+
+    fn rows(body: &[u8]) -> usize {
+        let path = std::env::temp_dir().join(format!("parseable-test-{}", utils::random_string()));
+        fs::write(&path, body).unwrap();
+        let mut reader = ParquetFileArrowReader::new(Arc::new(
+            SerializedFileReader::new(fs::File::open(&path).unwrap()).unwrap(),
+        ));
+        let rows = reader
+            .get_record_reader(2048)
+            .unwrap()
+            .map(|rb| rb.unwrap().num_rows())
+            .sum();
+        fs::remove_file(&path).unwrap();
+        rows
+    }
+
+    fn file(key: &str, size: u64) -> ParquetFile {
+        ParquetFile {
+            key: key.to_string(),
+            size,
+        }
+    }
+
+    #[rstest]
+    #[case::grouped_up_to_target(vec![("a", 40), ("b", 40), ("c", 40)], vec![vec!["a", "b"]])]
+    #[case::large_files_left_out(vec![("a", 40), ("b", 100), ("c", 40)], vec![vec!["a", "c"]])]
+    #[case::single_file_left_out(vec![("a", 40)], vec![])]
+    #[case::in_key_order(vec![("c", 30), ("a", 30), ("b", 30)], vec![vec!["a", "b", "c"]])]
+    fn groups_small_files(#[case] files: Vec<(&str, u64)>, #[case] expected: Vec<Vec<&str>>) {
+        let files = files
+            .into_iter()
+            .map(|(key, size)| file(key, size))
+            .collect();
+        let groups: Vec<Vec<String>> = groups(files, 100)
+            .into_iter()
+            .map(|group| group.into_iter().map(|file| file.key).collect())
+            .collect();
+
+        assert_eq!(groups, expected);
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn merges_files_of_past_hours() {
+        let stream_name = "compactstream";
+        STREAM_INFO
+            .add_stream(stream_name.to_string(), None, Default::default())
+            .unwrap();
+        let past = format!("{}/date=2022-10-15/hour=10/minute=00", stream_name);
+        let current = format!("{}/date=2022-10-15/hour=12/minute=00", stream_name);
+        let storage = MockStorage::default()
+            .with_object(&format!("{}/a.parquet", past), parquet_body(3))
+            .with_object(&format!("{}/b.parquet", past), parquet_body(4))
+            .with_object(&format!("{}/c.parquet", past), parquet_body(5))
+            .with_object(&format!("{}/a.parquet", current), parquet_body(1))
+            .with_object(&format!("{}/b.parquet", current), parquet_body(1));
+        let now = Utc.ymd(2022, 10, 15).and_hms(12, 30, 0);
+        manifest::create(&storage, stream_name).await.unwrap();
+        let files = [
+            "hour=10/minute=00/a",
+            "hour=10/minute=00/b",
+            "hour=10/minute=00/c",
+        ]
+        .iter()
+        .map(|file| ManifestFile::new(&format!("date=2022-10-15/{}.parquet", file), 10, 1))
+        .collect::<Option<Vec<_>>>()
+        .unwrap();
+        manifest::update(&storage, stream_name, |manifest| manifest.add(files)).await;
+
+        compactor()
+            .compact_stream(&storage, stream_name, schema(), now)
+            .await
+            .unwrap();
+
+        let objects = storage.objects();
+        let merged: Vec<&String> = objects
+            .iter()
+            .filter(|key| key.starts_with(&past))
+            .collect();
+        assert_eq!(merged.len(), 1);
+        assert!(merged[0].starts_with(&format!("{}/compacted-", past)));
+        assert_eq!(rows(&storage.get_object(merged[0]).await.unwrap()), 12);
+        // the current hour still gets files
+        assert_eq!(
+            objects
+                .iter()
+                .filter(|key| key.starts_with(&current))
+                .count(),
+            2
+        );
+        assert!(!objects.contains(&manifest_key(stream_name)));
+        // the manifest of the stream lists the merged object instead of the sources
+        let stream_manifest = manifest::load(&storage, stream_name)
+            .await
+            .unwrap()
+            .unwrap();
+        let merged_key = merged[0].strip_prefix("compactstream/").unwrap();
+        assert_eq!(stream_manifest.files.len(), 1);
+        assert_eq!(stream_manifest.files[0].key, merged_key);
+        assert_eq!(stream_manifest.files[0].rows, 12);
+
+        let stats = STREAM_INFO.stats(stream_name).unwrap();
+        assert_eq!(stats.compacted_files, 3);
+        assert_eq!(stats.last_compacted_at, Some(now));
+        STREAM_INFO.delete_stream(stream_name).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn resumes_interrupted_compaction() {
+        let stream_name = "resumedstream";
+        let partition = format!("{}/date=2022-10-15/hour=10/minute=00", stream_name);
+        let merged_body = parquet_body(2);
+        let path = std::env::temp_dir().join(format!("parseable-test-{}", utils::random_string()));
+        fs::write(&path, &merged_body).unwrap();
+        let manifest = CompactionManifest {
+            merged: format!("{}/compacted-x.parquet", partition),
+            digest: FileDigest::of_file(&path).unwrap(),
+            rows: 2,
+            sources: vec![
+                format!("{}/a.parquet", partition),
+                format!("{}/b.parquet", partition),
+            ],
+            started_at: Utc.ymd(2022, 10, 15).and_hms(11, 0, 0),
+        };
+        fs::remove_file(&path).unwrap();
+        let storage = MockStorage::default()
+            .with_object(&manifest.sources[0], parquet_body(1))
+            .with_object(&manifest.merged, merged_body)
+            .with_object(
+                &manifest_key(stream_name),
+                serde_json::to_vec(&manifest).unwrap(),
+            );
+
+        resume(&storage, stream_name).await.unwrap();
+
+        assert_eq!(storage.objects(), vec![manifest.merged]);
+    }
+
+    #[actix_web::test]
+    async fn drops_partial_upload_when_resuming() {
+        let stream_name = "partialstream";
+        let partition = format!("{}/date=2022-10-15/hour=10/minute=00", stream_name);
+        let manifest = CompactionManifest {
+            merged: format!("{}/compacted-x.parquet", partition),
+            digest: FileDigest {
+                size: 4096,
+                md5: [0; 16],
+            },
+            rows: 1,
+            sources: vec![format!("{}/a.parquet", partition)],
+            started_at: Utc.ymd(2022, 10, 15).and_hms(11, 0, 0),
+        };
+        let storage = MockStorage::default()
+            .with_object(&manifest.sources[0], parquet_body(1))
+            .with_object(&manifest.merged, parquet_body(1))
+            .with_object(
+                &manifest_key(stream_name),
+                serde_json::to_vec(&manifest).unwrap(),
+            );
+
+        resume(&storage, stream_name).await.unwrap();
+
+        assert_eq!(storage.objects(), manifest.sources);
+    }
+}
@@ -16,11 +16,12 @@
  *
  */
 
+use arrow::datatypes::DataType;
 use arrow::error::ArrowError;
 use datafusion::error::DataFusionError;
 use parquet::errors::ParquetError;
 
-use crate::{response::EventError, storage::ObjectStorageError};
+use crate::{event::SchemaViolation, response::EventError, storage::ObjectStorageError};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -44,22 +45,14 @@ pub enum Error {
     DataFusion(#[from] DataFusionError),
     #[error("UTF8 parsing error: {0}")]
     Utf8(#[from] std::string::FromUtf8Error),
-    #[error("log stream name cannot be empty")]
-    EmptyName,
-    #[error("log stream name cannot contain spaces: {0}")]
-    NameWhiteSpace(String),
-    #[error("log stream name cannot contain special characters: {0}")]
-    NameSpecialChar(String),
-    #[error("log stream name cannot contain uppercase characters: {0}")]
-    NameUpperCase(String),
-    #[error("log stream name cannot be numeric only: {0}")]
-    NameNumericOnly(String),
-    #[error("log stream name cannot start with a number: {0}")]
-    NameCantStartWithNumber(String),
-    #[error("log stream name cannot be a sql keyword: {0}")]
-    SQLKeyword(String),
+    #[error("invalid log stream name '{0}': {1}")]
+    InvalidStreamName(String, &'static str),
+    #[error("invalid tag key '{0}': {1}")]
+    InvalidTagKey(String, &'static str),
     #[error("queries across multiple streams are not supported currently: {0}")]
     MultipleStreams(String),
+    #[error("query for log stream {0} selects from log stream {1}")]
+    QueryStreamMismatch(String, String),
     #[error("start time can not be later than end time")]
     StartTimeAfterEndTime(),
     #[error("query '{0}' is incomplete")]
@@ -72,16 +65,48 @@ pub enum Error {
     EmptyEndTime,
     #[error("joins are not supported currently: {0}")]
     Join(String),
+    #[error("decompressed body is larger than the limit of {0} bytes")]
+    DecompressedTooLarge(usize),
+    #[error("event {0} has no valid RFC3339 or epoch milliseconds time in field {1}")]
+    InvalidEventTime(usize, String),
+    #[error("event {0} is older than the maximum allowed lateness of {1} days")]
+    EventTooLate(usize, u32),
+    #[error("event {0} is {1} bytes, larger than the limit of {2} bytes")]
+    EventTooLarge(usize, usize, usize),
+    #[error("{0} has {1} columns, more than the limit of {2} columns")]
+    TooManyColumns(String, usize, usize),
+    #[error("invalid limits: {0}")]
+    InvalidLimits(String),
     #[error("missing record batch")]
     MissingRecord,
+    #[error("log stream already exists: {0}")]
+    StreamAlreadyExists(String),
     #[error("metadata not found for log stream: {0}")]
     StreamMetaNotFound(String),
+    #[error("metadata of log stream {0} was changed while it was being added")]
+    InsertFailed(String),
+    #[error("rename in progress for log stream: {0}")]
+    RenameInProgress(String),
+    #[error("no deleted log stream to restore: {0}")]
+    DeletedStreamNotFound(String),
+    #[error("metadata not found for log streams: {}", .0.join(", "))]
+    StreamsMetaNotFound(Vec<String>),
     #[error("invalid alert config: {0}")]
     InvalidAlert(String),
-    #[error("this event schema doesn't match with stream schema. please ensure event data is in same format as previous events sent to the stream: {0}")]
-    SchemaMismatch(String),
-    #[error("alert for stream not found in storage: {0}")]
-    AlertNotInStore(String),
+    #[error("invalid retention period: {0} days, it must be at least 1 day")]
+    InvalidRetention(u32),
+    #[error("field {0} of this event has type {2:?} which is incompatible with type {1:?} in the stream schema")]
+    IncompatibleField(String, DataType, DataType),
+    #[error("metadata of log stream {0} in object storage has version {1}, this server only supports versions up to {2}. It was put by a newer server version, upgrade this server to load it")]
+    UnsupportedMetadataVersion(String, u32, u32),
     #[error("schema for stream not found in storage: {0}")]
     SchemaNotInStore(String),
+    #[error("schema for stream in storage is invalid: {0}")]
+    InvalidSchema(String),
+    #[error("log stream {0} has no schema version {1}")]
+    SchemaVersionNotFound(String, u32),
+    #[error("schema of log stream {0} kept being changed by other servers, gave up putting it after {1} attempts")]
+    SchemaConflict(String, u32),
+    #[error("event doesn't conform to the static schema of the stream: {}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "))]
+    SchemaViolations(Vec<SchemaViolation>),
 }
@@ -17,81 +17,155 @@
  *
  */
 
+use arrow::array::{new_null_array, ArrayRef};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Schema, SchemaRef};
+use arrow::error::ArrowError;
 use arrow::json;
 use arrow::json::reader::infer_json_schema;
 use arrow::record_batch::RecordBatch;
-use log::error;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use log::{error, info};
 use parquet::arrow::arrow_writer::ArrowWriter;
 use parquet::arrow::{ArrowReader, ParquetFileArrowReader};
 use parquet::file::properties::WriterProperties;
 use parquet::file::reader::SerializedFileReader;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::fs;
 use std::io::BufReader;
 use std::sync::Arc;
+use tracing::{info_span, Instrument};
 
-use crate::metadata;
+use crate::alerts;
+use crate::buffer::{BufferedEvents, EVENT_BUFFER};
+use crate::metadata::{self, Compression};
+use crate::metrics;
 use crate::option::CONFIG;
 use crate::response;
-use crate::storage::ObjectStorage;
+use crate::storage::{self, ObjectStorage, DATA_FILE};
+use crate::utils;
 use crate::Error;
 
+/// One or more events of a log stream, `body` holds one JSON object per line.
 pub struct Event {
     pub body: String,
     pub stream_name: String,
 }
 
-// Events holds the schema related to a each event for a single log stream
-pub struct Schema {
-    pub arrow_schema: arrow::datatypes::Schema,
-    pub string_schema: String,
-}
-
 impl Event {
-    fn data_file_path(&self) -> String {
-        format!(
-            "{}/{}",
-            CONFIG.parseable.local_stream_data_path(&self.stream_name),
-            "data.parquet"
-        )
-    }
-
+    #[tracing::instrument(
+        name = "ingest",
+        skip_all,
+        fields(stream = %self.stream_name, bytes = self.body.len())
+    )]
     pub async fn process(
         &self,
-        storage: &impl ObjectStorage,
+        storage: &dyn ObjectStorage,
     ) -> Result<response::EventResponse, Error> {
-        let Schema {
-            arrow_schema,
-            string_schema,
-        } = self.infer_schema().map_err(|e| {
-            error!("Failed to infer schema for event. {:?}", e);
-            e
-        })?;
+        let time_field = match metadata::STREAM_INFO.time_field(&self.stream_name)? {
+            Some(time_field) => time_field,
+            None => return self.process_partition(None, storage).await,
+        };
+
+        // all events are checked before any of them is written
+        let now = Utc::now();
+        let granularity = metadata::STREAM_INFO.partition(&self.stream_name)?;
+        let mut partitions: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+        for (record, line) in self.body.lines().enumerate() {
+            let event: Value = serde_json::from_str(line)?;
+            let time = check_event_time(
+                record,
+                &event,
+                &time_field,
+                now,
+                CONFIG.parseable.max_event_lateness,
+            )?;
+            let partition = granularity.prefix(time).replace('/', ".");
+            partitions.entry(partition).or_default().push(line);
+        }
 
-        let event = self.get_reader(arrow_schema);
-        let size = self.body_size();
+        let mut response = None;
+        for (partition, lines) in partitions {
+            let event = Event {
+                body: lines.join("\n"),
+                stream_name: self.stream_name.clone(),
+            };
+            response = Some(event.process_partition(Some(&partition), storage).await?);
+        }
+
+        response.ok_or(Error::MissingRecord)
+    }
 
+    #[tracing::instrument(
+        name = "ingest_partition",
+        skip_all,
+        fields(
+            stream = %self.stream_name,
+            partition = partition.unwrap_or_default(),
+            bytes = self.body.len()
+        )
+    )]
+    async fn process_partition(
+        &self,
+        partition: Option<&str>,
+        storage: &dyn ObjectStorage,
+    ) -> Result<response::EventResponse, Error> {
+        let static_schema = metadata::STREAM_INFO.static_schema(&self.stream_name)?;
         let stream_schema = metadata::STREAM_INFO.schema(&self.stream_name)?;
-        let is_first_event = stream_schema.is_empty();
-        // if stream schema is empty then it is first event.
-        let compressed_size = if is_first_event {
+        // the schema of the stream is inferred from its first events
+        let infers_schema = static_schema.is_none() && stream_schema.is_none();
+        let buffered = match (static_schema, stream_schema) {
+            // events of a stream with a static schema are never inferred
+            (Some(static_schema), _) => {
+                let mut violations = Vec::new();
+                for (record, event) in self.body.lines().enumerate() {
+                    let event: Value = serde_json::from_str(event)?;
+                    violations.extend(validate_event(record, &event, &static_schema));
+                }
+                if !violations.is_empty() {
+                    return Err(Error::SchemaViolations(violations));
+                }
+                let event = self.get_reader(static_schema.clone());
+                self.process_event(event, static_schema, partition)?
+            }
             // process first event and store schema in obect store
-            self.process_first_event(event, string_schema.clone(), storage)
-                .await?
-        } else {
-            // validate schema before processing the event
-            if stream_schema != string_schema {
-                return Err(Error::SchemaMismatch(self.stream_name.clone()));
-            } else {
-                self.process_event(event)?
+            (None, None) => {
+                // don't replace a schema in object storage that failed to load
+                if metadata::STREAM_INFO.is_schema_invalid(&self.stream_name)? {
+                    return Err(Error::InvalidSchema(self.stream_name.clone()));
+                }
+                let inferred_schema = self.infer_schema()?;
+                self.check_columns(&inferred_schema)?;
+                self.process_first_event(inferred_schema, partition, storage)
+                    .await?
+            }
+            (None, Some(stream_schema)) => {
+                // evolve the stream schema if this event doesn't fit in it
+                let inferred_schema = self.infer_schema()?;
+                let schema = if *stream_schema == inferred_schema {
+                    stream_schema
+                } else {
+                    Arc::new(
+                        self.evolve_schema(&stream_schema, inferred_schema, storage)
+                            .await?,
+                    )
+                };
+                let event = self.get_reader(schema.clone());
+                self.process_event(event, schema, partition)?
             }
         };
 
-        if let Err(e) = metadata::STREAM_INFO.update_stats(&self.stream_name, size, compressed_size)
-        {
-            error!("Couldn't update stream stats. {:?}", e);
+        metrics::EVENTS_INGESTED
+            .with_label_values(&[&self.stream_name])
+            .inc_by(self.events());
+        if let Some(buffered) = buffered {
+            flush(buffered, storage).await?;
         }
 
-        let msg = if is_first_event {
+        let msg = if infers_schema {
             format!(
                 "Intial Event recieved for log stream {}, schema uploaded successfully",
                 &self.stream_name,
@@ -105,86 +179,222 @@ impl Event {
 
     // This is called when the first event of a log stream is received. The first event is
     // special because we parse this event to generate the schema for the log stream. This
-    // schema is then enforced on rest of the events sent to this log stream.
-    async fn process_first_event<R: std::io::Read>(
+    // schema is then enforced on rest of the events sent to this log stream. Another server
+    // may have put a schema for the stream meanwhile, the event is read with both merged.
+    async fn process_first_event(
         &self,
-        mut event: json::Reader<R>,
-        string_schema: String,
-        storage: &impl ObjectStorage,
-    ) -> Result<u64, Error> {
-        let rb = event.next()?.ok_or(Error::MissingRecord)?;
+        schema: Schema,
+        partition: Option<&str>,
+        storage: &dyn ObjectStorage,
+    ) -> Result<Option<BufferedEvents>, Error> {
+        self.ensure_stream_exists()?;
+        let schema = Arc::new(self.put_schema(schema, storage).await?);
 
-        // Store record batch to Parquet file on local cache
-        let compressed_size = self.convert_arrow_parquet(rb)?;
+        let rb = read_record_batch(self.get_reader(schema.clone()), schema.clone())?;
+        self.evaluate_alerts(&rb);
 
-        // Put the inferred schema to object store
-        let stream_name = &self.stream_name;
-        storage
-            .put_schema(stream_name.clone(), string_schema.clone())
+        self.buffer(schema, rb, partition)
+    }
+
+    // Merge the inferred schema of this event into the stream schema. If the
+    // merged schema is wider than the stream schema, it is put to object store
+    // first and only then set in memory, so both always hold a complete schema.
+    async fn evolve_schema(
+        &self,
+        stream_schema: &Schema,
+        inferred_schema: Schema,
+        storage: &dyn ObjectStorage,
+    ) -> Result<Schema, Error> {
+        let merged_schema =
+            metadata::STREAM_INFO.merge_schema(&self.stream_name, inferred_schema)?;
+
+        if merged_schema == *stream_schema {
+            return Ok(merged_schema);
+        }
+
+        self.check_columns(&merged_schema)?;
+        self.ensure_stream_exists()?;
+
+        self.put_schema(merged_schema, storage).await
+    }
+
+    // Put the schema to object store, merged with the schema other servers sharing the
+    // bucket may have put since this server read it, and only then set it in memory.
+    // Returns the schema that was put.
+    async fn put_schema(
+        &self,
+        schema: Schema,
+        storage: &dyn ObjectStorage,
+    ) -> Result<Schema, Error> {
+        let merged_schema = storage::put_schema_merged(storage, &self.stream_name, &schema)
+            .instrument(info_span!(
+                "put_schema",
+                stream = %self.stream_name,
+                fields = schema.fields().len()
+            ))
             .await
-            .map_err(|e| response::EventError {
-                msg: format!(
-                    "Failed to upload schema for log stream {} due to err: {}",
-                    self.stream_name, e
-                ),
+            .map_err(|e| match e {
+                Error::Storage(e) => Error::Event(response::EventError {
+                    msg: format!(
+                        "Failed to upload schema for log stream {} due to err: {}",
+                        self.stream_name, e
+                    ),
+                }),
+                e => e,
             })?;
 
-        // set the schema in memory for this stream
-        metadata::STREAM_INFO
-            .set_schema(self.stream_name.clone(), string_schema)
+        self.put_schema_version(&merged_schema, storage).await?;
+        metadata::STREAM_INFO.set_schema(self.stream_name.clone(), merged_schema.clone())?;
+
+        Ok(merged_schema)
+    }
+
+    // Every schema the stream had is kept as a version of its schema, so that
+    // data written with it can still be read with it once the schema changed.
+    async fn put_schema_version(
+        &self,
+        schema: &Schema,
+        storage: &dyn ObjectStorage,
+    ) -> Result<(), Error> {
+        let version = metadata::STREAM_INFO.next_schema_version(&self.stream_name)?;
+        storage
+            .put_schema_version(&self.stream_name, version, schema)
+            .await
             .map_err(|e| response::EventError {
                 msg: format!(
-                    "Failed to set schema for log stream {} due to err: {}",
-                    &self.stream_name, e
+                    "Failed to upload schema version {} for log stream {} due to err: {}",
+                    version, self.stream_name, e
                 ),
             })?;
 
-        Ok(compressed_size)
+        Ok(())
     }
 
-    // event process all events after the 1st event. Concatenates record batches
-    // and puts them in memory store for each event.
-    fn process_event<R: std::io::Read>(&self, mut event: json::Reader<R>) -> Result<u64, Error> {
-        let next_event_rb = event.next()?.ok_or(Error::MissingRecord)?;
+    // event process all events after the 1st event. Reads them into a record
+    // batch and buffers it until enough events of the stream are buffered.
+    fn process_event<R: std::io::Read>(
+        &self,
+        event: json::Reader<R>,
+        schema: SchemaRef,
+        partition: Option<&str>,
+    ) -> Result<Option<BufferedEvents>, Error> {
+        let rb = read_record_batch(event, schema.clone())?;
+        self.evaluate_alerts(&rb);
 
-        let compressed_size = match self.convert_parquet_rb_reader() {
-            Ok(mut arrow_reader) => {
-                let mut total_size = 0;
-                let rb = arrow_reader.get_record_reader(2048).unwrap();
-                for prev_rb in rb {
-                    let new_rb = RecordBatch::concat(
-                        &std::sync::Arc::new(arrow_reader.get_schema().unwrap()),
-                        &[next_event_rb.clone(), prev_rb.unwrap()],
-                    )?;
-                    total_size += self.convert_arrow_parquet(new_rb)?;
-                }
+        self.buffer(schema, rb, partition)
+    }
 
-                total_size
+    // Returns the buffered events of the stream or partition if they are due to be written.
+    // Events of a stream being renamed are turned down, as its buffer was already written
+    // to be moved along with its data.
+    fn buffer(
+        &self,
+        schema: SchemaRef,
+        rb: RecordBatch,
+        partition: Option<&str>,
+    ) -> Result<Option<BufferedEvents>, Error> {
+        if metadata::STREAM_INFO.is_renaming(&self.stream_name) {
+            return Err(Error::RenameInProgress(self.stream_name.clone()));
+        }
+
+        Ok(EVENT_BUFFER.push(
+            &self.stream_name,
+            partition,
+            schema,
+            rb,
+            self.body_size(),
+            self.events(),
+            CONFIG.parseable.flush_size,
+        ))
+    }
+
+    // The stream may have been deleted while its events were processed. Its schema
+    // must not be put to object storage then, that would bring the stream back.
+    fn ensure_stream_exists(&self) -> Result<(), Error> {
+        if !metadata::STREAM_INFO.stream_exists(&self.stream_name) {
+            return Err(Error::StreamMetaNotFound(self.stream_name.clone()));
+        }
+
+        Ok(())
+    }
+
+    // Alerts are evaluated before events are written, a failure to
+    // evaluate alerts must not fail the ingestion of the events.
+    fn evaluate_alerts(&self, rb: &RecordBatch) {
+        match alerts::evaluate(&self.stream_name, rb) {
+            Ok(triggered) => {
+                let cooldown = std::time::Duration::from_secs(CONFIG.parseable.alert_cooldown);
+                let triggered = alerts::COOLDOWNS.throttle(
+                    &self.stream_name,
+                    triggered,
+                    cooldown,
+                    std::time::Instant::now(),
+                );
+                for (triggered, suppressed) in triggered {
+                    let matched = triggered
+                        .matched
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    info!(
+                        "Alert {} triggered for log stream {} by {}, {} additional matches",
+                        triggered.alert.name, self.stream_name, matched, suppressed
+                    );
+                    let context = alerts::EventContext {
+                        stream_name: self.stream_name.clone(),
+                        matched: triggered.matched,
+                        suppressed,
+                        timestamp: Utc::now(),
+                    };
+                    // deliver in the background, so that slow targets don't hold up ingestion
+                    actix_web::rt::spawn(async move {
+                        alerts::notify(&triggered.alert, &context).await;
+                    });
+                }
             }
-            Err(_) => self.convert_arrow_parquet(next_event_rb)?,
-        };
+            Err(e) => error!(
+                "Failed to evaluate alerts for log stream {}. {:?}",
+                self.stream_name, e
+            ),
+        }
+    }
+
+    // Reject a schema wider than the column limit of the stream, before any of
+    // the event is written or the schema is put to object store.
+    fn check_columns(&self, schema: &Schema) -> Result<(), Error> {
+        let max_columns = metadata::STREAM_INFO
+            .limits(&self.stream_name)?
+            .max_columns
+            .unwrap_or(CONFIG.parseable.max_columns);
+        let columns = schema.fields().len();
+        if columns > max_columns {
+            return Err(Error::TooManyColumns(
+                format!("schema of log stream {}", self.stream_name),
+                columns,
+                max_columns,
+            ));
+        }
 
-        Ok(compressed_size)
+        Ok(())
     }
 
-    // inferSchema is a constructor to Schema
-    // returns raw arrow schema type and arrow schema to string type.
-    fn infer_schema(&self) -> Result<Schema, Error> {
+    /// Arrow schema inferred from the event body, as for the first events of a stream
+    pub fn infer_schema(&self) -> Result<Schema, Error> {
         let reader = self.body.as_bytes();
         let mut buf_reader = BufReader::new(reader);
-        let inferred_schema = infer_json_schema(&mut buf_reader, None)?;
-        let str_inferred_schema = serde_json::to_string(&inferred_schema)?;
+        let inferred_schema = infer_json_schema(&mut buf_reader, None).map_err(|e| {
+            error!("Failed to infer schema for event. {:?}", e);
+            e
+        })?;
 
-        Ok(Schema {
-            arrow_schema: inferred_schema,
-            string_schema: str_inferred_schema,
-        })
+        Ok(inferred_schema)
     }
 
-    fn get_reader(&self, arrow_schema: arrow::datatypes::Schema) -> json::Reader<&[u8]> {
+    fn get_reader(&self, arrow_schema: SchemaRef) -> json::Reader<&[u8]> {
         json::Reader::new(
             self.body.as_bytes(),
-            Arc::new(arrow_schema),
+            arrow_schema,
             json::reader::DecoderOptions::new().with_batch_size(1024),
         )
     }
@@ -193,32 +403,546 @@ impl Event {
         self.body.as_bytes().len() as u64
     }
 
-    // convert arrow record batch to parquet
-    // and write it to local cache path as a data.parquet file.
-    fn convert_arrow_parquet(&self, rb: RecordBatch) -> Result<u64, Error> {
-        let parquet_path = self.data_file_path();
-        let parquet_file = fs::File::create(&parquet_path)?;
-        let props = WriterProperties::builder().build();
-        let mut writer = ArrowWriter::try_new(
-            parquet_file,
-            Arc::new(self.infer_schema()?.arrow_schema),
-            Some(props),
-        )?;
-        writer.write(&rb)?;
-        writer.close()?;
+    fn events(&self) -> u64 {
+        self.body.lines().count() as u64
+    }
+}
+
+/// Write events buffered for a stream, or a partition of it, to its data file and
+/// account for them in the stats of the stream.
+pub async fn flush(buffered: BufferedEvents, storage: &dyn ObjectStorage) -> Result<(), Error> {
+    let stream_name = buffered.stream_name.clone();
+    let (is_first_event, compressed_size) = write(&buffered)?;
+
+    // fails if the stream was deleted while its events were processed. Stats are
+    // put to object store by the stats sync, off the ingestion path.
+    metadata::STREAM_INFO.update_stats(
+        &stream_name,
+        buffered.size,
+        compressed_size,
+        buffered.events,
+    )?;
+
+    // first_event_at is set with the stats update of the first events
+    if is_first_event {
+        let sync = storage
+            .sync_timestamps(&stream_name)
+            .instrument(info_span!("sync_timestamps", stream = %stream_name));
+        if let Err(e) = sync.await {
+            error!("Couldn't put stream timestamps to object store. {:?}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Write all buffered events that are taken from the buffer, e.g. the ones buffered
+/// for too long. Events of streams deleted meanwhile are dropped. Stats of all streams
+/// written are updated at once.
+pub async fn flush_all(buffers: Vec<BufferedEvents>, storage: &dyn ObjectStorage) {
+    let mut updates = Vec::with_capacity(buffers.len());
+    let mut first_events: Vec<String> = Vec::new();
 
-        let compressed_size = fs::metadata(parquet_path)?.len();
+    for buffered in buffers {
+        let stream_name = buffered.stream_name.clone();
+        match write(&buffered) {
+            Ok((is_first_event, compressed_size)) => {
+                if is_first_event && !first_events.contains(&stream_name) {
+                    first_events.push(stream_name.clone());
+                }
+                updates.push((stream_name, buffered.size, compressed_size, buffered.events));
+            }
+            Err(e) => error!(
+                "Couldn't write buffered events of log stream {}. {:?}",
+                stream_name, e
+            ),
+        }
+    }
 
-        Ok(compressed_size)
+    if let Err(e) = metadata::STREAM_INFO.update_stats_batch(&updates) {
+        error!("Couldn't update stream stats. {:?}", e);
     }
 
-    pub fn convert_parquet_rb_reader(
-        &self,
-    ) -> Result<parquet::arrow::ParquetFileArrowReader, Error> {
-        let file = fs::File::open(&self.data_file_path())?;
-        let file_reader = SerializedFileReader::new(file)?;
-        let arrow_reader = ParquetFileArrowReader::new(Arc::new(file_reader));
+    for stream_name in first_events {
+        let sync = storage
+            .sync_timestamps(&stream_name)
+            .instrument(info_span!("sync_timestamps", stream = %stream_name));
+        if let Err(e) = sync.await {
+            error!("Couldn't put stream timestamps to object store. {:?}", e);
+        }
+    }
+}
+
+// Write the buffered events, returns whether they are the first events of the
+// stream, which sets its first_event_at, and the size of the data file.
+fn write(buffered: &BufferedEvents) -> Result<(bool, u64), Error> {
+    let is_first_event = metadata::STREAM_INFO
+        .timestamps(&buffered.stream_name)?
+        .first_event_at
+        .is_none();
+    let compressed_size = write_buffered(buffered)?;
+
+    Ok((is_first_event, compressed_size))
+}
+
+// Events partitioned by their own time are written to a file per partition,
+// all other events to the data file of the stream.
+fn data_file_path(stream_name: &str, partition: Option<&str>) -> String {
+    format!(
+        "{}/{}{}",
+        CONFIG.parseable.local_stream_data_path(stream_name),
+        partition.unwrap_or_default(),
+        DATA_FILE
+    )
+}
+
+// Write the buffered events along with the events already in the data file, as a
+// parquet file can't be appended to. Returns the size of the data file.
+fn write_buffered(buffered: &BufferedEvents) -> Result<u64, Error> {
+    let compression = metadata::STREAM_INFO
+        .compression(&buffered.stream_name)?
+        .unwrap_or(CONFIG.parseable.parquet_compression);
+    let path = data_file_path(&buffered.stream_name, buffered.partition.as_deref());
+    let schema = buffered.schema.clone();
+
+    // data written before a schema change has to be
+    // adapted to the current stream schema
+    let mut batches = Vec::new();
+    if let Ok(file) = fs::File::open(&path) {
+        let mut arrow_reader =
+            ParquetFileArrowReader::new(Arc::new(SerializedFileReader::new(file)?));
+        for rb in arrow_reader.get_record_reader(2048)? {
+            batches.push(adapt_record_batch(rb?, schema.clone())?);
+        }
+    }
+    for rb in &buffered.batches {
+        batches.push(adapt_record_batch(rb.clone(), schema.clone())?);
+    }
+
+    let rb = RecordBatch::concat(&schema, &batches)?;
+    write_parquet(&rb, &path, compression)
+}
+
+// Write the record batch to a parquet file at `path`, compressed with `compression`.
+// Returns the size of the file, which is the compressed size whatever the codec.
+fn write_parquet(rb: &RecordBatch, path: &str, compression: Compression) -> Result<u64, Error> {
+    let parquet_file = fs::File::create(path)?;
+    let props = WriterProperties::builder()
+        .set_compression(compression.into())
+        .build();
+    let mut writer = ArrowWriter::try_new(parquet_file, rb.schema(), Some(props))?;
+    writer.write(rb)?;
+    writer.close()?;
+
+    Ok(fs::metadata(path)?.len())
+}
+
+/// Time of the event in its `time_field`, either an RFC3339 string or epoch milliseconds.
+pub fn event_time(event: &Value, time_field: &str) -> Option<DateTime<Utc>> {
+    match event.get(time_field)? {
+        Value::String(time) => DateTime::parse_from_rfc3339(time)
+            .ok()
+            .map(|time| time.with_timezone(&Utc)),
+        Value::Number(millis) => Utc.timestamp_millis_opt(millis.as_i64()?).single(),
+        _ => None,
+    }
+}
+
+/// Time of the event with index `record`, for a stream partitioned by `time_field`. Events
+/// without a valid time or older than `max_lateness` days are rejected.
+pub fn check_event_time(
+    record: usize,
+    event: &Value,
+    time_field: &str,
+    now: DateTime<Utc>,
+    max_lateness: u32,
+) -> Result<DateTime<Utc>, Error> {
+    let time = event_time(event, time_field)
+        .ok_or_else(|| Error::InvalidEventTime(record, time_field.to_owned()))?;
+
+    if now - time > Duration::days(max_lateness.into()) {
+        return Err(Error::EventTooLate(record, max_lateness));
+    }
+
+    Ok(time)
+}
+
+// Read all events into a single record batch, so that they are written with a
+// single parquet write.
+fn read_record_batch<R: std::io::Read>(
+    mut event: json::Reader<R>,
+    schema: SchemaRef,
+) -> Result<RecordBatch, Error> {
+    let mut batches = Vec::new();
+    while let Some(rb) = event.next()? {
+        batches.push(rb);
+    }
+
+    match batches.len() {
+        0 => Err(Error::MissingRecord),
+        1 => Ok(batches.remove(0)),
+        _ => Ok(RecordBatch::concat(&schema, &batches)?),
+    }
+}
+
+// Adapt a record batch to a wider schema, as produced by `metadata::merge_schemas`.
+// Columns missing in the record batch are filled with nulls and
+// columns with a widened type are cast to the new type.
+pub fn adapt_record_batch(rb: RecordBatch, schema: SchemaRef) -> Result<RecordBatch, Error> {
+    if rb.schema() == schema {
+        return Ok(rb);
+    }
+
+    let columns = schema
+        .fields()
+        .iter()
+        .map(|field| match rb.schema().column_with_name(field.name()) {
+            Some((index, current)) if current.data_type() == field.data_type() => {
+                Ok(rb.column(index).clone())
+            }
+            Some((index, _)) => cast(rb.column(index), field.data_type()),
+            None => Ok(new_null_array(field.data_type(), rb.num_rows())),
+        })
+        .collect::<Result<Vec<ArrayRef>, ArrowError>>()?;
+
+    Ok(RecordBatch::try_new(schema, columns)?)
+}
+
+/// A field of an event that doesn't conform to the static schema of its stream
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SchemaViolation {
+    /// Index of the event in the request
+    pub record: usize,
+    pub field: String,
+    pub reason: String,
+}
+
+impl fmt::Display for SchemaViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "record {} field {}: {}",
+            self.record, self.field, self.reason
+        )
+    }
+}
+
+/// Check a flattened event against the static schema of its stream. Fields that
+/// are missing or null in the event are fine as long as they are nullable.
+pub fn validate_event(record: usize, event: &Value, schema: &Schema) -> Vec<SchemaViolation> {
+    let violation = |field: &str, reason: &str| SchemaViolation {
+        record,
+        field: field.to_owned(),
+        reason: reason.to_owned(),
+    };
+
+    let event = match event.as_object() {
+        Some(event) => event,
+        None => return vec![violation("", "event is not a JSON object")],
+    };
+
+    let mut violations = Vec::new();
+
+    for (name, value) in event {
+        match schema.field_with_name(name) {
+            Err(_) => violations.push(violation(name, "unknown field")),
+            Ok(field) if value.is_null() && !field.is_nullable() => {
+                violations.push(violation(name, "null in a field that isn't nullable"))
+            }
+            Ok(field) if !matches_type(value, field.data_type()) => violations.push(violation(
+                name,
+                &format!(
+                    "expected {:?}, found {}",
+                    field.data_type(),
+                    json_type(value)
+                ),
+            )),
+            Ok(_) => {}
+        }
+    }
+
+    for field in schema.fields() {
+        if !field.is_nullable() && !event.contains_key(field.name()) {
+            violations.push(violation(field.name(), "missing required field"));
+        }
+    }
+
+    violations
+}
+
+// Whether the arrow json reader can read the value into the type. Types
+// that events can't be checked against are left to the reader.
+fn matches_type(value: &Value, data_type: &DataType) -> bool {
+    let int = |fits: fn(i64) -> bool| value.as_i64().map_or(false, fits);
+    let uint = |fits: fn(u64) -> bool| value.as_u64().map_or(false, fits);
+
+    match data_type {
+        _ if value.is_null() => true,
+        DataType::Boolean => value.is_boolean(),
+        DataType::Int8 => int(|n| i8::try_from(n).is_ok()),
+        DataType::Int16 => int(|n| i16::try_from(n).is_ok()),
+        DataType::Int32 => int(|n| i32::try_from(n).is_ok()),
+        DataType::Int64 => int(|_| true),
+        DataType::UInt8 => uint(|n| u8::try_from(n).is_ok()),
+        DataType::UInt16 => uint(|n| u16::try_from(n).is_ok()),
+        DataType::UInt32 => uint(|n| u32::try_from(n).is_ok()),
+        DataType::UInt64 => uint(|_| true),
+        DataType::Float16 | DataType::Float32 | DataType::Float64 => value.is_number(),
+        DataType::Utf8 | DataType::LargeUtf8 => value.is_string(),
+        DataType::Timestamp(_, _) | DataType::Date32 | DataType::Date64 => {
+            value.is_string() || value.is_i64()
+        }
+        DataType::List(field) | DataType::LargeList(field) => match value.as_array() {
+            Some(values) => values.iter().all(|value| {
+                if value.is_null() {
+                    field.is_nullable()
+                } else {
+                    matches_type(value, field.data_type())
+                }
+            }),
+            None => false,
+        },
+        _ => true,
+    }
+}
+
+fn json_type(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow::array::StringArray;
+    use arrow::datatypes::{DataType, Field, Schema};
+    use arrow::record_batch::RecordBatch;
+    use chrono::{TimeZone, Utc};
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use rstest::*;
+    use serde_json::{json, Value};
+    use serial_test::serial;
+    use std::sync::Arc;
+
+    use super::{check_event_time, event_time, validate_event, write_parquet, Event};
+    use crate::alerts::Alerts;
+    use crate::buffer::EVENT_BUFFER;
+    use crate::metadata::{Compression, Limits, STREAM_INFO};
+    use crate::storage::mock::MockStorage;
+    use crate::storage::ObjectStorage;
+    use crate::{utils, Error};
+
+    fn schema() -> Schema {
+        Schema::new(vec![
+            Field::new("level", DataType::Utf8, false),
+            Field::new("code", DataType::Int32, true),
+            Field::new("ratio", DataType::Float64, true),
+            Field::new(
+                "tags",
+                DataType::List(Box::new(Field::new("item", DataType::Utf8, true))),
+                true,
+            ),
+        ])
+    }
+
+    #[rstest]
+    #[case::all_fields(json!({"level": "info", "code": 200, "ratio": 0.5, "tags": ["a", null]}))]
+    #[case::missing_nullable(json!({"level": "info"}))]
+    #[case::null_in_nullable(json!({"level": "info", "code": null}))]
+    #[case::int_as_float(json!({"level": "info", "ratio": 1}))]
+    fn valid_event(#[case] event: Value) {
+        assert_eq!(validate_event(0, &event, &schema()), vec![]);
+    }
+
+    #[rstest]
+    #[case::unknown_field(json!({"level": "info", "host": "a"}), "host", "unknown field")]
+    #[case::missing_required(json!({"code": 200}), "level", "missing required field")]
+    #[case::null_in_required(json!({"level": null}), "level", "null in a field that isn't nullable")]
+    #[case::wrong_type(json!({"level": "info", "code": "200"}), "code", "expected Int32, found string")]
+    #[case::out_of_range(json!({"level": "info", "code": 1u64 << 40}), "code", "expected Int32, found number")]
+    #[case::wrong_list_item(json!({"level": "info", "tags": [1]}), "tags", "expected List")]
+    fn invalid_event(#[case] event: Value, #[case] field: &str, #[case] reason: &str) {
+        let violations = validate_event(3, &event, &schema());
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].record, 3);
+        assert_eq!(violations[0].field, field);
+        assert!(violations[0].reason.starts_with(reason));
+    }
+
+    #[rstest]
+    #[case::rfc3339(json!({"time": "2022-10-15T10:30:00+02:00"}), Some(Utc.ymd(2022, 10, 15).and_hms(8, 30, 0)))]
+    #[case::epoch_millis(json!({"time": 1665829800000u64}), Some(Utc.ymd(2022, 10, 15).and_hms(10, 30, 0)))]
+    #[case::missing(json!({"level": "info"}), None)]
+    #[case::unparsable(json!({"time": "yesterday"}), None)]
+    fn time_of_event(#[case] event: Value, #[case] time: Option<chrono::DateTime<Utc>>) {
+        assert_eq!(event_time(&event, "time"), time);
+    }
+
+    #[test]
+    fn check_time_of_event() {
+        let now = Utc.ymd(2022, 10, 15).and_hms(10, 30, 0);
+
+        let late = json!({"time": "2022-10-01T10:30:00Z"});
+        assert_eq!(
+            check_event_time(0, &late, "time", now, 30).unwrap(),
+            Utc.ymd(2022, 10, 1).and_hms(10, 30, 0)
+        );
+        assert!(matches!(
+            check_event_time(1, &late, "time", now, 7),
+            Err(Error::EventTooLate(1, 7))
+        ));
+        assert!(matches!(
+            check_event_time(2, &json!({"level": "info"}), "time", now, 30),
+            Err(Error::InvalidEventTime(2, _))
+        ));
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_ingest_after_stream_deleted() {
+        let stream_name = "deletedstream".to_string();
+        STREAM_INFO
+            .add_stream(stream_name.clone(), None, Alerts::default())
+            .unwrap();
+        assert!(STREAM_INFO.stream_exists(&stream_name));
+
+        // deleted after the handler checked the stream exists
+        STREAM_INFO.delete_stream(&stream_name).unwrap();
+        let storage = MockStorage::default();
+        let event = Event {
+            body: json!({"level": "info"}).to_string(),
+            stream_name: stream_name.clone(),
+        };
+
+        assert!(matches!(
+            event.process(&storage).await,
+            Err(Error::StreamMetaNotFound(_))
+        ));
+        // neither the map nor object storage get the stream back
+        assert!(!STREAM_INFO.stream_exists(&stream_name));
+        assert!(storage.requests().is_empty());
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_ingest_while_stream_deleted() {
+        let stream_name = "deletedstream".to_string();
+        let stream_schema = Schema::new(vec![Field::new("level", DataType::Utf8, true)]);
+        STREAM_INFO
+            .add_stream(stream_name.clone(), Some(stream_schema), Alerts::default())
+            .unwrap();
+        let limits = Limits {
+            max_columns: Some(10),
+            ..Limits::default()
+        };
+        STREAM_INFO.set_limits(&stream_name, limits).unwrap();
+
+        // purged after the schema the event is merged into was fetched
+        let storage = MockStorage::default()
+            .with_stream(&stream_name, "")
+            .with_purge_on_schema_fetch();
+        let event = Event {
+            body: json!({"level": "info", "code": 200}).to_string(),
+            stream_name: stream_name.clone(),
+        };
+
+        assert!(matches!(
+            event.process(&storage).await,
+            Err(Error::StreamMetaNotFound(_))
+        ));
+        // the put was turned down, and the schema isn't put again without the stream
+        assert_eq!(storage.requests(), vec!["put schema deletedstream"]);
+        assert!(storage
+            .get_schema_tagged(&stream_name)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(!STREAM_INFO.stream_exists(&stream_name));
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_ingest_during_rename() {
+        let stream_schema = Arc::new(Schema::new(vec![Field::new("level", DataType::Utf8, true)]));
+        STREAM_INFO
+            .add_stream(
+                "renamingstream".to_string(),
+                Some(stream_schema.as_ref().clone()),
+                Alerts::default(),
+            )
+            .unwrap();
+        let storage = MockStorage::default();
+        // buffered before the rename started
+        let levels = StringArray::from(vec!["info"]);
+        let rb = RecordBatch::try_new(stream_schema.clone(), vec![Arc::new(levels)]).unwrap();
+        EVENT_BUFFER.push("renamingstream", None, stream_schema, rb, 16, 1, u64::MAX);
+
+        STREAM_INFO
+            .start_rename(&storage, "renamingstream", "renamedstream")
+            .await
+            .unwrap();
+        // turned down for the client to retry, instead of buffered under the old name
+        let event = Event {
+            body: json!({"level": "warn"}).to_string(),
+            stream_name: "renamingstream".to_string(),
+        };
+        assert!(matches!(
+            event.process(&storage).await,
+            Err(Error::RenameInProgress(name)) if name == "renamingstream"
+        ));
+        // what is written before the data is moved
+        let buffered = EVENT_BUFFER.take_stream("renamingstream");
+        STREAM_INFO
+            .finish_rename(
+                &storage,
+                &std::env::temp_dir().join(utils::random_string()),
+                "renamingstream",
+                "renamedstream",
+            )
+            .await
+            .unwrap();
+        let left = EVENT_BUFFER.take_stream("renamingstream");
+        STREAM_INFO.delete_stream("renamedstream").unwrap();
+
+        assert_eq!(buffered.iter().map(|b| b.events).sum::<u64>(), 1);
+        assert!(left.is_empty());
+        assert!(!STREAM_INFO.stream_exists("renamingstream"));
+    }
+
+    #[test]
+    fn compress_parquet() {
+        let messages = (0..1000)
+            .map(|i| format!("request {} of user {} completed", i, i % 7))
+            .collect::<Vec<_>>();
+        let messages = StringArray::from(messages.iter().map(String::as_str).collect::<Vec<_>>());
+        let schema = Schema::new(vec![Field::new("message", DataType::Utf8, true)]);
+        let rb = RecordBatch::try_new(Arc::new(schema), vec![Arc::new(messages)]).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("parseable-test-{}", utils::random_string()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |compression: Compression| {
+            let path = dir.join(format!("{:?}.parquet", compression));
+            let size = write_parquet(&rb, path.to_str().unwrap(), compression).unwrap();
+            // the size counted in stats is the size of the file
+            assert_eq!(size, std::fs::metadata(&path).unwrap().len());
+            // and the codec is recorded in the file
+            let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+            let column = reader.metadata().row_group(0).column(0).compression();
+            assert_eq!(column, compression.into());
+            size
+        };
+
+        let uncompressed = write(Compression::Uncompressed);
+        let zstd = write(Compression::Zstd);
+        write(Compression::Snappy);
+        write(Compression::Gzip);
+        std::fs::remove_dir_all(dir).unwrap();
 
-        Ok(arrow_reader)
+        assert!(zstd < uncompressed, "{} < {}", zstd, uncompressed);
     }
 }
@@ -0,0 +1,257 @@
+/*
+ * Parseable Server (C) 2022 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use arrow::datatypes::Schema;
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use futures::{future, stream, Stream, StreamExt};
+use serde::Serialize;
+use std::io;
+use std::sync::Arc;
+
+use crate::alerts::Alerts;
+use crate::metadata::{Stats, STREAM_INFO};
+use crate::storage::{ObjectStorage, ObjectStorageError, ParquetFile};
+use crate::Error;
+
+/// Name of the entry describing the exported log stream, first in every archive
+pub const SIDECAR_NAME: &str = "export.json";
+/// Tar archives are made of blocks of this size, entries are padded to whole blocks
+const BLOCK_SIZE: usize = 512;
+
+/// Log stream as exported, next to its parquet files
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Sidecar {
+    stream_name: String,
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+    schema: Option<Schema>,
+    alerts: Alerts,
+    stats: Stats,
+    /// Keys of the parquet files in the archive, in order
+    files: Vec<String>,
+}
+
+/// Parquet files of the log stream to export, sorted by key. All of them unless a
+/// time `range` is given, and only those after `after`, the last file a previous
+/// export got to.
+pub async fn files(
+    storage: &(impl ObjectStorage + ?Sized),
+    stream_name: &str,
+    range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    after: Option<&str>,
+) -> Result<Vec<ParquetFile>, ObjectStorageError> {
+    let prefixes = match range {
+        Some((start, end)) => storage.query_partitions(stream_name, start, end).await?,
+        None => vec![format!("{}/", stream_name)],
+    };
+
+    let mut files = Vec::new();
+    for prefix in prefixes {
+        files.extend(storage.list_parquet_files(&prefix).await?);
+    }
+    files.sort_by(|a, b| a.key.cmp(&b.key));
+    files.dedup_by(|a, b| a.key == b.key);
+    if let Some(after) = after {
+        files.retain(|file| file.key.as_str() > after);
+    }
+
+    Ok(files)
+}
+
+/// Body of the sidecar entry, with the schema, alerts and stats of the log stream
+pub fn sidecar(
+    stream_name: &str,
+    range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    files: &[ParquetFile],
+) -> Result<Bytes, Error> {
+    let sidecar = Sidecar {
+        stream_name: stream_name.to_string(),
+        start: range.map(|(start, _)| start),
+        end: range.map(|(_, end)| end),
+        schema: STREAM_INFO
+            .schema(stream_name)?
+            .map(|schema| schema.as_ref().clone()),
+        alerts: Alerts {
+            alerts: STREAM_INFO.alert(stream_name)?,
+        },
+        stats: STREAM_INFO.stats(stream_name)?,
+        files: files.iter().map(|file| file.key.clone()).collect(),
+    };
+
+    Ok(serde_json::to_vec_pretty(&sidecar)?.into())
+}
+
+/// Tar entry of a file: its header, its body and the padding to a whole block
+fn entry(path: &str, body: &[u8], modified: DateTime<Utc>) -> io::Result<Bytes> {
+    let mut header = tar::Header::new_ustar();
+    header.set_path(path)?;
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_mode(0o644);
+    header.set_size(body.len() as u64);
+    header.set_mtime(modified.timestamp().max(0) as u64);
+    header.set_cksum();
+
+    let padding = (BLOCK_SIZE - body.len() % BLOCK_SIZE) % BLOCK_SIZE;
+    let mut entry = BytesMut::with_capacity(BLOCK_SIZE + body.len() + padding);
+    entry.extend_from_slice(header.as_bytes());
+    entry.extend_from_slice(body);
+    entry.resize(entry.len() + padding, 0);
+
+    Ok(entry.freeze())
+}
+
+/// Tar archive of the sidecar and the parquet files, as a stream of one entry at a
+/// time. Files are fetched from object storage as the archive is read, so only one
+/// is held in memory. A file that can't be fetched fails the download from there on.
+pub fn archive(
+    storage: Arc<dyn ObjectStorage>,
+    sidecar: Bytes,
+    files: Vec<ParquetFile>,
+) -> impl Stream<Item = io::Result<Bytes>> {
+    let exported_at = Utc::now();
+    let sidecar = stream::once(future::ready(entry(SIDECAR_NAME, &sidecar, exported_at)));
+    let objects = stream::iter(files).then(move |file| {
+        let storage = Arc::clone(&storage);
+        async move {
+            let body = storage
+                .get_object(&file.key)
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            entry(&file.key, &body, exported_at)
+        }
+    });
+    // an archive ends with two empty blocks
+    let end = stream::once(future::ready(Ok(Bytes::from_static(&[0; 2 * BLOCK_SIZE]))));
+
+    sidecar.chain(objects).chain(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+    use std::io::Read;
+
+    use super::*;
+    use crate::memory::MemoryStorage;
+
+    fn storage() -> MemoryStorage {
+        MemoryStorage::default()
+            .with_object("stream/.schema", "")
+            .with_object("stream/date=2022-10-15/hour=10/minute=00/a.parquet", "a")
+            .with_object("stream/date=2022-10-15/hour=11/minute=00/b.parquet", "bb")
+            .with_object("stream/date=2022-10-15/hour=12/minute=00/c.parquet", "ccc")
+            .with_object("stream2/date=2022-10-15/hour=10/minute=00/d.parquet", "d")
+    }
+
+    fn time(rfc3339: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap().into()
+    }
+
+    fn keys(files: &[ParquetFile]) -> Vec<&str> {
+        files.iter().map(|file| file.key.as_str()).collect()
+    }
+
+    // name and body of every entry of the archive
+    fn entries(archive: &[u8]) -> Vec<(String, String)> {
+        tar::Archive::new(archive)
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut body = String::new();
+                entry.read_to_string(&mut body).unwrap();
+                (entry.path().unwrap().display().to_string(), body)
+            })
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn files_of_stream() {
+        let storage = storage();
+
+        let all = files(&storage, "stream", None, None).await.unwrap();
+        assert_eq!(
+            keys(&all),
+            vec![
+                "stream/date=2022-10-15/hour=10/minute=00/a.parquet",
+                "stream/date=2022-10-15/hour=11/minute=00/b.parquet",
+                "stream/date=2022-10-15/hour=12/minute=00/c.parquet",
+            ]
+        );
+
+        let range = Some((time("2022-10-15T10:30:00Z"), time("2022-10-15T11:30:00Z")));
+        let in_range = files(&storage, "stream", range, None).await.unwrap();
+        assert_eq!(
+            keys(&in_range),
+            vec!["stream/date=2022-10-15/hour=11/minute=00/b.parquet"]
+        );
+
+        // a resumed export skips the files already exported
+        let after = "stream/date=2022-10-15/hour=10/minute=00/a.parquet";
+        let resumed = files(&storage, "stream", None, Some(after)).await.unwrap();
+        assert_eq!(keys(&resumed), keys(&all[1..]));
+    }
+
+    #[actix_web::test]
+    async fn archive_of_files() {
+        let storage = storage();
+        let files = files(&storage, "stream", None, None).await.unwrap();
+
+        let chunks = archive(Arc::new(storage), Bytes::from("{}"), files)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        // one chunk per entry, and the end of the archive
+        assert_eq!(chunks.len(), 5);
+        assert!(chunks.iter().all(|chunk| chunk.len() % BLOCK_SIZE == 0));
+        assert_eq!(
+            entries(&chunks.concat()),
+            vec![
+                (SIDECAR_NAME.to_string(), "{}".to_string()),
+                (
+                    "stream/date=2022-10-15/hour=10/minute=00/a.parquet".to_string(),
+                    "a".to_string()
+                ),
+                (
+                    "stream/date=2022-10-15/hour=11/minute=00/b.parquet".to_string(),
+                    "bb".to_string()
+                ),
+                (
+                    "stream/date=2022-10-15/hour=12/minute=00/c.parquet".to_string(),
+                    "ccc".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn archive_ends_with_error_on_failed_fetch() {
+        let storage = storage().with_failures("get_object", 1);
+        let files = files(&storage, "stream", None, None).await.unwrap();
+
+        let chunks = archive(Arc::new(storage), Bytes::from("{}"), files)
+            .collect::<Vec<_>>()
+            .await;
+
+        assert!(chunks[0].is_ok());
+        assert!(chunks[1].is_err());
+    }
+}
@@ -0,0 +1,1112 @@
+/*
+ * Parseable Server (C) 2022 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use arrow::datatypes::Schema;
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Duration, Utc};
+use datafusion::arrow::record_batch::RecordBatch;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use reqwest::header::{CONTENT_LENGTH, CONTENT_RANGE, LOCATION, RANGE};
+use reqwest::{Client, RequestBuilder, Response, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use structopt::StructOpt;
+
+use crate::alerts::Alerts;
+use crate::metadata::{
+    Compression, Flatten, Limits, PartitionGranularity, Stats, StreamTimestamps,
+};
+use crate::migration::MetadataDocument;
+use crate::option::{StorageOpt, CONFIG};
+use crate::query::Query;
+use crate::retention::Retention;
+use crate::storage::{
+    DeletedObjects, LogStream, ObjectMeta, ObjectStorage, ObjectStorageError, ParquetFile,
+    StoredObjects,
+};
+use crate::utils;
+
+const GCS_URL: &str = "https://storage.googleapis.com";
+const GCS_SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+const JWT_BEARER_GRANT: &str = "urn:ietf:params:oauth:grant-type:jwt-bearer";
+/// Token endpoint of the metadata server, serves tokens of the service account
+/// of a GCE instance or, with workload identity, of a GKE pod
+const METADATA_TOKEN_URL: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+
+/// Files larger than this are uploaded in chunks of this size, through a resumable
+/// upload. Must be a multiple of 256 KiB.
+const RESUMABLE_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+/// Times in a row a chunk may fail to upload before the upload is given up
+const RESUMABLE_CHUNK_ATTEMPTS: usize = 3;
+
+/// An access token is refreshed this long before it actually expires,
+/// so that it doesn't expire while a request is in flight.
+const TOKEN_EXPIRY_MARGIN_SECS: i64 = 60;
+
+lazy_static::lazy_static! {
+    #[derive(Debug)]
+    pub static ref GCS_CONFIG: Arc<GcsConfig> = Arc::new(GcsConfig::from_args());
+
+    static ref SERVICE_ACCOUNT: Option<ServiceAccountKey> =
+        GCS_CONFIG.gcs_key_path.as_ref().map(|path| {
+            ServiceAccountKey::from_file(path).unwrap_or_else(|e| {
+                panic!(
+                    "Could not read the service account key {}. {}",
+                    path.display(),
+                    e
+                )
+            })
+        });
+
+    // access tokens are valid for an hour, share them between all clients
+    static ref ACCESS_TOKEN: Mutex<Option<AccessToken>> = Mutex::new(None);
+}
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(name = "GCS config", about = "configuration for Google Cloud Storage")]
+pub struct GcsConfig {
+    /// The path to the JSON key of the service account used to access Google Cloud Storage.
+    /// Without a key, the service account of the instance or, with workload identity,
+    /// of the GKE pod is used
+    #[structopt(long, env = "P_GCS_KEY_PATH")]
+    pub gcs_key_path: Option<PathBuf>,
+
+    /// The Google Cloud Storage bucket to be used for storage
+    #[structopt(long, env = "P_GCS_BUCKET")]
+    pub gcs_bucket_name: String,
+}
+
+impl StorageOpt for GcsConfig {
+    fn bucket_name(&self) -> &str {
+        &self.gcs_bucket_name
+    }
+
+    fn endpoint_url(&self) -> &str {
+        GCS_URL
+    }
+
+    fn is_default_url(&self) -> bool {
+        false
+    }
+
+    fn warning(&self) {}
+
+    fn object_storage(&self) -> Box<dyn ObjectStorage> {
+        Box::new(Gcs::new())
+    }
+}
+
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+impl ServiceAccountKey {
+    fn from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let key = serde_json::from_slice(&fs::read(path)?)?;
+        Ok(key)
+    }
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: i64,
+}
+
+#[derive(Clone)]
+struct AccessToken {
+    token: String,
+    expires_at: DateTime<Utc>,
+}
+
+impl AccessToken {
+    fn is_valid(&self) -> bool {
+        self.expires_at - Duration::seconds(TOKEN_EXPIRY_MARGIN_SECS) > Utc::now()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListObjectsResponse {
+    #[serde(default)]
+    items: Vec<ObjectResource>,
+    #[serde(default)]
+    prefixes: Vec<String>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ObjectResource {
+    name: String,
+    /// size in bytes, the JSON API returns it as a string
+    #[serde(default)]
+    size: String,
+    /// base64 encoded, not present on composite objects
+    #[serde(rename = "md5Hash", default)]
+    md5_hash: Option<String>,
+}
+
+pub struct Gcs {
+    client: Client,
+}
+
+impl Gcs {
+    pub fn new() -> Self {
+        Self {
+            client: Client::new(),
+        }
+    }
+
+    /// Returns a cached access token, or a new one from the service account key
+    /// or, without a key, from the metadata server.
+    async fn token(&self) -> Result<String, ObjectStorageError> {
+        if let Some(token) = ACCESS_TOKEN.lock().unwrap().as_ref() {
+            if token.is_valid() {
+                return Ok(token.token.clone());
+            }
+        }
+
+        let resp = match SERVICE_ACCOUNT.as_ref() {
+            Some(key) => self.service_account_token(key).await?,
+            None => self.metadata_server_token().await?,
+        };
+
+        let token = AccessToken {
+            token: resp.access_token,
+            expires_at: Utc::now() + Duration::seconds(resp.expires_in),
+        };
+        *ACCESS_TOKEN.lock().unwrap() = Some(token.clone());
+
+        Ok(token.token)
+    }
+
+    /// Exchange a JWT signed with the service account key for an access token.
+    async fn service_account_token(
+        &self,
+        key: &ServiceAccountKey,
+    ) -> Result<TokenResponse, ObjectStorageError> {
+        let now = Utc::now().timestamp();
+        let claims = Claims {
+            iss: &key.client_email,
+            scope: GCS_SCOPE,
+            aud: &key.token_uri,
+            iat: now,
+            exp: now + 3600,
+        };
+        let encoding_key = EncodingKey::from_rsa_pem(key.private_key.as_bytes())
+            .map_err(|e| ObjectStorageError::UnhandledError(e.into()))?;
+        let assertion =
+            jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &encoding_key)
+                .map_err(|e| ObjectStorageError::UnhandledError(e.into()))?;
+
+        let resp = self
+            .client
+            .post(&key.token_uri)
+            .form(&[("grant_type", JWT_BEARER_GRANT), ("assertion", &assertion)])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(resp)
+    }
+
+    /// Access token of the service account the server runs as.
+    /// See https://cloud.google.com/kubernetes-engine/docs/how-to/workload-identity
+    async fn metadata_server_token(&self) -> Result<TokenResponse, ObjectStorageError> {
+        let mut url = Url::parse(METADATA_TOKEN_URL).unwrap();
+        url.query_pairs_mut().append_pair("scopes", GCS_SCOPE);
+
+        let resp = self
+            .client
+            .get(url)
+            .header("Metadata-Flavor", "Google")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(resp)
+    }
+
+    async fn send(&self, request: RequestBuilder) -> Result<Response, ObjectStorageError> {
+        let token = self.token().await?;
+        let resp = request.bearer_auth(token).send().await?;
+
+        Ok(resp)
+    }
+
+    fn bucket_url(&self) -> Url {
+        let mut url = Url::parse(GCS_URL).unwrap();
+        url.path_segments_mut().unwrap().extend([
+            "storage",
+            "v1",
+            "b",
+            &GCS_CONFIG.gcs_bucket_name,
+        ]);
+        url
+    }
+
+    fn object_url(&self, key: &str) -> Url {
+        let mut url = self.bucket_url();
+        // the object name is a single path segment, so `/` in the key gets escaped
+        url.path_segments_mut().unwrap().extend(["o", key]);
+        url
+    }
+
+    async fn _get(&self, key: &str) -> Result<Bytes, ObjectStorageError> {
+        let mut url = self.object_url(key);
+        url.query_pairs_mut().append_pair("alt", "media");
+
+        let resp = self.send(self.client.get(url)).await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Err(ObjectStorageError::NoSuchKey(key.to_string()));
+        }
+
+        Ok(resp.error_for_status()?.bytes().await?)
+    }
+
+    fn upload_url(&self, key: &str, upload_type: &str) -> Url {
+        let mut url = Url::parse(GCS_URL).unwrap();
+        url.path_segments_mut().unwrap().extend([
+            "upload",
+            "storage",
+            "v1",
+            "b",
+            &GCS_CONFIG.gcs_bucket_name,
+            "o",
+        ]);
+        url.query_pairs_mut()
+            .append_pair("uploadType", upload_type)
+            .append_pair("name", key);
+        url
+    }
+
+    async fn _put(&self, key: &str, body: Vec<u8>) -> Result<(), ObjectStorageError> {
+        let url = self.upload_url(key, "media");
+        self.send(self.client.post(url).body(body))
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    /// Upload the file in chunks, through a resumable upload session. When a chunk
+    /// fails to upload, the session is asked how much it has received and the
+    /// upload carries on from there, instead of starting over.
+    /// See https://cloud.google.com/storage/docs/performing-resumable-uploads
+    async fn _resumable_upload(&self, key: &str, path: &str) -> Result<(), ObjectStorageError> {
+        let mut file = fs::File::open(path)?;
+        let total = file.metadata()?.len();
+
+        let resp = self
+            .send(
+                self.client
+                    .post(self.upload_url(key, "resumable"))
+                    .header(CONTENT_LENGTH, 0),
+            )
+            .await?
+            .error_for_status()?;
+        let session = resp
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .ok_or_else(|| {
+                ObjectStorageError::UnhandledError(
+                    format!("no resumable upload session was started for {}", key).into(),
+                )
+            })?
+            .to_string();
+
+        let mut offset = 0;
+        let mut attempts = 0;
+        while offset < total {
+            let len = RESUMABLE_CHUNK_SIZE.min(total - offset);
+            let mut chunk = vec![0; len as usize];
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut chunk)?;
+
+            let request = self
+                .client
+                .put(&session)
+                .header(
+                    CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", offset, offset + len - 1, total),
+                )
+                .body(chunk);
+            attempts += 1;
+            let resp = match self.send(request).await {
+                Ok(resp) if !resp.status().is_server_error() => Some(resp),
+                Ok(_) | Err(_) if attempts < RESUMABLE_CHUNK_ATTEMPTS => None,
+                Ok(resp) => Some(resp),
+                Err(e) => return Err(e),
+            };
+            let resp = match resp {
+                Some(resp) => resp,
+                // the chunk may have been received in part
+                None => {
+                    let request = self
+                        .client
+                        .put(&session)
+                        .header(CONTENT_RANGE, format!("bytes */{}", total))
+                        .header(CONTENT_LENGTH, 0);
+                    self.send(request).await?
+                }
+            };
+
+            match resp.status() {
+                status if status.is_success() => offset = total,
+                StatusCode::PERMANENT_REDIRECT => {
+                    let range = resp
+                        .headers()
+                        .get(RANGE)
+                        .and_then(|range| range.to_str().ok());
+                    let received = received_bytes(range);
+                    if received > offset {
+                        attempts = 0;
+                    }
+                    offset = received;
+                }
+                status => {
+                    return Err(ObjectStorageError::UnhandledError(
+                        format!("resumable upload of {} failed with {}", key, status).into(),
+                    ))
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn _delete(&self, key: &str) -> Result<(), ObjectStorageError> {
+        let resp = self.send(self.client.delete(self.object_url(key))).await?;
+        // object is already gone
+        if resp.status() != StatusCode::NOT_FOUND {
+            resp.error_for_status()?;
+        }
+
+        Ok(())
+    }
+
+    /// Copy the object within the bucket, without downloading it.
+    async fn _copy(&self, from: &str, to: &str) -> Result<(), ObjectStorageError> {
+        let mut url = self.object_url(from);
+        url.path_segments_mut().unwrap().extend([
+            "copyTo",
+            "b",
+            &GCS_CONFIG.gcs_bucket_name,
+            "o",
+            to,
+        ]);
+
+        self.send(self.client.post(url).body(Vec::new()))
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    /// List all objects under `prefix`. With a `delimiter`, objects nested deeper
+    /// are not listed and their common prefixes are returned instead.
+    async fn _list(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+    ) -> Result<(Vec<ObjectResource>, Vec<String>), ObjectStorageError> {
+        let mut objects = Vec::new();
+        let mut prefixes = Vec::new();
+        let mut page_token: Option<String> = None;
+
+        loop {
+            let page = self
+                ._list_page(prefix, delimiter, page_token.as_deref())
+                .await?;
+
+            objects.extend(page.items);
+            prefixes.extend(page.prefixes);
+
+            match page.next_page_token {
+                Some(token) => page_token = Some(token),
+                None => break,
+            }
+        }
+
+        Ok((objects, prefixes))
+    }
+
+    async fn _list_page(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+        page_token: Option<&str>,
+    ) -> Result<ListObjectsResponse, ObjectStorageError> {
+        let mut url = self.bucket_url();
+        url.path_segments_mut().unwrap().push("o");
+        {
+            let mut query = url.query_pairs_mut();
+            query.append_pair("prefix", prefix);
+            if let Some(delimiter) = delimiter {
+                query.append_pair("delimiter", delimiter);
+            }
+            if let Some(page_token) = page_token {
+                query.append_pair("pageToken", page_token);
+            }
+        }
+
+        let page = self
+            .send(self.client.get(url))
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(page)
+    }
+}
+
+#[async_trait]
+impl ObjectStorage for Gcs {
+    async fn check(&self) -> Result<(), ObjectStorageError> {
+        let resp = self.send(self.client.get(self.bucket_url())).await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Err(ObjectStorageError::NoSuchBucket(
+                GCS_CONFIG.gcs_bucket_name.clone(),
+            ));
+        }
+        if matches!(
+            resp.status(),
+            StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED
+        ) {
+            return Err(ObjectStorageError::AccessDenied(
+                GCS_CONFIG.gcs_bucket_name.clone(),
+            ));
+        }
+        resp.error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn put_schema(
+        &self,
+        stream_name: String,
+        schema: &Schema,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(schema)?;
+        self._put(&format!("{}/.schema", stream_name), body).await
+    }
+
+    async fn create_stream(&self, stream_name: &str) -> Result<(), ObjectStorageError> {
+        self._put(&format!("{}/.schema", stream_name), Vec::new())
+            .await?;
+        // Prefix created on GCS, now create the directory in
+        // the local storage as well
+        let _res = fs::create_dir_all(CONFIG.parseable.local_stream_data_path(stream_name));
+
+        Ok(())
+    }
+
+    async fn create_alert(
+        &self,
+        stream_name: &str,
+        alerts: &Alerts,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(alerts)?;
+        self._put(&format!("{}/.alert.json", stream_name), body)
+            .await
+    }
+
+    async fn get_schema(&self, stream_name: &str) -> Result<Bytes, ObjectStorageError> {
+        self._get(&format!("{}/.schema", stream_name)).await
+    }
+
+    // The generation of the object is its version tag
+    async fn get_schema_tagged(
+        &self,
+        stream_name: &str,
+    ) -> Result<Option<(Bytes, String)>, ObjectStorageError> {
+        let mut url = self.object_url(&format!("{}/.schema", stream_name));
+        url.query_pairs_mut().append_pair("alt", "media");
+
+        let resp = self.send(self.client.get(url)).await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let resp = resp.error_for_status()?;
+        let generation = resp
+            .headers()
+            .get("x-goog-generation")
+            .and_then(|generation| generation.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+
+        Ok(Some((resp.bytes().await?, generation)))
+    }
+
+    // See https://cloud.google.com/storage/docs/request-preconditions
+    async fn put_schema_if(
+        &self,
+        stream_name: &str,
+        schema: &Schema,
+        tag: Option<&str>,
+    ) -> Result<(), ObjectStorageError> {
+        let key = format!("{}/.schema", stream_name);
+        let body = serde_json::to_vec(schema)?;
+        let mut url = self.upload_url(&key, "media");
+        // generation 0 only matches if there is no object yet
+        url.query_pairs_mut()
+            .append_pair("ifGenerationMatch", tag.unwrap_or("0"));
+
+        let resp = self.send(self.client.post(url).body(body)).await?;
+        if resp.status() == StatusCode::PRECONDITION_FAILED {
+            return Err(ObjectStorageError::PreconditionFailed(key));
+        }
+        resp.error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn get_alert(&self, stream_name: &str) -> Result<Bytes, ObjectStorageError> {
+        self._get(&format!("{}/.alert.json", stream_name)).await
+    }
+
+    async fn get_stats(&self, stream_name: &str) -> Result<Stats, ObjectStorageError> {
+        let body = self._get(&format!("{}/.stats.json", stream_name)).await?;
+        let stats = serde_json::from_slice(&body)?;
+
+        Ok(stats)
+    }
+
+    async fn put_stats(&self, stream_name: &str, stats: &Stats) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(stats)?;
+        self._put(&format!("{}/.stats.json", stream_name), body)
+            .await
+    }
+
+    async fn put_retention(
+        &self,
+        stream_name: &str,
+        retention: &Retention,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(retention)?;
+        self._put(&format!("{}/.retention.json", stream_name), body)
+            .await
+    }
+
+    async fn get_retention(&self, stream_name: &str) -> Result<Retention, ObjectStorageError> {
+        let body = self
+            ._get(&format!("{}/.retention.json", stream_name))
+            .await?;
+        let retention = serde_json::from_slice(&body)?;
+
+        Ok(retention)
+    }
+
+    async fn put_tags(
+        &self,
+        stream_name: &str,
+        tags: &HashMap<String, String>,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(tags)?;
+        self._put(&format!("{}/.tags.json", stream_name), body)
+            .await
+    }
+
+    async fn get_tags(
+        &self,
+        stream_name: &str,
+    ) -> Result<HashMap<String, String>, ObjectStorageError> {
+        let body = self._get(&format!("{}/.tags.json", stream_name)).await?;
+        let tags = serde_json::from_slice(&body)?;
+
+        Ok(tags)
+    }
+
+    async fn put_static_schema(
+        &self,
+        stream_name: &str,
+        static_schema: bool,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(&static_schema)?;
+        self._put(&format!("{}/.static_schema.json", stream_name), body)
+            .await
+    }
+
+    async fn get_static_schema(&self, stream_name: &str) -> Result<bool, ObjectStorageError> {
+        let body = self
+            ._get(&format!("{}/.static_schema.json", stream_name))
+            .await?;
+        let static_schema = serde_json::from_slice(&body)?;
+
+        Ok(static_schema)
+    }
+
+    async fn put_deleted_at(
+        &self,
+        stream_name: &str,
+        deleted_at: Option<DateTime<Utc>>,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(&deleted_at)?;
+        self._put(&format!("{}/.deleted.json", stream_name), body)
+            .await
+    }
+
+    async fn get_deleted_at(
+        &self,
+        stream_name: &str,
+    ) -> Result<Option<DateTime<Utc>>, ObjectStorageError> {
+        let body = self._get(&format!("{}/.deleted.json", stream_name)).await?;
+        let deleted_at = serde_json::from_slice(&body)?;
+
+        Ok(deleted_at)
+    }
+
+    async fn put_renamed_from(
+        &self,
+        stream_name: &str,
+        renamed_from: &str,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(renamed_from)?;
+        self._put(&format!("{}/.renamed_from.json", stream_name), body)
+            .await
+    }
+
+    async fn get_renamed_from(&self, stream_name: &str) -> Result<String, ObjectStorageError> {
+        let body = self
+            ._get(&format!("{}/.renamed_from.json", stream_name))
+            .await?;
+        let renamed_from = serde_json::from_slice(&body)?;
+
+        Ok(renamed_from)
+    }
+
+    async fn put_time_field(
+        &self,
+        stream_name: &str,
+        time_field: &str,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(time_field)?;
+        self._put(&format!("{}/.time_field.json", stream_name), body)
+            .await
+    }
+
+    async fn get_time_field(&self, stream_name: &str) -> Result<String, ObjectStorageError> {
+        let body = self
+            ._get(&format!("{}/.time_field.json", stream_name))
+            .await?;
+        let time_field = serde_json::from_slice(&body)?;
+
+        Ok(time_field)
+    }
+
+    async fn put_limits(
+        &self,
+        stream_name: &str,
+        limits: &Limits,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(limits)?;
+        self._put(&format!("{}/.limits.json", stream_name), body)
+            .await
+    }
+
+    async fn get_limits(&self, stream_name: &str) -> Result<Limits, ObjectStorageError> {
+        let body = self._get(&format!("{}/.limits.json", stream_name)).await?;
+        let limits = serde_json::from_slice(&body)?;
+
+        Ok(limits)
+    }
+
+    async fn put_compression(
+        &self,
+        stream_name: &str,
+        compression: Compression,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(&compression)?;
+        self._put(&format!("{}/.compression.json", stream_name), body)
+            .await
+    }
+
+    async fn get_compression(&self, stream_name: &str) -> Result<Compression, ObjectStorageError> {
+        let body = self
+            ._get(&format!("{}/.compression.json", stream_name))
+            .await?;
+        let compression = serde_json::from_slice(&body)?;
+
+        Ok(compression)
+    }
+
+    async fn put_flatten(
+        &self,
+        stream_name: &str,
+        flatten: Flatten,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(&flatten)?;
+        self._put(&format!("{}/.flatten.json", stream_name), body)
+            .await
+    }
+
+    async fn get_flatten(&self, stream_name: &str) -> Result<Flatten, ObjectStorageError> {
+        let body = self._get(&format!("{}/.flatten.json", stream_name)).await?;
+        let flatten = serde_json::from_slice(&body)?;
+
+        Ok(flatten)
+    }
+
+    async fn put_partition(
+        &self,
+        stream_name: &str,
+        partition: PartitionGranularity,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(&partition)?;
+        self._put(&format!("{}/.partition.json", stream_name), body)
+            .await
+    }
+
+    async fn get_partition(
+        &self,
+        stream_name: &str,
+    ) -> Result<PartitionGranularity, ObjectStorageError> {
+        let body = self
+            ._get(&format!("{}/.partition.json", stream_name))
+            .await?;
+        let partition = serde_json::from_slice(&body)?;
+
+        Ok(partition)
+    }
+
+    async fn put_metadata(
+        &self,
+        stream_name: &str,
+        document: &MetadataDocument,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(document)?;
+        self._put(&format!("{}/.metadata.json", stream_name), body)
+            .await
+    }
+
+    async fn get_metadata(
+        &self,
+        stream_name: &str,
+    ) -> Result<MetadataDocument, ObjectStorageError> {
+        let body = self
+            ._get(&format!("{}/.metadata.json", stream_name))
+            .await?;
+        let document = serde_json::from_slice(&body)?;
+
+        Ok(document)
+    }
+
+    async fn put_timestamps(
+        &self,
+        stream_name: &str,
+        timestamps: &StreamTimestamps,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(timestamps)?;
+        self._put(&format!("{}/.timestamps.json", stream_name), body)
+            .await
+    }
+
+    async fn get_timestamps(
+        &self,
+        stream_name: &str,
+    ) -> Result<StreamTimestamps, ObjectStorageError> {
+        let body = self
+            ._get(&format!("{}/.timestamps.json", stream_name))
+            .await?;
+        let timestamps = serde_json::from_slice(&body)?;
+
+        Ok(timestamps)
+    }
+
+    async fn list_streams(&self) -> Result<Vec<LogStream>, ObjectStorageError> {
+        let (_, prefixes) = self._list("", Some("/")).await?;
+        let streams = prefixes
+            .into_iter()
+            .map(|prefix| LogStream {
+                name: prefix.trim_end_matches('/').to_string(),
+            })
+            .collect();
+
+        Ok(streams)
+    }
+
+    async fn list_dirs(&self, prefix: &str) -> Result<Vec<String>, ObjectStorageError> {
+        let (_, prefixes) = self._list(prefix, Some("/")).await?;
+        let dirs = prefixes
+            .iter()
+            .map(|dir| {
+                dir.trim_start_matches(prefix)
+                    .trim_end_matches('/')
+                    .to_string()
+            })
+            .collect();
+
+        Ok(dirs)
+    }
+
+    async fn put_object(&self, key: &str, body: Bytes) -> Result<(), ObjectStorageError> {
+        let url = self.upload_url(key, "media");
+        let resp = self.send(self.client.post(url).body(body)).await?;
+        if matches!(
+            resp.status(),
+            StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED
+        ) {
+            return Err(ObjectStorageError::AccessDenied(
+                GCS_CONFIG.gcs_bucket_name.clone(),
+            ));
+        }
+        resp.error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn get_object(&self, key: &str) -> Result<Bytes, ObjectStorageError> {
+        self._get(key).await
+    }
+
+    async fn upload_file(&self, key: &str, path: &str) -> Result<(), ObjectStorageError> {
+        if fs::metadata(path)?.len() > RESUMABLE_CHUNK_SIZE {
+            return self._resumable_upload(key, path).await;
+        }
+
+        let body = fs::read(path)?;
+        self._put(key, body).await
+    }
+
+    async fn object_meta(&self, key: &str) -> Result<ObjectMeta, ObjectStorageError> {
+        let resp = self.send(self.client.get(self.object_url(key))).await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Err(ObjectStorageError::NoSuchKey(key.to_string()));
+        }
+        let object: ObjectResource = resp.error_for_status()?.json().await?;
+
+        Ok(ObjectMeta {
+            size: object.size.parse().unwrap_or_default(),
+            md5: object
+                .md5_hash
+                .and_then(|md5| base64::decode(md5).ok())
+                .and_then(|md5| md5.try_into().ok()),
+        })
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> Result<DeletedObjects, ObjectStorageError> {
+        let (objects, _) = self._list(prefix, None).await?;
+
+        let mut deleted = DeletedObjects::default();
+        for object in objects {
+            // carry on with the rest, so that as little as possible is left behind
+            match self._delete(&object.name).await {
+                Ok(()) => {
+                    deleted.objects += 1;
+                    deleted.size += object.size.parse::<u64>().unwrap_or_default();
+                }
+                Err(e) => {
+                    log::warn!("failed to delete object {}. {:?}", object.name, e);
+                    deleted.failed += 1;
+                }
+            }
+        }
+
+        deleted.complete()
+    }
+
+    async fn copy_prefix(&self, from: &str, to: &str) -> Result<u64, ObjectStorageError> {
+        let mut copied = 0;
+        let mut page_token: Option<String> = None;
+
+        loop {
+            let page = self._list_page(from, None, page_token.as_deref()).await?;
+            for object in page.items {
+                let target = format!(
+                    "{}{}",
+                    to,
+                    object.name.strip_prefix(from).unwrap_or(&object.name)
+                );
+                self._copy(&object.name, &target).await?;
+                copied += 1;
+            }
+
+            match page.next_page_token {
+                Some(token) => page_token = Some(token),
+                None => break,
+            }
+        }
+
+        Ok(copied)
+    }
+
+    async fn count_objects(&self, prefix: &str) -> Result<u64, ObjectStorageError> {
+        let (objects, _) = self._list(prefix, None).await?;
+
+        Ok(objects.len() as u64)
+    }
+
+    async fn parquet_objects(&self, prefix: &str) -> Result<StoredObjects, ObjectStorageError> {
+        let mut stored = StoredObjects::default();
+        let mut page_token: Option<String> = None;
+
+        // summed up page by page, a stream can have a lot of objects
+        loop {
+            let page = self._list_page(prefix, None, page_token.as_deref()).await?;
+            for object in page.items.iter().filter(|o| o.name.ends_with(".parquet")) {
+                stored.objects += 1;
+                stored.size += object.size.parse::<u64>().unwrap_or_default();
+            }
+
+            match page.next_page_token {
+                Some(token) => page_token = Some(token),
+                None => break,
+            }
+        }
+
+        Ok(stored)
+    }
+
+    async fn list_parquet_files(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<ParquetFile>, ObjectStorageError> {
+        let mut files = Vec::new();
+        let mut page_token: Option<String> = None;
+
+        loop {
+            let page = self._list_page(prefix, None, page_token.as_deref()).await?;
+            for object in page.items {
+                if object.name.ends_with(".parquet") {
+                    files.push(ParquetFile {
+                        size: object.size.parse().unwrap_or_default(),
+                        key: object.name,
+                    });
+                }
+            }
+
+            match page.next_page_token {
+                Some(token) => page_token = Some(token),
+                None => break,
+            }
+        }
+
+        Ok(files)
+    }
+
+    async fn query(
+        &self,
+        query: &Query,
+        results: &mut Vec<RecordBatch>,
+    ) -> Result<(), ObjectStorageError> {
+        // DataFusion can't read from GCS directly, so parquet files of the
+        // queried prefixes are downloaded to a local directory first.
+        let dir = std::env::temp_dir().join(format!("parseable-{}", utils::random_string()));
+        fs::create_dir_all(&dir)?;
+
+        let result = self.query_in_dir(query, &dir, results).await;
+        if let Err(e) = fs::remove_dir_all(&dir) {
+            log::warn!("failed to remove query directory {}. {}", dir.display(), e);
+        }
+
+        result
+    }
+}
+
+impl Gcs {
+    async fn query_in_dir(
+        &self,
+        query: &Query,
+        dir: &Path,
+        results: &mut Vec<RecordBatch>,
+    ) -> Result<(), ObjectStorageError> {
+        let mut downloaded = 0;
+        for prefix in self
+            .query_partitions(&query.stream_name, query.start, query.end)
+            .await?
+        {
+            let (objects, _) = self._list(&prefix, None).await?;
+            for object in objects.iter().filter(|obj| obj.name.ends_with(".parquet")) {
+                let body = self._get(&object.name).await?;
+                fs::write(dir.join(object.name.replace('/', ".")), body)?;
+                downloaded += 1;
+            }
+        }
+
+        if downloaded > 0 {
+            query
+                .execute_on_dir(&dir.display().to_string(), results)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Bytes a resumable upload session has received, from the `Range: bytes=0-{last}`
+/// header of its 308 response. The header is missing if nothing was received.
+fn received_bytes(range: Option<&str>) -> u64 {
+    range
+        .and_then(|range| range.rsplit_once('-'))
+        .and_then(|(_, last)| last.parse::<u64>().ok())
+        .map_or(0, |last| last + 1)
+}
+
+impl From<reqwest::Error> for ObjectStorageError {
+    fn from(error: reqwest::Error) -> Self {
+        let is_unavailable = error.status().map_or(false, |status| {
+            status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+        });
+        if error.is_connect() || error.is_timeout() {
+            ObjectStorageError::ConnectionError(error.into())
+        } else if is_unavailable {
+            ObjectStorageError::Unavailable(error.into())
+        } else {
+            ObjectStorageError::UnhandledError(error.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn received_bytes_of_resumable_upload() {
+        assert_eq!(
+            received_bytes(Some("bytes=0-8388607")),
+            RESUMABLE_CHUNK_SIZE
+        );
+        assert_eq!(received_bytes(None), 0);
+        assert_eq!(received_bytes(Some("bytes=0-")), 0);
+    }
+}
@@ -16,19 +16,25 @@
  *
  */
 
-use actix_web::http::StatusCode;
+use actix_web::error::PayloadError;
+use actix_web::http::{header, StatusCode};
 use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::auth;
 use crate::event;
 use crate::metadata;
-use crate::query::Query;
+use crate::metrics;
+use crate::option::CONFIG;
+use crate::query::{self, Query};
 use crate::response::{self, EventResponse};
-use crate::s3::S3;
 use crate::storage::ObjectStorage;
-use crate::utils;
+use crate::utils::{self, LineError};
 
-pub async fn query(_req: HttpRequest, json: web::Json<Value>) -> HttpResponse {
+pub async fn query(req: HttpRequest, json: web::Json<Value>) -> HttpResponse {
     let json = json.into_inner();
     let query = match Query::parse(json) {
         Ok(s) => s,
@@ -48,16 +54,25 @@ pub async fn query(_req: HttpRequest, json: web::Json<Value>) -> HttpResponse {
         }
     };
 
-    let storage = S3::new();
+    // the API key was checked before the stream it queries was known
+    if !auth::permits(&req, auth::Action::Read, &query.stream_name) {
+        return response::ServerResponse {
+            msg: format!("not permitted to query log stream {}", query.stream_name),
+            code: StatusCode::FORBIDDEN,
+        }
+        .to_http();
+    }
 
-    if storage.get_schema(&query.stream_name).await.is_err() {
+    if !metadata::STREAM_INFO.stream_exists(&query.stream_name) {
         return response::ServerResponse {
             msg: format!("log stream {} does not exist", query.stream_name),
-            code: StatusCode::BAD_REQUEST,
+            code: StatusCode::NOT_FOUND,
         }
         .to_http();
     }
 
+    let storage = CONFIG.object_storage();
+
     match query.execute(&storage).await {
         Ok(results) => response::QueryResponse {
             body: results,
@@ -72,35 +87,134 @@ pub async fn query(_req: HttpRequest, json: web::Json<Value>) -> HttpResponse {
     }
 }
 
-pub async fn post_event(req: HttpRequest, body: web::Json<serde_json::Value>) -> HttpResponse {
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamQuery {
+    query: String,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+}
+
+// Query a single log stream, the results are returned as JSON rows.
+pub async fn query_stream(req: HttpRequest, body: web::Json<StreamQuery>) -> HttpResponse {
     let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
-    let labels = utils::collect_labels(&req);
+    let StreamQuery {
+        query,
+        start_time,
+        end_time,
+    } = body.into_inner();
 
-    if let Err(e) = metadata::STREAM_INFO.schema(&stream_name) {
-        // if stream doesn't exist, fail to post data
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
         return response::ServerResponse {
-            msg: format!(
-                "Failed to post event. Log stream {} does not exist. Error: {}",
-                stream_name, e
-            ),
+            msg: format!("log stream {} does not exist", stream_name),
             code: StatusCode::NOT_FOUND,
         }
         .to_http();
+    }
+
+    let storage = CONFIG.object_storage();
+    match query::run_sql(storage.as_ref(), &stream_name, &query, start_time..end_time).await {
+        Ok(results) => response::QueryResponse {
+            body: results,
+            code: StatusCode::OK,
+        }
+        .to_http(),
+        Err(e @ (crate::Error::DataFusion(_) | crate::Error::Storage(_))) => {
+            response::ServerResponse {
+                msg: format!("Failed to execute query due to err: {}", e),
+                code: StatusCode::INTERNAL_SERVER_ERROR,
+            }
+            .to_http()
+        }
+        Err(e) => response::ServerResponse {
+            msg: format!("Bad Request: {}", e),
+            code: StatusCode::BAD_REQUEST,
+        }
+        .to_http(),
+    }
+}
+
+pub async fn post_event(req: HttpRequest, payload: web::Payload) -> HttpResponse {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let labels = utils::collect_labels(&req);
+
+    // if stream doesn't exist, fail to post data
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return stream_not_found(&stream_name);
+    }
+    // events would be split across the old and new prefix
+    if metadata::STREAM_INFO.is_renaming(&stream_name) {
+        return rename_in_progress(&stream_name);
+    }
+
+    let body = match read_body(&req, payload, &stream_name).await {
+        Ok(body) => body,
+        Err(resp) => return resp,
+    };
+    let body: Value = match serde_json::from_slice(&body) {
+        Ok(body) => body,
+        Err(e) => {
+            metrics::EVENTS_FAILED
+                .with_label_values(&[&stream_name])
+                .inc();
+            return response::ServerResponse {
+                msg: format!("Failed to post event. Invalid JSON body: {}", e),
+                code: StatusCode::BAD_REQUEST,
+            }
+            .to_http();
+        }
     };
 
-    let s3 = S3::new();
+    let storage = CONFIG.object_storage();
 
-    if let Some(array) = body.as_array() {
+    // events of a stream with a static schema are all checked before any of
+    // them is ingested, so that a batch is ingested either completely or not at all
+    let is_batch = body.is_array();
+    let events = match body {
+        Value::Array(array) => array,
+        body => vec![body],
+    };
+    let flatten = metadata::STREAM_INFO
+        .flatten(&stream_name)
+        .unwrap_or_default();
+    let events = events
+        .into_iter()
+        .map(|event| utils::flatten_json_body(web::Json(event), labels.clone(), flatten).unwrap())
+        .enumerate()
+        .collect::<Vec<_>>();
+    if let Err(resp) = validate_limits(&stream_name, &events) {
+        return resp;
+    }
+    if let Err(resp) = validate_static_schema(&stream_name, &events) {
+        return resp;
+    }
+    if let Err(resp) = validate_event_time(&stream_name, &events) {
+        return resp;
+    }
+
+    if is_batch {
         let mut i = 0;
 
-        for body in array {
-            let body = utils::flatten_json_body(web::Json(body.clone()), labels.clone()).unwrap();
+        for (_, body) in events {
             let e = event::Event {
                 body,
                 stream_name: stream_name.clone(),
             };
 
-            if let Err(e) = e.process(&s3).await {
+            if let Err(e) = e.process(&storage).await {
+                if let crate::Error::StreamMetaNotFound(stream_name) = &e {
+                    return stream_not_found(stream_name);
+                }
+                if let crate::Error::RenameInProgress(stream_name) = &e {
+                    return rename_in_progress(stream_name);
+                }
+                if let crate::Error::TooManyColumns(..) = &e {
+                    return response::ServerResponse {
+                        msg: format!("Failed to post event. {}", e),
+                        code: StatusCode::BAD_REQUEST,
+                    }
+                    .to_http();
+                }
                 return response::ServerResponse {
                     msg: format!("failed to process event because {}", e),
                     code: StatusCode::INTERNAL_SERVER_ERROR,
@@ -118,17 +232,29 @@ pub async fn post_event(req: HttpRequest, body: web::Json<serde_json::Value>) ->
         .to_http();
     }
 
-    let event = event::Event {
-        body: utils::flatten_json_body(body, labels).unwrap(),
-        stream_name,
-    };
+    let (_, body) = events.into_iter().next().unwrap();
+    let event = event::Event { body, stream_name };
 
-    match event.process(&s3).await {
+    match event.process(&storage).await {
         Ok(EventResponse { msg }) => response::ServerResponse {
             msg,
             code: StatusCode::INTERNAL_SERVER_ERROR,
         }
         .to_http(),
+        Err(crate::Error::SchemaViolations(violations)) => {
+            HttpResponse::BadRequest().json(violations)
+        }
+        Err(crate::Error::StreamMetaNotFound(stream_name)) => stream_not_found(&stream_name),
+        Err(crate::Error::RenameInProgress(stream_name)) => rename_in_progress(&stream_name),
+        Err(
+            e @ (crate::Error::InvalidEventTime(..)
+            | crate::Error::EventTooLate(..)
+            | crate::Error::TooManyColumns(..)),
+        ) => response::ServerResponse {
+            msg: format!("Failed to post event. {}", e),
+            code: StatusCode::BAD_REQUEST,
+        }
+        .to_http(),
         Err(e) => response::ServerResponse {
             msg: format!("Failed to process event due to err: {}", e),
             code: StatusCode::INTERNAL_SERVER_ERROR,
@@ -136,3 +262,439 @@ pub async fn post_event(req: HttpRequest, body: web::Json<serde_json::Value>) ->
         .to_http(),
     }
 }
+
+// Events for a stream being renamed are turned down for the client to retry
+// once the rename is done, instead of being buffered under the old name.
+fn rename_in_progress(stream_name: &str) -> HttpResponse {
+    response::ServerResponse {
+        msg: format!(
+            "Failed to post event. Log stream {} has a rename in progress",
+            stream_name
+        ),
+        code: StatusCode::CONFLICT,
+    }
+    .to_http()
+}
+
+// Events for a stream that doesn't exist, or was deleted while they were
+// processed, are rejected without creating the stream.
+fn stream_not_found(stream_name: &str) -> HttpResponse {
+    response::ServerResponse {
+        msg: format!(
+            "Failed to post event. Log stream {} does not exist",
+            stream_name
+        ),
+        code: StatusCode::NOT_FOUND,
+    }
+    .to_http()
+}
+
+/// Response to a newline delimited JSON or CSV body, lines that aren't
+/// valid events are reported without failing the other lines.
+#[derive(Serialize)]
+struct LinesResponse {
+    ingested: usize,
+    failed: Vec<LineError>,
+}
+
+pub async fn post_ndjson(req: HttpRequest, payload: web::Payload) -> HttpResponse {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let labels = utils::collect_labels(&req);
+
+    // if stream doesn't exist, fail to post data
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return stream_not_found(&stream_name);
+    }
+    // events would be split across the old and new prefix
+    if metadata::STREAM_INFO.is_renaming(&stream_name) {
+        return rename_in_progress(&stream_name);
+    }
+
+    let body = match read_body(&req, payload, &stream_name).await {
+        Ok(body) => body,
+        Err(resp) => return resp,
+    };
+
+    let flatten = metadata::STREAM_INFO
+        .flatten(&stream_name)
+        .unwrap_or_default();
+    let (events, failed) = utils::flatten_ndjson_body(&body, labels, flatten);
+    ingest_lines(stream_name, events, failed).await
+}
+
+#[derive(Deserialize)]
+pub struct CsvQuery {
+    /// Ingest values that parse as numbers as numbers, instead of strings
+    #[serde(default)]
+    detect_numbers: bool,
+}
+
+pub async fn post_csv(
+    req: HttpRequest,
+    payload: web::Payload,
+    query: web::Query<CsvQuery>,
+) -> HttpResponse {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let labels = utils::collect_labels(&req);
+
+    // if stream doesn't exist, fail to post data
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return stream_not_found(&stream_name);
+    }
+    // events would be split across the old and new prefix
+    if metadata::STREAM_INFO.is_renaming(&stream_name) {
+        return rename_in_progress(&stream_name);
+    }
+
+    let body = match read_body(&req, payload, &stream_name).await {
+        Ok(body) => body,
+        Err(resp) => return resp,
+    };
+
+    let (events, failed) = utils::flatten_csv_body(&body, labels, query.detect_numbers);
+    ingest_lines(stream_name, events, failed).await
+}
+
+// Ingest the events read from the lines of a body, reporting the lines that
+// failed to be read. The events are checked and ingested together.
+async fn ingest_lines(
+    stream_name: String,
+    events: Vec<(usize, String)>,
+    failed: Vec<LineError>,
+) -> HttpResponse {
+    metrics::EVENTS_FAILED
+        .with_label_values(&[&stream_name])
+        .inc_by(failed.len() as u64);
+    if events.is_empty() {
+        return HttpResponse::BadRequest().json(LinesResponse {
+            ingested: 0,
+            failed,
+        });
+    }
+
+    if let Err(resp) = validate_limits(&stream_name, &events) {
+        return resp;
+    }
+    if let Err(resp) = validate_static_schema(&stream_name, &events) {
+        return resp;
+    }
+    if let Err(resp) = validate_event_time(&stream_name, &events) {
+        return resp;
+    }
+
+    // all lines are ingested as a single event, for a single parquet write
+    let event = event::Event {
+        body: events
+            .iter()
+            .map(|(_, event)| event.as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        stream_name,
+    };
+
+    match event.process(&CONFIG.object_storage()).await {
+        Ok(_) => HttpResponse::Ok().json(LinesResponse {
+            ingested: events.len(),
+            failed,
+        }),
+        Err(crate::Error::SchemaViolations(violations)) => {
+            HttpResponse::BadRequest().json(violations)
+        }
+        Err(crate::Error::StreamMetaNotFound(stream_name)) => stream_not_found(&stream_name),
+        Err(crate::Error::RenameInProgress(stream_name)) => rename_in_progress(&stream_name),
+        Err(
+            e @ (crate::Error::InvalidEventTime(..)
+            | crate::Error::EventTooLate(..)
+            | crate::Error::TooManyColumns(..)),
+        ) => response::ServerResponse {
+            msg: format!("Failed to post event. {}", e),
+            code: StatusCode::BAD_REQUEST,
+        }
+        .to_http(),
+        Err(e) => response::ServerResponse {
+            msg: format!("Failed to process event due to err: {}", e),
+            code: StatusCode::INTERNAL_SERVER_ERROR,
+        }
+        .to_http(),
+    }
+}
+
+// Reject the request if any of its events is larger than the event size limit of
+// the stream, or has more fields than its column limit. Limits the stream doesn't
+// set fall back to the server defaults.
+fn validate_limits(stream_name: &str, events: &[(usize, String)]) -> Result<(), HttpResponse> {
+    let limits = match metadata::STREAM_INFO.limits(stream_name) {
+        Ok(limits) => limits,
+        Err(crate::Error::StreamMetaNotFound(stream_name)) => {
+            return Err(stream_not_found(&stream_name))
+        }
+        Err(e) => {
+            return Err(response::ServerResponse {
+                msg: format!("Failed to process event due to err: {}", e),
+                code: StatusCode::INTERNAL_SERVER_ERROR,
+            }
+            .to_http())
+        }
+    };
+    let max_event_size = limits
+        .max_event_size
+        .unwrap_or(CONFIG.parseable.max_event_size);
+    let max_columns = limits.max_columns.unwrap_or(CONFIG.parseable.max_columns);
+
+    for (record, event) in events {
+        if event.len() > max_event_size {
+            let e = crate::Error::EventTooLarge(*record, event.len(), max_event_size);
+            return Err(response::ServerResponse {
+                msg: format!("Failed to post event. {}", e),
+                code: StatusCode::PAYLOAD_TOO_LARGE,
+            }
+            .to_http());
+        }
+
+        let event: Value = serde_json::from_str(event).unwrap();
+        let columns = event.as_object().map_or(0, |fields| fields.len());
+        if columns > max_columns {
+            let e = crate::Error::TooManyColumns(format!("event {}", record), columns, max_columns);
+            return Err(response::ServerResponse {
+                msg: format!("Failed to post event. {}", e),
+                code: StatusCode::BAD_REQUEST,
+            }
+            .to_http());
+        }
+    }
+
+    Ok(())
+}
+
+// Reject the request if any of its events has no valid time or is too late,
+// for a stream partitioned by the time of its events.
+fn validate_event_time(stream_name: &str, events: &[(usize, String)]) -> Result<(), HttpResponse> {
+    let time_field = match metadata::STREAM_INFO.time_field(stream_name) {
+        Ok(Some(time_field)) => time_field,
+        Ok(None) => return Ok(()),
+        Err(crate::Error::StreamMetaNotFound(stream_name)) => {
+            return Err(stream_not_found(&stream_name))
+        }
+        Err(e) => {
+            return Err(response::ServerResponse {
+                msg: format!("Failed to process event due to err: {}", e),
+                code: StatusCode::INTERNAL_SERVER_ERROR,
+            }
+            .to_http())
+        }
+    };
+
+    let now = Utc::now();
+    for (record, event) in events {
+        let event: Value = serde_json::from_str(event).unwrap();
+        if let Err(e) = event::check_event_time(
+            *record,
+            &event,
+            &time_field,
+            now,
+            CONFIG.parseable.max_event_lateness,
+        ) {
+            return Err(response::ServerResponse {
+                msg: format!("Failed to post event. {}", e),
+                code: StatusCode::BAD_REQUEST,
+            }
+            .to_http());
+        }
+    }
+
+    Ok(())
+}
+
+// Respond with all fields that don't conform to the static schema of the stream,
+// if it has one, along with the index of their event in the request.
+fn validate_static_schema(
+    stream_name: &str,
+    events: &[(usize, String)],
+) -> Result<(), HttpResponse> {
+    let schema = match metadata::STREAM_INFO.static_schema(stream_name) {
+        Ok(Some(schema)) => schema,
+        Ok(None) => return Ok(()),
+        Err(crate::Error::StreamMetaNotFound(stream_name)) => {
+            return Err(stream_not_found(&stream_name))
+        }
+        Err(e) => {
+            return Err(response::ServerResponse {
+                msg: format!("Failed to process event due to err: {}", e),
+                code: StatusCode::INTERNAL_SERVER_ERROR,
+            }
+            .to_http())
+        }
+    };
+
+    let mut violations = Vec::new();
+    for (record, event) in events {
+        let event: Value = serde_json::from_str(event).unwrap();
+        violations.extend(event::validate_event(*record, &event, &schema));
+    }
+
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(HttpResponse::BadRequest().json(violations))
+    }
+}
+
+// Read the body of an ingestion request, decompressing it if it's gzip encoded. The
+// body is limited to the configured max_ingest_body_bytes as sent, and to the
+// configured max_decompressed_size once decompressed.
+async fn read_body(
+    req: &HttpRequest,
+    payload: web::Payload,
+    stream_name: &str,
+) -> Result<web::Bytes, HttpResponse> {
+    let content_length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.trim().parse().ok());
+    let body = read_limited(
+        payload,
+        content_length,
+        CONFIG.parseable.max_ingest_body_bytes,
+    )
+    .await?;
+
+    let encoding = req
+        .headers()
+        .get(header::CONTENT_ENCODING)
+        .map(|encoding| encoding.to_str().unwrap_or_default().trim().to_lowercase());
+
+    match encoding.as_deref() {
+        None | Some("identity") => Ok(body.freeze()),
+        Some("gzip") => {
+            match utils::decompress_gzip(&body, CONFIG.parseable.max_decompressed_size) {
+                Ok(decompressed) => {
+                    log::info!(
+                        "received {} bytes of gzip compressed events for log stream {}, {} bytes decompressed",
+                        body.len(),
+                        stream_name,
+                        decompressed.len()
+                    );
+                    Ok(decompressed.into())
+                }
+                Err(e @ crate::Error::DecompressedTooLarge(_)) => Err(response::ServerResponse {
+                    msg: format!("Failed to post event. {}", e),
+                    code: StatusCode::PAYLOAD_TOO_LARGE,
+                }
+                .to_http()),
+                Err(e) => Err(response::ServerResponse {
+                    msg: format!("Failed to decompress event body due to err: {}", e),
+                    code: StatusCode::BAD_REQUEST,
+                }
+                .to_http()),
+            }
+        }
+        Some(encoding) => Err(response::ServerResponse {
+            msg: format!(
+                "Failed to post event. Unsupported content encoding {}",
+                encoding
+            ),
+            code: StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        }
+        .to_http()),
+    }
+}
+
+// Buffer a request body of at most limit bytes. A body whose declared length is
+// over the limit is rejected before any of it is read, and one without a
+// declared length (or sent with a wrong one) is rejected as soon as the bytes
+// read so far go over the limit.
+async fn read_limited<S>(
+    mut payload: S,
+    content_length: Option<usize>,
+    limit: usize,
+) -> Result<web::BytesMut, HttpResponse>
+where
+    S: Stream<Item = Result<web::Bytes, PayloadError>> + Unpin,
+{
+    if matches!(content_length, Some(len) if len > limit) {
+        return Err(body_too_large(limit));
+    }
+
+    let mut body = web::BytesMut::with_capacity(content_length.unwrap_or_default());
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| {
+            response::ServerResponse {
+                msg: format!("Failed to read event body due to err: {}", e),
+                code: StatusCode::BAD_REQUEST,
+            }
+            .to_http()
+        })?;
+        if body.len() + chunk.len() > limit {
+            return Err(body_too_large(limit));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+fn body_too_large(limit: usize) -> HttpResponse {
+    response::ServerResponse {
+        msg: format!(
+            "Failed to post event. Body is larger than the limit of {} bytes, set by P_MAX_INGEST_BODY_BYTES",
+            limit
+        ),
+        code: StatusCode::PAYLOAD_TOO_LARGE,
+    }
+    .to_http()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::body::to_bytes;
+    use actix_web::error::PayloadError;
+    use actix_web::http::StatusCode;
+    use actix_web::web::Bytes;
+    use futures::{stream, StreamExt};
+
+    use super::read_limited;
+
+    fn chunks(
+        chunks: &[&'static str],
+    ) -> impl futures::Stream<Item = Result<Bytes, PayloadError>> + Unpin {
+        stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok(Bytes::from_static(chunk.as_bytes())))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[actix_web::test]
+    async fn body_under_limit_is_read() {
+        for content_length in [Some(10), None] {
+            let body = read_limited(chunks(&["{\"a\":", "1}", "\n{}"]), content_length, 10)
+                .await
+                .unwrap();
+            assert_eq!(&body[..], b"{\"a\":1}\n{}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn body_over_limit_is_rejected() {
+        let cases = [
+            // rejected from the declared length alone, the failing stream is never read
+            (
+                stream::iter(vec![Err(PayloadError::Incomplete(None))]).boxed_local(),
+                Some(11),
+            ),
+            // rejected while streaming, without or despite the declared length
+            (chunks(&["0123456789", "0"]).boxed_local(), None),
+            (chunks(&["0123456789", "0"]).boxed_local(), Some(10)),
+        ];
+
+        for (payload, content_length) in cases {
+            let resp = read_limited(payload, content_length, 10).await.unwrap_err();
+
+            assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+            let body = to_bytes(resp.into_body()).await.unwrap();
+            assert!(String::from_utf8_lossy(&body).contains("limit of 10 bytes"));
+        }
+    }
+}
@@ -16,16 +16,39 @@
  *
  */
 
-use actix_web::http::StatusCode;
-use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse};
+use arrow::datatypes::Schema;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
 
-use crate::metadata;
+use crate::alerts::Alerts;
+use crate::auth;
+use crate::buffer;
+use crate::event;
+use crate::export;
+use crate::manifest;
+use crate::metadata::{self, Compression, Flatten, Limits, StreamSettings};
+use crate::option::CONFIG;
 use crate::response;
-use crate::s3::S3;
+use crate::retention::Retention;
 use crate::storage::ObjectStorage;
+use crate::utils;
 use crate::validator;
 
-pub async fn delete(req: HttpRequest) -> HttpResponse {
+#[derive(Deserialize)]
+pub struct DeleteQuery {
+    /// Delete the log stream along with its data right away, instead of
+    /// keeping it restorable for the grace period
+    #[serde(default)]
+    force: bool,
+}
+
+pub async fn delete(req: HttpRequest, query: web::Query<DeleteQuery>) -> HttpResponse {
     let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
     if let Err(e) = validator::stream_name(&stream_name) {
         // fail to proceed if there is an error in log stream name validation
@@ -36,9 +59,9 @@ pub async fn delete(req: HttpRequest) -> HttpResponse {
         .to_http();
     }
 
-    let s3 = S3::new();
+    let storage = CONFIG.object_storage();
 
-    if s3.get_schema(&stream_name).await.is_err() {
+    if storage.get_schema(&stream_name).await.is_err() {
         return response::ServerResponse {
             msg: format!("log stream {} does not exist", stream_name),
             code: StatusCode::BAD_REQUEST,
@@ -46,7 +69,41 @@ pub async fn delete(req: HttpRequest) -> HttpResponse {
         .to_http();
     }
 
-    if let Err(e) = s3.delete_stream(&stream_name).await {
+    if !query.force {
+        return match metadata::STREAM_INFO
+            .soft_delete_stream(storage.as_ref(), &stream_name)
+            .await
+        {
+            Ok(()) => response::ServerResponse {
+                msg: format!(
+                    "log stream {} deleted, it can be restored for {} hours",
+                    stream_name, CONFIG.parseable.delete_grace_period
+                ),
+                code: StatusCode::OK,
+            }
+            .to_http(),
+            Err(crate::Error::StreamMetaNotFound(_)) => response::ServerResponse {
+                msg: format!("log stream {} is already deleted", stream_name),
+                code: StatusCode::NOT_FOUND,
+            }
+            .to_http(),
+            Err(e) => response::ServerResponse {
+                msg: format!(
+                    "failed to delete log stream {} due to err: {}",
+                    stream_name, e
+                ),
+                code: StatusCode::INTERNAL_SERVER_ERROR,
+            }
+            .to_http(),
+        };
+    }
+
+    // the stream stays in metadata unless all of its data is deleted, so that
+    // the user can retry instead of leaving data behind that no API can reach
+    if let Err(e) = metadata::STREAM_INFO
+        .purge_stream(storage.as_ref(), &stream_name)
+        .await
+    {
         return response::ServerResponse {
             msg: format!(
                 "failed to delete log stream {} due to err: {}",
@@ -57,198 +114,1466 @@ pub async fn delete(req: HttpRequest) -> HttpResponse {
         .to_http();
     }
 
-    if let Err(e) = metadata::STREAM_INFO.delete_stream(&stream_name) {
-        return response::ServerResponse {
+    response::ServerResponse {
+        msg: format!("log stream {} deleted", stream_name),
+        code: StatusCode::OK,
+    }
+    .to_http()
+}
+
+pub async fn undelete(req: HttpRequest) -> HttpResponse {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    match metadata::STREAM_INFO
+        .undelete_stream(CONFIG.object_storage().as_ref(), &stream_name)
+        .await
+    {
+        Ok(()) => response::ServerResponse {
+            msg: format!("log stream {} restored", stream_name),
+            code: StatusCode::OK,
+        }
+        .to_http(),
+        Err(e @ crate::Error::DeletedStreamNotFound(_)) => response::ServerResponse {
+            msg: format!("failed to restore log stream due to err: {}", e),
+            code: StatusCode::NOT_FOUND,
+        }
+        .to_http(),
+        Err(e) => response::ServerResponse {
             msg: format!(
-                "failed to delete log stream {} from metadata due to err: {}",
+                "failed to restore log stream {} due to err: {}",
                 stream_name, e
             ),
             code: StatusCode::INTERNAL_SERVER_ERROR,
         }
+        .to_http(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RenameRequest {
+    name: String,
+}
+
+pub async fn rename(req: HttpRequest, body: web::Json<RenameRequest>) -> HttpResponse {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let new_name = body.into_inner().name;
+    if !auth::permits(&req, auth::Action::Write, &new_name) {
+        return response::ServerResponse {
+            msg: format!("not permitted to rename a log stream to {}", new_name),
+            code: StatusCode::FORBIDDEN,
+        }
         .to_http();
     }
+    let storage = CONFIG.object_storage();
+
+    if let Err(e) = metadata::STREAM_INFO
+        .start_rename(storage.as_ref(), &stream_name, &new_name)
+        .await
+    {
+        return rename_failed(&stream_name, e);
+    }
+    // events buffered under the old name are written to be moved along with the stream,
+    // events arriving from now on are turned down until the rename is done
+    let buffered = buffer::EVENT_BUFFER.take_stream(&stream_name);
+    event::flush_all(buffered, storage.as_ref()).await;
+
+    let result = metadata::STREAM_INFO
+        .finish_rename(
+            storage.as_ref(),
+            Path::new(&CONFIG.parseable.local_disk_path),
+            &stream_name,
+            &new_name,
+        )
+        .await;
+
+    match result {
+        Ok(()) => response::ServerResponse {
+            msg: format!("log stream {} renamed to {}", stream_name, new_name),
+            code: StatusCode::OK,
+        }
+        .to_http(),
+        Err(e) => rename_failed(&stream_name, e),
+    }
+}
 
+fn rename_failed(stream_name: &str, e: crate::Error) -> HttpResponse {
+    let code = match e {
+        crate::Error::InvalidStreamName(..) => StatusCode::BAD_REQUEST,
+        crate::Error::StreamMetaNotFound(_) => StatusCode::NOT_FOUND,
+        crate::Error::StreamAlreadyExists(_) | crate::Error::RenameInProgress(_) => {
+            StatusCode::CONFLICT
+        }
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
     response::ServerResponse {
-        msg: format!("log stream {} deleted", stream_name),
-        code: StatusCode::OK,
+        msg: format!(
+            "failed to rename log stream {} due to err: {}",
+            stream_name, e
+        ),
+        code,
     }
     .to_http()
 }
 
-pub async fn list(_: HttpRequest) -> impl Responder {
-    response::list_response(S3::new().list_streams().await.unwrap())
+#[derive(Deserialize)]
+pub struct CloneRequest {
+    name: String,
 }
 
-pub async fn schema(req: HttpRequest) -> HttpResponse {
+// Create a log stream with the schema and alerts of given log stream, without its data
+pub async fn clone(req: HttpRequest, body: web::Json<CloneRequest>) -> HttpResponse {
     let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let new_name = body.into_inner().name;
+    if !auth::permits(&req, auth::Action::Write, &new_name) {
+        return response::ServerResponse {
+            msg: format!("not permitted to create log stream {}", new_name),
+            code: StatusCode::FORBIDDEN,
+        }
+        .to_http();
+    }
 
-    match metadata::STREAM_INFO.schema(&stream_name) {
-        Ok(schema) => response::ServerResponse {
-            msg: schema,
+    let result = metadata::STREAM_INFO
+        .clone_stream(CONFIG.object_storage().as_ref(), &stream_name, &new_name)
+        .await;
+
+    match result {
+        Ok(()) => response::ServerResponse {
+            msg: format!("log stream {} cloned to {}", stream_name, new_name),
             code: StatusCode::OK,
         }
         .to_http(),
-        Err(_) => match S3::new().get_schema(&stream_name).await {
-            Ok(schema) if schema.is_empty() => response::ServerResponse {
-                msg: "log stream is not initialized, please post an event before fetching schema"
-                    .to_string(),
-                code: StatusCode::BAD_REQUEST,
+        Err(e) => {
+            let code = match e {
+                crate::Error::InvalidStreamName(..) => StatusCode::BAD_REQUEST,
+                crate::Error::StreamMetaNotFound(_) => StatusCode::NOT_FOUND,
+                crate::Error::StreamAlreadyExists(_) => StatusCode::CONFLICT,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            response::ServerResponse {
+                msg: format!(
+                    "failed to clone log stream {} due to err: {}",
+                    stream_name, e
+                ),
+                code,
             }
-            .to_http(),
-            Ok(schema) => {
-                let buf = schema.as_ref();
-                response::ServerResponse {
-                    msg: String::from_utf8(buf.to_vec()).unwrap(),
-                    code: StatusCode::OK,
+            .to_http()
+        }
+    }
+}
+
+pub async fn list(req: HttpRequest, query: web::Query<Vec<(String, String)>>) -> HttpResponse {
+    // every ?tag.key=value or ?tag=key:value filter must match
+    let mut tags = Vec::new();
+    for (param, filter) in query.into_inner() {
+        if let Some(key) = param.strip_prefix("tag.") {
+            tags.push((key.to_owned(), filter));
+            continue;
+        }
+        if param != "tag" {
+            continue;
+        }
+        match filter.split_once(':') {
+            Some((key, value)) => tags.push((key.to_owned(), value.to_owned())),
+            None => {
+                return response::ServerResponse {
+                    msg: format!("invalid tag filter {}, expected key:value", filter),
+                    code: StatusCode::BAD_REQUEST,
                 }
                 .to_http()
             }
-            Err(_) => response::ServerResponse {
-                msg: "failed to get log stream schema, because log stream doesn't exist"
-                    .to_string(),
-                code: StatusCode::BAD_REQUEST,
-            }
-            .to_http(),
-        },
+        }
     }
+
+    let streams = metadata::STREAM_INFO
+        .list_stream_summaries()
+        .into_iter()
+        .filter(|stream| stream.has_tags(&tags))
+        // API keys limited to some streams only see those
+        .filter(|stream| auth::permits(&req, auth::Action::Read, &stream.name))
+        .collect();
+
+    response::list_response(streams)
 }
 
-pub async fn get_alert(req: HttpRequest) -> HttpResponse {
+// Lets agents check a log stream exists before shipping events to it,
+// without the cost of a response body.
+pub async fn head(req: HttpRequest) -> HttpResponse {
     let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
 
-    match metadata::STREAM_INFO.alert(&stream_name) {
-        Ok(alert) => response::ServerResponse {
-            msg: alert,
-            code: StatusCode::OK,
+    if metadata::STREAM_INFO.stream_exists(&stream_name) {
+        HttpResponse::Ok().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+/// Schema of a stream that hasn't had any events yet, so its schema isn't inferred yet
+#[derive(Serialize)]
+struct PendingSchema {
+    #[serde(flatten)]
+    schema: Schema,
+    inferred: bool,
+}
+
+pub async fn schema(req: HttpRequest) -> HttpResponse {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    match metadata::STREAM_INFO.schema(&stream_name) {
+        Ok(Some(schema)) => HttpResponse::Ok().json(schema.as_ref()),
+        Ok(None) => HttpResponse::Ok().json(PendingSchema {
+            schema: Schema::empty(),
+            inferred: false,
+        }),
+        Err(e @ crate::Error::StreamMetaNotFound(_)) => response::ServerResponse {
+            msg: format!("failed to get log stream schema due to err: {}", e),
+            code: StatusCode::NOT_FOUND,
         }
         .to_http(),
-        Err(_) => match S3::new().get_alert(&stream_name).await {
-            Ok(alert) if alert.is_empty() => response::ServerResponse {
-                msg: "alert configuration not set for log stream {}".to_string(),
+        Err(e) => response::ServerResponse {
+            msg: format!("failed to get log stream schema due to err: {}", e),
+            code: StatusCode::INTERNAL_SERVER_ERROR,
+        }
+        .to_http(),
+    }
+}
+
+/// A past or the current version of the schema of the stream, e.g. to read data
+/// that was written before the schema changed
+pub async fn schema_version(req: HttpRequest) -> HttpResponse {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let version: u32 = match req.match_info().get("version").unwrap().parse() {
+        Ok(version) => version,
+        Err(_) => {
+            return response::ServerResponse {
+                msg: "schema version must be a positive integer".to_string(),
                 code: StatusCode::BAD_REQUEST,
             }
-            .to_http(),
-            Ok(alert) => {
-                let buf = alert.as_ref();
-                response::ServerResponse {
-                    msg: String::from_utf8(buf.to_vec()).unwrap(),
-                    code: StatusCode::OK,
+            .to_http()
+        }
+    };
+
+    let schema = metadata::STREAM_INFO
+        .schema_at_version(CONFIG.object_storage().as_ref(), &stream_name, version)
+        .await;
+
+    match schema {
+        Ok(schema) => HttpResponse::Ok().json(schema),
+        Err(e) => {
+            let code = match e {
+                crate::Error::StreamMetaNotFound(_) | crate::Error::SchemaVersionNotFound(..) => {
+                    StatusCode::NOT_FOUND
                 }
-                .to_http()
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            response::ServerResponse {
+                msg: format!("failed to get log stream schema due to err: {}", e),
+                code,
             }
-            Err(_) => response::ServerResponse {
-                msg: "alert doesn't exist".to_string(),
+            .to_http()
+        }
+    }
+}
+
+/// Schema inferred from sample events, and how it compares to the stored schema
+#[derive(Serialize)]
+struct InferredSchema {
+    schema: Schema,
+    /// Missing when the stream doesn't exist or has no schema yet, there is nothing to merge into
+    merge: Option<metadata::SchemaDiff>,
+}
+
+/// Infer the schema of sample events like ingestion would, without ingesting
+/// them or changing the stream.
+pub async fn infer_schema(req: HttpRequest, body: web::Json<serde_json::Value>) -> HttpResponse {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let labels = utils::collect_labels(&req);
+
+    let events = match body.into_inner() {
+        serde_json::Value::Array(array) => array,
+        body => vec![body],
+    };
+    // events of a stream that doesn't exist yet are flattened with the default
+    let flatten = metadata::STREAM_INFO
+        .flatten(&stream_name)
+        .unwrap_or_default();
+    let lines = events
+        .into_iter()
+        .map(|event| utils::flatten_json_body(web::Json(event), labels.clone(), flatten))
+        .collect::<Result<Vec<_>, _>>();
+    let schema = lines.and_then(|lines| {
+        event::Event {
+            body: lines.join("\n"),
+            stream_name: stream_name.clone(),
+        }
+        .infer_schema()
+    });
+    let schema = match schema {
+        Ok(schema) => schema,
+        Err(e) => {
+            return response::ServerResponse {
+                msg: format!("failed to infer schema due to err: {}", e),
                 code: StatusCode::BAD_REQUEST,
             }
-            .to_http(),
-        },
+            .to_http()
+        }
+    };
+
+    let stored = match metadata::STREAM_INFO.schema(&stream_name) {
+        Ok(stored) => stored,
+        Err(crate::Error::StreamMetaNotFound(_)) => None,
+        Err(e) => {
+            return response::ServerResponse {
+                msg: format!("failed to get log stream schema due to err: {}", e),
+                code: StatusCode::INTERNAL_SERVER_ERROR,
+            }
+            .to_http()
+        }
+    };
+    let merge = stored.map(|stored| metadata::diff_schemas(&stored, &schema));
+
+    HttpResponse::Ok().json(InferredSchema { schema, merge })
+}
+
+pub async fn get_stats(req: HttpRequest) -> HttpResponse {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    match metadata::STREAM_INFO.stats(&stream_name) {
+        Ok(stats) => HttpResponse::Ok().json(stats),
+        Err(e @ crate::Error::StreamMetaNotFound(_)) => response::ServerResponse {
+            msg: format!("failed to get log stream stats due to err: {}", e),
+            code: StatusCode::NOT_FOUND,
+        }
+        .to_http(),
+        Err(e) => response::ServerResponse {
+            msg: format!("failed to get log stream stats due to err: {}", e),
+            code: StatusCode::INTERNAL_SERVER_ERROR,
+        }
+        .to_http(),
     }
 }
 
-pub async fn put(req: HttpRequest) -> HttpResponse {
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    /// Only export the data of this time range, both or neither must be set
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+    /// Resume an export after this file, the last one a cut off export got
+    after: Option<String>,
+}
+
+// Download all parquet files of the log stream as a tar archive, with a JSON entry
+// describing the stream first. The archive is sent in chunks as the files are read
+// from object storage, without a Content-Length.
+pub async fn export(req: HttpRequest, query: web::Query<ExportQuery>) -> HttpResponse {
     let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
 
-    // fail to proceed if there is an error in log stream name validation
-    if let Err(e) = validator::stream_name(&stream_name) {
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
         return response::ServerResponse {
-            msg: format!("failed to create log stream due to err: {}", e),
-            code: StatusCode::BAD_REQUEST,
+            msg: format!("log stream {} does not exist", stream_name),
+            code: StatusCode::NOT_FOUND,
         }
         .to_http();
     }
 
-    let s3 = S3::new();
+    let range = match (query.start, query.end) {
+        (None, None) => None,
+        (Some(start), Some(end)) if start < end => Some((start, end)),
+        _ => {
+            return response::ServerResponse {
+                msg: "start and end must both be set, with start before end".to_string(),
+                code: StatusCode::BAD_REQUEST,
+            }
+            .to_http()
+        }
+    };
 
-    // Proceed to create log stream if it doesn't exist
-    if s3.get_schema(&stream_name).await.is_err() {
-        if let Err(e) = metadata::STREAM_INFO.add_stream(
-            stream_name.to_string(),
-            "".to_string(),
-            "".to_string(),
-        ) {
+    let storage: Arc<dyn ObjectStorage> = Arc::from(CONFIG.object_storage());
+    let files = match export::files(
+        storage.as_ref(),
+        &stream_name,
+        range,
+        query.after.as_deref(),
+    )
+    .await
+    {
+        Ok(files) => files,
+        Err(e) => {
             return response::ServerResponse {
                 msg: format!(
-                    "failed to create log stream {} due to error: {}",
+                    "failed to list files of log stream {} due to err: {}",
                     stream_name, e
                 ),
                 code: StatusCode::INTERNAL_SERVER_ERROR,
             }
-            .to_http();
+            .to_http()
         }
-        // Fail if unable to create log stream on object store backend
-        if let Err(e) = s3.create_stream(&stream_name).await {
-            // delete the stream from metadata because we couldn't create it on object store backend
-            metadata::STREAM_INFO.delete_stream(&stream_name).unwrap();
+    };
+    let sidecar = match export::sidecar(&stream_name, range, &files) {
+        Ok(sidecar) => sidecar,
+        Err(e) => {
             return response::ServerResponse {
                 msg: format!(
-                    "failed to create log stream {} due to err: {}",
+                    "failed to export log stream {} due to err: {}",
                     stream_name, e
                 ),
                 code: StatusCode::INTERNAL_SERVER_ERROR,
             }
-            .to_http();
+            .to_http()
         }
+    };
+
+    HttpResponse::Ok()
+        .content_type("application/x-tar")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}.tar\"", stream_name),
+        ))
+        .streaming(export::archive(storage, sidecar, files))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RecalculatedStats {
+    stats: metadata::Stats,
+    duration_ms: u128,
+}
+
+// Sum up the parquet files of the log stream in object storage and overwrite its
+// compressed size and event count with it, for when stats drifted from what is
+// actually stored.
+pub async fn recalculate_stats(req: HttpRequest) -> HttpResponse {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
         return response::ServerResponse {
-            msg: format!("created log stream {}", stream_name),
-            code: StatusCode::OK,
+            msg: format!("log stream {} does not exist", stream_name),
+            code: StatusCode::NOT_FOUND,
         }
         .to_http();
     }
 
-    // Error if the log stream already exists
-    response::ServerResponse {
-        msg: format!(
-            "log stream {} already exists, please create a new log stream with unique name",
-            stream_name
-        ),
-        code: StatusCode::BAD_REQUEST,
+    let started = Instant::now();
+    let recomputed = metadata::STREAM_INFO
+        .recompute_stats(&stream_name, CONFIG.object_storage().as_ref())
+        .await
+        .map_err(|e| e.to_string());
+
+    match recomputed {
+        Ok(stats) => HttpResponse::Ok().json(RecalculatedStats {
+            stats,
+            duration_ms: started.elapsed().as_millis(),
+        }),
+        Err(e) => response::ServerResponse {
+            msg: format!("failed to recalculate log stream stats due to err: {}", e),
+            code: StatusCode::INTERNAL_SERVER_ERROR,
+        }
+        .to_http(),
     }
-    .to_http()
 }
 
-pub async fn put_alert(req: HttpRequest, body: web::Json<serde_json::Value>) -> HttpResponse {
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RebuiltManifest {
+    files: usize,
+    rows: u64,
+    duration_ms: u128,
+}
+
+// Regenerate the manifest of the log stream from a listing of its parquet files, for
+// when the manifest is missing or missed an update and queries fall back to listing.
+pub async fn rebuild_manifest(req: HttpRequest) -> HttpResponse {
     let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
-    let alert_config = body.clone();
-    match validator::alert(serde_json::to_string(&body.as_object()).unwrap()) {
-        Ok(_) => match S3::new()
-            .create_alert(&stream_name, alert_config.to_string())
-            .await
-        {
-            Ok(_) => {
-                if let Err(e) =
-                    metadata::STREAM_INFO.set_alert(stream_name.clone(), alert_config.to_string())
-                {
-                    return response::ServerResponse {
-                        msg: format!(
-                            "failed to set alert configuration for log stream {} due to err: {}",
-                            stream_name, e
-                        ),
-                        code: StatusCode::INTERNAL_SERVER_ERROR,
-                    }
-                    .to_http();
-                }
+
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return response::ServerResponse {
+            msg: format!("log stream {} does not exist", stream_name),
+            code: StatusCode::NOT_FOUND,
+        }
+        .to_http();
+    }
+
+    let started = Instant::now();
+    match manifest::rebuild(CONFIG.object_storage().as_ref(), &stream_name).await {
+        Ok(manifest) => HttpResponse::Ok().json(RebuiltManifest {
+            files: manifest.files.len(),
+            rows: manifest.files.iter().map(|file| file.rows).sum(),
+            duration_ms: started.elapsed().as_millis(),
+        }),
+        Err(e) => response::ServerResponse {
+            msg: format!("failed to rebuild log stream manifest due to err: {}", e),
+            code: StatusCode::INTERNAL_SERVER_ERROR,
+        }
+        .to_http(),
+    }
+}
+
+pub async fn total_stats(req: HttpRequest) -> HttpResponse {
+    let total = metadata::STREAM_INFO
+        .total_stats(|stream_name| auth::permits(&req, auth::Action::Read, stream_name));
+
+    HttpResponse::Ok().json(total)
+}
+
+// Re-read the log stream from object storage, for when another server sharing
+// the bucket changed it. Responds with the settings that changed.
+pub async fn refresh(req: HttpRequest) -> HttpResponse {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    match metadata::STREAM_INFO
+        .refresh_stream(CONFIG.object_storage().as_ref(), &stream_name)
+        .await
+    {
+        Ok(changes) => HttpResponse::Ok().json(changes),
+        Err(e @ (crate::Error::StreamMetaNotFound(_) | crate::Error::SchemaNotInStore(_))) => {
+            response::ServerResponse {
+                msg: format!("failed to refresh log stream due to err: {}", e),
+                code: StatusCode::NOT_FOUND,
+            }
+            .to_http()
+        }
+        Err(e) => response::ServerResponse {
+            msg: format!("failed to refresh log stream due to err: {}", e),
+            code: StatusCode::INTERNAL_SERVER_ERROR,
+        }
+        .to_http(),
+    }
+}
+
+pub async fn refresh_all(req: HttpRequest) -> HttpResponse {
+    if auth::is_scoped(&req) {
+        return response::ServerResponse {
+            msg: "not permitted to refresh all log streams".to_string(),
+            code: StatusCode::FORBIDDEN,
+        }
+        .to_http();
+    }
+
+    match metadata::STREAM_INFO
+        .refresh(CONFIG.object_storage().as_ref())
+        .await
+    {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => response::ServerResponse {
+            msg: format!("failed to refresh log streams due to err: {}", e),
+            code: StatusCode::INTERNAL_SERVER_ERROR,
+        }
+        .to_http(),
+    }
+}
+
+pub async fn get_alert(req: HttpRequest) -> HttpResponse {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    match metadata::STREAM_INFO.alert(&stream_name) {
+        Ok(alerts) if alerts.is_empty() => response::ServerResponse {
+            msg: format!("alert configuration not set for log stream {}", stream_name),
+            code: StatusCode::BAD_REQUEST,
+        }
+        .to_http(),
+        Ok(alerts) => response::ServerResponse {
+            msg: serde_json::to_string(&Alerts { alerts }).unwrap(),
+            code: StatusCode::OK,
+        }
+        .to_http(),
+        Err(_) => match CONFIG.object_storage().get_alert(&stream_name).await {
+            Ok(alert) if alert.is_empty() => response::ServerResponse {
+                msg: format!("alert configuration not set for log stream {}", stream_name),
+                code: StatusCode::BAD_REQUEST,
+            }
+            .to_http(),
+            Ok(alert) => {
+                let buf = alert.as_ref();
                 response::ServerResponse {
-                    msg: format!("set alert configuration for log stream {}", stream_name),
+                    msg: String::from_utf8(buf.to_vec()).unwrap(),
                     code: StatusCode::OK,
                 }
                 .to_http()
             }
-            Err(e) => response::ServerResponse {
-                msg: format!(
-                    "failed to set alert configuration for log stream {} due to err: {}",
-                    stream_name, e
-                ),
-                code: StatusCode::INTERNAL_SERVER_ERROR,
+            Err(_) => response::ServerResponse {
+                msg: "alert doesn't exist".to_string(),
+                code: StatusCode::BAD_REQUEST,
             }
             .to_http(),
         },
-        Err(e) => response::ServerResponse {
-            msg: format!(
+    }
+}
+
+pub async fn put(req: HttpRequest, body: web::Bytes) -> HttpResponse {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    // fail to proceed if there is an error in log stream name validation
+    if let Err(e) = validator::stream_name(&stream_name) {
+        return response::ServerResponse {
+            msg: format!("failed to create log stream due to err: {}", e),
+            code: StatusCode::BAD_REQUEST,
+        }
+        .to_http();
+    }
+
+    // settings are optional, a log stream can be created without a body
+    let settings: StreamSettings = if body.is_empty() {
+        StreamSettings::default()
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(settings) => settings,
+            Err(e) => {
+                return response::ServerResponse {
+                    msg: format!(
+                        "failed to create log stream {} due to err: {}",
+                        stream_name, e
+                    ),
+                    code: StatusCode::BAD_REQUEST,
+                }
+                .to_http()
+            }
+        }
+    };
+
+    let valid = validator::tags(&settings.tags)
+        .and_then(|_| match settings.retention {
+            Some(retention) => validator::retention(retention.days),
+            None => Ok(()),
+        })
+        .and_then(|_| validator::limits(&settings.limits));
+    if let Err(e) = valid {
+        return response::ServerResponse {
+            msg: format!(
+                "failed to create log stream {} due to err: {}",
+                stream_name, e
+            ),
+            code: StatusCode::BAD_REQUEST,
+        }
+        .to_http();
+    }
+
+    // creating a log stream that exists is fine, as long as the settings match
+    if metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return existing_stream(&stream_name, &settings);
+    }
+
+    let storage = CONFIG.object_storage();
+
+    // a log stream in object storage but not in memory was created elsewhere
+    if storage.get_schema(&stream_name).await.is_ok() {
+        return response::ServerResponse {
+            msg: format!(
+                "log stream {} already exists, please create a new log stream with unique name",
+                stream_name
+            ),
+            code: StatusCode::BAD_REQUEST,
+        }
+        .to_http();
+    }
+
+    // the settings are stored along with the stream, it isn't listed or
+    // ingested into before they all are
+    match metadata::STREAM_INFO
+        .create_stream_with(storage.as_ref(), &stream_name, settings.clone())
+        .await
+    {
+        Ok(()) => {}
+        // another request created the log stream meanwhile
+        Err(crate::Error::StreamAlreadyExists(_)) => {
+            return existing_stream(&stream_name, &settings)
+        }
+        Err(e) => {
+            return response::ServerResponse {
+                msg: format!(
+                    "failed to create log stream {} due to err: {}",
+                    stream_name, e
+                ),
+                code: StatusCode::INTERNAL_SERVER_ERROR,
+            }
+            .to_http()
+        }
+    }
+
+    // without a manifest queries list the stream, which still finds all of its files
+    if let Err(e) = manifest::create(storage.as_ref(), &stream_name).await {
+        log::warn!(
+            "failed to put manifest of log stream {} to object storage. {:?}",
+            stream_name,
+            e
+        );
+    }
+
+    // the stream is usable without its timestamps, the earliest data
+    // partition stands in for them after a restart
+    if let Err(e) = storage.sync_timestamps(&stream_name).await {
+        log::warn!(
+            "failed to put timestamps of log stream {} to object storage. {:?}",
+            stream_name,
+            e
+        );
+    }
+
+    response::ServerResponse {
+        msg: format!("created log stream {}", stream_name),
+        code: StatusCode::OK,
+    }
+    .to_http()
+}
+
+// Respond to a create request for a log stream that already exists, with its
+// summary if the requested settings match and the conflicting ones otherwise.
+fn existing_stream(stream_name: &str, settings: &StreamSettings) -> HttpResponse {
+    let conflicts = metadata::STREAM_INFO.setting_conflicts(stream_name, settings);
+    let summary = metadata::STREAM_INFO.summary(stream_name);
+
+    match (conflicts, summary) {
+        (Ok(conflicts), Ok(summary)) if conflicts.is_empty() => HttpResponse::Ok().json(summary),
+        (Ok(conflicts), Ok(_)) => HttpResponse::Conflict().json(conflicts),
+        (Err(e), _) | (_, Err(e)) => response::ServerResponse {
+            msg: format!(
+                "failed to create log stream {} due to err: {}",
+                stream_name, e
+            ),
+            code: StatusCode::INTERNAL_SERVER_ERROR,
+        }
+        .to_http(),
+    }
+}
+
+pub async fn put_alert(req: HttpRequest, body: web::Json<serde_json::Value>) -> HttpResponse {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    let alerts: Alerts = match serde_json::from_value(body.into_inner()) {
+        Ok(alerts) => alerts,
+        Err(e) => {
+            return response::ServerResponse {
+                msg: format!(
+                    "failed to set alert configuration for log stream {} due to err: {}",
+                    stream_name,
+                    crate::Error::InvalidAlert(e.to_string())
+                ),
+                code: StatusCode::BAD_REQUEST,
+            }
+            .to_http()
+        }
+    };
+
+    if let Err(e) = validator::alert(&alerts) {
+        return response::ServerResponse {
+            msg: format!(
                 "failed to set alert configuration for log stream {} due to err: {}",
                 stream_name, e
             ),
             code: StatusCode::BAD_REQUEST,
         }
+        .to_http();
+    }
+
+    if let Err(e) = CONFIG
+        .object_storage()
+        .create_alert(&stream_name, &alerts)
+        .await
+    {
+        return response::ServerResponse {
+            msg: format!(
+                "failed to set alert configuration for log stream {} due to err: {}",
+                stream_name, e
+            ),
+            code: StatusCode::INTERNAL_SERVER_ERROR,
+        }
+        .to_http();
+    }
+
+    if let Err(e) = metadata::STREAM_INFO.set_alert(stream_name.clone(), alerts) {
+        return response::ServerResponse {
+            msg: format!(
+                "failed to set alert configuration for log stream {} due to err: {}",
+                stream_name, e
+            ),
+            code: StatusCode::INTERNAL_SERVER_ERROR,
+        }
+        .to_http();
+    }
+
+    response::ServerResponse {
+        msg: format!("set alert configuration for log stream {}", stream_name),
+        code: StatusCode::OK,
+    }
+    .to_http()
+}
+
+pub async fn put_retention(req: HttpRequest, body: web::Json<serde_json::Value>) -> HttpResponse {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    let retention: Retention = match serde_json::from_value(body.into_inner()) {
+        Ok(retention) => retention,
+        Err(e) => {
+            return response::ServerResponse {
+                msg: format!(
+                    "failed to set retention period for log stream {} due to err: {}",
+                    stream_name, e
+                ),
+                code: StatusCode::BAD_REQUEST,
+            }
+            .to_http()
+        }
+    };
+
+    if let Err(e) = validator::retention(retention.days) {
+        return response::ServerResponse {
+            msg: format!(
+                "failed to set retention period for log stream {} due to err: {}",
+                stream_name, e
+            ),
+            code: StatusCode::BAD_REQUEST,
+        }
+        .to_http();
+    }
+
+    // don't put retention of a stream that doesn't exist to object storage
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return response::ServerResponse {
+            msg: format!(
+                "failed to set retention period for log stream {} due to err: {}",
+                stream_name,
+                crate::Error::StreamMetaNotFound(stream_name.clone())
+            ),
+            code: StatusCode::NOT_FOUND,
+        }
+        .to_http();
+    }
+
+    if let Err(e) = CONFIG
+        .object_storage()
+        .put_retention(&stream_name, &retention)
+        .await
+    {
+        return response::ServerResponse {
+            msg: format!(
+                "failed to set retention period for log stream {} due to err: {}",
+                stream_name, e
+            ),
+            code: StatusCode::INTERNAL_SERVER_ERROR,
+        }
+        .to_http();
+    }
+
+    if let Err(e) = metadata::STREAM_INFO.set_retention(&stream_name, retention.days) {
+        return response::ServerResponse {
+            msg: format!(
+                "failed to set retention period for log stream {} due to err: {}",
+                stream_name, e
+            ),
+            code: StatusCode::INTERNAL_SERVER_ERROR,
+        }
+        .to_http();
+    }
+
+    response::ServerResponse {
+        msg: format!(
+            "set retention period of log stream {} to {} days",
+            stream_name, retention.days
+        ),
+        code: StatusCode::OK,
+    }
+    .to_http()
+}
+
+pub async fn get_tags(req: HttpRequest) -> HttpResponse {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    match metadata::STREAM_INFO.tags(&stream_name) {
+        Ok(tags) => HttpResponse::Ok().json(tags),
+        Err(e) => response::ServerResponse {
+            msg: format!("failed to get log stream tags due to err: {}", e),
+            code: StatusCode::NOT_FOUND,
+        }
         .to_http(),
     }
 }
+
+pub async fn put_tags(req: HttpRequest, body: web::Json<serde_json::Value>) -> HttpResponse {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    let tags: HashMap<String, String> = match serde_json::from_value(body.into_inner()) {
+        Ok(tags) => tags,
+        Err(e) => {
+            return response::ServerResponse {
+                msg: format!(
+                    "failed to set tags for log stream {} due to err: {}",
+                    stream_name, e
+                ),
+                code: StatusCode::BAD_REQUEST,
+            }
+            .to_http()
+        }
+    };
+
+    if let Err(e) = validator::tags(&tags) {
+        return response::ServerResponse {
+            msg: format!(
+                "failed to set tags for log stream {} due to err: {}",
+                stream_name, e
+            ),
+            code: StatusCode::BAD_REQUEST,
+        }
+        .to_http();
+    }
+
+    // don't put tags of a stream that doesn't exist to object storage
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return response::ServerResponse {
+            msg: format!(
+                "failed to set tags for log stream {} due to err: {}",
+                stream_name,
+                crate::Error::StreamMetaNotFound(stream_name.clone())
+            ),
+            code: StatusCode::NOT_FOUND,
+        }
+        .to_http();
+    }
+
+    if let Err(e) = set_tags(CONFIG.object_storage().as_ref(), &stream_name, tags).await {
+        let code = match e {
+            crate::Error::StreamMetaNotFound(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        return response::ServerResponse {
+            msg: format!(
+                "failed to set tags for log stream {} due to err: {}",
+                stream_name, e
+            ),
+            code,
+        }
+        .to_http();
+    }
+
+    response::ServerResponse {
+        msg: format!("set tags for log stream {}", stream_name),
+        code: StatusCode::OK,
+    }
+    .to_http()
+}
+
+// Tags are put to object storage first, so that they survive a restart once set in memory.
+async fn set_tags(
+    storage: &dyn ObjectStorage,
+    stream_name: &str,
+    tags: HashMap<String, String>,
+) -> Result<(), crate::Error> {
+    storage.put_tags(stream_name, &tags).await?;
+    metadata::STREAM_INFO.set_tags(stream_name, tags)
+}
+
+pub async fn put_limits(req: HttpRequest, body: web::Json<serde_json::Value>) -> HttpResponse {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    let limits: Limits = match serde_json::from_value(body.into_inner()) {
+        Ok(limits) => limits,
+        Err(e) => {
+            return response::ServerResponse {
+                msg: format!(
+                    "failed to set limits for log stream {} due to err: {}",
+                    stream_name, e
+                ),
+                code: StatusCode::BAD_REQUEST,
+            }
+            .to_http()
+        }
+    };
+
+    if let Err(e) = validator::limits(&limits) {
+        return response::ServerResponse {
+            msg: format!(
+                "failed to set limits for log stream {} due to err: {}",
+                stream_name, e
+            ),
+            code: StatusCode::BAD_REQUEST,
+        }
+        .to_http();
+    }
+
+    // don't put limits of a stream that doesn't exist to object storage
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return response::ServerResponse {
+            msg: format!(
+                "failed to set limits for log stream {} due to err: {}",
+                stream_name,
+                crate::Error::StreamMetaNotFound(stream_name.clone())
+            ),
+            code: StatusCode::NOT_FOUND,
+        }
+        .to_http();
+    }
+
+    if let Err(e) = set_limits(CONFIG.object_storage().as_ref(), &stream_name, limits).await {
+        let code = match e {
+            crate::Error::StreamMetaNotFound(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        return response::ServerResponse {
+            msg: format!(
+                "failed to set limits for log stream {} due to err: {}",
+                stream_name, e
+            ),
+            code,
+        }
+        .to_http();
+    }
+
+    response::ServerResponse {
+        msg: format!("set limits for log stream {}", stream_name),
+        code: StatusCode::OK,
+    }
+    .to_http()
+}
+
+// Only parquet files written after the change are compressed with the new codec,
+// existing files are left as they are.
+pub async fn put_compression(req: HttpRequest, body: web::Json<serde_json::Value>) -> HttpResponse {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    let compression: Compression = match serde_json::from_value(body.into_inner()) {
+        Ok(compression) => compression,
+        Err(e) => {
+            return response::ServerResponse {
+                msg: format!(
+                    "failed to set compression for log stream {} due to err: {}",
+                    stream_name, e
+                ),
+                code: StatusCode::BAD_REQUEST,
+            }
+            .to_http()
+        }
+    };
+
+    // don't put compression of a stream that doesn't exist to object storage
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return response::ServerResponse {
+            msg: format!(
+                "failed to set compression for log stream {} due to err: {}",
+                stream_name,
+                crate::Error::StreamMetaNotFound(stream_name.clone())
+            ),
+            code: StatusCode::NOT_FOUND,
+        }
+        .to_http();
+    }
+
+    if let Err(e) = CONFIG
+        .object_storage()
+        .put_compression(&stream_name, compression)
+        .await
+    {
+        return response::ServerResponse {
+            msg: format!(
+                "failed to set compression for log stream {} due to err: {}",
+                stream_name, e
+            ),
+            code: StatusCode::INTERNAL_SERVER_ERROR,
+        }
+        .to_http();
+    }
+
+    if let Err(e) = metadata::STREAM_INFO.set_compression(&stream_name, compression) {
+        let code = match e {
+            crate::Error::StreamMetaNotFound(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        return response::ServerResponse {
+            msg: format!(
+                "failed to set compression for log stream {} due to err: {}",
+                stream_name, e
+            ),
+            code,
+        }
+        .to_http();
+    }
+
+    response::ServerResponse {
+        msg: format!("set compression for log stream {}", stream_name),
+        code: StatusCode::OK,
+    }
+    .to_http()
+}
+
+// Only events ingested after the change are flattened the new way, columns of the
+// events already ingested stay in the schema as they are.
+pub async fn put_flatten(req: HttpRequest, body: web::Json<serde_json::Value>) -> HttpResponse {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    let flatten: Flatten = match serde_json::from_value(body.into_inner()) {
+        Ok(flatten) => flatten,
+        Err(e) => {
+            return response::ServerResponse {
+                msg: format!(
+                    "failed to set flattening for log stream {} due to err: {}",
+                    stream_name, e
+                ),
+                code: StatusCode::BAD_REQUEST,
+            }
+            .to_http()
+        }
+    };
+
+    // don't put flattening of a stream that doesn't exist to object storage
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return response::ServerResponse {
+            msg: format!(
+                "failed to set flattening for log stream {} due to err: {}",
+                stream_name,
+                crate::Error::StreamMetaNotFound(stream_name.clone())
+            ),
+            code: StatusCode::NOT_FOUND,
+        }
+        .to_http();
+    }
+
+    if let Err(e) = CONFIG
+        .object_storage()
+        .put_flatten(&stream_name, flatten)
+        .await
+    {
+        return response::ServerResponse {
+            msg: format!(
+                "failed to set flattening for log stream {} due to err: {}",
+                stream_name, e
+            ),
+            code: StatusCode::INTERNAL_SERVER_ERROR,
+        }
+        .to_http();
+    }
+
+    if let Err(e) = metadata::STREAM_INFO.set_flatten(&stream_name, flatten) {
+        let code = match e {
+            crate::Error::StreamMetaNotFound(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        return response::ServerResponse {
+            msg: format!(
+                "failed to set flattening for log stream {} due to err: {}",
+                stream_name, e
+            ),
+            code,
+        }
+        .to_http();
+    }
+
+    response::ServerResponse {
+        msg: format!("set flattening for log stream {}", stream_name),
+        code: StatusCode::OK,
+    }
+    .to_http()
+}
+
+// Limits are put to object storage first, so that they survive a restart once set in memory.
+async fn set_limits(
+    storage: &dyn ObjectStorage,
+    stream_name: &str,
+    limits: Limits,
+) -> Result<(), crate::Error> {
+    storage.put_limits(stream_name, &limits).await?;
+    metadata::STREAM_INFO.set_limits(stream_name, limits)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::body::to_bytes;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use actix_web::{web, HttpRequest};
+    use arrow::datatypes::{DataType, Field, Schema};
+    use serde_json::{json, Value};
+    use serial_test::serial;
+
+    use super::{
+        export, get_stats, infer_schema, list, put_retention, refresh_all, schema, total_stats,
+        ExportQuery,
+    };
+    use crate::alerts::Alerts;
+    use crate::auth::{Action, Permission};
+    use crate::metadata::STREAM_INFO;
+
+    async fn get_schema(stream_name: &str) -> (StatusCode, Vec<u8>) {
+        let req = TestRequest::default()
+            .param("logstream", stream_name.to_string())
+            .to_http_request();
+        let resp = schema(req).await;
+        let status = resp.status();
+        let body = to_bytes(resp.into_body()).await.unwrap();
+
+        (status, body.to_vec())
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn get_stored_schema() {
+        let stream_schema = Schema::new(vec![Field::new("level", DataType::Utf8, true)]);
+        STREAM_INFO
+            .add_stream(
+                "schemastream".to_string(),
+                Some(stream_schema.clone()),
+                Alerts::default(),
+            )
+            .unwrap();
+
+        let (status, body) = get_schema("schemastream").await;
+        STREAM_INFO.delete_stream("schemastream").unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            serde_json::from_slice::<Value>(&body).unwrap(),
+            serde_json::to_value(&stream_schema).unwrap()
+        );
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn list_streams_by_tags() {
+        let streams = [
+            ("ordersprod", Some(("orders", "prod"))),
+            ("ordersdev", Some(("orders", "dev"))),
+            ("ordersuntagged", None),
+        ];
+        for (stream_name, tags) in streams {
+            STREAM_INFO
+                .add_stream(stream_name.to_string(), None, Alerts::default())
+                .unwrap();
+            if let Some((team, env)) = tags {
+                let tags = [("team", team), ("env", env)]
+                    .into_iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect();
+                STREAM_INFO.set_tags(stream_name, tags).unwrap();
+            }
+        }
+
+        let names = |query: &'static str| async move {
+            let req = TestRequest::with_uri(&format!("/logstream?{}", query)).to_http_request();
+            let query = web::Query::from_query(req.query_string()).unwrap();
+            let body = to_bytes(list(req, query).await.into_body()).await.unwrap();
+            let mut names: Vec<String> = serde_json::from_slice::<Vec<Value>>(&body)
+                .unwrap()
+                .into_iter()
+                .map(|stream| stream["name"].as_str().unwrap().to_string())
+                .filter(|name| name.starts_with("orders"))
+                .collect();
+            names.sort();
+            names
+        };
+        let team = names("tag.team=orders").await;
+        let prod = names("tag.team=orders&tag.env=prod").await;
+        let other = names("tag.team=orders&tag.env=staging").await;
+        let all = names("").await;
+        for (stream_name, _) in streams {
+            STREAM_INFO.delete_stream(stream_name).unwrap();
+        }
+
+        assert_eq!(team, vec!["ordersdev", "ordersprod"]);
+        assert_eq!(prod, vec!["ordersprod"]);
+        assert!(other.is_empty());
+        assert_eq!(all, vec!["ordersdev", "ordersprod", "ordersuntagged"]);
+    }
+
+    // request with an API key that may only read `streams`
+    fn scoped_request(uri: &str, streams: &[&str]) -> HttpRequest {
+        let req = TestRequest::with_uri(uri).to_http_request();
+        req.extensions_mut().insert(Permission {
+            streams: streams.iter().map(|stream| stream.to_string()).collect(),
+            actions: vec![Action::Read],
+        });
+        req
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn scoped_key_sees_its_streams_only() {
+        for stream_name in ["scopedfirst", "scopedsecond"] {
+            STREAM_INFO
+                .add_stream(stream_name.to_string(), None, Alerts::default())
+                .unwrap();
+        }
+        STREAM_INFO.update_stats("scopedfirst", 100, 10, 4).unwrap();
+        STREAM_INFO.update_stats("scopedsecond", 50, 5, 1).unwrap();
+
+        let req = scoped_request("/logstream", &["scopedfirst"]);
+        let query = web::Query::from_query(req.query_string()).unwrap();
+        let listed = to_bytes(list(req, query).await.into_body()).await.unwrap();
+        let req = scoped_request("/logstream/stats", &["scopedfirst"]);
+        let total = to_bytes(total_stats(req).await.into_body()).await.unwrap();
+        for stream_name in ["scopedfirst", "scopedsecond"] {
+            STREAM_INFO.delete_stream(stream_name).unwrap();
+        }
+
+        let names: Vec<Value> = serde_json::from_slice::<Vec<Value>>(&listed)
+            .unwrap()
+            .into_iter()
+            .map(|stream| stream["name"].clone())
+            .collect();
+        assert_eq!(names, vec![json!("scopedfirst")]);
+        let total: Value = serde_json::from_slice(&total).unwrap();
+        assert_eq!(total["streams"], 1);
+        assert_eq!(total["size"], 100);
+        assert_eq!(total["events"], 4);
+    }
+
+    #[actix_web::test]
+    async fn scoped_key_cant_refresh_all() {
+        let req = scoped_request("/refresh", &["app"]);
+
+        assert_eq!(refresh_all(req).await.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn get_schema_of_missing_stream() {
+        let (status, _) = get_schema("missingstream").await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn get_schema_before_first_event() {
+        STREAM_INFO
+            .add_stream("emptystream".to_string(), None, Alerts::default())
+            .unwrap();
+
+        let (status, body) = get_schema("emptystream").await;
+        STREAM_INFO.delete_stream("emptystream").unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            serde_json::from_slice::<Value>(&body).unwrap(),
+            json!({"fields": [], "metadata": {}, "inferred": false})
+        );
+    }
+
+    async fn post_infer_schema(stream_name: &str, sample: Value) -> (StatusCode, Value) {
+        let req = TestRequest::default()
+            .param("logstream", stream_name.to_string())
+            .to_http_request();
+        let resp = infer_schema(req, actix_web::web::Json(sample)).await;
+        let status = resp.status();
+        let body = to_bytes(resp.into_body()).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn infer_schema_against_stored_schema() {
+        let stream_schema = Schema::new(vec![
+            Field::new("labels", DataType::Utf8, true),
+            Field::new("level", DataType::Utf8, true),
+            Field::new("status", DataType::Int64, true),
+        ]);
+        STREAM_INFO
+            .add_stream(
+                "inferstream".to_string(),
+                Some(stream_schema.clone()),
+                Alerts::default(),
+            )
+            .unwrap();
+
+        let sample = json!([
+            {"level": "info", "status": "500", "host": "a"},
+            {"level": "warn", "status": "404"}
+        ]);
+        let (status, body) = post_infer_schema("inferstream", sample).await;
+        let stored = STREAM_INFO.schema("inferstream").unwrap();
+        STREAM_INFO.delete_stream("inferstream").unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        let added = body["merge"]["added"].as_array().unwrap();
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0]["name"], "host");
+        assert_eq!(
+            body["merge"]["conflicting"],
+            json!([{"name": "status", "stored": "Int64", "incoming": "Utf8"}])
+        );
+        assert_eq!(body["schema"]["fields"].as_array().unwrap().len(), 4);
+        // nothing is merged into the stored schema
+        assert_eq!(stored.unwrap().as_ref(), &stream_schema);
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn infer_schema_of_new_stream() {
+        let (status, body) = post_infer_schema("newstream", json!({"level": "info"})).await;
+
+        assert_eq!(status, StatusCode::OK);
+        let fields = body["schema"]["fields"].as_array().unwrap();
+        assert!(fields.iter().any(|field| field["name"] == "level"));
+        assert_eq!(body["merge"], Value::Null);
+        assert!(!STREAM_INFO.stream_exists("newstream"));
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn get_stream_stats() {
+        STREAM_INFO
+            .add_stream("statsstream".to_string(), None, Alerts::default())
+            .unwrap();
+        STREAM_INFO.update_stats("statsstream", 100, 10, 4).unwrap();
+
+        let req = TestRequest::default()
+            .param("logstream", "statsstream")
+            .to_http_request();
+        let resp = get_stats(req).await;
+        STREAM_INFO.delete_stream("statsstream").unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = to_bytes(resp.into_body()).await.unwrap();
+        let stats: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(stats["size"], 100);
+        assert_eq!(stats["compressed_size"], 10);
+        assert_eq!(stats["events"], 4);
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn get_stats_of_missing_stream() {
+        let req = TestRequest::default()
+            .param("logstream", "missingstream")
+            .to_http_request();
+
+        assert_eq!(get_stats(req).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn put_retention_of_missing_stream() {
+        let req = TestRequest::default()
+            .param("logstream", "missingstream")
+            .to_http_request();
+        let resp = put_retention(req, web::Json(json!({"days": 7}))).await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(!STREAM_INFO.stream_exists("missingstream"));
+    }
+
+    async fn export_status(stream_name: &str, query: &str) -> StatusCode {
+        let req = TestRequest::default()
+            .param("logstream", stream_name.to_string())
+            .to_http_request();
+        let query = web::Query::<ExportQuery>::from_query(query).unwrap();
+
+        export(req, query).await.status()
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn export_checks_stream_and_range() {
+        STREAM_INFO
+            .add_stream("exportstream".to_string(), None, Alerts::default())
+            .unwrap();
+
+        let missing = export_status("missingstream", "").await;
+        let start_only = export_status("exportstream", "start=2022-10-15T10:00:00Z").await;
+        let reversed = export_status(
+            "exportstream",
+            "start=2022-10-15T11:00:00Z&end=2022-10-15T10:00:00Z",
+        )
+        .await;
+        STREAM_INFO.delete_stream("exportstream").unwrap();
+
+        assert_eq!(missing, StatusCode::NOT_FOUND);
+        assert_eq!(start_only, StatusCode::BAD_REQUEST);
+        assert_eq!(reversed, StatusCode::BAD_REQUEST);
+    }
+}
@@ -20,10 +20,16 @@ pub mod event;
 pub mod logstream;
 
 use actix_web::http::StatusCode;
-use actix_web::HttpResponse;
+use actix_web::{HttpRequest, HttpResponse};
+use serde_json::json;
+use std::time::Duration;
 use sysinfo::{System, SystemExt};
 
-use crate::s3::S3;
+use crate::auth;
+use crate::metadata;
+use crate::metrics;
+use crate::option::CONFIG;
+use crate::response;
 use crate::storage::ObjectStorage;
 
 pub async fn liveness() -> HttpResponse {
@@ -36,10 +42,123 @@ pub async fn liveness() -> HttpResponse {
     HttpResponse::new(StatusCode::OK)
 }
 
+// A probe taking longer counts as a failure, health checks can't wait on it
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub async fn health() -> HttpResponse {
+    storage_health(CONFIG.object_storage().as_ref(), HEALTH_CHECK_TIMEOUT).await
+}
+
+/// 200 if the object storage can be reached within `timeout`, 503 with the reason otherwise
+async fn storage_health(storage: &dyn ObjectStorage, timeout: Duration) -> HttpResponse {
+    let msg = match actix_web::rt::time::timeout(timeout, storage.check()).await {
+        Ok(Ok(())) => return HttpResponse::new(StatusCode::OK),
+        Ok(Err(e)) => format!("object storage is unreachable due to err: {}", e),
+        Err(_) => format!(
+            "object storage did not respond within {} seconds",
+            timeout.as_secs_f32()
+        ),
+    };
+
+    response::ServerResponse {
+        msg,
+        code: StatusCode::SERVICE_UNAVAILABLE,
+    }
+    .to_http()
+}
+
+pub async fn metrics(req: HttpRequest) -> HttpResponse {
+    // metrics are labeled with the streams they are of, of all streams
+    if auth::is_scoped(&req) {
+        return response::ServerResponse {
+            msg: "not permitted to get metrics of all log streams".to_string(),
+            code: StatusCode::FORBIDDEN,
+        }
+        .to_http();
+    }
+
+    match metrics::gather() {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(prometheus::TEXT_FORMAT)
+            .body(body),
+        Err(e) => response::ServerResponse {
+            msg: format!("failed to gather metrics due to err: {}", e),
+            code: StatusCode::INTERNAL_SERVER_ERROR,
+        }
+        .to_http(),
+    }
+}
+
 pub async fn readiness() -> HttpResponse {
-    if let Ok(()) = S3::new().check().await {
-        return HttpResponse::new(StatusCode::OK);
+    if let Ok(()) = CONFIG.object_storage().check().await {
+        // server is ready even if some log streams failed to load or
+        // can't be synced, report them so that they can be fixed
+        let load_errors = metadata::STREAM_INFO.load_errors();
+        let sync_failing = metadata::STREAM_INFO.sync_failing();
+        if load_errors.is_empty() && !sync_failing {
+            return HttpResponse::new(StatusCode::OK);
+        }
+
+        let degraded = load_errors
+            .into_iter()
+            .map(|(stream_name, error)| json!({ "name": stream_name, "error": error.to_string() }))
+            .collect::<Vec<_>>();
+        return HttpResponse::Ok().json(json!({
+            "degradedStreams": degraded,
+            "syncFailing": sync_failing,
+        }));
     }
 
     HttpResponse::new(StatusCode::SERVICE_UNAVAILABLE)
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::body::to_bytes;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use std::time::Duration;
+
+    use super::{metrics, storage_health};
+    use crate::auth::{Action, Permission};
+    use crate::memory::MemoryStorage;
+    use crate::storage::mock::MockStorage;
+    use crate::storage::ObjectStorage;
+
+    #[actix_web::test]
+    async fn health_follows_storage() {
+        let timeout = Duration::from_millis(50);
+        let cases: [(Box<dyn ObjectStorage>, _, _); 3] = [
+            (Box::new(MemoryStorage::default()), StatusCode::OK, ""),
+            (
+                Box::new(MemoryStorage::default().with_failures("check", 1)),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "object storage is unreachable",
+            ),
+            (
+                Box::new(MockStorage::default().with_check_delay(Duration::from_secs(5))),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "object storage did not respond",
+            ),
+        ];
+
+        for (storage, status, reason) in cases {
+            let resp = storage_health(storage.as_ref(), timeout).await;
+
+            assert_eq!(resp.status(), status);
+            let body = to_bytes(resp.into_body()).await.unwrap();
+            assert!(String::from_utf8_lossy(&body).starts_with(reason));
+        }
+    }
+
+    #[actix_web::test]
+    async fn scoped_key_cant_get_metrics() {
+        let req = TestRequest::with_uri("/metrics").to_http_request();
+        req.extensions_mut().insert(Permission {
+            streams: vec!["app".to_string()],
+            actions: vec![Action::Read],
+        });
+
+        assert_eq!(metrics(req).await.status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,636 @@
+/*
+ * Parseable Server (C) 2022 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use arrow::datatypes::Schema;
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use datafusion::arrow::record_batch::RecordBatch;
+use std::collections::HashMap;
+use std::future::Future;
+
+use crate::alerts::Alerts;
+use crate::metadata::{
+    Compression, Flatten, Limits, PartitionGranularity, Stats, StreamTimestamps, STREAM_INFO,
+};
+use crate::metrics;
+use crate::migration::MetadataDocument;
+use crate::query::Query;
+use crate::retention::Retention;
+use crate::storage::{
+    DeletedObjects, LogStream, ObjectMeta, ObjectStorage, ObjectStorageError, ParquetFile,
+    StoredObjects, StreamsPage,
+};
+
+/// Stream label of operations that aren't on data objects
+const NO_STREAM: &str = "";
+
+/// Log stream of a data object, to label operations on it with. Only parquet files of
+/// streams that exist are labelled, so that metadata objects and the objects of deleted
+/// streams don't grow the number of label values.
+fn data_stream(key: &str) -> &str {
+    match key.split_once('/') {
+        Some((stream_name, _))
+            if key.ends_with(".parquet") && STREAM_INFO.stream_exists(stream_name) =>
+        {
+            stream_name
+        }
+        _ => NO_STREAM,
+    }
+}
+
+/// Object storage that records the count, duration and errors of every operation,
+/// and the bytes of objects put and fetched, in the metrics served at /metrics.
+/// Retries of the wrapped storage are counted by it, as part of one operation here.
+pub struct InstrumentedStorage<T: ObjectStorage> {
+    inner: T,
+}
+
+impl<T: ObjectStorage> InstrumentedStorage<T> {
+    pub fn new(inner: T) -> Self {
+        Self { inner }
+    }
+
+    async fn instrument<R, Fut>(
+        &self,
+        operation: &str,
+        stream_name: &str,
+        attempt: Fut,
+    ) -> Result<R, ObjectStorageError>
+    where
+        Fut: Future<Output = Result<R, ObjectStorageError>>,
+    {
+        let labels = [operation, stream_name];
+        let timer = metrics::STORAGE_DURATION
+            .with_label_values(&labels)
+            .start_timer();
+        let result = attempt.await;
+        timer.observe_duration();
+
+        metrics::STORAGE_OPERATIONS.with_label_values(&labels).inc();
+        if result.is_err() {
+            metrics::STORAGE_ERRORS.with_label_values(&labels).inc();
+        }
+        result
+    }
+
+    fn transferred(&self, operation: &str, stream_name: &str, bytes: u64) {
+        metrics::STORAGE_BYTES
+            .with_label_values(&[operation, stream_name])
+            .inc_by(bytes);
+    }
+}
+
+#[async_trait]
+impl<T: ObjectStorage> ObjectStorage for InstrumentedStorage<T> {
+    async fn check(&self) -> Result<(), ObjectStorageError> {
+        self.instrument("check", NO_STREAM, self.inner.check())
+            .await
+    }
+
+    async fn put_schema(
+        &self,
+        stream_name: String,
+        schema: &Schema,
+    ) -> Result<(), ObjectStorageError> {
+        self.instrument(
+            "put_schema",
+            NO_STREAM,
+            self.inner.put_schema(stream_name, schema),
+        )
+        .await
+    }
+
+    async fn create_stream(&self, stream_name: &str) -> Result<(), ObjectStorageError> {
+        self.instrument(
+            "create_stream",
+            NO_STREAM,
+            self.inner.create_stream(stream_name),
+        )
+        .await
+    }
+
+    async fn create_alert(
+        &self,
+        stream_name: &str,
+        alerts: &Alerts,
+    ) -> Result<(), ObjectStorageError> {
+        self.instrument(
+            "create_alert",
+            NO_STREAM,
+            self.inner.create_alert(stream_name, alerts),
+        )
+        .await
+    }
+
+    async fn get_schema(&self, stream_name: &str) -> Result<Bytes, ObjectStorageError> {
+        self.instrument("get_schema", NO_STREAM, self.inner.get_schema(stream_name))
+            .await
+    }
+
+    async fn get_schema_tagged(
+        &self,
+        stream_name: &str,
+    ) -> Result<Option<(Bytes, String)>, ObjectStorageError> {
+        self.instrument(
+            "get_schema_tagged",
+            NO_STREAM,
+            self.inner.get_schema_tagged(stream_name),
+        )
+        .await
+    }
+
+    async fn put_schema_if(
+        &self,
+        stream_name: &str,
+        schema: &Schema,
+        tag: Option<&str>,
+    ) -> Result<(), ObjectStorageError> {
+        self.instrument(
+            "put_schema_if",
+            NO_STREAM,
+            self.inner.put_schema_if(stream_name, schema, tag),
+        )
+        .await
+    }
+
+    async fn get_alert(&self, stream_name: &str) -> Result<Bytes, ObjectStorageError> {
+        self.instrument("get_alert", NO_STREAM, self.inner.get_alert(stream_name))
+            .await
+    }
+
+    async fn get_stats(&self, stream_name: &str) -> Result<Stats, ObjectStorageError> {
+        self.instrument("get_stats", NO_STREAM, self.inner.get_stats(stream_name))
+            .await
+    }
+
+    async fn put_stats(&self, stream_name: &str, stats: &Stats) -> Result<(), ObjectStorageError> {
+        self.instrument(
+            "put_stats",
+            NO_STREAM,
+            self.inner.put_stats(stream_name, stats),
+        )
+        .await
+    }
+
+    async fn put_retention(
+        &self,
+        stream_name: &str,
+        retention: &Retention,
+    ) -> Result<(), ObjectStorageError> {
+        self.instrument(
+            "put_retention",
+            NO_STREAM,
+            self.inner.put_retention(stream_name, retention),
+        )
+        .await
+    }
+
+    async fn get_retention(&self, stream_name: &str) -> Result<Retention, ObjectStorageError> {
+        self.instrument(
+            "get_retention",
+            NO_STREAM,
+            self.inner.get_retention(stream_name),
+        )
+        .await
+    }
+
+    async fn put_tags(
+        &self,
+        stream_name: &str,
+        tags: &HashMap<String, String>,
+    ) -> Result<(), ObjectStorageError> {
+        self.instrument(
+            "put_tags",
+            NO_STREAM,
+            self.inner.put_tags(stream_name, tags),
+        )
+        .await
+    }
+
+    async fn get_tags(
+        &self,
+        stream_name: &str,
+    ) -> Result<HashMap<String, String>, ObjectStorageError> {
+        self.instrument("get_tags", NO_STREAM, self.inner.get_tags(stream_name))
+            .await
+    }
+
+    async fn put_static_schema(
+        &self,
+        stream_name: &str,
+        static_schema: bool,
+    ) -> Result<(), ObjectStorageError> {
+        self.instrument(
+            "put_static_schema",
+            NO_STREAM,
+            self.inner.put_static_schema(stream_name, static_schema),
+        )
+        .await
+    }
+
+    async fn get_static_schema(&self, stream_name: &str) -> Result<bool, ObjectStorageError> {
+        self.instrument(
+            "get_static_schema",
+            NO_STREAM,
+            self.inner.get_static_schema(stream_name),
+        )
+        .await
+    }
+
+    async fn put_deleted_at(
+        &self,
+        stream_name: &str,
+        deleted_at: Option<DateTime<Utc>>,
+    ) -> Result<(), ObjectStorageError> {
+        self.instrument(
+            "put_deleted_at",
+            NO_STREAM,
+            self.inner.put_deleted_at(stream_name, deleted_at),
+        )
+        .await
+    }
+
+    async fn get_deleted_at(
+        &self,
+        stream_name: &str,
+    ) -> Result<Option<DateTime<Utc>>, ObjectStorageError> {
+        self.instrument(
+            "get_deleted_at",
+            NO_STREAM,
+            self.inner.get_deleted_at(stream_name),
+        )
+        .await
+    }
+
+    async fn put_renamed_from(
+        &self,
+        stream_name: &str,
+        renamed_from: &str,
+    ) -> Result<(), ObjectStorageError> {
+        self.instrument(
+            "put_renamed_from",
+            NO_STREAM,
+            self.inner.put_renamed_from(stream_name, renamed_from),
+        )
+        .await
+    }
+
+    async fn get_renamed_from(&self, stream_name: &str) -> Result<String, ObjectStorageError> {
+        self.instrument(
+            "get_renamed_from",
+            NO_STREAM,
+            self.inner.get_renamed_from(stream_name),
+        )
+        .await
+    }
+
+    async fn put_time_field(
+        &self,
+        stream_name: &str,
+        time_field: &str,
+    ) -> Result<(), ObjectStorageError> {
+        self.instrument(
+            "put_time_field",
+            NO_STREAM,
+            self.inner.put_time_field(stream_name, time_field),
+        )
+        .await
+    }
+
+    async fn get_time_field(&self, stream_name: &str) -> Result<String, ObjectStorageError> {
+        self.instrument(
+            "get_time_field",
+            NO_STREAM,
+            self.inner.get_time_field(stream_name),
+        )
+        .await
+    }
+
+    async fn put_limits(
+        &self,
+        stream_name: &str,
+        limits: &Limits,
+    ) -> Result<(), ObjectStorageError> {
+        self.instrument(
+            "put_limits",
+            NO_STREAM,
+            self.inner.put_limits(stream_name, limits),
+        )
+        .await
+    }
+
+    async fn get_limits(&self, stream_name: &str) -> Result<Limits, ObjectStorageError> {
+        self.instrument("get_limits", NO_STREAM, self.inner.get_limits(stream_name))
+            .await
+    }
+
+    async fn put_compression(
+        &self,
+        stream_name: &str,
+        compression: Compression,
+    ) -> Result<(), ObjectStorageError> {
+        self.instrument(
+            "put_compression",
+            NO_STREAM,
+            self.inner.put_compression(stream_name, compression),
+        )
+        .await
+    }
+
+    async fn get_compression(&self, stream_name: &str) -> Result<Compression, ObjectStorageError> {
+        self.instrument(
+            "get_compression",
+            NO_STREAM,
+            self.inner.get_compression(stream_name),
+        )
+        .await
+    }
+
+    async fn put_flatten(
+        &self,
+        stream_name: &str,
+        flatten: Flatten,
+    ) -> Result<(), ObjectStorageError> {
+        self.instrument(
+            "put_flatten",
+            NO_STREAM,
+            self.inner.put_flatten(stream_name, flatten),
+        )
+        .await
+    }
+
+    async fn get_flatten(&self, stream_name: &str) -> Result<Flatten, ObjectStorageError> {
+        self.instrument(
+            "get_flatten",
+            NO_STREAM,
+            self.inner.get_flatten(stream_name),
+        )
+        .await
+    }
+
+    async fn put_partition(
+        &self,
+        stream_name: &str,
+        partition: PartitionGranularity,
+    ) -> Result<(), ObjectStorageError> {
+        self.instrument(
+            "put_partition",
+            NO_STREAM,
+            self.inner.put_partition(stream_name, partition),
+        )
+        .await
+    }
+
+    async fn get_partition(
+        &self,
+        stream_name: &str,
+    ) -> Result<PartitionGranularity, ObjectStorageError> {
+        self.instrument(
+            "get_partition",
+            NO_STREAM,
+            self.inner.get_partition(stream_name),
+        )
+        .await
+    }
+
+    async fn put_timestamps(
+        &self,
+        stream_name: &str,
+        timestamps: &StreamTimestamps,
+    ) -> Result<(), ObjectStorageError> {
+        self.instrument(
+            "put_timestamps",
+            NO_STREAM,
+            self.inner.put_timestamps(stream_name, timestamps),
+        )
+        .await
+    }
+
+    async fn get_timestamps(
+        &self,
+        stream_name: &str,
+    ) -> Result<StreamTimestamps, ObjectStorageError> {
+        self.instrument(
+            "get_timestamps",
+            NO_STREAM,
+            self.inner.get_timestamps(stream_name),
+        )
+        .await
+    }
+
+    async fn put_metadata(
+        &self,
+        stream_name: &str,
+        document: &MetadataDocument,
+    ) -> Result<(), ObjectStorageError> {
+        self.instrument(
+            "put_metadata",
+            NO_STREAM,
+            self.inner.put_metadata(stream_name, document),
+        )
+        .await
+    }
+
+    async fn get_metadata(
+        &self,
+        stream_name: &str,
+    ) -> Result<MetadataDocument, ObjectStorageError> {
+        self.instrument(
+            "get_metadata",
+            NO_STREAM,
+            self.inner.get_metadata(stream_name),
+        )
+        .await
+    }
+
+    async fn list_streams(&self) -> Result<Vec<LogStream>, ObjectStorageError> {
+        self.instrument("list_streams", NO_STREAM, self.inner.list_streams())
+            .await
+    }
+
+    async fn list_streams_paged(
+        &self,
+        continuation_token: Option<&str>,
+        limit: usize,
+    ) -> Result<StreamsPage, ObjectStorageError> {
+        self.instrument(
+            "list_streams_paged",
+            NO_STREAM,
+            self.inner.list_streams_paged(continuation_token, limit),
+        )
+        .await
+    }
+
+    async fn list_dirs(&self, prefix: &str) -> Result<Vec<String>, ObjectStorageError> {
+        self.instrument("list_dirs", NO_STREAM, self.inner.list_dirs(prefix))
+            .await
+    }
+
+    async fn put_object(&self, key: &str, body: Bytes) -> Result<(), ObjectStorageError> {
+        let size = body.len() as u64;
+        self.instrument(
+            "put_object",
+            data_stream(key),
+            self.inner.put_object(key, body),
+        )
+        .await?;
+        self.transferred("put_object", data_stream(key), size);
+
+        Ok(())
+    }
+
+    async fn get_object(&self, key: &str) -> Result<Bytes, ObjectStorageError> {
+        let body = self
+            .instrument("get_object", data_stream(key), self.inner.get_object(key))
+            .await?;
+        self.transferred("get_object", data_stream(key), body.len() as u64);
+
+        Ok(body)
+    }
+
+    async fn upload_file(&self, key: &str, path: &str) -> Result<(), ObjectStorageError> {
+        self.instrument(
+            "upload_file",
+            data_stream(key),
+            self.inner.upload_file(key, path),
+        )
+        .await?;
+        // the file is still staged until the upload is verified
+        if let Ok(meta) = std::fs::metadata(path) {
+            self.transferred("upload_file", data_stream(key), meta.len());
+        }
+
+        Ok(())
+    }
+
+    async fn object_meta(&self, key: &str) -> Result<ObjectMeta, ObjectStorageError> {
+        self.instrument("object_meta", NO_STREAM, self.inner.object_meta(key))
+            .await
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> Result<DeletedObjects, ObjectStorageError> {
+        self.instrument("delete_prefix", NO_STREAM, self.inner.delete_prefix(prefix))
+            .await
+    }
+
+    async fn copy_prefix(&self, from: &str, to: &str) -> Result<u64, ObjectStorageError> {
+        self.instrument("copy_prefix", NO_STREAM, self.inner.copy_prefix(from, to))
+            .await
+    }
+
+    async fn count_objects(&self, prefix: &str) -> Result<u64, ObjectStorageError> {
+        self.instrument("count_objects", NO_STREAM, self.inner.count_objects(prefix))
+            .await
+    }
+
+    async fn parquet_objects(&self, prefix: &str) -> Result<StoredObjects, ObjectStorageError> {
+        self.instrument(
+            "parquet_objects",
+            NO_STREAM,
+            self.inner.parquet_objects(prefix),
+        )
+        .await
+    }
+
+    async fn list_parquet_files(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<ParquetFile>, ObjectStorageError> {
+        self.instrument(
+            "list_parquet_files",
+            NO_STREAM,
+            self.inner.list_parquet_files(prefix),
+        )
+        .await
+    }
+
+    async fn query(
+        &self,
+        query: &Query,
+        results: &mut Vec<RecordBatch>,
+    ) -> Result<(), ObjectStorageError> {
+        self.instrument("query", NO_STREAM, self.inner.query(query, results))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::*;
+    use serial_test::serial;
+
+    use super::*;
+    use crate::memory::MemoryStorage;
+
+    fn count(operation: &str, stream_name: &str) -> (u64, u64, u64, u64) {
+        let labels = [operation, stream_name];
+        (
+            metrics::STORAGE_OPERATIONS.with_label_values(&labels).get(),
+            metrics::STORAGE_ERRORS.with_label_values(&labels).get(),
+            metrics::STORAGE_BYTES.with_label_values(&labels).get(),
+            metrics::STORAGE_DURATION
+                .with_label_values(&labels)
+                .get_sample_count(),
+        )
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn records_operations_errors_and_bytes() {
+        STREAM_INFO
+            .add_stream("metricstream".to_string(), None, Alerts::default())
+            .unwrap();
+        let key = "metricstream/date=2022-10-15/hour=10/minute=00/a.parquet";
+        let storage =
+            InstrumentedStorage::new(MemoryStorage::default().with_failures("get_object", 1));
+        let put = count("put_object", "metricstream");
+        let get = count("get_object", "metricstream");
+
+        storage.put_object(key, Bytes::from("PAR1")).await.unwrap();
+        assert!(storage.get_object(key).await.is_err());
+        storage.get_object(key).await.unwrap();
+        STREAM_INFO.delete_stream("metricstream").unwrap();
+
+        let (operations, errors, bytes, durations) = count("put_object", "metricstream");
+        assert_eq!((operations - put.0, errors - put.1), (1, 0));
+        assert_eq!((bytes - put.2, durations - put.3), (4, 1));
+        let (operations, errors, bytes, durations) = count("get_object", "metricstream");
+        assert_eq!((operations - get.0, errors - get.1), (2, 1));
+        assert_eq!((bytes - get.2, durations - get.3), (4, 2));
+    }
+
+    #[rstest]
+    #[case::data_object(
+        "metricstream2/date=2022-10-15/hour=10/minute=00/a.parquet",
+        "metricstream2"
+    )]
+    #[case::metadata_object("metricstream2/.stats.json", NO_STREAM)]
+    #[case::deleted_stream("gonestream/date=2022-10-15/hour=10/minute=00/a.parquet", NO_STREAM)]
+    #[case::no_stream("a.parquet", NO_STREAM)]
+    #[serial]
+    fn stream_label(#[case] key: &str, #[case] stream_name: &str) {
+        STREAM_INFO
+            .add_stream("metricstream2".to_string(), None, Alerts::default())
+            .unwrap();
+        let label = data_stream(key).to_string();
+        STREAM_INFO.delete_stream("metricstream2").unwrap();
+
+        assert_eq!(label, stream_name);
+    }
+}
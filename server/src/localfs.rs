@@ -0,0 +1,1128 @@
+/*
+ * Parseable Server (C) 2022 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use arrow::datatypes::Schema;
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, TimeZone, Utc};
+use datafusion::arrow::record_batch::RecordBatch;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+use structopt::StructOpt;
+use walkdir::WalkDir;
+
+use crate::alerts::Alerts;
+use crate::metadata::{
+    Compression, Flatten, Limits, PartitionGranularity, Stats, StreamTimestamps,
+};
+use crate::migration::MetadataDocument;
+use crate::option::{StorageOpt, CONFIG};
+use crate::query::Query;
+use crate::retention::Retention;
+use crate::storage::{
+    DeletedObjects, FileDigest, LogStream, ObjectMeta, ObjectStorage, ObjectStorageError,
+    ParquetFile, StoredObjects,
+};
+use crate::utils;
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(
+    name = "Local storage config",
+    about = "configuration for local filesystem storage"
+)]
+pub struct LocalStorageConfig {
+    /// The directory used in place of an object storage bucket,
+    /// each log stream is stored in its own sub directory
+    #[structopt(long, env = "P_FS_DIR", default_value = "./data-store")]
+    pub fs_dir: PathBuf,
+}
+
+impl StorageOpt for LocalStorageConfig {
+    fn bucket_name(&self) -> &str {
+        self.fs_dir.to_str().unwrap_or_default()
+    }
+
+    fn endpoint_url(&self) -> &str {
+        "file://"
+    }
+
+    fn is_default_url(&self) -> bool {
+        false
+    }
+
+    fn warning(&self) {}
+
+    fn object_storage(&self) -> Box<dyn ObjectStorage> {
+        Box::new(LocalStorage::new(self.fs_dir.clone()))
+    }
+}
+
+/// Tries to take the lock of an object before giving up on the process holding it
+const LOCK_ATTEMPTS: u32 = 100;
+const LOCK_WAIT: Duration = Duration::from_millis(10);
+/// A lock held longer than this was left by a process that died holding it
+const LOCK_TTL: Duration = Duration::from_secs(30);
+
+/// MD5 hash of the body of an object in hex, as in S3 ETags
+fn etag(body: &[u8]) -> String {
+    format!("{:x}", md5::compute(body))
+}
+
+/// Lock of an object, released when dropped
+struct ObjectLock(PathBuf);
+
+// Whether the lock at `path` is older than LOCK_TTL. Locks are dated by their content,
+// or by the time of the file if the process died before it could write it.
+fn is_stale_lock(path: &Path) -> bool {
+    let taken_at = fs::read_to_string(path).ok().and_then(|holder| {
+        let millis = holder.split_whitespace().nth(1)?.parse().ok()?;
+        Utc.timestamp_millis_opt(millis).single()
+    });
+    let age = match taken_at {
+        Some(taken_at) => (Utc::now() - taken_at).to_std().unwrap_or_default(),
+        None => match fs::metadata(path).and_then(|meta| meta.modified()) {
+            Ok(modified) => modified.elapsed().unwrap_or_default(),
+            Err(_) => return false,
+        },
+    };
+
+    age > LOCK_TTL
+}
+
+impl Drop for ObjectLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Object storage backed by a local directory, meant for development and tests.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    /// Path of the object with the given key. Keys are relative to the root,
+    /// those with `..` or an absolute path would escape it and are rejected.
+    fn path(&self, key: &str) -> Result<PathBuf, ObjectStorageError> {
+        let relative = Path::new(key);
+        let mut components = relative.components().peekable();
+        if components.peek().is_none()
+            || !components.all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(ObjectStorageError::InvalidKey(key.to_string()));
+        }
+
+        Ok(self.root.join(relative))
+    }
+
+    fn _get(&self, key: &str) -> Result<Bytes, ObjectStorageError> {
+        match fs::read(self.path(key)?) {
+            Ok(body) => Ok(body.into()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                Err(ObjectStorageError::NoSuchKey(key.to_string()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn _put(&self, key: &str, body: &[u8]) -> Result<(), ObjectStorageError> {
+        self.persist(key, |file| file.write_all(body))
+    }
+
+    /// Write the object through `write` into a temporary file next to it and
+    /// rename that into place, so readers never see a partly written object.
+    /// The file and then its directory are synced before returning, which
+    /// makes the object durable like an acknowledged upload to a bucket.
+    fn persist(
+        &self,
+        key: &str,
+        write: impl FnOnce(&mut fs::File) -> io::Result<()>,
+    ) -> Result<(), ObjectStorageError> {
+        let path = self.path(key)?;
+        let parent = path.parent().unwrap_or(&self.root);
+        fs::create_dir_all(parent)?;
+
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let tmp = parent.join(format!(".{}.{}.tmp", file_name, utils::random_string()));
+        let written = fs::File::create(&tmp).and_then(|mut file| {
+            write(&mut file)?;
+            file.sync_all()
+        });
+        if let Err(e) = written.and_then(|_| fs::rename(&tmp, &path)) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e.into());
+        }
+        fs::File::open(parent)?.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Lock the object at `key` until the returned guard is dropped. The lock is a
+    /// file next to the object that can only be created by one process at a time,
+    /// holding the pid of the process and when it took the lock.
+    async fn lock(&self, key: &str) -> Result<ObjectLock, ObjectStorageError> {
+        let path = self.path(&format!("{}.lock", key))?;
+        fs::create_dir_all(path.parent().unwrap_or(&self.root))?;
+
+        let mut attempts = 1;
+        loop {
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(mut file) => {
+                    let lock = ObjectLock(path);
+                    writeln!(
+                        file,
+                        "{} {}",
+                        std::process::id(),
+                        Utc::now().timestamp_millis()
+                    )?;
+                    return Ok(lock);
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempts < LOCK_ATTEMPTS => {
+                    attempts += 1;
+                    if is_stale_lock(&path) {
+                        log::warn!("breaking lock {} left behind", path.display());
+                        let _ = fs::remove_file(&path);
+                        continue;
+                    }
+                    actix_web::rt::time::sleep(LOCK_WAIT).await;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Names of the directories directly inside `path`
+    fn dirs(&self, path: PathBuf) -> Result<Vec<String>, ObjectStorageError> {
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut dirs = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                dirs.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+
+        Ok(dirs)
+    }
+}
+
+#[async_trait]
+impl ObjectStorage for LocalStorage {
+    async fn check(&self) -> Result<(), ObjectStorageError> {
+        fs::create_dir_all(&self.root)?;
+
+        Ok(())
+    }
+
+    async fn put_schema(
+        &self,
+        stream_name: String,
+        schema: &Schema,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(schema)?;
+        self._put(&format!("{}/.schema", stream_name), &body)
+    }
+
+    async fn create_stream(&self, stream_name: &str) -> Result<(), ObjectStorageError> {
+        self._put(&format!("{}/.schema", stream_name), &[])?;
+        // Stream created in the store, now create the directory in
+        // the local storage as well
+        let _res = fs::create_dir_all(CONFIG.parseable.local_stream_data_path(stream_name));
+
+        Ok(())
+    }
+
+    async fn create_alert(
+        &self,
+        stream_name: &str,
+        alerts: &Alerts,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(alerts)?;
+        self._put(&format!("{}/.alert.json", stream_name), &body)
+    }
+
+    async fn get_schema(&self, stream_name: &str) -> Result<Bytes, ObjectStorageError> {
+        self._get(&format!("{}/.schema", stream_name))
+    }
+
+    // The MD5 hash of the schema is its version tag, like the ETag of an S3 object
+    async fn get_schema_tagged(
+        &self,
+        stream_name: &str,
+    ) -> Result<Option<(Bytes, String)>, ObjectStorageError> {
+        match self._get(&format!("{}/.schema", stream_name)) {
+            Ok(body) => {
+                let tag = etag(&body);
+                Ok(Some((body, tag)))
+            }
+            Err(ObjectStorageError::NoSuchKey(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn put_schema_if(
+        &self,
+        stream_name: &str,
+        schema: &Schema,
+        tag: Option<&str>,
+    ) -> Result<(), ObjectStorageError> {
+        let key = format!("{}/.schema", stream_name);
+        let body = serde_json::to_vec(schema)?;
+
+        // the schema is checked and put while holding its lock, so that servers
+        // sharing the directory see the same semantics as a conditional put
+        let _lock = self.lock(&key).await?;
+        let current = match self._get(&key) {
+            Ok(body) => Some(etag(&body)),
+            Err(ObjectStorageError::NoSuchKey(_)) => None,
+            Err(e) => return Err(e),
+        };
+        if current.as_deref() != tag {
+            return Err(ObjectStorageError::PreconditionFailed(key));
+        }
+
+        self._put(&key, &body)
+    }
+
+    async fn get_alert(&self, stream_name: &str) -> Result<Bytes, ObjectStorageError> {
+        self._get(&format!("{}/.alert.json", stream_name))
+    }
+
+    async fn get_stats(&self, stream_name: &str) -> Result<Stats, ObjectStorageError> {
+        let body = self._get(&format!("{}/.stats.json", stream_name))?;
+        let stats = serde_json::from_slice(&body)?;
+
+        Ok(stats)
+    }
+
+    async fn put_stats(&self, stream_name: &str, stats: &Stats) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(stats)?;
+        self._put(&format!("{}/.stats.json", stream_name), &body)
+    }
+
+    async fn put_retention(
+        &self,
+        stream_name: &str,
+        retention: &Retention,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(retention)?;
+        self._put(&format!("{}/.retention.json", stream_name), &body)
+    }
+
+    async fn get_retention(&self, stream_name: &str) -> Result<Retention, ObjectStorageError> {
+        let body = self._get(&format!("{}/.retention.json", stream_name))?;
+        let retention = serde_json::from_slice(&body)?;
+
+        Ok(retention)
+    }
+
+    async fn put_tags(
+        &self,
+        stream_name: &str,
+        tags: &HashMap<String, String>,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(tags)?;
+        self._put(&format!("{}/.tags.json", stream_name), &body)
+    }
+
+    async fn get_tags(
+        &self,
+        stream_name: &str,
+    ) -> Result<HashMap<String, String>, ObjectStorageError> {
+        let body = self._get(&format!("{}/.tags.json", stream_name))?;
+        let tags = serde_json::from_slice(&body)?;
+
+        Ok(tags)
+    }
+
+    async fn put_static_schema(
+        &self,
+        stream_name: &str,
+        static_schema: bool,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(&static_schema)?;
+        self._put(&format!("{}/.static_schema.json", stream_name), &body)
+    }
+
+    async fn get_static_schema(&self, stream_name: &str) -> Result<bool, ObjectStorageError> {
+        let body = self._get(&format!("{}/.static_schema.json", stream_name))?;
+        let static_schema = serde_json::from_slice(&body)?;
+
+        Ok(static_schema)
+    }
+
+    async fn put_deleted_at(
+        &self,
+        stream_name: &str,
+        deleted_at: Option<DateTime<Utc>>,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(&deleted_at)?;
+        self._put(&format!("{}/.deleted.json", stream_name), &body)
+    }
+
+    async fn get_deleted_at(
+        &self,
+        stream_name: &str,
+    ) -> Result<Option<DateTime<Utc>>, ObjectStorageError> {
+        let body = self._get(&format!("{}/.deleted.json", stream_name))?;
+        let deleted_at = serde_json::from_slice(&body)?;
+
+        Ok(deleted_at)
+    }
+
+    async fn put_renamed_from(
+        &self,
+        stream_name: &str,
+        renamed_from: &str,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(renamed_from)?;
+        self._put(&format!("{}/.renamed_from.json", stream_name), &body)
+    }
+
+    async fn get_renamed_from(&self, stream_name: &str) -> Result<String, ObjectStorageError> {
+        let body = self._get(&format!("{}/.renamed_from.json", stream_name))?;
+        let renamed_from = serde_json::from_slice(&body)?;
+
+        Ok(renamed_from)
+    }
+
+    async fn put_time_field(
+        &self,
+        stream_name: &str,
+        time_field: &str,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(time_field)?;
+        self._put(&format!("{}/.time_field.json", stream_name), &body)
+    }
+
+    async fn get_time_field(&self, stream_name: &str) -> Result<String, ObjectStorageError> {
+        let body = self._get(&format!("{}/.time_field.json", stream_name))?;
+        let time_field = serde_json::from_slice(&body)?;
+
+        Ok(time_field)
+    }
+
+    async fn put_limits(
+        &self,
+        stream_name: &str,
+        limits: &Limits,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(limits)?;
+        self._put(&format!("{}/.limits.json", stream_name), &body)
+    }
+
+    async fn get_limits(&self, stream_name: &str) -> Result<Limits, ObjectStorageError> {
+        let body = self._get(&format!("{}/.limits.json", stream_name))?;
+        let limits = serde_json::from_slice(&body)?;
+
+        Ok(limits)
+    }
+
+    async fn put_compression(
+        &self,
+        stream_name: &str,
+        compression: Compression,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(&compression)?;
+        self._put(&format!("{}/.compression.json", stream_name), &body)
+    }
+
+    async fn get_compression(&self, stream_name: &str) -> Result<Compression, ObjectStorageError> {
+        let body = self._get(&format!("{}/.compression.json", stream_name))?;
+        let compression = serde_json::from_slice(&body)?;
+
+        Ok(compression)
+    }
+
+    async fn put_flatten(
+        &self,
+        stream_name: &str,
+        flatten: Flatten,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(&flatten)?;
+        self._put(&format!("{}/.flatten.json", stream_name), &body)
+    }
+
+    async fn get_flatten(&self, stream_name: &str) -> Result<Flatten, ObjectStorageError> {
+        let body = self._get(&format!("{}/.flatten.json", stream_name))?;
+        let flatten = serde_json::from_slice(&body)?;
+
+        Ok(flatten)
+    }
+
+    async fn put_partition(
+        &self,
+        stream_name: &str,
+        partition: PartitionGranularity,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(&partition)?;
+        self._put(&format!("{}/.partition.json", stream_name), &body)
+    }
+
+    async fn get_partition(
+        &self,
+        stream_name: &str,
+    ) -> Result<PartitionGranularity, ObjectStorageError> {
+        let body = self._get(&format!("{}/.partition.json", stream_name))?;
+        let partition = serde_json::from_slice(&body)?;
+
+        Ok(partition)
+    }
+
+    async fn put_metadata(
+        &self,
+        stream_name: &str,
+        document: &MetadataDocument,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(document)?;
+        self._put(&format!("{}/.metadata.json", stream_name), &body)
+    }
+
+    async fn get_metadata(
+        &self,
+        stream_name: &str,
+    ) -> Result<MetadataDocument, ObjectStorageError> {
+        let body = self._get(&format!("{}/.metadata.json", stream_name))?;
+        let document = serde_json::from_slice(&body)?;
+
+        Ok(document)
+    }
+
+    async fn put_timestamps(
+        &self,
+        stream_name: &str,
+        timestamps: &StreamTimestamps,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(timestamps)?;
+        self._put(&format!("{}/.timestamps.json", stream_name), &body)
+    }
+
+    async fn get_timestamps(
+        &self,
+        stream_name: &str,
+    ) -> Result<StreamTimestamps, ObjectStorageError> {
+        let body = self._get(&format!("{}/.timestamps.json", stream_name))?;
+        let timestamps = serde_json::from_slice(&body)?;
+
+        Ok(timestamps)
+    }
+
+    async fn list_streams(&self) -> Result<Vec<LogStream>, ObjectStorageError> {
+        let streams = self
+            .dirs(self.root.clone())?
+            .into_iter()
+            .map(|name| LogStream { name })
+            .collect();
+
+        Ok(streams)
+    }
+
+    async fn list_dirs(&self, prefix: &str) -> Result<Vec<String>, ObjectStorageError> {
+        self.dirs(self.path(prefix)?)
+    }
+
+    async fn put_object(&self, key: &str, body: Bytes) -> Result<(), ObjectStorageError> {
+        self._put(key, &body)
+    }
+
+    async fn get_object(&self, key: &str) -> Result<Bytes, ObjectStorageError> {
+        self._get(key)
+    }
+
+    async fn upload_file(&self, key: &str, path: &str) -> Result<(), ObjectStorageError> {
+        let mut source = fs::File::open(path)?;
+        self.persist(key, |file| io::copy(&mut source, file).map(|_| ()))
+    }
+
+    async fn object_meta(&self, key: &str) -> Result<ObjectMeta, ObjectStorageError> {
+        let path = self.path(key)?;
+        if !path.is_file() {
+            return Err(ObjectStorageError::NoSuchKey(key.to_string()));
+        }
+        let digest = FileDigest::of_file(path)?;
+
+        Ok(ObjectMeta {
+            size: digest.size,
+            md5: Some(digest.md5),
+        })
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> Result<DeletedObjects, ObjectStorageError> {
+        let path = self.path(prefix)?;
+        if !path.exists() {
+            return Ok(DeletedObjects::default());
+        }
+
+        let mut deleted = DeletedObjects::default();
+        for entry in WalkDir::new(&path)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+        {
+            let metadata = entry.metadata().map_err(io::Error::from)?;
+            if metadata.is_file() {
+                deleted.objects += 1;
+                deleted.size += metadata.len();
+            }
+        }
+        // a prefix can be the key of a single object
+        if path.is_file() {
+            fs::remove_file(path)?;
+        } else {
+            fs::remove_dir_all(path)?;
+        }
+
+        Ok(deleted)
+    }
+
+    async fn count_objects(&self, prefix: &str) -> Result<u64, ObjectStorageError> {
+        let path = self.path(prefix)?;
+        if !path.exists() {
+            return Ok(0);
+        }
+
+        let mut objects = 0;
+        for entry in WalkDir::new(&path) {
+            if entry.map_err(io::Error::from)?.file_type().is_file() {
+                objects += 1;
+            }
+        }
+
+        Ok(objects)
+    }
+
+    async fn copy_prefix(&self, from: &str, to: &str) -> Result<u64, ObjectStorageError> {
+        let path = self.path(from)?;
+        if !path.exists() {
+            return Ok(0);
+        }
+
+        let mut copied = 0;
+        for entry in WalkDir::new(&path) {
+            let entry = entry.map_err(io::Error::from)?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            let rest = entry.path().strip_prefix(&path).unwrap();
+            let target = self.path(to)?.join(rest);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(entry.path(), target)?;
+            copied += 1;
+        }
+
+        Ok(copied)
+    }
+
+    async fn parquet_objects(&self, prefix: &str) -> Result<StoredObjects, ObjectStorageError> {
+        let path = self.path(prefix)?;
+        if !path.exists() {
+            return Ok(StoredObjects::default());
+        }
+
+        let mut stored = StoredObjects::default();
+        for entry in WalkDir::new(&path) {
+            let entry = entry.map_err(io::Error::from)?;
+            if entry.file_type().is_file()
+                && entry.file_name().to_string_lossy().ends_with(".parquet")
+            {
+                stored.objects += 1;
+                stored.size += entry.metadata().map_err(io::Error::from)?.len();
+            }
+        }
+
+        Ok(stored)
+    }
+
+    async fn list_parquet_files(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<ParquetFile>, ObjectStorageError> {
+        let path = self.path(prefix)?;
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut files = Vec::new();
+        for entry in WalkDir::new(&path) {
+            let entry = entry.map_err(io::Error::from)?;
+            if entry.file_type().is_file()
+                && entry.file_name().to_string_lossy().ends_with(".parquet")
+            {
+                let relative = entry
+                    .path()
+                    .strip_prefix(&self.root)
+                    .unwrap_or(entry.path());
+                files.push(ParquetFile {
+                    key: relative
+                        .iter()
+                        .map(|part| part.to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/"),
+                    size: entry.metadata().map_err(io::Error::from)?.len(),
+                });
+            }
+        }
+
+        Ok(files)
+    }
+
+    async fn query(
+        &self,
+        query: &Query,
+        results: &mut Vec<RecordBatch>,
+    ) -> Result<(), ObjectStorageError> {
+        for prefix in self
+            .query_partitions(&query.stream_name, query.start, query.end)
+            .await?
+        {
+            let path = self.path(&prefix)?;
+            query
+                .execute_on_dir(&path.display().to_string(), results)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alerts::Alerts;
+    use crate::metadata::{PartitionGranularity, STREAM_INFO};
+    use crate::storage::StreamsPage;
+    use chrono::{DateTime, Utc};
+    use rstest::*;
+    use serial_test::serial;
+
+    fn time(rfc3339: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap().into()
+    }
+
+    fn storage() -> LocalStorage {
+        let root = std::env::temp_dir().join(format!("parseable-test-{}", utils::random_string()));
+        LocalStorage::new(root)
+    }
+
+    #[actix_web::test]
+    async fn put_and_get_schema() {
+        let storage = storage();
+        let schema = Schema::empty();
+
+        storage
+            .put_schema("teststream".to_string(), &schema)
+            .await
+            .unwrap();
+        let body = storage.get_schema("teststream").await.unwrap();
+
+        assert_eq!(serde_json::from_slice::<Schema>(&body).unwrap(), schema);
+        fs::remove_dir_all(&storage.root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn conditional_schema_put() {
+        let storage = storage();
+        let schema = Schema::empty();
+
+        assert_eq!(storage.get_schema_tagged("teststream").await.unwrap(), None);
+        storage
+            .put_schema_if("teststream", &schema, None)
+            .await
+            .unwrap();
+        // the schema exists now
+        assert!(matches!(
+            storage.put_schema_if("teststream", &schema, None).await,
+            Err(ObjectStorageError::PreconditionFailed(_))
+        ));
+
+        let (_, tag) = storage
+            .get_schema_tagged("teststream")
+            .await
+            .unwrap()
+            .unwrap();
+        storage
+            .put_schema("teststream".to_string(), &with_field("a"))
+            .await
+            .unwrap();
+        // another server put the schema since it was read
+        assert!(matches!(
+            storage
+                .put_schema_if("teststream", &schema, Some(&tag))
+                .await,
+            Err(ObjectStorageError::PreconditionFailed(_))
+        ));
+
+        let (_, tag) = storage
+            .get_schema_tagged("teststream")
+            .await
+            .unwrap()
+            .unwrap();
+        storage
+            .put_schema_if("teststream", &with_field("b"), Some(&tag))
+            .await
+            .unwrap();
+        let body = storage.get_schema("teststream").await.unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Schema>(&body).unwrap(),
+            with_field("b")
+        );
+        // the lock is released
+        assert!(!storage.root.join("teststream/.schema.lock").exists());
+        fs::remove_dir_all(&storage.root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn put_schema_breaks_stale_lock() {
+        let storage = storage();
+        let lock = storage.root.join("teststream/.schema.lock");
+        fs::create_dir_all(lock.parent().unwrap()).unwrap();
+        // left by a process that died holding it
+        let taken_at = Utc::now() - chrono::Duration::minutes(5);
+        fs::write(&lock, format!("1 {}", taken_at.timestamp_millis())).unwrap();
+        assert!(is_stale_lock(&lock));
+
+        storage
+            .put_schema_if("teststream", &with_field("a"), None)
+            .await
+            .unwrap();
+
+        assert!(!lock.exists());
+        fs::remove_dir_all(&storage.root).unwrap();
+    }
+
+    #[test]
+    fn lock_held_by_live_process_is_not_stale() {
+        let root = std::env::temp_dir().join(format!("parseable-test-{}", utils::random_string()));
+        fs::create_dir_all(&root).unwrap();
+        let lock = root.join(".schema.lock");
+
+        fs::write(
+            &lock,
+            format!("{} {}", std::process::id(), Utc::now().timestamp_millis()),
+        )
+        .unwrap();
+        assert!(!is_stale_lock(&lock));
+        // not written yet, dated by the file
+        fs::write(&lock, "").unwrap();
+        assert!(!is_stale_lock(&lock));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    fn with_field(name: &str) -> Schema {
+        Schema::new(vec![arrow::datatypes::Field::new(
+            name,
+            arrow::datatypes::DataType::Utf8,
+            true,
+        )])
+    }
+
+    #[actix_web::test]
+    async fn get_missing_alert() {
+        let storage = storage();
+
+        assert!(matches!(
+            storage.get_alert("teststream").await,
+            Err(ObjectStorageError::NoSuchKey(_))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn list_streams_in_pages() {
+        let storage = storage();
+        for stream_name in ["stream1", "stream2", "stream3"] {
+            storage
+                .put_stats(stream_name, &Stats::default())
+                .await
+                .unwrap();
+        }
+        let names = |page: &StreamsPage| {
+            page.streams
+                .iter()
+                .map(|stream| stream.name.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let first = storage.list_streams_paged(None, 2).await.unwrap();
+        assert_eq!(names(&first), vec!["stream1", "stream2"]);
+        let second = storage
+            .list_streams_paged(first.next_token.as_deref(), 2)
+            .await
+            .unwrap();
+        assert_eq!(names(&second), vec!["stream3"]);
+        assert_eq!(second.next_token, None);
+        fs::remove_dir_all(&storage.root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn list_streams_and_dirs() {
+        let storage = storage();
+        storage
+            .put_stats("stream1", &Stats::default())
+            .await
+            .unwrap();
+        storage
+            ._put("stream2/date=2022-10-15/hour=10/data.parquet", &[])
+            .unwrap();
+
+        let mut streams = storage
+            .list_streams()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|stream| stream.name)
+            .collect::<Vec<_>>();
+        streams.sort();
+
+        assert_eq!(streams, vec!["stream1", "stream2"]);
+        assert_eq!(
+            storage.list_dirs("stream2/").await.unwrap(),
+            vec!["date=2022-10-15"]
+        );
+        fs::remove_dir_all(&storage.root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn delete_prefix() {
+        let storage = storage();
+        storage
+            ._put("stream/date=2022-10-14/hour=10/a.parquet", b"data")
+            .unwrap();
+        storage
+            ._put("stream/date=2022-10-14/hour=11/b.parquet", b"more data")
+            .unwrap();
+        storage
+            ._put("stream/date=2022-10-15/hour=10/c.parquet", b"data")
+            .unwrap();
+
+        let deleted = storage
+            .delete_prefix("stream/date=2022-10-14/")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            deleted,
+            DeletedObjects {
+                objects: 2,
+                size: 13,
+                failed: 0,
+            }
+        );
+        assert_eq!(
+            storage.list_dirs("stream/").await.unwrap(),
+            vec!["date=2022-10-15"]
+        );
+        fs::remove_dir_all(&storage.root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn sum_parquet_objects() {
+        let storage = storage();
+        storage
+            ._put("stream/date=2022-10-14/hour=10/a.parquet", b"data")
+            .unwrap();
+        storage
+            ._put("stream/date=2022-10-15/hour=10/b.parquet", b"more data")
+            .unwrap();
+        storage
+            .put_stats("stream", &Stats::default())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            storage.parquet_objects("stream/").await.unwrap(),
+            StoredObjects {
+                objects: 2,
+                size: 13
+            }
+        );
+        assert_eq!(
+            storage.parquet_objects("missing/").await.unwrap(),
+            StoredObjects::default()
+        );
+        fs::remove_dir_all(&storage.root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn put_and_get_timestamps() {
+        let storage = storage();
+        let timestamps = StreamTimestamps {
+            created_at: Some(time("2022-10-14T09:00:00+00:00")),
+            first_event_at: None,
+        };
+
+        storage.put_timestamps("stream", &timestamps).await.unwrap();
+
+        assert_eq!(storage.get_timestamps("stream").await.unwrap(), timestamps);
+        fs::remove_dir_all(&storage.root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn list_partitions_in_range() {
+        let storage = storage();
+        for key in [
+            "stream/date=2022-10-13/hour=23/minute=59/a.parquet",
+            "stream/date=2022-10-14/hour=09/minute=59/a.parquet",
+            "stream/date=2022-10-14/hour=10/minute=05/a.parquet",
+            "stream/date=2022-10-14/hour=22/minute=00/a.parquet",
+            "stream/date=2022-10-15/hour=08/minute=00/a.parquet",
+            "stream/date=2022-10-15/hour=12/minute=00/a.parquet",
+            "stream/date=2022-10-16/hour=00/minute=00/a.parquet",
+        ] {
+            storage._put(key, b"data").unwrap();
+        }
+
+        let partitions = storage
+            .list_partitions_in_range(
+                "stream",
+                time("2022-10-14T10:00:00+00:00"),
+                time("2022-10-15T10:00:00+00:00"),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            partitions,
+            vec![
+                "stream/date=2022-10-14/hour=10/",
+                "stream/date=2022-10-14/hour=22/",
+                "stream/date=2022-10-15/hour=08/",
+            ]
+        );
+        fs::remove_dir_all(&storage.root).unwrap();
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn list_partitions_in_range_by_hour() {
+        let storage = storage();
+        for key in [
+            "hourlystream/date=2022-10-14/hour=09/a.parquet",
+            "hourlystream/date=2022-10-14/hour=10/a.parquet",
+            "hourlystream/date=2022-10-14/hour=22/a.parquet",
+            "hourlystream/date=2022-10-15/hour=08/a.parquet",
+            "hourlystream/date=2022-10-15/hour=12/a.parquet",
+        ] {
+            storage._put(key, b"data").unwrap();
+        }
+        STREAM_INFO
+            .add_stream("hourlystream".to_string(), None, Alerts::default())
+            .unwrap();
+        STREAM_INFO
+            .set_partition("hourlystream", PartitionGranularity::Hour)
+            .unwrap();
+
+        let partitions = storage
+            .list_partitions_in_range(
+                "hourlystream",
+                time("2022-10-14T10:30:00+00:00"),
+                time("2022-10-15T08:30:00+00:00"),
+            )
+            .await
+            .unwrap();
+
+        // the hours the range starts and ends within are kept whole
+        assert_eq!(
+            partitions,
+            vec![
+                "hourlystream/date=2022-10-14/hour=10/",
+                "hourlystream/date=2022-10-14/hour=22/",
+                "hourlystream/date=2022-10-15/hour=08/",
+            ]
+        );
+        STREAM_INFO.delete_stream("hourlystream").unwrap();
+        fs::remove_dir_all(&storage.root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn earliest_and_latest_event_time() {
+        let storage = storage();
+        storage
+            ._put(
+                "stream/date=2022-10-14/hour=10/minute=05/a.parquet",
+                b"data",
+            )
+            .unwrap();
+        storage
+            ._put(
+                "stream/date=2022-10-15/hour=08/minute=00/b.parquet",
+                b"data",
+            )
+            .unwrap();
+
+        assert_eq!(
+            storage.earliest_event_time("stream").await.unwrap(),
+            Some(time("2022-10-14T10:05:00+00:00"))
+        );
+        assert_eq!(
+            storage.latest_event_time("stream").await.unwrap(),
+            Some(time("2022-10-15T08:00:00+00:00"))
+        );
+        fs::remove_dir_all(&storage.root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn put_replaces_object_without_leftovers() {
+        let storage = storage();
+        storage._put("stream/.stats", b"old").unwrap();
+        storage._put("stream/.stats", b"new").unwrap();
+
+        assert_eq!(storage._get("stream/.stats").unwrap(), Bytes::from("new"));
+        let files = fs::read_dir(storage.root.join("stream"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(files, vec![".stats"]);
+        fs::remove_dir_all(&storage.root).unwrap();
+    }
+
+    #[rstest]
+    #[case("../outside")]
+    #[case("stream/../../outside")]
+    #[case("/tmp/outside")]
+    #[case("")]
+    fn keys_outside_root_are_rejected(#[case] key: &str) {
+        let storage = storage();
+
+        assert!(matches!(
+            storage._put(key, b"data"),
+            Err(ObjectStorageError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            storage._get(key),
+            Err(ObjectStorageError::InvalidKey(_))
+        ));
+        assert!(!storage.root.exists());
+    }
+}
@@ -17,43 +17,60 @@
  */
 
 use actix_cors::Cors;
-use actix_web::dev::ServiceRequest;
-use actix_web::{middleware, web, App, HttpServer};
-use actix_web_httpauth::extractors::basic::BasicAuth;
+use actix_web::dev::{Server, ServiceRequest};
+use actix_web::http::header;
+use actix_web::{guard, middleware, web, App, HttpMessage, HttpServer};
 use actix_web_httpauth::middleware::HttpAuthentication;
 use actix_web_static_files::ResourceFiles;
 use clokwerk::{AsyncScheduler, Scheduler, TimeUnits};
-use log::warn;
+use log::{info, warn};
 use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
 
 include!(concat!(env!("OUT_DIR"), "/generated.rs"));
 
 use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
 use std::thread::{self, JoinHandle};
 use std::time::Duration;
 use tokio::sync::oneshot;
 use tokio::sync::oneshot::error::TryRecvError;
 
+mod alerts;
+mod auth;
+#[cfg(feature = "azure")]
+mod azure;
 mod banner;
+mod buffer;
+mod compaction;
 mod error;
 mod event;
+mod export;
+mod gcs;
 mod handlers;
+mod instrumented;
+mod localfs;
+mod manifest;
+#[cfg(any(test, feature = "memory"))]
+mod memory;
 mod metadata;
+mod metrics;
+mod migration;
 mod option;
 mod query;
+mod recovery;
 mod response;
+mod retention;
+mod retry;
 mod s3;
 mod storage;
 mod utils;
 mod validator;
 
 use error::Error;
-use option::CONFIG;
-use s3::S3;
+use option::{StorageBackend, CONFIG};
 use storage::ObjectStorage;
 
 // Global configurations
-const MAX_EVENT_PAYLOAD_SIZE: usize = 102400;
 const API_BASE_PATH: &str = "/api";
 const API_VERSION: &str = "v1";
 
@@ -62,26 +79,77 @@ async fn main() -> anyhow::Result<()> {
     env_logger::init();
     CONFIG.print();
     CONFIG.validate();
-    let storage = S3::new();
+    if CONFIG.parseable.storage_backend == StorageBackend::S3
+        && !CONFIG.parseable.demo
+        && s3::S3_CONFIG.migrate_to_storage_prefix
+    {
+        let moved = s3::migrate_to_storage_prefix()
+            .await
+            .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+        info!("moved {} log streams under the storage prefix", moved.len());
+        return Ok(());
+    }
+    let storage = CONFIG.object_storage();
     CONFIG.validate_storage(&storage).await;
-    if let Err(e) = metadata::STREAM_INFO.load(&storage).await {
-        warn!("could not populate local metadata. {:?}", e);
+    match metadata::STREAM_INFO.load(&storage).await {
+        Ok(()) => {}
+        // metadata of a newer server version can't be read correctly
+        Err(e @ Error::UnsupportedMetadataVersion(..)) => panic!("{}", e),
+        Err(e) => warn!("could not populate local metadata. {:?}", e),
+    }
+    // permissions may be set up ahead of the streams they name
+    for stream_name in CONFIG.parseable.api_key_permissions.streams() {
+        if !metadata::STREAM_INFO.stream_exists(stream_name) {
+            warn!(
+                "API key permissions name log stream {}, which doesn't exist",
+                stream_name
+            );
+        }
+    }
+    metrics::register().expect("metrics can be registered once");
+    // staged files of a server that was killed are synced before new events are written
+    let recovered = recovery::recover(
+        storage.as_ref(),
+        Path::new(&CONFIG.parseable.local_disk_path),
+        &CONFIG.parseable.quarantine_path(),
+        chrono::Utc::now(),
+    )
+    .await;
+    if recovered != recovery::Recovered::default() {
+        info!("recovered staged files on start up. {:?}", recovered);
     }
 
     let (localsync_handler, mut localsync_outbox, localsync_inbox) = run_local_sync();
     let (mut s3sync_handler, mut s3sync_outbox, mut s3sync_inbox) = s3_sync();
 
-    let app = run_http();
-    tokio::pin!(app);
+    let server = run_http()?;
+    let server_handle = server.handle();
+    tokio::pin!(server);
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
     loop {
         tokio::select! {
-            e = &mut app => {
-                // actix server finished .. stop other threads and stop the server
-                s3sync_inbox.send(()).unwrap_or(());
-                localsync_inbox.send(()).unwrap_or(());
-                localsync_handler.join().unwrap_or(());
-                s3sync_handler.join().unwrap_or(());
-                return e
+            _ = &mut signal => {
+                // stop accepting requests, in-flight ones get up to the shutdown timeout
+                info!("shutdown signal received, stopping the server");
+                server_handle.stop(true).await;
+                shutdown(
+                    storage.as_ref(),
+                    (localsync_handler, localsync_inbox),
+                    (s3sync_handler, s3sync_inbox),
+                )
+                .await;
+                return Ok(())
+            },
+            e = &mut server => {
+                // actix server finished .. write buffered events, stop other threads and stop the server
+                shutdown(
+                    storage.as_ref(),
+                    (localsync_handler, localsync_inbox),
+                    (s3sync_handler, s3sync_inbox),
+                )
+                .await;
+                return e.map_err(Into::into)
             },
             _ = &mut localsync_outbox => {
                 // crash the server if localsync fails for any reason
@@ -97,6 +165,55 @@ async fn main() -> anyhow::Result<()> {
     }
 }
 
+/// Resolves once the process is asked to stop, by SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate =
+            signal(SignalKind::terminate()).expect("SIGTERM handler can be installed");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.unwrap_or(());
+}
+
+/// Write buffered events to their data files, stop the sync threads, letting an
+/// upload in progress finish, and then put the stats of all streams to object storage.
+/// Gives up after the shutdown timeout, so that a hanging object store can't keep
+/// the server from exiting.
+async fn shutdown(
+    storage: &dyn ObjectStorage,
+    localsync: (JoinHandle<()>, oneshot::Sender<()>),
+    s3sync: (JoinHandle<()>, oneshot::Sender<()>),
+) {
+    let persist = async {
+        event::flush_all(buffer::EVENT_BUFFER.take_all(), storage).await;
+
+        for (handler, inbox) in [localsync, s3sync] {
+            inbox.send(()).unwrap_or(());
+            // joining blocks, keep it off the runtime so the timeout can fire
+            let _ = actix_web::rt::task::spawn_blocking(move || handler.join()).await;
+        }
+
+        if let Err(e) = storage.stats_sync().await {
+            warn!("failed to sync stream stats with object store. {:?}", e);
+        }
+    };
+
+    let timeout = Duration::from_secs(CONFIG.parseable.shutdown_timeout);
+    if tokio::time::timeout(timeout, persist).await.is_err() {
+        warn!(
+            "buffered events and stats were not persisted within {}s of shutdown",
+            timeout.as_secs()
+        );
+    }
+}
+
 fn s3_sync() -> (JoinHandle<()>, oneshot::Receiver<()>, oneshot::Sender<()>) {
     let (outbox_tx, outbox_rx) = oneshot::channel::<()>();
     let (inbox_tx, inbox_rx) = oneshot::channel::<()>();
@@ -109,10 +226,53 @@ fn s3_sync() -> (JoinHandle<()>, oneshot::Receiver<()>, oneshot::Sender<()>) {
                 scheduler
                     .every((CONFIG.parseable.upload_interval as u32).seconds())
                     .run(|| async {
-                        if let Err(e) = S3::new().s3_sync().await {
+                        if let Err(e) = CONFIG.object_storage().s3_sync().await {
                             warn!("failed to sync local data with object store. {:?}", e);
                         }
                     });
+                scheduler.every(1.seconds()).run(|| async {
+                    let max_age = Duration::from_secs(CONFIG.parseable.flush_interval);
+                    let expired = buffer::EVENT_BUFFER.take_expired(max_age);
+                    let storage = CONFIG.object_storage();
+                    event::flush_all(expired, storage.as_ref()).await;
+                    // stats that piled up many updates are put without waiting for the stats sync
+                    if let Err(e) = storage.due_stats_sync().await {
+                        warn!("failed to sync stream stats with object store. {:?}", e);
+                    }
+                });
+                scheduler
+                    .every((CONFIG.parseable.stats_sync_interval as u32).seconds())
+                    .run(|| async {
+                        if let Err(e) = CONFIG.object_storage().stats_sync().await {
+                            warn!("failed to sync stream stats with object store. {:?}", e);
+                        }
+                    });
+                scheduler
+                    .every(retention::RETENTION_INTERVAL.seconds())
+                    .run(|| async {
+                        let storage = CONFIG.object_storage();
+                        retention::enforce(&storage).await;
+                        // after retention, so files it deletes aren't merged
+                        let compactor = compaction::Compactor {
+                            target_size: CONFIG.parseable.compaction_target_size,
+                            staging_dir: CONFIG.parseable.compaction_staging_path(),
+                            compression: CONFIG.parseable.parquet_compression,
+                        };
+                        compactor.run(storage.as_ref(), chrono::Utc::now()).await;
+                    });
+                scheduler
+                    .every(metadata::PURGE_INTERVAL.seconds())
+                    .run(|| async {
+                        let grace_period =
+                            chrono::Duration::hours(CONFIG.parseable.delete_grace_period as i64);
+                        metadata::STREAM_INFO
+                            .purge_deleted_streams(
+                                CONFIG.object_storage().as_ref(),
+                                chrono::Utc::now(),
+                                grace_period,
+                            )
+                            .await;
+                    });
 
                 loop {
                     scheduler.run_pending().await;
@@ -146,7 +306,7 @@ fn run_local_sync() -> (JoinHandle<()>, oneshot::Receiver<()>, oneshot::Sender<(
             scheduler
                 .every((storage::LOCAL_SYNC_INTERVAL as u32).seconds())
                 .run(move || {
-                    if let Err(e) = S3::new().local_sync() {
+                    if let Err(e) = CONFIG.object_storage().local_sync() {
                         warn!("failed to sync local data. {:?}", e);
                     }
                 });
@@ -175,18 +335,39 @@ fn run_local_sync() -> (JoinHandle<()>, oneshot::Receiver<()>, oneshot::Sender<(
 
 async fn validator(
     req: ServiceRequest,
-    credentials: BasicAuth,
+    credentials: auth::Credentials,
 ) -> Result<ServiceRequest, actix_web::Error> {
-    if credentials.user_id().trim() == CONFIG.parseable.username
-        && credentials.password().unwrap().trim() == CONFIG.parseable.password
-    {
-        return Ok(req);
+    // health checks are made by orchestrators, without credentials
+    let exempt_paths = [
+        format!("{}{}", base_path(), liveness_path()),
+        format!("{}{}", base_path(), readiness_path()),
+        format!("{}{}", base_path(), health_path()),
+    ];
+    let access = auth::Access {
+        username: &CONFIG.parseable.username,
+        password: &CONFIG.parseable.password,
+        api_keys: &CONFIG.parseable.api_keys,
+        permissions: &CONFIG.parseable.api_key_permissions,
+        exempt_paths: &exempt_paths,
+    };
+    let path = req.path().to_string();
+    let (action, stream) = auth::request_scope(req.method(), path.trim_start_matches(&base_path()));
+
+    match access.authorize(&path, action, stream, &credentials) {
+        Ok(Some(permission)) => {
+            // for handlers of requests naming streams in their body
+            req.extensions_mut().insert(permission);
+            Ok(req)
+        }
+        Ok(None) => Ok(req),
+        Err(auth::Denied::Unauthorized) => Err(actix_web::error::ErrorUnauthorized("Unauthorized")),
+        Err(auth::Denied::Forbidden) => Err(actix_web::error::ErrorForbidden("Forbidden")),
     }
-
-    Err(actix_web::error::ErrorUnauthorized("Unauthorized"))
 }
 
-async fn run_http() -> anyhow::Result<()> {
+/// Bind the http server, signals are left to `shutdown_signal` so the server
+/// is stopped together with the sync threads.
+fn run_http() -> anyhow::Result<Server> {
     let ssl_acceptor = match (
         &CONFIG.parseable.tls_cert_path,
         &CONFIG.parseable.tls_key_path,
@@ -200,17 +381,18 @@ async fn run_http() -> anyhow::Result<()> {
         (_, _) => None,
     };
 
-    let http_server = HttpServer::new(move || create_app!());
-    if let Some(builder) = ssl_acceptor {
+    let http_server = HttpServer::new(move || create_app!())
+        .disable_signals()
+        .shutdown_timeout(CONFIG.parseable.shutdown_timeout);
+    let server = if let Some(builder) = ssl_acceptor {
         http_server
             .bind_openssl(&CONFIG.parseable.address, builder)?
             .run()
-            .await?;
     } else {
-        http_server.bind(&CONFIG.parseable.address)?.run().await?;
-    }
+        http_server.bind(&CONFIG.parseable.address)?.run()
+    };
 
-    Ok(())
+    Ok(server)
 }
 
 pub fn configure_routes(cfg: &mut web::ServiceConfig) {
@@ -221,16 +403,43 @@ pub fn configure_routes(cfg: &mut web::ServiceConfig) {
         web::scope(&base_path())
             // POST "/query" ==> Get results of the SQL query passed in request body
             .service(web::resource(query_path()).route(web::post().to(handlers::event::query)))
+            // POST "/refresh" ==> Reload all log streams from object storage
+            .service(
+                web::resource(refresh_path(""))
+                    .route(web::post().to(handlers::logstream::refresh_all)),
+            )
+            .service(
+                // GET "/logstream/stats" ==> Get stats of all log streams added up. Only GET
+                // is guarded here, other methods still reach a log stream named stats
+                web::resource(logstream_path("stats"))
+                    .guard(guard::Get())
+                    .route(web::get().to(handlers::logstream::total_stats)),
+            )
             .service(
                 // logstream API
                 web::resource(logstream_path("{logstream}"))
                     // PUT "/logstream/{logstream}" ==> Create log stream
                     .route(web::put().to(handlers::logstream::put))
+                    // POST "/logstream/{logstream}" with a newline delimited JSON body
+                    // ==> Post a batch of logs to given log stream
+                    .route(
+                        web::post()
+                            .guard(content_type("application/x-ndjson"))
+                            .to(handlers::event::post_ndjson),
+                    )
+                    // POST "/logstream/{logstream}" with a CSV body, its first row naming
+                    // the fields ==> Post a batch of logs to given log stream
+                    .route(
+                        web::post()
+                            .guard(content_type("text/csv"))
+                            .to(handlers::event::post_csv),
+                    )
                     // POST "/logstream/{logstream}" ==> Post logs to given log stream
                     .route(web::post().to(handlers::event::post_event))
                     // DELETE "/logstream/{logstream}" ==> Delete log stream
                     .route(web::delete().to(handlers::logstream::delete))
-                    .app_data(web::JsonConfig::default().limit(MAX_EVENT_PAYLOAD_SIZE)),
+                    // HEAD "/logstream/{logstream}" ==> Check if log stream exists
+                    .route(web::head().to(handlers::logstream::head)),
             )
             .service(
                 web::resource(alert_path("{logstream}"))
@@ -239,7 +448,84 @@ pub fn configure_routes(cfg: &mut web::ServiceConfig) {
                     // GET "/logstream/{logstream}/alert" ==> Get alert for given log stream
                     .route(web::get().to(handlers::logstream::get_alert)),
             )
-            // GET "/logstream" ==> Get list of all Log Streams on the server
+            .service(
+                // PUT "/logstream/{logstream}/retention" ==> Set retention period for given log stream
+                web::resource(retention_path("{logstream}"))
+                    .route(web::put().to(handlers::logstream::put_retention)),
+            )
+            .service(
+                // POST "/logstream/{logstream}/query" ==> Query given log stream
+                web::resource(stream_query_path("{logstream}"))
+                    .route(web::post().to(handlers::event::query_stream)),
+            )
+            .service(
+                // POST "/logstream/{logstream}/undelete" ==> Restore given deleted log stream
+                web::resource(undelete_path("{logstream}"))
+                    .route(web::post().to(handlers::logstream::undelete)),
+            )
+            .service(
+                // POST "/logstream/{logstream}/rename" ==> Rename given log stream along with its data
+                web::resource(rename_path("{logstream}"))
+                    .route(web::post().to(handlers::logstream::rename)),
+            )
+            .service(
+                // POST "/logstream/{logstream}/clone" ==> Create a log stream with the schema
+                // and alerts of given log stream, without its data
+                web::resource(clone_path("{logstream}"))
+                    .route(web::post().to(handlers::logstream::clone)),
+            )
+            .service(
+                // POST "/logstream/{logstream}/refresh" ==> Reload given log stream from object storage
+                web::resource(refresh_path("{logstream}"))
+                    .route(web::post().to(handlers::logstream::refresh)),
+            )
+            .service(
+                // GET "/logstream/{logstream}/export" ==> Download a tar archive of the parquet
+                // files of given log stream, optionally only of a time range
+                web::resource(export_path("{logstream}"))
+                    .route(web::get().to(handlers::logstream::export)),
+            )
+            .service(
+                // GET "/logstream/{logstream}/stats" ==> Get stats for given log stream
+                web::resource(stats_path("{logstream}"))
+                    .route(web::get().to(handlers::logstream::get_stats)),
+            )
+            .service(
+                // POST "/logstream/{logstream}/stats/recalculate" ==> Recompute compressed size
+                // and event count of given log stream from object storage
+                web::resource(recalculate_stats_path("{logstream}"))
+                    .route(web::post().to(handlers::logstream::recalculate_stats)),
+            )
+            .service(
+                // POST "/logstream/{logstream}/manifest/rebuild" ==> Regenerate the manifest
+                // of given log stream from a listing of its parquet files
+                web::resource(rebuild_manifest_path("{logstream}"))
+                    .route(web::post().to(handlers::logstream::rebuild_manifest)),
+            )
+            .service(
+                web::resource(tags_path("{logstream}"))
+                    // PUT "/logstream/{logstream}/tags" ==> Set tags for given log stream
+                    .route(web::put().to(handlers::logstream::put_tags))
+                    // GET "/logstream/{logstream}/tags" ==> Get tags of given log stream
+                    .route(web::get().to(handlers::logstream::get_tags)),
+            )
+            .service(
+                // PUT "/logstream/{logstream}/compression" ==> Set parquet compression for given log stream
+                web::resource(compression_path("{logstream}"))
+                    .route(web::put().to(handlers::logstream::put_compression)),
+            )
+            .service(
+                // PUT "/logstream/{logstream}/flatten" ==> Set how nested events of given log stream are flattened
+                web::resource(flatten_path("{logstream}"))
+                    .route(web::put().to(handlers::logstream::put_flatten)),
+            )
+            .service(
+                // PUT "/logstream/{logstream}/limits" ==> Set event size and column limits for given log stream
+                web::resource(limits_path("{logstream}"))
+                    .route(web::put().to(handlers::logstream::put_limits)),
+            )
+            // GET "/logstream" ==> Get list of all Log Streams on the server,
+            // optionally filtered by tags with ?tag=key:value
             .service(
                 web::resource(logstream_path("")).route(web::get().to(handlers::logstream::list)),
             )
@@ -248,16 +534,43 @@ pub fn configure_routes(cfg: &mut web::ServiceConfig) {
                 web::resource(schema_path("{logstream}"))
                     .route(web::get().to(handlers::logstream::schema)),
             )
+            .service(
+                // POST "/logstream/{logstream}/schema/infer" ==> Infer schema of sample events
+                // and compare it to the schema of given log stream, without ingesting them
+                web::resource(infer_schema_path("{logstream}"))
+                    .route(web::post().to(handlers::logstream::infer_schema)),
+            )
+            .service(
+                // GET "/logstream/{logstream}/schema/versions/{version}" ==> Get given version
+                // of the schema of given log stream
+                web::resource(schema_version_path("{logstream}", "{version}"))
+                    .route(web::get().to(handlers::logstream::schema_version)),
+            )
+            // GET "/health" ==> Health check, failing when object storage can't be reached
+            .service(web::resource(health_path()).route(web::get().to(handlers::health)))
             // GET "/liveness" ==> Livenss check as per https://kubernetes.io/docs/tasks/configure-pod-container/configure-liveness-readiness-startup-probes/#define-a-liveness-command
             .service(web::resource(liveness_path()).route(web::get().to(handlers::liveness)))
+            // GET "/metrics" ==> Ingestion and storage metrics in the Prometheus text format
+            .service(web::resource(metrics_path()).route(web::get().to(handlers::metrics)))
             // GET "/readiness" ==> Readiness check as per https://kubernetes.io/docs/tasks/configure-pod-container/configure-liveness-readiness-startup-probes/#define-readiness-probes
             .service(web::resource(readiness_path()).route(web::get().to(handlers::readiness)))
-            .wrap(HttpAuthentication::basic(validator)),
+            .wrap(HttpAuthentication::with_fn(validator)),
     )
     // GET "/" ==> Serve the static frontend directory
     .service(ResourceFiles::new("/", generated));
 }
 
+// Matches requests with a body of the media type `essence`, whatever parameters
+// like the charset follow it in the content type header
+fn content_type(essence: &'static str) -> impl guard::Guard {
+    guard::fn_guard(move |ctx| {
+        ctx.header::<header::ContentType>()
+            .map_or(false, |content_type| {
+                content_type.0.essence_str() == essence
+            })
+    })
+}
+
 #[macro_export]
 macro_rules! create_app {
     () => {
@@ -290,6 +603,14 @@ fn readiness_path() -> String {
     "/readiness".to_string()
 }
 
+fn metrics_path() -> String {
+    "/metrics".to_string()
+}
+
+fn health_path() -> String {
+    "/health".to_string()
+}
+
 fn liveness_path() -> String {
     "/liveness".to_string()
 }
@@ -302,6 +623,122 @@ fn alert_path(stream_name: &str) -> String {
     format!("{}/alert", logstream_path(stream_name))
 }
 
+fn retention_path(stream_name: &str) -> String {
+    format!("{}/retention", logstream_path(stream_name))
+}
+
+fn refresh_path(stream_name: &str) -> String {
+    if stream_name.is_empty() {
+        "/refresh".to_string()
+    } else {
+        format!("{}/refresh", logstream_path(stream_name))
+    }
+}
+
+fn export_path(stream_name: &str) -> String {
+    format!("{}/export", logstream_path(stream_name))
+}
+
+fn stats_path(stream_name: &str) -> String {
+    format!("{}/stats", logstream_path(stream_name))
+}
+
+fn recalculate_stats_path(stream_name: &str) -> String {
+    format!("{}/recalculate", stats_path(stream_name))
+}
+
+fn rebuild_manifest_path(stream_name: &str) -> String {
+    format!("{}/manifest/rebuild", logstream_path(stream_name))
+}
+
+fn tags_path(stream_name: &str) -> String {
+    format!("{}/tags", logstream_path(stream_name))
+}
+
+fn compression_path(stream_name: &str) -> String {
+    format!("{}/compression", logstream_path(stream_name))
+}
+
+fn flatten_path(stream_name: &str) -> String {
+    format!("{}/flatten", logstream_path(stream_name))
+}
+
+fn limits_path(stream_name: &str) -> String {
+    format!("{}/limits", logstream_path(stream_name))
+}
+
+fn stream_query_path(stream_name: &str) -> String {
+    format!("{}/query", logstream_path(stream_name))
+}
+
+fn undelete_path(stream_name: &str) -> String {
+    format!("{}/undelete", logstream_path(stream_name))
+}
+
+fn rename_path(stream_name: &str) -> String {
+    format!("{}/rename", logstream_path(stream_name))
+}
+
+fn clone_path(stream_name: &str) -> String {
+    format!("{}/clone", logstream_path(stream_name))
+}
+
 fn schema_path(stream_name: &str) -> String {
     format!("{}/schema", logstream_path(stream_name))
 }
+
+fn infer_schema_path(stream_name: &str) -> String {
+    format!("{}/infer", schema_path(stream_name))
+}
+
+fn schema_version_path(stream_name: &str, version: &str) -> String {
+    format!("{}/versions/{}", schema_path(stream_name), version)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, web, App, HttpResponse};
+
+    use super::content_type;
+
+    #[actix_web::test]
+    async fn route_by_content_type() {
+        let app = test::init_service(
+            App::new().service(
+                web::resource("/logstream/{logstream}")
+                    .route(
+                        web::post()
+                            .guard(content_type("application/x-ndjson"))
+                            .to(|| async { HttpResponse::Ok().body("ndjson") }),
+                    )
+                    .route(
+                        web::post()
+                            .guard(content_type("text/csv"))
+                            .to(|| async { HttpResponse::Ok().body("csv") }),
+                    )
+                    .route(web::post().to(|| async { HttpResponse::Ok().body("json") })),
+            ),
+        )
+        .await;
+
+        let cases = [
+            ("application/x-ndjson", "ndjson"),
+            ("application/x-ndjson; charset=utf-8", "ndjson"),
+            ("Application/X-NDJSON", "ndjson"),
+            ("text/csv", "csv"),
+            ("text/csv; charset=utf-8", "csv"),
+            ("text/csv;header=present", "csv"),
+            ("application/json", "json"),
+        ];
+
+        for (content, route) in cases {
+            let req = test::TestRequest::post()
+                .uri("/logstream/app")
+                .insert_header(("content-type", content))
+                .to_request();
+            let body = test::call_and_read_body(&app, req).await;
+
+            assert_eq!(body, route, "content type {}", content);
+        }
+    }
+}
@@ -0,0 +1,396 @@
+/*
+ * Parseable Server (C) 2022 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use bytes::Bytes;
+use chrono::{DateTime, Duration, Utc};
+use dashmap::DashSet;
+use lazy_static::lazy_static;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+use crate::storage::{ObjectStorage, ObjectStorageError, OBJECT_STORE_DATA_GRANULARITY};
+use crate::utils;
+use crate::Error;
+
+lazy_static! {
+    /// Streams whose manifest missed an update and couldn't be removed either. Their
+    /// manifest isn't used until it is rebuilt.
+    static ref STALE: DashSet<String> = DashSet::new();
+}
+
+/// Parquet file of a stream as recorded in its manifest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestFile {
+    /// Key of the file relative to the stream, e.g.
+    /// `date=2022-10-15/hour=10/minute=30/data.parquet`, so a renamed stream keeps its manifest
+    pub key: String,
+    pub size: u64,
+    pub rows: u64,
+    /// Events are partitioned by time, the events of a file are within the time span
+    /// of its partition
+    pub min_time: DateTime<Utc>,
+    pub max_time: DateTime<Utc>,
+}
+
+impl ManifestFile {
+    /// Entry of the file at `key`, relative to the stream, or None if the key isn't
+    /// in a partition
+    pub fn new(key: &str, size: u64, rows: u64) -> Option<Self> {
+        let dirs: Vec<String> = key.split('/').map(str::to_string).collect();
+        let partitions = &dirs[..dirs.len() - 1];
+        let min_time = utils::partition_to_time(partitions)?;
+        // streams partitioned by hour or day have keys without the finer levels
+        let span = match partitions.len() {
+            1 => Duration::days(1),
+            2 => Duration::hours(1),
+            _ => Duration::minutes(OBJECT_STORE_DATA_GRANULARITY as i64),
+        };
+
+        Some(Self {
+            key: key.to_string(),
+            size,
+            rows,
+            min_time,
+            max_time: min_time + span,
+        })
+    }
+
+    /// Entry of the staged parquet file at `path`, to be uploaded to `key`
+    pub fn of_file(key: &str, path: impl AsRef<Path>) -> Result<Option<Self>, Error> {
+        let file = fs::File::open(path)?;
+        let size = file.metadata()?.len();
+        let rows = SerializedFileReader::new(file)?
+            .metadata()
+            .file_metadata()
+            .num_rows();
+
+        Ok(Self::new(key, size, rows.max(0) as u64))
+    }
+
+    fn of_body(key: &str, body: Bytes) -> Result<Option<Self>, Error> {
+        let size = body.len() as u64;
+        let rows = row_count(body)?;
+
+        Ok(Self::new(key, size, rows))
+    }
+}
+
+/// Number of rows of the parquet file, read from its footer
+pub fn row_count(body: Bytes) -> Result<u64, Error> {
+    let rows = SerializedFileReader::new(body)?
+        .metadata()
+        .file_metadata()
+        .num_rows();
+
+    Ok(rows.max(0) as u64)
+}
+
+/// Parquet files of a stream, so queries find the files of a time range without
+/// listing the stream. Updated after every sync, compaction and retention run.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub files: Vec<ManifestFile>,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+impl Manifest {
+    /// Add files, replacing entries with the same key
+    pub fn add(&mut self, files: Vec<ManifestFile>) {
+        self.files
+            .retain(|existing| !files.iter().any(|file| file.key == existing.key));
+        self.files.extend(files);
+    }
+
+    /// Remove the files under `prefix`, relative to the stream
+    pub fn remove_prefix(&mut self, prefix: &str) {
+        self.files.retain(|file| !file.key.starts_with(prefix));
+    }
+
+    /// Prefixes of the partitions holding files with events between `start` and `end`,
+    /// like `ObjectStorage::list_partitions_in_range` returns them
+    pub fn partitions_in_range(
+        &self,
+        stream_name: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Vec<String> {
+        self.files
+            .iter()
+            .filter(|file| file.min_time < end && file.max_time > start)
+            .filter_map(|file| file.key.rsplit_once('/'))
+            .map(|(partition, _)| format!("{}/{}/", stream_name, partition))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+}
+
+fn manifest_key(stream_name: &str) -> String {
+    format!("{}/.manifest.json", stream_name)
+}
+
+/// Manifest of the stream, None if it has none or it is stale
+pub async fn load(
+    storage: &(impl ObjectStorage + ?Sized),
+    stream_name: &str,
+) -> Result<Option<Manifest>, ObjectStorageError> {
+    if STALE.contains(stream_name) {
+        return Ok(None);
+    }
+
+    match storage.get_object(&manifest_key(stream_name)).await {
+        Ok(body) => Ok(Some(serde_json::from_slice(&body)?)),
+        Err(ObjectStorageError::NoSuchKey(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+async fn save(
+    storage: &(impl ObjectStorage + ?Sized),
+    stream_name: &str,
+    manifest: &mut Manifest,
+) -> Result<(), ObjectStorageError> {
+    manifest.updated_at = Some(Utc::now());
+    let body = serde_json::to_vec(manifest)?;
+    storage
+        .put_object(&manifest_key(stream_name), body.into())
+        .await
+}
+
+/// Put an empty manifest for a new stream, it has no files yet
+pub async fn create(
+    storage: &(impl ObjectStorage + ?Sized),
+    stream_name: &str,
+) -> Result<(), ObjectStorageError> {
+    save(storage, stream_name, &mut Manifest::default()).await
+}
+
+/// Apply `change` to the manifest of the stream, if it has one. A stream without a
+/// manifest doesn't get one, it may have files the manifest would leave out.
+pub async fn update(
+    storage: &(impl ObjectStorage + ?Sized),
+    stream_name: &str,
+    change: impl FnOnce(&mut Manifest) + Send,
+) {
+    let loaded = load(storage, stream_name).await.map_err(|e| e.to_string());
+    let updated = match loaded {
+        Ok(Some(mut manifest)) => {
+            change(&mut manifest);
+            save(storage, stream_name, &mut manifest)
+                .await
+                .map_err(|e| e.to_string())
+        }
+        Ok(None) => return,
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = updated {
+        log::warn!(
+            "failed to update manifest of log stream {}, queries list the stream until it is rebuilt. {}",
+            stream_name,
+            e
+        );
+        invalidate(storage, stream_name).await;
+    }
+}
+
+/// Stop queries from using the manifest of the stream, until it is rebuilt
+pub async fn invalidate(storage: &(impl ObjectStorage + ?Sized), stream_name: &str) {
+    if storage
+        .delete_prefix(&manifest_key(stream_name))
+        .await
+        .is_err()
+    {
+        STALE.insert(stream_name.to_string());
+    }
+}
+
+/// Replace the manifest of the stream with one of the parquet files listed under it.
+/// The row count of every file is read from its footer, so every file is downloaded.
+pub async fn rebuild(
+    storage: &(impl ObjectStorage + ?Sized),
+    stream_name: &str,
+) -> Result<Manifest, Error> {
+    let prefix = format!("{}/", stream_name);
+    let mut manifest = Manifest::default();
+    for file in storage.list_parquet_files(&prefix).await? {
+        let body = storage.get_object(&file.key).await?;
+        let key = file.key.strip_prefix(&prefix).unwrap_or(&file.key);
+        manifest.files.extend(ManifestFile::of_body(key, body)?);
+    }
+
+    save(storage, stream_name, &mut manifest).await?;
+    STALE.remove(stream_name);
+
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::Int64Array;
+    use arrow::datatypes::{DataType, Field, Schema};
+    use arrow::record_batch::RecordBatch;
+    use chrono::TimeZone;
+    use parquet::arrow::arrow_writer::ArrowWriter;
+    use rstest::*;
+    use std::sync::Arc;
+
+    use crate::storage::mock::MockStorage;
+
+    fn time(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.ymd(2022, 10, 15).and_hms(hour, minute, 0)
+    }
+
+    fn file(key: &str) -> ManifestFile {
+        ManifestFile::new(key, 10, 1).unwrap()
+    }
+
+    fn parquet_body(rows: usize) -> Vec<u8> {
+        let schema = Arc::new(Schema::new(vec![Field::new("code", DataType::Int64, true)]));
+        let rb = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(Int64Array::from(vec![200; rows]))],
+        )
+        .unwrap();
+
+        let path = std::env::temp_dir().join(format!("parseable-test-{}", utils::random_string()));
+        let mut writer =
+            ArrowWriter::try_new(fs::File::create(&path).unwrap(), schema, None).unwrap();
+        writer.write(&rb).unwrap();
+        writer.close().unwrap();
+        let body = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        body
+    }
+
+    #[test]
+    fn file_spans_its_partition() {
+        let file = file("date=2022-10-15/hour=10/minute=30/data.parquet");
+        assert_eq!(file.min_time, time(10, 30));
+        assert_eq!(file.max_time, time(10, 31));
+        assert!(ManifestFile::new("data.parquet", 10, 1).is_none());
+
+        // streams partitioned by hour or by day
+        let file = ManifestFile::new("date=2022-10-15/hour=10/data.parquet", 10, 1).unwrap();
+        assert_eq!(file.min_time, time(10, 0));
+        assert_eq!(file.max_time, time(11, 0));
+        let file = ManifestFile::new("date=2022-10-15/data.parquet", 10, 1).unwrap();
+        assert_eq!(file.min_time, time(0, 0));
+        assert_eq!(file.max_time, time(0, 0) + Duration::days(1));
+    }
+
+    #[rstest]
+    #[case::within(time(10, 0), time(11, 0), vec!["teststream/date=2022-10-15/hour=10/minute=30/"])]
+    #[case::all(time(9, 0), time(12, 0), vec![
+        "teststream/date=2022-10-15/hour=10/minute=30/",
+        "teststream/date=2022-10-15/hour=11/minute=00/",
+    ])]
+    #[case::end_exclusive(time(9, 0), time(10, 30), vec![])]
+    fn partitions_in_range(
+        #[case] start: DateTime<Utc>,
+        #[case] end: DateTime<Utc>,
+        #[case] expected: Vec<&str>,
+    ) {
+        let mut manifest = Manifest::default();
+        manifest.add(vec![
+            file("date=2022-10-15/hour=10/minute=30/a.parquet"),
+            file("date=2022-10-15/hour=10/minute=30/b.parquet"),
+            file("date=2022-10-15/hour=11/minute=00/a.parquet"),
+        ]);
+
+        assert_eq!(
+            manifest.partitions_in_range("teststream", start, end),
+            expected
+        );
+    }
+
+    #[test]
+    fn add_replaces_and_remove_prefix() {
+        let mut manifest = Manifest::default();
+        manifest.add(vec![
+            file("date=2022-10-14/hour=10/minute=30/a.parquet"),
+            file("date=2022-10-15/hour=10/minute=30/a.parquet"),
+        ]);
+        manifest.add(vec![file("date=2022-10-15/hour=10/minute=30/a.parquet")]);
+        assert_eq!(manifest.files.len(), 2);
+
+        manifest.remove_prefix("date=2022-10-14/");
+        assert_eq!(
+            manifest.files,
+            vec![file("date=2022-10-15/hour=10/minute=30/a.parquet")]
+        );
+    }
+
+    #[actix_web::test]
+    async fn update_skips_streams_without_manifest() {
+        let storage = MockStorage::default();
+
+        update(&storage, "teststream", |manifest| {
+            manifest.add(vec![file("date=2022-10-15/hour=10/minute=30/a.parquet")])
+        })
+        .await;
+
+        assert!(load(&storage, "teststream").await.unwrap().is_none());
+
+        create(&storage, "teststream").await.unwrap();
+        update(&storage, "teststream", |manifest| {
+            manifest.add(vec![file("date=2022-10-15/hour=10/minute=30/a.parquet")])
+        })
+        .await;
+
+        let manifest = load(&storage, "teststream").await.unwrap().unwrap();
+        assert_eq!(manifest.files.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn rebuild_from_listing() {
+        let storage = MockStorage::default()
+            .with_object(
+                "rebuiltstream/date=2022-10-15/hour=10/minute=30/a.parquet",
+                parquet_body(3),
+            )
+            .with_object(
+                "rebuiltstream/date=2022-10-15/hour=11/minute=00/b.parquet",
+                parquet_body(5),
+            );
+
+        let manifest = rebuild(&storage, "rebuiltstream").await.unwrap();
+
+        let mut files: Vec<(&str, u64)> = manifest
+            .files
+            .iter()
+            .map(|file| (file.key.as_str(), file.rows))
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                ("date=2022-10-15/hour=10/minute=30/a.parquet", 3),
+                ("date=2022-10-15/hour=11/minute=00/b.parquet", 5),
+            ]
+        );
+        assert_eq!(
+            load(&storage, "rebuiltstream").await.unwrap(),
+            Some(manifest)
+        );
+    }
+}
@@ -0,0 +1,798 @@
+/*
+ * Parseable Server (C) 2022 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use arrow::datatypes::Schema;
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use datafusion::arrow::record_batch::RecordBatch;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::alerts::Alerts;
+use crate::metadata::{
+    Compression, Flatten, Limits, PartitionGranularity, Stats, StreamTimestamps,
+};
+use crate::migration::MetadataDocument;
+#[cfg(feature = "memory")]
+use crate::option::StorageOpt;
+use crate::query::Query;
+use crate::retention::Retention;
+use crate::storage::{
+    DeletedObjects, LogStream, ObjectMeta, ObjectStorage, ObjectStorageError, ParquetFile,
+    StoredObjects,
+};
+use crate::utils;
+
+/// Storage of a demo server, started with `--demo`. Log streams are kept in memory
+/// and lost when the server stops.
+#[cfg(feature = "memory")]
+pub struct MemoryStorageConfig {
+    storage: MemoryStorage,
+}
+
+#[cfg(feature = "memory")]
+impl MemoryStorageConfig {
+    /// Storage staging data of new streams under `local_disk_path`
+    pub fn new(local_disk_path: &str) -> Self {
+        Self {
+            storage: MemoryStorage::default().with_staging_dir(PathBuf::from(local_disk_path)),
+        }
+    }
+}
+
+#[cfg(feature = "memory")]
+impl StorageOpt for MemoryStorageConfig {
+    fn bucket_name(&self) -> &str {
+        "memory"
+    }
+
+    fn endpoint_url(&self) -> &str {
+        "memory://"
+    }
+
+    // the warning is always shown, nothing put to the storage is kept
+    fn is_default_url(&self) -> bool {
+        true
+    }
+
+    fn warning(&self) {
+        eprintln!(
+            "
+    Parseable is running in demo mode, log streams are kept in memory and lost when
+    the server stops. Start the server without --demo to keep them in object storage."
+        );
+    }
+
+    // every client shares the objects of the one storage
+    fn object_storage(&self) -> Box<dyn ObjectStorage> {
+        Box::new(self.storage.clone())
+    }
+}
+
+/// Failure injected into the operations of a `MemoryStorage`
+struct Failure {
+    operation: String,
+    target_prefix: String,
+    remaining: u32,
+}
+
+/// Object storage kept in memory, for tests and the demo mode. Clones share the same
+/// objects. Operations are recorded, e.g. `put_schema teststream`, and failures can be
+/// injected into them, so that tests can check how object storage is used.
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    objects: Arc<Mutex<HashMap<String, Bytes>>>,
+    operations: Arc<Mutex<Vec<String>>>,
+    failures: Arc<Mutex<Vec<Failure>>>,
+    /// Directory the data of new streams is staged in, like the local disk path
+    /// of the server. Nothing is created locally if not set.
+    staging_dir: Option<PathBuf>,
+}
+
+// helpers for tests, the demo server doesn't use them
+#[cfg_attr(not(test), allow(dead_code))]
+impl MemoryStorage {
+    /// Add an object with the given key and body
+    pub fn with_object(self, key: &str, body: impl Into<Bytes>) -> Self {
+        self._put(key, body);
+        self
+    }
+
+    /// Fail the next `times` calls of `operation`, named like the method of
+    /// `ObjectStorage` e.g. `put_schema`, with a connection error
+    pub fn with_failures(self, operation: &str, times: u32) -> Self {
+        self.fail(operation, "", times);
+        self
+    }
+
+    /// Fail the next `times` calls of `operation` on a target, a stream or a key,
+    /// starting with `target_prefix`
+    pub fn fail(&self, operation: &str, target_prefix: &str, times: u32) {
+        self.failures.lock().unwrap().push(Failure {
+            operation: operation.to_string(),
+            target_prefix: target_prefix.to_string(),
+            remaining: times,
+        });
+    }
+
+    /// Operations called so far along with their target, in order
+    pub fn operations(&self) -> Vec<String> {
+        self.operations.lock().unwrap().clone()
+    }
+
+    /// Keys of all objects, sorted
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.objects.lock().unwrap().keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+}
+
+impl MemoryStorage {
+    pub fn with_staging_dir(mut self, staging_dir: PathBuf) -> Self {
+        self.staging_dir = Some(staging_dir);
+        self
+    }
+
+    fn record(&self, operation: &str, target: &str) -> Result<(), ObjectStorageError> {
+        self.operations.lock().unwrap().push(if target.is_empty() {
+            operation.to_string()
+        } else {
+            format!("{} {}", operation, target)
+        });
+
+        let mut failures = self.failures.lock().unwrap();
+        let failure = failures.iter_mut().find(|failure| {
+            failure.remaining > 0
+                && failure.operation == operation
+                && target.starts_with(&failure.target_prefix)
+        });
+        match failure {
+            Some(failure) => {
+                failure.remaining -= 1;
+                Err(ObjectStorageError::ConnectionError(
+                    format!("injected failure of {}", operation).into(),
+                ))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn _put(&self, key: &str, body: impl Into<Bytes>) {
+        self.objects
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), body.into());
+    }
+
+    fn _get(&self, key: &str) -> Result<Bytes, ObjectStorageError> {
+        self.objects
+            .lock()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .ok_or_else(|| ObjectStorageError::NoSuchKey(key.to_string()))
+    }
+
+    fn put_json(&self, key: &str, value: &impl Serialize) -> Result<(), ObjectStorageError> {
+        self._put(key, serde_json::to_vec(value)?);
+
+        Ok(())
+    }
+
+    fn get_json<T: DeserializeOwned>(&self, key: &str) -> Result<T, ObjectStorageError> {
+        let body = self._get(key)?;
+
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Objects under `prefix`, sorted by key. Like a path, the prefix is the key of an
+    /// object or a directory of objects, `stream` doesn't cover `stream2/.schema`.
+    fn under(&self, prefix: &str) -> Vec<(String, Bytes)> {
+        let dir = dir_prefix(prefix);
+        let mut objects: Vec<(String, Bytes)> = self
+            .objects
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(key, _)| *key == prefix || key.starts_with(&dir))
+            .map(|(key, body)| (key.clone(), body.clone()))
+            .collect();
+        objects.sort_by(|a, b| a.0.cmp(&b.0));
+        objects
+    }
+
+    /// Names of the directories directly under `prefix`
+    fn dirs(&self, prefix: &str) -> Vec<String> {
+        let dir = dir_prefix(prefix);
+        self.objects
+            .lock()
+            .unwrap()
+            .keys()
+            .filter_map(|key| key.strip_prefix(&dir))
+            .filter_map(|rest| rest.split_once('/'))
+            .map(|(name, _)| name.to_string())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+}
+
+/// `prefix` as a directory, ending with `/` unless it is the root
+fn dir_prefix(prefix: &str) -> String {
+    match prefix.trim_end_matches('/') {
+        "" => String::new(),
+        dir => format!("{}/", dir),
+    }
+}
+
+/// MD5 hash of the body of an object in hex, as in S3 ETags
+fn etag(body: &[u8]) -> String {
+    format!("{:x}", md5::compute(body))
+}
+
+fn is_parquet(key: &str) -> bool {
+    key.ends_with(".parquet")
+}
+
+#[async_trait]
+impl ObjectStorage for MemoryStorage {
+    async fn check(&self) -> Result<(), ObjectStorageError> {
+        self.record("check", "")
+    }
+
+    async fn put_schema(
+        &self,
+        stream_name: String,
+        schema: &Schema,
+    ) -> Result<(), ObjectStorageError> {
+        self.record("put_schema", &stream_name)?;
+        self.put_json(&format!("{}/.schema", stream_name), schema)
+    }
+
+    async fn create_stream(&self, stream_name: &str) -> Result<(), ObjectStorageError> {
+        self.record("create_stream", stream_name)?;
+        self._put(&format!("{}/.schema", stream_name), Bytes::new());
+        if let Some(staging_dir) = &self.staging_dir {
+            let _res = fs::create_dir_all(staging_dir.join(stream_name));
+        }
+
+        Ok(())
+    }
+
+    async fn create_alert(
+        &self,
+        stream_name: &str,
+        alerts: &Alerts,
+    ) -> Result<(), ObjectStorageError> {
+        self.record("create_alert", stream_name)?;
+        self.put_json(&format!("{}/.alert.json", stream_name), alerts)
+    }
+
+    async fn get_schema(&self, stream_name: &str) -> Result<Bytes, ObjectStorageError> {
+        self.record("get_schema", stream_name)?;
+        self._get(&format!("{}/.schema", stream_name))
+    }
+
+    async fn get_schema_tagged(
+        &self,
+        stream_name: &str,
+    ) -> Result<Option<(Bytes, String)>, ObjectStorageError> {
+        self.record("get_schema_tagged", stream_name)?;
+        match self._get(&format!("{}/.schema", stream_name)) {
+            Ok(body) => {
+                let tag = etag(&body);
+                Ok(Some((body, tag)))
+            }
+            Err(ObjectStorageError::NoSuchKey(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn put_schema_if(
+        &self,
+        stream_name: &str,
+        schema: &Schema,
+        tag: Option<&str>,
+    ) -> Result<(), ObjectStorageError> {
+        self.record("put_schema_if", stream_name)?;
+        let key = format!("{}/.schema", stream_name);
+        let body = serde_json::to_vec(schema)?;
+
+        // the objects stay locked from the check to the put
+        let mut objects = self.objects.lock().unwrap();
+        let current = objects.get(&key).map(|body| etag(body));
+        if current.as_deref() != tag {
+            return Err(ObjectStorageError::PreconditionFailed(key));
+        }
+        objects.insert(key, body.into());
+
+        Ok(())
+    }
+
+    async fn get_alert(&self, stream_name: &str) -> Result<Bytes, ObjectStorageError> {
+        self.record("get_alert", stream_name)?;
+        self._get(&format!("{}/.alert.json", stream_name))
+    }
+
+    async fn get_stats(&self, stream_name: &str) -> Result<Stats, ObjectStorageError> {
+        self.record("get_stats", stream_name)?;
+        self.get_json(&format!("{}/.stats.json", stream_name))
+    }
+
+    async fn put_stats(&self, stream_name: &str, stats: &Stats) -> Result<(), ObjectStorageError> {
+        self.record("put_stats", stream_name)?;
+        self.put_json(&format!("{}/.stats.json", stream_name), stats)
+    }
+
+    async fn put_retention(
+        &self,
+        stream_name: &str,
+        retention: &Retention,
+    ) -> Result<(), ObjectStorageError> {
+        self.record("put_retention", stream_name)?;
+        self.put_json(&format!("{}/.retention.json", stream_name), retention)
+    }
+
+    async fn get_retention(&self, stream_name: &str) -> Result<Retention, ObjectStorageError> {
+        self.record("get_retention", stream_name)?;
+        self.get_json(&format!("{}/.retention.json", stream_name))
+    }
+
+    async fn put_tags(
+        &self,
+        stream_name: &str,
+        tags: &HashMap<String, String>,
+    ) -> Result<(), ObjectStorageError> {
+        self.record("put_tags", stream_name)?;
+        self.put_json(&format!("{}/.tags.json", stream_name), tags)
+    }
+
+    async fn get_tags(
+        &self,
+        stream_name: &str,
+    ) -> Result<HashMap<String, String>, ObjectStorageError> {
+        self.record("get_tags", stream_name)?;
+        self.get_json(&format!("{}/.tags.json", stream_name))
+    }
+
+    async fn put_static_schema(
+        &self,
+        stream_name: &str,
+        static_schema: bool,
+    ) -> Result<(), ObjectStorageError> {
+        self.record("put_static_schema", stream_name)?;
+        self.put_json(
+            &format!("{}/.static_schema.json", stream_name),
+            &static_schema,
+        )
+    }
+
+    async fn get_static_schema(&self, stream_name: &str) -> Result<bool, ObjectStorageError> {
+        self.record("get_static_schema", stream_name)?;
+        self.get_json(&format!("{}/.static_schema.json", stream_name))
+    }
+
+    async fn put_deleted_at(
+        &self,
+        stream_name: &str,
+        deleted_at: Option<DateTime<Utc>>,
+    ) -> Result<(), ObjectStorageError> {
+        self.record("put_deleted_at", stream_name)?;
+        self.put_json(&format!("{}/.deleted.json", stream_name), &deleted_at)
+    }
+
+    async fn get_deleted_at(
+        &self,
+        stream_name: &str,
+    ) -> Result<Option<DateTime<Utc>>, ObjectStorageError> {
+        self.record("get_deleted_at", stream_name)?;
+        self.get_json(&format!("{}/.deleted.json", stream_name))
+    }
+
+    async fn put_renamed_from(
+        &self,
+        stream_name: &str,
+        renamed_from: &str,
+    ) -> Result<(), ObjectStorageError> {
+        self.record("put_renamed_from", stream_name)?;
+        self.put_json(
+            &format!("{}/.renamed_from.json", stream_name),
+            &renamed_from,
+        )
+    }
+
+    async fn get_renamed_from(&self, stream_name: &str) -> Result<String, ObjectStorageError> {
+        self.record("get_renamed_from", stream_name)?;
+        self.get_json(&format!("{}/.renamed_from.json", stream_name))
+    }
+
+    async fn put_time_field(
+        &self,
+        stream_name: &str,
+        time_field: &str,
+    ) -> Result<(), ObjectStorageError> {
+        self.record("put_time_field", stream_name)?;
+        self.put_json(&format!("{}/.time_field.json", stream_name), &time_field)
+    }
+
+    async fn get_time_field(&self, stream_name: &str) -> Result<String, ObjectStorageError> {
+        self.record("get_time_field", stream_name)?;
+        self.get_json(&format!("{}/.time_field.json", stream_name))
+    }
+
+    async fn put_limits(
+        &self,
+        stream_name: &str,
+        limits: &Limits,
+    ) -> Result<(), ObjectStorageError> {
+        self.record("put_limits", stream_name)?;
+        self.put_json(&format!("{}/.limits.json", stream_name), limits)
+    }
+
+    async fn get_limits(&self, stream_name: &str) -> Result<Limits, ObjectStorageError> {
+        self.record("get_limits", stream_name)?;
+        self.get_json(&format!("{}/.limits.json", stream_name))
+    }
+
+    async fn put_compression(
+        &self,
+        stream_name: &str,
+        compression: Compression,
+    ) -> Result<(), ObjectStorageError> {
+        self.record("put_compression", stream_name)?;
+        self.put_json(&format!("{}/.compression.json", stream_name), &compression)
+    }
+
+    async fn get_compression(&self, stream_name: &str) -> Result<Compression, ObjectStorageError> {
+        self.record("get_compression", stream_name)?;
+        self.get_json(&format!("{}/.compression.json", stream_name))
+    }
+
+    async fn put_flatten(
+        &self,
+        stream_name: &str,
+        flatten: Flatten,
+    ) -> Result<(), ObjectStorageError> {
+        self.record("put_flatten", stream_name)?;
+        self.put_json(&format!("{}/.flatten.json", stream_name), &flatten)
+    }
+
+    async fn get_flatten(&self, stream_name: &str) -> Result<Flatten, ObjectStorageError> {
+        self.record("get_flatten", stream_name)?;
+        self.get_json(&format!("{}/.flatten.json", stream_name))
+    }
+
+    async fn put_partition(
+        &self,
+        stream_name: &str,
+        partition: PartitionGranularity,
+    ) -> Result<(), ObjectStorageError> {
+        self.record("put_partition", stream_name)?;
+        self.put_json(&format!("{}/.partition.json", stream_name), &partition)
+    }
+
+    async fn get_partition(
+        &self,
+        stream_name: &str,
+    ) -> Result<PartitionGranularity, ObjectStorageError> {
+        self.record("get_partition", stream_name)?;
+        self.get_json(&format!("{}/.partition.json", stream_name))
+    }
+
+    async fn put_metadata(
+        &self,
+        stream_name: &str,
+        document: &MetadataDocument,
+    ) -> Result<(), ObjectStorageError> {
+        self.record("put_metadata", stream_name)?;
+        self.put_json(&format!("{}/.metadata.json", stream_name), document)
+    }
+
+    async fn get_metadata(
+        &self,
+        stream_name: &str,
+    ) -> Result<MetadataDocument, ObjectStorageError> {
+        self.record("get_metadata", stream_name)?;
+        self.get_json(&format!("{}/.metadata.json", stream_name))
+    }
+
+    async fn put_timestamps(
+        &self,
+        stream_name: &str,
+        timestamps: &StreamTimestamps,
+    ) -> Result<(), ObjectStorageError> {
+        self.record("put_timestamps", stream_name)?;
+        self.put_json(&format!("{}/.timestamps.json", stream_name), timestamps)
+    }
+
+    async fn get_timestamps(
+        &self,
+        stream_name: &str,
+    ) -> Result<StreamTimestamps, ObjectStorageError> {
+        self.record("get_timestamps", stream_name)?;
+        self.get_json(&format!("{}/.timestamps.json", stream_name))
+    }
+
+    async fn list_streams(&self) -> Result<Vec<LogStream>, ObjectStorageError> {
+        self.record("list_streams", "")?;
+        let streams = self
+            .dirs("")
+            .into_iter()
+            .map(|name| LogStream { name })
+            .collect();
+
+        Ok(streams)
+    }
+
+    async fn list_dirs(&self, prefix: &str) -> Result<Vec<String>, ObjectStorageError> {
+        self.record("list_dirs", prefix)?;
+
+        Ok(self.dirs(prefix))
+    }
+
+    async fn put_object(&self, key: &str, body: Bytes) -> Result<(), ObjectStorageError> {
+        self.record("put_object", key)?;
+        self._put(key, body);
+
+        Ok(())
+    }
+
+    async fn get_object(&self, key: &str) -> Result<Bytes, ObjectStorageError> {
+        self.record("get_object", key)?;
+        self._get(key)
+    }
+
+    async fn upload_file(&self, key: &str, path: &str) -> Result<(), ObjectStorageError> {
+        self.record("upload_file", key)?;
+        self._put(key, fs::read(path)?);
+
+        Ok(())
+    }
+
+    async fn object_meta(&self, key: &str) -> Result<ObjectMeta, ObjectStorageError> {
+        self.record("object_meta", key)?;
+        let body = self._get(key)?;
+
+        Ok(ObjectMeta {
+            size: body.len() as u64,
+            md5: Some(md5::compute(&body).0),
+        })
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> Result<DeletedObjects, ObjectStorageError> {
+        self.record("delete_prefix", prefix)?;
+        let mut deleted = DeletedObjects::default();
+        let under = self.under(prefix);
+        let mut objects = self.objects.lock().unwrap();
+        for (key, _) in under {
+            if let Some(body) = objects.remove(&key) {
+                deleted.objects += 1;
+                deleted.size += body.len() as u64;
+            }
+        }
+
+        Ok(deleted)
+    }
+
+    async fn copy_prefix(&self, from: &str, to: &str) -> Result<u64, ObjectStorageError> {
+        self.record("copy_prefix", from)?;
+        let objects = self.under(from);
+        let from_dir = dir_prefix(from);
+        let to_dir = dir_prefix(to);
+        for (key, body) in &objects {
+            let target = match key.strip_prefix(&from_dir) {
+                Some(rest) => format!("{}{}", to_dir, rest),
+                // the prefix is the key of a single object
+                None => to.to_string(),
+            };
+            self._put(&target, body.clone());
+        }
+
+        Ok(objects.len() as u64)
+    }
+
+    async fn count_objects(&self, prefix: &str) -> Result<u64, ObjectStorageError> {
+        self.record("count_objects", prefix)?;
+
+        Ok(self.under(prefix).len() as u64)
+    }
+
+    async fn parquet_objects(&self, prefix: &str) -> Result<StoredObjects, ObjectStorageError> {
+        self.record("parquet_objects", prefix)?;
+        let mut stored = StoredObjects::default();
+        for (_, body) in self.under(prefix).iter().filter(|(key, _)| is_parquet(key)) {
+            stored.objects += 1;
+            stored.size += body.len() as u64;
+        }
+
+        Ok(stored)
+    }
+
+    async fn list_parquet_files(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<ParquetFile>, ObjectStorageError> {
+        self.record("list_parquet_files", prefix)?;
+        let files = self
+            .under(prefix)
+            .into_iter()
+            .filter(|(key, _)| is_parquet(key))
+            .map(|(key, body)| ParquetFile {
+                key,
+                size: body.len() as u64,
+            })
+            .collect();
+
+        Ok(files)
+    }
+
+    // The parquet files of each partition are written to a temporary directory
+    // for the query to run on
+    async fn query(
+        &self,
+        query: &Query,
+        results: &mut Vec<RecordBatch>,
+    ) -> Result<(), ObjectStorageError> {
+        self.record("query", &query.stream_name)?;
+        for prefix in self
+            .query_partitions(&query.stream_name, query.start, query.end)
+            .await?
+        {
+            let dir =
+                std::env::temp_dir().join(format!("parseable-memory-{}", utils::random_string()));
+            fs::create_dir_all(&dir)?;
+            for (key, body) in self
+                .under(&prefix)
+                .iter()
+                .filter(|(key, _)| is_parquet(key))
+            {
+                fs::write(dir.join(key.replace('/', ".")), body)?;
+            }
+
+            let executed = query
+                .execute_on_dir(&dir.display().to_string(), results)
+                .await;
+            let _ = fs::remove_dir_all(&dir);
+            executed?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage() -> MemoryStorage {
+        MemoryStorage::default()
+            .with_object("stream/.schema", "")
+            .with_object("stream/date=2022-10-15/hour=10/minute=00/a.parquet", "aaa")
+            .with_object("stream/date=2022-10-15/hour=11/minute=00/b.parquet", "bb")
+            .with_object("stream2/.schema", "")
+    }
+
+    #[actix_web::test]
+    async fn lists_by_prefix() {
+        let storage = storage();
+
+        let streams = storage.list_streams().await.unwrap();
+        assert_eq!(
+            streams.iter().map(|s| s.name.as_str()).collect::<Vec<_>>(),
+            vec!["stream", "stream2"]
+        );
+        assert_eq!(
+            storage.list_dirs("stream/date=2022-10-15/").await.unwrap(),
+            vec!["hour=10", "hour=11"]
+        );
+        assert_eq!(storage.count_objects("stream").await.unwrap(), 3);
+        assert_eq!(
+            storage.parquet_objects("stream/").await.unwrap(),
+            StoredObjects {
+                objects: 2,
+                size: 5
+            }
+        );
+        assert_eq!(
+            storage
+                .list_parquet_files("stream/date=2022-10-15/hour=11/")
+                .await
+                .unwrap(),
+            vec![ParquetFile {
+                key: "stream/date=2022-10-15/hour=11/minute=00/b.parquet".to_string(),
+                size: 2
+            }]
+        );
+    }
+
+    #[actix_web::test]
+    async fn copies_and_deletes_prefix() {
+        let storage = storage();
+
+        assert_eq!(storage.copy_prefix("stream/", "copied/").await.unwrap(), 3);
+        let deleted = storage.delete_prefix("stream").await.unwrap();
+
+        assert_eq!(deleted.objects, 3);
+        assert_eq!(deleted.size, 5);
+        assert_eq!(
+            storage.keys(),
+            vec![
+                "copied/.schema",
+                "copied/date=2022-10-15/hour=10/minute=00/a.parquet",
+                "copied/date=2022-10-15/hour=11/minute=00/b.parquet",
+                "stream2/.schema",
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn injected_failures_and_operations() {
+        let storage = storage();
+        storage.fail("get_schema", "stream2", 1);
+
+        assert!(storage.get_schema("stream").await.is_ok());
+        assert!(matches!(
+            storage.get_schema("stream2").await,
+            Err(ObjectStorageError::ConnectionError(_))
+        ));
+        // only the given number of calls fail
+        assert!(storage.get_schema("stream2").await.is_ok());
+        assert!(matches!(
+            storage.get_stats("stream").await,
+            Err(ObjectStorageError::NoSuchKey(_))
+        ));
+
+        assert_eq!(
+            storage.operations(),
+            vec![
+                "get_schema stream",
+                "get_schema stream2",
+                "get_schema stream2",
+                "get_stats stream"
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn create_stream_stages_locally() {
+        let dir = std::env::temp_dir().join(format!("parseable-test-{}", utils::random_string()));
+        let storage = MemoryStorage::default().with_staging_dir(dir.clone());
+
+        storage.create_stream("stream").await.unwrap();
+
+        assert!(dir.join("stream").is_dir());
+        assert_eq!(storage.keys(), vec!["stream/.schema"]);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[actix_web::test]
+    async fn clones_share_objects() {
+        let storage = MemoryStorage::default();
+        let clone = storage.clone();
+
+        clone.put_stats("stream", &Stats::default()).await.unwrap();
+
+        assert_eq!(storage.get_stats("stream").await.unwrap(), Stats::default());
+    }
+}
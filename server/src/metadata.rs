@@ -16,28 +16,394 @@
  *
  */
 
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
 use bytes::Bytes;
+use chrono::{DateTime, Duration, Utc};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use futures::stream::{self, StreamExt};
 use lazy_static::lazy_static;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::RwLock;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
 
+use crate::alerts::{Alert, Alerts, COOLDOWNS};
 use crate::error::Error;
-use crate::storage::ObjectStorage;
+use crate::manifest::{self, Manifest};
+use crate::migration::{self, MetadataDocument};
+use crate::option::CONFIG;
+use crate::retention::Retention;
+use crate::storage::{
+    LogStream, ObjectStorage, ObjectStorageError, StoredObjects, OBJECT_STORE_DATA_GRANULARITY,
+    RESERVED_PREFIX,
+};
+use crate::utils;
+use crate::validator;
 
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct LogStreamMetadata {
-    pub schema: String,
-    pub alert_config: String,
+    /// Shared with every caller of `schema`, so that events don't have to copy it.
+    /// It's replaced as a whole when the schema changes, never modified in place.
+    pub schema: Option<SchemaRef>,
+    /// Version of the schema, bumped whenever it changes. Versions start at 1,
+    /// a stream without a schema has version 0.
+    pub schema_version: u32,
+    /// Events must conform to the schema set when the stream was created,
+    /// instead of the schema being inferred from events
+    pub static_schema: bool,
+    pub alert_config: Alerts,
     pub stats: Stats,
+    /// Data older than this is deleted from object storage, kept forever if not set
+    pub retention: Option<Duration>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub first_event_at: Option<DateTime<Utc>>,
+    /// Free form key value pairs to group streams by, e.g. team or environment
+    pub tags: HashMap<String, String>,
+    /// Field of events that holds their time, which partitions them instead
+    /// of the time they were received at
+    pub time_field: Option<String>,
+    /// When the stream was soft deleted, it can be restored until its grace period is over
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Limits on the events of the stream, the server defaults apply to the ones not set
+    pub limits: Limits,
+    /// Codec of the parquet files written for the stream, the server default if not set
+    pub compression: Option<Compression>,
+    /// How nested JSON events of the stream are flattened into columns
+    pub flatten: Flatten,
+    /// Granularity of the time partitions of the stream in object storage
+    pub partition: PartitionGranularity,
+    /// New name of the stream while it is being renamed, events are rejected meanwhile
+    pub renaming_to: Option<String>,
+    /// Reasons the stream couldn't be loaded completely during server start up.
+    /// A stream with any of these is considered degraded.
+    pub load_errors: Vec<LoadError>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum LoadError {
+    #[error("schema in object storage is invalid")]
+    InvalidSchema,
+    #[error("schema not found in object storage")]
+    SchemaNotInStore,
+    #[error("alert in object storage is invalid: {0}")]
+    InvalidAlert(String),
+}
+
+impl LogStreamMetadata {
+    // Account for `events` events of `size` bytes written at `now`
+    fn record_update(&mut self, size: u64, compressed_size: u64, events: u64, now: DateTime<Utc>) {
+        if events > 0 && self.first_event_at.is_none() {
+            self.first_event_at = Some(now);
+        }
+        self.stats.update(size, compressed_size);
+        self.stats.record_events(events, now);
+        self.stats.ingest_rate.record(now, events, size);
+    }
+}
+
+/// Overview of a log stream, as listed by the list log streams API
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StreamSummary {
+    pub name: String,
+    pub has_schema: bool,
+    pub static_schema: bool,
+    pub has_alerts: bool,
+    /// Whether the stream failed to load completely during server start up
+    pub degraded: bool,
+    pub stats: Stats,
+    pub created_at: Option<DateTime<Utc>>,
+    pub first_event_at: Option<DateTime<Utc>>,
+    /// Average ingestion rate over the last 1, 5 and 15 minutes
+    pub rate_1m: Rate,
+    pub rate_5m: Rate,
+    pub rate_15m: Rate,
+    pub retention: Option<Retention>,
+    pub tags: HashMap<String, String>,
+    pub time_field: Option<String>,
+    pub limits: Limits,
+    pub compression: Option<Compression>,
+    pub flatten: Flatten,
+    pub partition: PartitionGranularity,
+}
+
+impl StreamSummary {
+    fn new(stream_name: &str, meta: &LogStreamMetadata, now: DateTime<Utc>) -> Self {
+        Self {
+            name: stream_name.to_owned(),
+            has_schema: meta.schema.is_some(),
+            static_schema: meta.static_schema,
+            has_alerts: !meta.alert_config.alerts.is_empty(),
+            degraded: !meta.load_errors.is_empty(),
+            stats: meta.stats.clone(),
+            created_at: meta.created_at,
+            first_event_at: meta.first_event_at,
+            rate_1m: meta.stats.ingest_rate.rate(now, 1),
+            rate_5m: meta.stats.ingest_rate.rate(now, 5),
+            rate_15m: meta.stats.ingest_rate.rate(now, 15),
+            retention: meta.retention.map(Retention::from),
+            tags: meta.tags.clone(),
+            time_field: meta.time_field.clone(),
+            limits: meta.limits,
+            compression: meta.compression,
+            flatten: meta.flatten,
+            partition: meta.partition,
+        }
+    }
+
+    /// Whether the stream has all of the given tags, as `(key, value)` pairs.
+    pub fn has_tags(&self, tags: &[(String, String)]) -> bool {
+        tags.iter()
+            .all(|(key, value)| self.tags.get(key) == Some(value))
+    }
+}
+
+/// Settings of a log stream that can be given when creating it
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+pub struct StreamSettings {
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+    #[serde(default)]
+    pub retention: Option<Retention>,
+    /// Static schema of the stream, the schema is inferred from events if not set
+    #[serde(default)]
+    pub schema: Option<Schema>,
+    /// Field of events, after flattening, to partition them by instead of ingest time
+    #[serde(default, alias = "time_partition")]
+    pub time_field: Option<String>,
+    /// Limits are given alongside the other settings, not nested
+    #[serde(flatten)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    /// Given alongside the other settings as `flatten` and `flatten_arrays`
+    #[serde(flatten)]
+    pub flatten: Option<Flatten>,
+    /// Granularity of the time partitions, by minute if not set
+    #[serde(default)]
+    pub partition: Option<PartitionGranularity>,
+}
+
+/// How nested objects and arrays of JSON events of a log stream are flattened into
+/// columns. Without `flatten`, the path to a nested field is joined with `_`, as
+/// streams created before the setting are.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Flatten {
+    /// Name columns of nested fields by their dotted path, e.g. `user.id`, and put
+    /// arrays in a single column as a JSON string
+    pub flatten: bool,
+    /// Flatten arrays into a column per element too, e.g. `tags.0`
+    #[serde(default)]
+    pub flatten_arrays: bool,
+}
+
+/// Codec parquet files of a log stream are compressed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Snappy,
+    Zstd,
+    Gzip,
+    Uncompressed,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "snappy" => Ok(Compression::Snappy),
+            "zstd" => Ok(Compression::Zstd),
+            "gzip" => Ok(Compression::Gzip),
+            "uncompressed" => Ok(Compression::Uncompressed),
+            _ => Err(format!(
+                "unknown compression {}, expected snappy, zstd, gzip or uncompressed",
+                s
+            )),
+        }
+    }
+}
+
+impl From<Compression> for parquet::basic::Compression {
+    fn from(compression: Compression) -> Self {
+        match compression {
+            Compression::Snappy => parquet::basic::Compression::SNAPPY,
+            Compression::Zstd => parquet::basic::Compression::ZSTD,
+            Compression::Gzip => parquet::basic::Compression::GZIP,
+            Compression::Uncompressed => parquet::basic::Compression::UNCOMPRESSED,
+        }
+    }
+}
+
+/// Granularity of the time partitions parquet files of a log stream are written to,
+/// and so the depth of their object key prefix. Streams created without it are
+/// partitioned by minute.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionGranularity {
+    #[default]
+    Minute,
+    Hour,
+    Day,
+}
+
+impl PartitionGranularity {
+    /// Prefix of the partition that `time` falls in, e.g. `date=2022-06-11/hour=12/`
+    pub fn prefix(&self, time: DateTime<Utc>) -> String {
+        self.truncate(&utils::time_to_prefix(time, OBJECT_STORE_DATA_GRANULARITY))
+    }
+
+    /// Cut a minute level partition prefix, optionally led by the stream name,
+    /// down to this granularity
+    pub fn truncate(&self, prefix: &str) -> String {
+        let dropped: &[&str] = match self {
+            PartitionGranularity::Minute => &[],
+            PartitionGranularity::Hour => &["minute="],
+            PartitionGranularity::Day => &["hour=", "minute="],
+        };
+
+        prefix
+            .split_terminator('/')
+            .filter(|dir| !dropped.iter().any(|key| dir.starts_with(key)))
+            .map(|dir| format!("{}/", dir))
+            .collect()
+    }
+}
+
+impl FromStr for PartitionGranularity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "minute" => Ok(PartitionGranularity::Minute),
+            "hour" => Ok(PartitionGranularity::Hour),
+            "day" => Ok(PartitionGranularity::Day),
+            _ => Err(format!(
+                "unknown partition granularity {}, expected minute, hour or day",
+                s
+            )),
+        }
+    }
+}
+
+/// Limits on the size of single events of a log stream and on the number of its columns.
+/// Limits that aren't set fall back to the server defaults.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Limits {
+    /// In bytes, of a single event after flattening
+    #[serde(default)]
+    pub max_event_size: Option<usize>,
+    /// Of the schema of the stream, and so of the fields of a single event
+    #[serde(default)]
+    pub max_columns: Option<usize>,
+}
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        self == &Limits::default()
+    }
+}
+
+/// A setting given for creating a stream that differs from the one the stream already has
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SettingConflict {
+    pub setting: &'static str,
+    pub existing: serde_json::Value,
+    pub requested: serde_json::Value,
+}
+
+impl SettingConflict {
+    fn new(setting: &'static str, existing: &impl Serialize, requested: &impl Serialize) -> Self {
+        Self {
+            setting,
+            existing: serde_json::to_value(existing).unwrap_or_default(),
+            requested: serde_json::to_value(requested).unwrap_or_default(),
+        }
+    }
+}
+
+/// A setting of a stream that differs between memory and object storage, as found by a refresh
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MetadataChange {
+    pub stream: String,
+    pub setting: &'static str,
+    pub existing: serde_json::Value,
+    pub refreshed: serde_json::Value,
+}
+
+/// What a refresh of all streams from object storage changed in memory
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct RefreshReport {
+    pub changed: Vec<MetadataChange>,
+    /// Streams created by another server since they were loaded here
+    pub added: Vec<String>,
+    /// Streams deleted from object storage, or soft deleted, by another server
+    pub removed: Vec<String>,
+}
+
+/// Number of stats updates after which stats of a stream are put to object storage
+/// right away, instead of waiting for the next periodic stats sync.
+const STATS_SYNC_THRESHOLD: u64 = 1000;
+
+/// Number of syncs in a row that must fail for every stream before the server
+/// reports that data isn't reaching object storage.
+const SYNC_FAILURES_DEGRADED: u32 = 3;
+
+/// Interval in seconds between two runs of the job purging soft deleted streams
+pub const PURGE_INTERVAL: u32 = 60 * 60;
+
+/// Number of streams listed in a page while loading streams at startup
+const STREAMS_PAGE_SIZE: usize = 1000;
+
 #[derive(Debug, Deserialize, Serialize, Default, Clone, PartialEq, Eq)]
 pub struct Stats {
     pub size: u64,
     pub compressed_size: u64,
+    #[serde(default)]
+    pub events: u64,
+    #[serde(default)]
+    pub last_event_at: Option<DateTime<Utc>>,
+    /// Number of parquet files uploaded to object storage.
+    #[serde(default)]
+    pub parquet_files: u64,
+    /// Key of the parquet file uploaded last.
+    #[serde(default)]
+    pub latest_object: Option<String>,
+    /// Number of parquet files merged into larger ones by compaction.
+    #[serde(default)]
+    pub compacted_files: u64,
+    /// When compaction last merged parquet files of the stream.
+    #[serde(default)]
+    pub last_compacted_at: Option<DateTime<Utc>>,
+    /// Number of uploads of parquet files found to not match the staged file.
+    #[serde(default)]
+    pub corrupted_uploads: u64,
+    /// Number of staged files that couldn't be read and were moved aside, on start up.
+    #[serde(default)]
+    pub quarantined_files: u64,
+    /// Number of syncs that failed to upload parquet files of the stream.
+    #[serde(default)]
+    pub sync_failures: u64,
+    /// Error of the last sync, if it failed.
+    #[serde(default)]
+    pub last_sync_error: Option<SyncError>,
+    /// Number of syncs in a row that failed, starts afresh after a restart.
+    #[serde(skip)]
+    pub failed_syncs_in_row: u32,
+    /// Monotonic counter of updates, used to tell which copy of stats is newer.
+    #[serde(default)]
+    pub sequence: u64,
     #[serde(skip)]
     pub prev_compressed: u64,
+    /// Sequence of the latest copy of stats known to be in object storage.
+    #[serde(skip)]
+    pub synced_sequence: u64,
+    /// Ingestion over the last few minutes, starts afresh after a restart.
+    #[serde(skip)]
+    pub ingest_rate: IngestRate,
 }
 
 impl Stats {
@@ -47,14 +413,219 @@ impl Stats {
     pub fn update(&mut self, size: u64, compressed_size: u64) {
         self.size += size;
         self.compressed_size = self.prev_compressed + compressed_size;
+        self.sequence += 1;
+    }
+
+    /// Count `events` received at `time` towards the stats.
+    pub fn record_events(&mut self, events: u64, time: DateTime<Utc>) {
+        self.events += events;
+        self.last_event_at = Some(self.last_event_at.map_or(time, |last| last.max(time)));
+    }
+
+    /// Account for `compressed_size` bytes of parquet files removed from object storage.
+    pub fn remove_compressed(&mut self, compressed_size: u64) {
+        self.compressed_size = self.compressed_size.saturating_sub(compressed_size);
+        self.prev_compressed = self.prev_compressed.saturating_sub(compressed_size);
+        self.sequence += 1;
+    }
+
+    /// Account for the parquet file with `key` uploaded to object storage.
+    pub fn record_upload(&mut self, key: String) {
+        self.parquet_files += 1;
+        self.latest_object = Some(key);
+        self.sequence += 1;
+    }
+
+    /// Record that syncing parquet files of the stream failed with `error` at `time`.
+    pub fn record_sync_failure(&mut self, error: String, time: DateTime<Utc>) {
+        self.sync_failures += 1;
+        self.failed_syncs_in_row += 1;
+        self.last_sync_error = Some(SyncError { time, error });
+        self.sequence += 1;
+    }
+
+    /// Account for `files` parquet files of `source_size` bytes merged at `time` into
+    /// one of `merged_size` bytes.
+    pub fn record_compaction(
+        &mut self,
+        files: u64,
+        source_size: u64,
+        merged_size: u64,
+        time: DateTime<Utc>,
+    ) {
+        self.parquet_files = self.parquet_files.saturating_sub(files) + 1;
+        self.compressed_size = self.compressed_size.saturating_sub(source_size) + merged_size;
+        self.prev_compressed = self.prev_compressed.saturating_sub(source_size) + merged_size;
+        self.compacted_files += files;
+        self.last_compacted_at = Some(time);
+        self.sequence += 1;
+    }
+
+    /// Record that an upload of a parquet file of the stream didn't match the staged file.
+    pub fn record_corrupted_upload(&mut self) {
+        self.corrupted_uploads += 1;
+        self.sequence += 1;
+    }
+
+    /// Record that a staged file that couldn't be read was quarantined.
+    pub fn record_quarantine(&mut self) {
+        self.quarantined_files += 1;
+        self.sequence += 1;
+    }
+
+    /// Record that all parquet files of the stream were synced.
+    pub fn record_sync_success(&mut self) {
+        self.failed_syncs_in_row = 0;
+        if self.last_sync_error.take().is_some() {
+            self.sequence += 1;
+        }
+    }
+
+    /// Replace what is accounted for as already in object storage with what is actually
+    /// there, the part of `compressed_size` not yet uploaded is kept as is.
+    pub fn recalculate(&mut self, stored: StoredObjects) {
+        let local = self.compressed_size.saturating_sub(self.prev_compressed);
+        self.compressed_size = stored.size + local;
+        self.prev_compressed = stored.size;
+        self.parquet_files = stored.objects;
+        self.sequence += 1;
+    }
+
+    /// Like `recalculate`, and replace the number of events with the rows of the parquet
+    /// files in object storage, if they could all be read.
+    pub fn recompute(&mut self, stored: StoredObjects, events: Option<u64>) {
+        self.recalculate(stored);
+        if let Some(events) = events {
+            self.events = events;
+        }
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.synced_sequence >= self.sequence
+    }
+
+    /// Whether enough updates piled up since the last sync that the stats should be
+    /// put right away, instead of with the next periodic stats sync.
+    pub fn is_sync_due(&self) -> bool {
+        self.sequence.saturating_sub(self.synced_sequence) >= STATS_SYNC_THRESHOLD
+    }
+
+    /// Prepare stats read back from object storage for further updates,
+    /// everything accounted for before the restart is considered already compressed.
+    pub fn restore(mut self) -> Self {
+        self.prev_compressed = self.compressed_size;
+        self.synced_sequence = self.sequence;
+        self
+    }
+}
+
+/// Failure of syncing parquet files of a stream to object storage
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct SyncError {
+    pub time: DateTime<Utc>,
+    pub error: String,
+}
+
+/// Stats of all log streams added up
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TotalStats {
+    pub streams: usize,
+    pub size: u64,
+    pub compressed_size: u64,
+    pub events: u64,
+}
+
+/// Lifecycle timestamps of a log stream as put to object storage
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StreamTimestamps {
+    #[serde(default)]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub first_event_at: Option<DateTime<Utc>>,
+}
+
+/// Number of minutes for which per minute ingestion is kept
+const RATE_WINDOW_MINUTES: usize = 15;
+
+/// Events and bytes ingested per minute, averaged over a window
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub struct Rate {
+    pub events: f64,
+    pub bytes: f64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct RateBucket {
+    minute: i64,
+    events: u64,
+    bytes: u64,
+}
+
+/// Ring buffer of per minute ingestion counters for the last `RATE_WINDOW_MINUTES` minutes.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct IngestRate {
+    buckets: [RateBucket; RATE_WINDOW_MINUTES],
+    latest_minute: i64,
+}
+
+impl IngestRate {
+    /// Count `events` events of `bytes` total size received at `time`.
+    pub fn record(&mut self, time: DateTime<Utc>, events: u64, bytes: u64) {
+        // If the clock went back, count towards the latest minute seen instead
+        // of overwriting the bucket of a newer minute.
+        let minute = minute_of(time).max(self.latest_minute);
+        self.latest_minute = minute;
+
+        let bucket = &mut self.buckets[bucket_index(minute)];
+        if bucket.minute != minute {
+            *bucket = RateBucket {
+                minute,
+                ..Default::default()
+            };
+        }
+        bucket.events = bucket.events.saturating_add(events);
+        bucket.bytes = bucket.bytes.saturating_add(bytes);
+    }
+
+    /// Average ingestion per minute over the `minutes` minutes up to `now`,
+    /// the current minute included. Minutes beyond the buffer count as zero.
+    pub fn rate(&self, now: DateTime<Utc>, minutes: usize) -> Rate {
+        let current = minute_of(now);
+        let oldest = current - minutes.min(RATE_WINDOW_MINUTES) as i64;
+
+        let (events, bytes) = self
+            .buckets
+            .iter()
+            .filter(|bucket| bucket.minute > oldest && bucket.minute <= current)
+            .fold((0, 0), |(events, bytes), bucket| {
+                (
+                    events.saturating_add(bucket.events),
+                    bytes.saturating_add(bucket.bytes),
+                )
+            });
+
+        let minutes = minutes.max(1) as f64;
+        Rate {
+            events: events as f64 / minutes,
+            bytes: bytes as f64 / minutes,
+        }
     }
 }
 
+fn minute_of(time: DateTime<Utc>) -> i64 {
+    time.timestamp().div_euclid(60)
+}
+
+fn bucket_index(minute: i64) -> usize {
+    minute.rem_euclid(RATE_WINDOW_MINUTES as i64) as usize
+}
+
 lazy_static! {
     #[derive(Debug)]
-    // A read-write lock to allow multiple reads while and isolated write
-    pub static ref STREAM_INFO: RwLock<HashMap<String, LogStreamMetadata>> =
-        RwLock::new(HashMap::new());
+    // A sharded map, so that updates to one stream don't block other streams
+    pub static ref STREAM_INFO: DashMap<String, LogStreamMetadata> = DashMap::new();
+    // Soft deleted streams, until they are restored or their grace period is over
+    static ref DELETED_STREAMS: DashMap<String, LogStreamMetadata> = DashMap::new();
 }
 
 // STREAM_INFO should be updated
@@ -65,185 +636,3050 @@ lazy_static! {
 // 5. When set alert API is called (update the alert)
 #[allow(clippy::all)]
 impl STREAM_INFO {
-    pub fn set_schema(&self, stream_name: String, schema: String) -> Result<(), Error> {
-        let alert_config = self.alert(&stream_name)?;
-        self.add_stream(stream_name, schema, alert_config)
+    // Entry guards returned by the map lock a whole shard. They must never be held
+    // across calls to other methods of STREAM_INFO, as that can deadlock.
+
+    pub fn contains_stream(&self, stream_name: &str) -> bool {
+        self.contains_key(stream_name)
     }
 
-    pub fn schema(&self, stream_name: &str) -> Result<String, Error> {
-        let map = self.read().unwrap();
-        let meta = map
+    /// Whether the stream exists, soft deleted streams don't. The stream may be
+    /// deleted right after, so this doesn't replace handling `StreamMetaNotFound`.
+    pub fn stream_exists(&self, stream_name: &str) -> bool {
+        self.contains_stream(stream_name)
+    }
+
+    /// Whether the stream is being renamed, as it doesn't take events meanwhile.
+    pub fn is_renaming(&self, stream_name: &str) -> bool {
+        self.get(stream_name)
+            .map_or(false, |meta| meta.renaming_to.is_some())
+    }
+
+    #[cfg(test)]
+    pub fn stream_count(&self) -> usize {
+        self.len()
+    }
+
+    /// Replace the schema of the stream, unless it has more columns than the limit of the stream.
+    pub fn set_schema(&self, stream_name: String, schema: Schema) -> Result<(), Error> {
+        let mut meta = self
+            .get_mut(&stream_name)
+            .ok_or_else(|| Error::StreamMetaNotFound(stream_name.clone()))?;
+
+        if let Some(max_columns) = meta.limits.max_columns {
+            let columns = schema.fields().len();
+            if columns > max_columns {
+                return Err(Error::TooManyColumns(
+                    format!("schema of log stream {}", stream_name),
+                    columns,
+                    max_columns,
+                ));
+            }
+        }
+
+        meta.schema = Some(Arc::new(schema));
+        meta.schema_version += 1;
+
+        Ok(())
+    }
+
+    /// Fix the schema of the stream, events that don't conform to it are rejected
+    /// from now on. Streams get a static schema when they are created, see
+    /// `create_stream_with`.
+    #[cfg(test)]
+    pub fn set_static_schema(&self, stream_name: &str, schema: Schema) -> Result<(), Error> {
+        let mut meta = self
+            .get_mut(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+
+        meta.schema = Some(Arc::new(with_labels_field(schema)));
+        meta.schema_version += 1;
+        meta.static_schema = true;
+
+        Ok(())
+    }
+
+    /// Returns the schema events of the stream must conform to, or `None` if the
+    /// schema of the stream is inferred from its events.
+    pub fn static_schema(&self, stream_name: &str) -> Result<Option<SchemaRef>, Error> {
+        let meta = self
             .get(stream_name)
-            .ok_or(Error::StreamMetaNotFound(stream_name.to_string()))?;
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
 
-        Ok(meta.schema.clone())
+        if !meta.static_schema {
+            return Ok(None);
+        }
+
+        // a static schema that failed to load must not be replaced by inference
+        match &meta.schema {
+            Some(schema) => Ok(Some(Arc::clone(schema))),
+            None => Err(Error::InvalidSchema(stream_name.to_owned())),
+        }
     }
 
-    pub fn set_alert(&self, stream_name: String, alert_config: String) -> Result<(), Error> {
-        let schema = self.schema(&stream_name)?;
-        self.add_stream(stream_name, schema, alert_config)
+    /// Returns the arrow schema of the stream, or `None` if no event has been
+    /// sent to the stream yet.
+    pub fn schema(&self, stream_name: &str) -> Result<Option<SchemaRef>, Error> {
+        let meta = self
+            .get(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_string()))?;
+
+        Ok(meta.schema.clone())
     }
 
-    pub fn alert(&self, stream_name: &str) -> Result<String, Error> {
-        let map = self.read().unwrap();
-        let meta = map
+    /// Version the schema of the stream gets with its next change, callers are expected to
+    /// put the new schema to object storage as this version before setting it.
+    pub fn next_schema_version(&self, stream_name: &str) -> Result<u32, Error> {
+        let meta = self
             .get(stream_name)
             .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
 
-        Ok(meta.alert_config.clone())
+        Ok(meta.schema_version + 1)
     }
 
-    pub fn add_stream(
+    /// Returns the schema of the stream as of `version`. Past versions are fetched from
+    /// object storage, the current one is returned as is.
+    pub async fn schema_at_version(
         &self,
-        stream_name: String,
-        schema: String,
-        alert_config: String,
-    ) -> Result<(), Error> {
-        let mut map = self.write().unwrap();
-        let metadata = LogStreamMetadata {
-            schema,
-            alert_config,
-            ..Default::default()
+        storage: &dyn ObjectStorage,
+        stream_name: &str,
+        version: u32,
+    ) -> Result<Schema, Error> {
+        let current = {
+            let meta = self
+                .get(stream_name)
+                .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+            if version == 0 || version > meta.schema_version {
+                return Err(Error::SchemaVersionNotFound(
+                    stream_name.to_owned(),
+                    version,
+                ));
+            }
+            meta.schema
+                .clone()
+                .filter(|_| version == meta.schema_version)
         };
-        // TODO: Add check to confirm data insertion
-        map.insert(stream_name, metadata);
+
+        match current {
+            Some(schema) => Ok(schema.as_ref().clone()),
+            None => Ok(storage.get_schema_version(stream_name, version).await?),
+        }
+    }
+
+    /// Returns the union of the stream's current schema and the given schema.
+    /// The stream itself is left untouched, callers are expected to persist
+    /// the merged schema to object storage before committing it with `set_schema`.
+    pub fn merge_schema(&self, stream_name: &str, schema: Schema) -> Result<Schema, Error> {
+        match self.schema(stream_name)? {
+            Some(current) => merge_schemas(&current, &schema),
+            None => Ok(schema),
+        }
+    }
+
+    pub fn set_alert(&self, stream_name: String, alert_config: Alerts) -> Result<(), Error> {
+        validator::alert(&alert_config)?;
+
+        let mut meta = self
+            .get_mut(&stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name))?;
+
+        meta.alert_config = alert_config;
 
         Ok(())
     }
 
-    pub fn delete_stream(&self, stream_name: &str) -> Result<(), Error> {
-        let mut map = self.write().unwrap();
-        // TODO: Add check to confirm data deletion
-        map.remove(stream_name);
+    pub fn alert(&self, stream_name: &str) -> Result<Vec<Alert>, Error> {
+        let meta = self
+            .get(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+
+        Ok(meta.alert_config.alerts.clone())
+    }
+
+    /// Keep data of the stream for `days` days, which must be at least one day.
+    /// Callers are expected to persist the retention to object storage first.
+    pub fn set_retention(&self, stream_name: &str, days: u32) -> Result<(), Error> {
+        validator::retention(days)?;
+
+        let mut meta = self
+            .get_mut(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+
+        meta.retention = Some(Duration::days(days as i64));
 
         Ok(())
     }
 
-    pub async fn load(&self, storage: &impl ObjectStorage) -> Result<(), Error> {
-        for stream in storage.list_streams().await? {
-            // Ignore S3 errors here, because we are just trying
-            // to load the stream metadata based on whatever is available.
-            //
-            // TODO: ignore failure(s) if any and skip to next stream
-            let alert_config = storage
-                .get_alert(&stream.name)
-                .await
-                .map_err(|e| e.into())
-                .and_then(parse_string)
-                .map_err(|_| Error::AlertNotInStore(stream.name.to_owned()));
+    pub fn time_field(&self, stream_name: &str) -> Result<Option<String>, Error> {
+        let meta = self
+            .get(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
 
-            let schema = storage
-                .get_schema(&stream.name)
-                .await
-                .map_err(|e| e.into())
-                .and_then(parse_string)
-                .map_err(|_| Error::SchemaNotInStore(stream.name.to_owned()));
+        Ok(meta.time_field.clone())
+    }
 
-            let metadata = LogStreamMetadata {
-                schema: schema.unwrap_or_default(),
-                alert_config: alert_config.unwrap_or_default(),
-                ..Default::default()
-            };
+    /// Replace the limits of the stream, the ones not set fall back to the server defaults.
+    /// Callers are expected to persist the limits to object storage first.
+    pub fn set_limits(&self, stream_name: &str, limits: Limits) -> Result<(), Error> {
+        validator::limits(&limits)?;
 
-            let mut map = self.write().unwrap();
-            map.insert(stream.name.clone(), metadata);
-        }
+        let mut meta = self
+            .get_mut(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+
+        meta.limits = limits;
 
         Ok(())
     }
 
-    pub fn update_stats(
+    /// Returns the limits set for the stream, without the server defaults.
+    pub fn limits(&self, stream_name: &str) -> Result<Limits, Error> {
+        let meta = self
+            .get(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+
+        Ok(meta.limits)
+    }
+
+    /// Compress parquet files of the stream written from now on with `compression`.
+    /// Callers are expected to persist the compression to object storage first.
+    pub fn set_compression(
         &self,
         stream_name: &str,
-        size: u64,
-        compressed_size: u64,
+        compression: Compression,
     ) -> Result<(), Error> {
-        let mut map = self.write().unwrap();
-        let stream = map
+        let mut meta = self
             .get_mut(stream_name)
             .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
 
-        stream.stats.update(size, compressed_size);
+        meta.compression = Some(compression);
 
         Ok(())
     }
-}
 
-fn parse_string(bytes: Bytes) -> Result<String, Error> {
-    String::from_utf8(bytes.to_vec()).map_err(|e| e.into())
-}
+    pub fn compression(&self, stream_name: &str) -> Result<Option<Compression>, Error> {
+        let meta = self
+            .get(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use maplit::hashmap;
-    use rstest::*;
-    use serial_test::serial;
+        Ok(meta.compression)
+    }
 
-    #[rstest]
-    #[case::zero(0, 0, 0)]
-    #[case::some(1024, 512, 2048)]
-    fn update_stats(#[case] size: u64, #[case] compressed_size: u64, #[case] prev_compressed: u64) {
-        let mut stats = Stats {
-            size,
-            compressed_size,
-            prev_compressed,
-        };
+    /// Flatten events of the stream ingested from now on as set by `flatten`.
+    /// Callers are expected to persist the setting to object storage first.
+    pub fn set_flatten(&self, stream_name: &str, flatten: Flatten) -> Result<(), Error> {
+        let mut meta = self
+            .get_mut(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
 
-        stats.update(2056, 2000);
+        meta.flatten = flatten;
 
-        assert_eq!(
-            stats,
-            Stats {
-                size: size + 2056,
-                compressed_size: prev_compressed + 2000,
-                prev_compressed
-            }
-        )
+        Ok(())
     }
 
-    fn clear_map() {
-        STREAM_INFO.write().unwrap().clear();
-    }
+    pub fn flatten(&self, stream_name: &str) -> Result<Flatten, Error> {
+        let meta = self
+            .get(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
 
-    #[rstest]
-    #[case::nonempty_string("Hello world")]
-    #[case::empty_string("")]
-    fn test_parse_string(#[case] string: String) {
-        let bytes = Bytes::from(string);
-        assert!(parse_string(bytes).is_ok())
+        Ok(meta.flatten)
     }
 
-    #[test]
-    fn test_bad_parse_string() {
-        let bad: Vec<u8> = vec![195, 40];
-        let bytes = Bytes::from(bad);
-        assert!(parse_string(bytes).is_err());
-    }
+    /// Partition parquet files of the stream written from now on by `partition`.
+    /// Callers are expected to persist it to object storage first.
+    #[cfg(test)]
+    pub fn set_partition(
+        &self,
+        stream_name: &str,
+        partition: PartitionGranularity,
+    ) -> Result<(), Error> {
+        let mut meta = self
+            .get_mut(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+
+        meta.partition = partition;
+
+        Ok(())
+    }
+
+    pub fn partition(&self, stream_name: &str) -> Result<PartitionGranularity, Error> {
+        let meta = self
+            .get(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+
+        Ok(meta.partition)
+    }
+
+    /// Replace the tags of the stream.
+    /// Callers are expected to persist the tags to object storage first.
+    pub fn set_tags(&self, stream_name: &str, tags: HashMap<String, String>) -> Result<(), Error> {
+        validator::tags(&tags)?;
+
+        let mut meta = self
+            .get_mut(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+
+        meta.tags = tags;
+
+        Ok(())
+    }
+
+    pub fn tags(&self, stream_name: &str) -> Result<HashMap<String, String>, Error> {
+        let meta = self
+            .get(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+
+        Ok(meta.tags.clone())
+    }
+
+    /// Returns the lifecycle timestamps of the stream, to be put to object storage.
+    pub fn timestamps(&self, stream_name: &str) -> Result<StreamTimestamps, Error> {
+        let meta = self
+            .get(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+
+        Ok(StreamTimestamps {
+            created_at: meta.created_at,
+            first_event_at: meta.first_event_at,
+        })
+    }
+
+    /// Returns the retention period of all streams that have one set.
+    pub fn retentions(&self) -> Vec<(String, Duration)> {
+        self.iter()
+            .filter_map(|entry| Some((entry.key().clone(), entry.retention?)))
+            .collect()
+    }
+
+    /// Streams whose parquet files can be compacted, with their schema. Streams without
+    /// a schema have no files yet, deleted streams and streams being renamed are left alone.
+    pub fn compactable_streams(&self) -> Vec<(String, SchemaRef)> {
+        self.iter()
+            .filter(|entry| entry.deleted_at.is_none() && entry.renaming_to.is_none())
+            .filter_map(|entry| Some((entry.key().clone(), entry.schema.clone()?)))
+            .collect()
+    }
+
+    /// Returns a summary of all streams ordered by name. Each shard is only
+    /// locked while copying, so callers can take their time with the result.
+    pub fn list_stream_summaries(&self) -> Vec<StreamSummary> {
+        let now = Utc::now();
+        let mut streams = self
+            .iter()
+            .map(|entry| StreamSummary::new(entry.key(), entry.value(), now))
+            .collect::<Vec<_>>();
+
+        streams.sort_by(|a, b| a.name.cmp(&b.name));
+        streams
+    }
+
+    pub fn stats(&self, stream_name: &str) -> Result<Stats, Error> {
+        let meta = self
+            .get(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+
+        Ok(meta.stats.clone())
+    }
+
+    /// Stats of the streams `include` returns true for, added up
+    pub fn total_stats(&self, include: impl Fn(&str) -> bool) -> TotalStats {
+        self.iter().filter(|entry| include(entry.key())).fold(
+            TotalStats::default(),
+            |total, entry| TotalStats {
+                streams: total.streams + 1,
+                size: total.size + entry.stats.size,
+                compressed_size: total.compressed_size + entry.stats.compressed_size,
+                events: total.events + entry.stats.events,
+            },
+        )
+    }
+
+    /// Returns a summary of the stream, as listed by `list_stream_summaries`.
+    pub fn summary(&self, stream_name: &str) -> Result<StreamSummary, Error> {
+        let meta = self
+            .get(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+
+        Ok(StreamSummary::new(stream_name, &meta, Utc::now()))
+    }
+
+    /// Returns the settings given for creating the stream that differ from the ones it
+    /// already has. Settings that are not given never conflict.
+    pub fn setting_conflicts(
+        &self,
+        stream_name: &str,
+        settings: &StreamSettings,
+    ) -> Result<Vec<SettingConflict>, Error> {
+        let meta = self
+            .get(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+
+        let mut conflicts = Vec::new();
+
+        if !settings.tags.is_empty() && settings.tags != meta.tags {
+            conflicts.push(SettingConflict::new("tags", &meta.tags, &settings.tags));
+        }
+
+        let retention = meta.retention.map(Retention::from);
+        if settings.retention.is_some() && settings.retention != retention {
+            conflicts.push(SettingConflict::new(
+                "retention",
+                &retention,
+                &settings.retention,
+            ));
+        }
+
+        if settings.time_field.is_some() && settings.time_field != meta.time_field {
+            conflicts.push(SettingConflict::new(
+                "time_field",
+                &meta.time_field,
+                &settings.time_field,
+            ));
+        }
+
+        if !settings.limits.is_empty() && settings.limits != meta.limits {
+            conflicts.push(SettingConflict::new(
+                "limits",
+                &meta.limits,
+                &settings.limits,
+            ));
+        }
+
+        if settings.compression.is_some() && settings.compression != meta.compression {
+            conflicts.push(SettingConflict::new(
+                "compression",
+                &meta.compression,
+                &settings.compression,
+            ));
+        }
+
+        if let Some(flatten) = settings.flatten.filter(|flatten| *flatten != meta.flatten) {
+            conflicts.push(SettingConflict::new("flatten", &meta.flatten, &flatten));
+        }
+
+        if let Some(partition) = settings
+            .partition
+            .filter(|partition| *partition != meta.partition)
+        {
+            conflicts.push(SettingConflict::new(
+                "partition",
+                &meta.partition,
+                &partition,
+            ));
+        }
+
+        if let Some(schema) = &settings.schema {
+            let existing = meta.schema.as_deref().filter(|_| meta.static_schema);
+            let requested = with_labels_field(schema.clone());
+            if existing != Some(&requested) {
+                conflicts.push(SettingConflict::new("schema", &existing, &requested));
+            }
+        }
+
+        Ok(conflicts)
+    }
+
+    /// Create a new stream, with an empty schema put to object storage. This fails
+    /// if the stream already exists.
+    #[cfg(test)]
+    pub async fn create_stream(
+        &self,
+        storage: &dyn ObjectStorage,
+        stream_name: &str,
+    ) -> Result<(), Error> {
+        self.create_stream_from(storage, stream_name, LogStreamMetadata::default())
+            .await
+    }
+
+    /// Create a new stream with `settings`. They are all put to object storage before
+    /// the stream is added, so that it doesn't take events without them.
+    pub async fn create_stream_with(
+        &self,
+        storage: &dyn ObjectStorage,
+        stream_name: &str,
+        settings: StreamSettings,
+    ) -> Result<(), Error> {
+        let meta = LogStreamMetadata {
+            static_schema: settings.schema.is_some(),
+            schema: settings
+                .schema
+                .map(|schema| Arc::new(with_labels_field(schema))),
+            tags: settings.tags,
+            retention: settings
+                .retention
+                .map(|retention| Duration::days(retention.days as i64)),
+            time_field: settings.time_field,
+            limits: settings.limits,
+            compression: settings.compression,
+            flatten: settings.flatten.unwrap_or_default(),
+            partition: settings.partition.unwrap_or_default(),
+            ..Default::default()
+        };
+
+        self.create_stream_from(storage, stream_name, meta).await
+    }
+
+    /// Create the stream `dest` with the schema and alerts of `source`, e.g. for a staging
+    /// stream set up like a production one. Stats, settings and data are not copied.
+    pub async fn clone_stream(
+        &self,
+        storage: &dyn ObjectStorage,
+        source: &str,
+        dest: &str,
+    ) -> Result<(), Error> {
+        let meta = {
+            let source_meta = self
+                .get(source)
+                .ok_or(Error::StreamMetaNotFound(source.to_owned()))?;
+            LogStreamMetadata {
+                schema: source_meta.schema.clone(),
+                alert_config: source_meta.alert_config.clone(),
+                ..Default::default()
+            }
+        };
+
+        self.create_stream_from(storage, dest, meta).await
+    }
+
+    /// Create the stream in object storage with the schema, alerts and settings of
+    /// `meta`, then add it with `meta` as its metadata
+    async fn create_stream_from(
+        &self,
+        storage: &dyn ObjectStorage,
+        stream_name: &str,
+        meta: LogStreamMetadata,
+    ) -> Result<(), Error> {
+        validator::stream_name(stream_name)?;
+
+        // the name of a soft deleted stream is taken until it is purged
+        if self.stream_exists(stream_name) || DELETED_STREAMS.contains_key(stream_name) {
+            return Err(Error::StreamAlreadyExists(stream_name.to_owned()));
+        }
+
+        storage.create_stream(stream_name).await?;
+        storage
+            .put_metadata(stream_name, &MetadataDocument::default())
+            .await?;
+        if let Some(schema) = &meta.schema {
+            storage.put_schema_version(stream_name, 1, schema).await?;
+            storage.put_schema(stream_name.to_owned(), schema).await?;
+        }
+        if meta.alert_config != Alerts::default() {
+            storage
+                .create_alert(stream_name, &meta.alert_config)
+                .await?;
+        }
+        // the schema has to be in object storage before the flag, a static
+        // stream without a schema would reject all of its events after a restart
+        if meta.static_schema {
+            storage.put_static_schema(stream_name, true).await?;
+        }
+        if !meta.tags.is_empty() {
+            storage.put_tags(stream_name, &meta.tags).await?;
+        }
+        if let Some(retention) = meta.retention {
+            storage
+                .put_retention(stream_name, &Retention::from(retention))
+                .await?;
+        }
+        if let Some(time_field) = &meta.time_field {
+            storage.put_time_field(stream_name, time_field).await?;
+        }
+        if !meta.limits.is_empty() {
+            storage.put_limits(stream_name, &meta.limits).await?;
+        }
+        if let Some(compression) = meta.compression {
+            storage.put_compression(stream_name, compression).await?;
+        }
+        if meta.flatten != Flatten::default() {
+            storage.put_flatten(stream_name, meta.flatten).await?;
+        }
+        if meta.partition != PartitionGranularity::default() {
+            storage.put_partition(stream_name, meta.partition).await?;
+        }
+
+        let meta = LogStreamMetadata {
+            schema_version: meta.schema.is_some().into(),
+            created_at: Some(Utc::now()),
+            ..meta
+        };
+        // another request may have created the stream meanwhile
+        match self.entry(stream_name.to_owned()) {
+            Entry::Occupied(_) => return Err(Error::StreamAlreadyExists(stream_name.to_owned())),
+            Entry::Vacant(entry) => {
+                entry.insert(meta.clone());
+            }
+        }
+
+        // a concurrent delete or replace may have changed the entry right after the insert
+        self.confirm_inserted(stream_name, &meta)
+    }
+
+    // Whether the stream is still the one inserted with `inserted`. Only the fields set
+    // when the stream is created are compared, as stats change with every event.
+    fn confirm_inserted(
+        &self,
+        stream_name: &str,
+        inserted: &LogStreamMetadata,
+    ) -> Result<(), Error> {
+        match self.get(stream_name) {
+            Some(stored)
+                if stored.schema == inserted.schema
+                    && stored.alert_config == inserted.alert_config
+                    && stored.created_at == inserted.created_at =>
+            {
+                Ok(())
+            }
+            _ => Err(Error::InsertFailed(stream_name.to_owned())),
+        }
+    }
+
+    /// Add a stream or replace the metadata of an existing one, without object storage.
+    #[cfg(test)]
+    pub fn add_stream(
+        &self,
+        stream_name: String,
+        schema: Option<Schema>,
+        alert_config: Alerts,
+    ) -> Result<(), Error> {
+        validator::stream_name(&stream_name)?;
+
+        let metadata = LogStreamMetadata {
+            schema_version: schema.is_some().into(),
+            schema: schema.map(Arc::new),
+            alert_config,
+            created_at: Some(Utc::now()),
+            ..Default::default()
+        };
+        self.insert(stream_name, metadata);
+
+        Ok(())
+    }
+
+    /// Delete the stream along with all of its data in object storage. The stream
+    /// is only removed from the map once object storage confirms that none of its
+    /// data is left, so that a failed delete can be retried.
+    pub async fn purge_stream(
+        &self,
+        storage: &dyn ObjectStorage,
+        stream_name: &str,
+    ) -> Result<(), Error> {
+        storage.delete_stream(stream_name).await?;
+        DELETED_STREAMS.remove(stream_name);
+        self.delete_stream(stream_name)
+    }
+
+    /// Remove the stream from the map only, its data in object storage is left as is.
+    /// `purge_stream` deletes both and confirms that no object of the stream is left.
+    pub fn delete_stream(&self, stream_name: &str) -> Result<(), Error> {
+        self.remove(stream_name);
+        COOLDOWNS.remove_stream(stream_name);
+
+        Ok(())
+    }
+
+    /// Rename the stream along with its data in object storage and the data under
+    /// `local_root` not synced yet. The stream doesn't take events until the rename
+    /// is done. A rename that failed halfway, even one interrupted by a restart, is
+    /// completed by running it again.
+    pub async fn rename_stream(
+        &self,
+        storage: &dyn ObjectStorage,
+        local_root: &Path,
+        from: &str,
+        to: &str,
+    ) -> Result<(), Error> {
+        self.start_rename(storage, from, to).await?;
+        self.finish_rename(storage, local_root, from, to).await
+    }
+
+    /// First step of `rename_stream`, from which on the stream doesn't take events.
+    /// Events buffered before are to be written before the rename is finished.
+    pub async fn start_rename(
+        &self,
+        storage: &dyn ObjectStorage,
+        from: &str,
+        to: &str,
+    ) -> Result<(), Error> {
+        validator::stream_name(to)?;
+
+        // the target of an incomplete rename is loaded as a stream of its own after a restart
+        let resumed = matches!(storage.get_renamed_from(to).await, Ok(source) if source == from);
+        let taken = self.stream_exists(to) || DELETED_STREAMS.contains_key(to);
+        if from == to || (taken && !resumed) {
+            return Err(Error::StreamAlreadyExists(to.to_owned()));
+        }
+
+        let mut meta = self
+            .get_mut(from)
+            .ok_or(Error::StreamMetaNotFound(from.to_owned()))?;
+        if meta.renaming_to.is_some() {
+            return Err(Error::RenameInProgress(from.to_owned()));
+        }
+        meta.renaming_to = Some(to.to_owned());
+
+        Ok(())
+    }
+
+    /// Second step of `rename_stream`, moving the data of a stream `start_rename`
+    /// was called for. The stream takes events again under its new name once done.
+    pub async fn finish_rename(
+        &self,
+        storage: &dyn ObjectStorage,
+        local_root: &Path,
+        from: &str,
+        to: &str,
+    ) -> Result<(), Error> {
+        if let Err(e) = move_stream_data(storage, local_root, from, to).await {
+            if let Some(mut meta) = self.get_mut(from) {
+                meta.renaming_to = None;
+            }
+            return Err(e);
+        }
+
+        // the new name takes events before the old one is removed
+        let mut meta = self
+            .get(from)
+            .map(|meta| meta.clone())
+            .ok_or(Error::StreamMetaNotFound(from.to_owned()))?;
+        meta.renaming_to = None;
+        self.insert(to.to_owned(), meta);
+        self.remove(from);
+
+        Ok(())
+    }
+
+    /// Delete the stream but keep its data in object storage, so that it can be
+    /// restored with `undelete_stream` until `purge_deleted_streams` removes it.
+    /// A soft deleted stream is neither listed nor takes events.
+    pub async fn soft_delete_stream(
+        &self,
+        storage: &dyn ObjectStorage,
+        stream_name: &str,
+    ) -> Result<(), Error> {
+        if !self.stream_exists(stream_name) {
+            return Err(Error::StreamMetaNotFound(stream_name.to_owned()));
+        }
+
+        let deleted_at = Utc::now();
+        storage
+            .put_deleted_at(stream_name, Some(deleted_at))
+            .await?;
+
+        let (stream_name, mut meta) = self
+            .remove(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+        meta.deleted_at = Some(deleted_at);
+        DELETED_STREAMS.insert(stream_name, meta);
+
+        Ok(())
+    }
+
+    /// Restore a stream deleted with `soft_delete_stream`, as it was before.
+    pub async fn undelete_stream(
+        &self,
+        storage: &dyn ObjectStorage,
+        stream_name: &str,
+    ) -> Result<(), Error> {
+        if !DELETED_STREAMS.contains_key(stream_name) {
+            return Err(Error::DeletedStreamNotFound(stream_name.to_owned()));
+        }
+
+        storage.put_deleted_at(stream_name, None).await?;
+
+        let (stream_name, mut meta) = DELETED_STREAMS
+            .remove(stream_name)
+            .ok_or(Error::DeletedStreamNotFound(stream_name.to_owned()))?;
+        meta.deleted_at = None;
+        self.insert(stream_name, meta);
+
+        Ok(())
+    }
+
+    /// Returns the names of soft deleted streams whose grace period is over at `now`.
+    pub fn expired_deletions(&self, now: DateTime<Utc>, grace_period: Duration) -> Vec<String> {
+        DELETED_STREAMS
+            .iter()
+            .filter(|entry| {
+                entry
+                    .deleted_at
+                    .map_or(false, |deleted_at| deleted_at + grace_period <= now)
+            })
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    /// Permanently delete soft deleted streams whose grace period is over at `now`.
+    /// Streams that fail to be purged are retried with the next run.
+    pub async fn purge_deleted_streams(
+        &self,
+        storage: &dyn ObjectStorage,
+        now: DateTime<Utc>,
+        grace_period: Duration,
+    ) {
+        for stream_name in self.expired_deletions(now, grace_period) {
+            // the stream may have been restored meanwhile
+            if !DELETED_STREAMS.contains_key(&stream_name) {
+                continue;
+            }
+
+            match self.purge_stream(storage, &stream_name).await {
+                Ok(()) => info!("purged deleted log stream {}", stream_name),
+                Err(e) => warn!(
+                    "failed to purge deleted log stream {}. {:?}",
+                    stream_name, e
+                ),
+            }
+        }
+    }
+
+    /// Populate the map with metadata of all streams found in object storage.
+    /// Streams with broken metadata don't fail the load, they are loaded with
+    /// whatever is available and flagged as degraded. Such failures are logged
+    /// and can be retrieved later with `load_errors`.
+    #[tracing::instrument(name = "load_streams", skip_all, fields(streams))]
+    pub async fn load(&self, storage: &dyn ObjectStorage) -> Result<(), Error> {
+        self.load_concurrently(storage, CONFIG.parseable.load_concurrency)
+            .await
+    }
+
+    /// Fetch metadata of up to `concurrency` streams at a time, so that
+    /// one slow stream doesn't hold up loading the others. Streams are listed
+    /// a page at a time, each page loaded before the next is listed.
+    async fn load_concurrently(
+        &self,
+        storage: &dyn ObjectStorage,
+        concurrency: usize,
+    ) -> Result<(), Error> {
+        let mut loaded: u64 = 0;
+        let mut continuation_token: Option<String> = None;
+        loop {
+            let page = storage
+                .list_streams_paged(continuation_token.as_deref(), STREAMS_PAGE_SIZE)
+                .await?;
+            let stream_list = page.streams.into_iter().filter(is_stream);
+            let mut streams = stream::iter(stream_list)
+                .map(|stream| load_stream(storage, stream.name))
+                .buffer_unordered(concurrency.max(1));
+
+            while let Some(stream) = streams.next().await {
+                let (stream_name, metadata) = stream?;
+                loaded += 1;
+                for e in &metadata.load_errors {
+                    warn!(
+                        "failed to load metadata of log stream {}. {}",
+                        stream_name, e
+                    );
+                }
+
+                if metadata.deleted_at.is_some() {
+                    DELETED_STREAMS.insert(stream_name, metadata);
+                } else {
+                    self.insert(stream_name, metadata);
+                }
+            }
+
+            match page.next_token {
+                Some(token) => continuation_token = Some(token),
+                None => break,
+            }
+        }
+        tracing::Span::current().record("streams", &loaded);
+
+        Ok(())
+    }
+
+    /// Read the metadata of the stream from object storage again and replace the
+    /// one in memory with it, returning what changed. Useful when another server
+    /// shares the bucket and changed the stream.
+    pub async fn refresh_stream(
+        &self,
+        storage: &dyn ObjectStorage,
+        stream_name: &str,
+    ) -> Result<Vec<MetadataChange>, Error> {
+        if !self.stream_exists(stream_name) {
+            return Err(Error::StreamMetaNotFound(stream_name.to_owned()));
+        }
+
+        // a stream missing from object storage would be refreshed to an empty one
+        match storage.get_schema(stream_name).await {
+            Ok(_) => {}
+            Err(ObjectStorageError::NoSuchKey(_)) => {
+                return Err(Error::SchemaNotInStore(stream_name.to_owned()))
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let (stream_name, refreshed) = load_stream(storage, stream_name.to_owned()).await?;
+        self.replace_refreshed(&stream_name, refreshed)
+            .ok_or(Error::StreamMetaNotFound(stream_name))
+    }
+
+    pub async fn refresh(&self, storage: &dyn ObjectStorage) -> Result<RefreshReport, Error> {
+        self.refresh_concurrently(storage, CONFIG.parseable.load_concurrency)
+            .await
+    }
+
+    /// Refresh all streams from object storage like `refresh_stream`, adding the
+    /// streams created and removing the ones deleted by another server.
+    async fn refresh_concurrently(
+        &self,
+        storage: &dyn ObjectStorage,
+        concurrency: usize,
+    ) -> Result<RefreshReport, Error> {
+        // streams created here while object storage is listed must not be removed
+        let known: Vec<String> = self.iter().map(|entry| entry.key().clone()).collect();
+        let stored: HashSet<String> = storage
+            .list_streams()
+            .await?
+            .into_iter()
+            .filter(is_stream)
+            .map(|stream| stream.name)
+            .collect();
+
+        let mut report = RefreshReport::default();
+        let mut streams = stream::iter(stored.iter().cloned())
+            .map(|stream_name| load_stream(storage, stream_name))
+            .buffer_unordered(concurrency.max(1));
+
+        while let Some(stream) = streams.next().await {
+            let (stream_name, refreshed) = stream?;
+            let deleted = refreshed.deleted_at.is_some();
+            match self.replace_refreshed(&stream_name, refreshed) {
+                Some(_) if deleted => report.removed.push(stream_name),
+                Some(changes) => report.changed.extend(changes),
+                // soft deleted streams are only restored with undelete
+                None if deleted || DELETED_STREAMS.contains_key(&stream_name) => {}
+                None => report.added.push(stream_name),
+            }
+        }
+
+        for stream_name in known {
+            if !stored.contains(&stream_name) && self.remove(&stream_name).is_some() {
+                report.removed.push(stream_name);
+            }
+        }
+
+        report.changed.sort_by(|a, b| a.stream.cmp(&b.stream));
+        report.added.sort();
+        report.removed.sort();
+
+        Ok(report)
+    }
+
+    // Swap in the metadata refreshed from object storage, keeping stats updates
+    // that aren't in object storage yet. A stream that isn't in the map is added
+    // instead, unless it's soft deleted, and `None` is returned.
+    fn replace_refreshed(
+        &self,
+        stream_name: &str,
+        mut refreshed: LogStreamMetadata,
+    ) -> Option<Vec<MetadataChange>> {
+        let changes = match self.entry(stream_name.to_owned()) {
+            Entry::Occupied(mut entry) => {
+                let meta = entry.get_mut();
+                refreshed.stats = merge_stats(&meta.stats, refreshed.stats);
+                refreshed.created_at = refreshed.created_at.or(meta.created_at);
+                refreshed.first_event_at = refreshed.first_event_at.or(meta.first_event_at);
+                let changes = metadata_changes(stream_name, meta, &refreshed);
+                *meta = refreshed;
+                changes
+            }
+            Entry::Vacant(entry) => {
+                if refreshed.deleted_at.is_none() && !DELETED_STREAMS.contains_key(stream_name) {
+                    entry.insert(refreshed);
+                }
+                return None;
+            }
+        };
+
+        // soft deleted by another server
+        if let Some((stream_name, meta)) =
+            self.remove_if(stream_name, |_, meta| meta.deleted_at.is_some())
+        {
+            DELETED_STREAMS.insert(stream_name, meta);
+        }
+
+        Some(changes)
+    }
+
+    /// Record the result of syncing parquet files of the stream to object storage.
+    pub fn record_sync(
+        &self,
+        stream_name: &str,
+        result: Result<(), &ObjectStorageError>,
+    ) -> Result<(), Error> {
+        let mut stream = self
+            .get_mut(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+
+        match result {
+            Ok(()) => stream.stats.record_sync_success(),
+            Err(e) => stream.stats.record_sync_failure(e.to_string(), Utc::now()),
+        }
+
+        Ok(())
+    }
+
+    /// Whether the last few syncs of every stream failed, that is no data is
+    /// reaching object storage.
+    pub fn sync_failing(&self) -> bool {
+        self.len() > 0
+            && self
+                .iter()
+                .all(|entry| entry.stats.failed_syncs_in_row >= SYNC_FAILURES_DEGRADED)
+    }
+
+    /// Returns the log streams that failed to load completely during server
+    /// start up, along with the reason, ordered by stream name.
+    pub fn load_errors(&self) -> Vec<(String, LoadError)> {
+        let mut errors = self
+            .iter()
+            .flat_map(|entry| {
+                entry
+                    .load_errors
+                    .iter()
+                    .map(|e| (entry.key().clone(), e.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        errors.sort_by(|a, b| a.0.cmp(&b.0));
+        errors
+    }
+
+    /// Whether the schema of the stream is present in object storage but couldn't be
+    /// loaded. Such a stream must not take a new schema from the next event.
+    pub fn is_schema_invalid(&self, stream_name: &str) -> Result<bool, Error> {
+        let meta = self
+            .get(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+
+        Ok(meta.load_errors.contains(&LoadError::InvalidSchema))
+    }
+
+    /// Returns a copy of the stats of all streams that changed since they were last
+    /// put to object storage, so that they can be persisted without holding the lock.
+    pub fn unsynced_stats(&self) -> Vec<(String, Stats)> {
+        self.iter()
+            .filter(|entry| !entry.stats.is_synced())
+            .map(|entry| (entry.key().clone(), entry.stats.clone()))
+            .collect()
+    }
+
+    /// Like `unsynced_stats`, for the streams whose stats are due to be put right away.
+    pub fn due_stats(&self) -> Vec<(String, Stats)> {
+        self.iter()
+            .filter(|entry| entry.stats.is_sync_due())
+            .map(|entry| (entry.key().clone(), entry.stats.clone()))
+            .collect()
+    }
+
+    /// Account for data of the stream removed from object storage.
+    pub fn remove_compressed(&self, stream_name: &str, compressed_size: u64) -> Result<(), Error> {
+        let mut stream = self
+            .get_mut(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+
+        stream.stats.remove_compressed(compressed_size);
+
+        Ok(())
+    }
+
+    /// Record that the parquet file with `key` was uploaded for the stream.
+    pub fn record_upload(&self, stream_name: &str, key: String) -> Result<(), Error> {
+        let mut stream = self
+            .get_mut(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+
+        stream.stats.record_upload(key);
+
+        Ok(())
+    }
+
+    pub fn record_compaction(
+        &self,
+        stream_name: &str,
+        files: u64,
+        source_size: u64,
+        merged_size: u64,
+        time: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let mut stream = self
+            .get_mut(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+
+        stream
+            .stats
+            .record_compaction(files, source_size, merged_size, time);
+
+        Ok(())
+    }
+
+    pub fn record_corrupted_upload(&self, stream_name: &str) -> Result<(), Error> {
+        let mut stream = self
+            .get_mut(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+
+        stream.stats.record_corrupted_upload();
+
+        Ok(())
+    }
+
+    pub fn record_quarantine(&self, stream_name: &str) -> Result<(), Error> {
+        let mut stream = self
+            .get_mut(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+
+        stream.stats.record_quarantine();
+
+        Ok(())
+    }
+
+    /// Rebuild stats of the stream from its parquet files in object storage, for when they
+    /// drifted from what is stored, e.g. after a migration. Row counts of the files are taken
+    /// from the manifest of the stream, files missing from it are downloaded to read theirs.
+    /// The stream is only locked to swap in the result. Returns a copy of the updated stats.
+    pub async fn recompute_stats(
+        &self,
+        stream_name: &str,
+        storage: &(impl ObjectStorage + ?Sized),
+    ) -> Result<Stats, Error> {
+        if !self.stream_exists(stream_name) {
+            return Err(Error::StreamMetaNotFound(stream_name.to_owned()));
+        }
+
+        let prefix = format!("{}/", stream_name);
+        let files = storage.list_parquet_files(&prefix).await?;
+        let manifest = manifest::load(storage, stream_name)
+            .await
+            .map_err(|e| e.to_string());
+        let manifest = match manifest {
+            Ok(manifest) => manifest.unwrap_or_default(),
+            Err(e) => {
+                warn!(
+                    "failed to load manifest of log stream {}. {}",
+                    stream_name, e
+                );
+                Manifest::default()
+            }
+        };
+
+        let mut stored = StoredObjects::default();
+        let mut events = Some(0);
+        for file in files {
+            stored.objects += 1;
+            stored.size += file.size;
+
+            let key = file.key.strip_prefix(&prefix).unwrap_or(&file.key);
+            let rows = match manifest.files.iter().find(|entry| entry.key == key) {
+                Some(entry) => Some(entry.rows),
+                None => row_count(storage, &file.key).await,
+            };
+            events = events.zip(rows).map(|(events, rows)| events + rows);
+        }
+
+        let mut stream = self
+            .get_mut(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+
+        stream.stats.recompute(stored, events);
+
+        Ok(stream.stats.clone())
+    }
+
+    /// Record that stats up to the given sequence are persisted in object storage.
+    pub fn set_stats_synced(&self, stream_name: &str, sequence: u64) -> Result<(), Error> {
+        let mut stream = self
+            .get_mut(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+
+        stream.stats.synced_sequence = stream.stats.synced_sequence.max(sequence);
+
+        Ok(())
+    }
+
+    /// Update stats of the stream with `events` events received just now. Stats are
+    /// put to object storage by the stats sync, once they are due.
+    pub fn update_stats(
+        &self,
+        stream_name: &str,
+        size: u64,
+        compressed_size: u64,
+        events: u64,
+    ) -> Result<(), Error> {
+        let mut stream = self
+            .get_mut(stream_name)
+            .ok_or(Error::StreamMetaNotFound(stream_name.to_owned()))?;
+
+        stream.record_update(size, compressed_size, events, Utc::now());
+
+        Ok(())
+    }
+
+    /// Apply stats updates to several streams at once, as `(stream_name, size,
+    /// compressed_size, events)`. Streams that don't exist are skipped and reported
+    /// together after all other updates are applied.
+    pub fn update_stats_batch(&self, updates: &[(String, u64, u64, u64)]) -> Result<(), Error> {
+        let now = Utc::now();
+        let mut missing = Vec::new();
+
+        for (stream_name, size, compressed_size, events) in updates {
+            match self.get_mut(stream_name) {
+                Some(mut stream) => {
+                    stream.record_update(*size, *compressed_size, *events, now);
+                }
+                None => missing.push(stream_name.clone()),
+            }
+        }
+
+        if !missing.is_empty() {
+            return Err(Error::StreamsMetaNotFound(missing));
+        }
+
+        Ok(())
+    }
+}
+
+// Whether the listed prefix is a stream, rather than objects parseable keeps for
+// itself, like a probe left over from a startup check.
+fn is_stream(stream: &LogStream) -> bool {
+    stream.name != RESERVED_PREFIX.trim_end_matches('/')
+}
+
+/// Migrate metadata of a single stream in object storage to the current version
+/// and fetch it. Only metadata of an unknown version fails loading the stream.
+#[tracing::instrument(skip(storage))]
+async fn load_stream(
+    storage: &dyn ObjectStorage,
+    stream_name: String,
+) -> Result<(String, LogStreamMetadata), Error> {
+    match migration::migrate(storage, &stream_name).await {
+        Ok(()) => {}
+        Err(e @ Error::UnsupportedMetadataVersion(..)) => return Err(e),
+        Err(e) => warn!(
+            "failed to migrate metadata of log stream {}. {}",
+            stream_name, e
+        ),
+    }
+
+    Ok(fetch_stream_metadata(storage, stream_name).await)
+}
+
+/// Move data of the stream that is not synced yet to the local dir of the new name,
+/// so that it's synced to the new prefix, then move the objects of the stream.
+async fn move_stream_data(
+    storage: &dyn ObjectStorage,
+    local_root: &Path,
+    from: &str,
+    to: &str,
+) -> Result<(), Error> {
+    let local_from = local_root.join(from);
+    if local_from.exists() {
+        fs::rename(local_from, local_root.join(to))?;
+    }
+
+    storage.rename_stream(from, to).await?;
+
+    Ok(())
+}
+
+// Stats in memory may have updates that aren't synced to object storage yet, the
+// stats in object storage only replace them if they are newer.
+fn merge_stats(existing: &Stats, stored: Stats) -> Stats {
+    if stored.sequence > existing.sequence {
+        Stats {
+            ingest_rate: existing.ingest_rate.clone(),
+            failed_syncs_in_row: existing.failed_syncs_in_row,
+            ..stored
+        }
+    } else {
+        existing.clone()
+    }
+}
+
+fn metadata_changes(
+    stream_name: &str,
+    existing: &LogStreamMetadata,
+    refreshed: &LogStreamMetadata,
+) -> Vec<MetadataChange> {
+    fn value(value: &impl Serialize) -> serde_json::Value {
+        serde_json::to_value(value).unwrap_or_default()
+    }
+
+    let settings = [
+        (
+            "schema",
+            value(&existing.schema.as_deref()),
+            value(&refreshed.schema.as_deref()),
+        ),
+        (
+            "static_schema",
+            value(&existing.static_schema),
+            value(&refreshed.static_schema),
+        ),
+        (
+            "alerts",
+            value(&existing.alert_config),
+            value(&refreshed.alert_config),
+        ),
+        (
+            "retention",
+            value(&existing.retention.map(Retention::from)),
+            value(&refreshed.retention.map(Retention::from)),
+        ),
+        ("tags", value(&existing.tags), value(&refreshed.tags)),
+        (
+            "time_field",
+            value(&existing.time_field),
+            value(&refreshed.time_field),
+        ),
+        ("limits", value(&existing.limits), value(&refreshed.limits)),
+        (
+            "compression",
+            value(&existing.compression),
+            value(&refreshed.compression),
+        ),
+        (
+            "flatten",
+            value(&existing.flatten),
+            value(&refreshed.flatten),
+        ),
+        (
+            "partition",
+            value(&existing.partition),
+            value(&refreshed.partition),
+        ),
+        ("stats", value(&existing.stats), value(&refreshed.stats)),
+        (
+            "deleted_at",
+            value(&existing.deleted_at),
+            value(&refreshed.deleted_at),
+        ),
+    ];
+
+    settings
+        .into_iter()
+        .filter(|(_, existing, refreshed)| existing != refreshed)
+        .map(|(setting, existing, refreshed)| MetadataChange {
+            stream: stream_name.to_owned(),
+            setting,
+            existing,
+            refreshed,
+        })
+        .collect()
+}
+
+/// Fetch metadata of a single stream from object storage. A stream is always
+/// returned, whatever failed to load is recorded in its `load_errors`.
+async fn fetch_stream_metadata(
+    storage: &dyn ObjectStorage,
+    stream_name: String,
+) -> (String, LogStreamMetadata) {
+    let mut load_errors = Vec::new();
+
+    // Ignore S3 errors here, because we are just trying
+    // to load the stream metadata based on whatever is available.
+    let alert_config = match storage.get_alert(&stream_name).await {
+        Ok(bytes) => parse_alerts(bytes).unwrap_or_else(|e| {
+            load_errors.push(LoadError::InvalidAlert(e.to_string()));
+            Alerts::default()
+        }),
+        // alert is only put to storage once it is set for the stream
+        Err(_) => Alerts::default(),
+    };
+
+    let schema = match storage.get_schema(&stream_name).await {
+        Ok(bytes) => parse_schema(bytes).unwrap_or_else(|_| {
+            load_errors.push(LoadError::InvalidSchema);
+            None
+        }),
+        // A missing schema is committed again with the next event.
+        Err(_) => {
+            load_errors.push(LoadError::SchemaNotInStore);
+            None
+        }
+    };
+
+    // versions of the schema are put from 1 on, the schema of a stream that had one
+    // before versions were kept becomes its first version
+    let schema_version = match storage
+        .count_objects(&format!("{}/schema/", stream_name))
+        .await
+        .map_err(|e| e.to_string())
+    {
+        Ok(0) => match &schema {
+            Some(schema) => match storage.put_schema_version(&stream_name, 1, schema).await {
+                Ok(()) => 1,
+                Err(e) => {
+                    warn!(
+                        "failed to put schema version of log stream {}. {:?}",
+                        stream_name, e
+                    );
+                    0
+                }
+            },
+            None => 0,
+        },
+        Ok(versions) => versions as u32,
+        Err(e) => {
+            warn!(
+                "failed to count schema versions of log stream {}. {}",
+                stream_name, e
+            );
+            schema.is_some().into()
+        }
+    };
+
+    // stats are only put to storage after the first stats sync, the parquet
+    // files already uploaded are counted from object storage until then
+    let stats = match storage.get_stats(&stream_name).await {
+        Ok(stats) => stats.restore(),
+        Err(_) => Stats {
+            parquet_files: storage
+                .parquet_objects(&format!("{}/", stream_name))
+                .await
+                .map_or(0, |stored| stored.objects),
+            ..Default::default()
+        },
+    };
+
+    // retention is only put to storage once it is set for the stream
+    let retention = storage
+        .get_retention(&stream_name)
+        .await
+        .ok()
+        .map(|retention| retention.duration());
+
+    // tags are only put to storage once they are set for the stream
+    let tags = storage.get_tags(&stream_name).await.unwrap_or_default();
+
+    // the static schema flag is only put to storage for streams created with a schema
+    let static_schema = storage
+        .get_static_schema(&stream_name)
+        .await
+        .unwrap_or_default();
+
+    // timestamps are put to storage when the stream is created and when its first
+    // event arrives, older streams get them from their migration
+    let timestamps = storage
+        .get_timestamps(&stream_name)
+        .await
+        .unwrap_or_default();
+
+    // the time field is only put to storage for streams created with one
+    let time_field = storage.get_time_field(&stream_name).await.ok();
+
+    // limits are only put to storage once they are set for the stream
+    let limits = storage.get_limits(&stream_name).await.unwrap_or_default();
+
+    // compression is only put to storage once it is set for the stream
+    let compression = storage.get_compression(&stream_name).await.ok();
+
+    // flattening is only put to storage once it is set for the stream
+    let flatten = storage.get_flatten(&stream_name).await.unwrap_or_default();
+
+    // partitioning is only put to storage for streams created with a granularity
+    let partition = storage
+        .get_partition(&stream_name)
+        .await
+        .unwrap_or_default();
+
+    // streams are only marked in storage once they are soft deleted
+    let deleted_at = storage
+        .get_deleted_at(&stream_name)
+        .await
+        .unwrap_or_default();
+
+    let metadata = LogStreamMetadata {
+        schema: schema.map(Arc::new),
+        schema_version,
+        static_schema,
+        alert_config,
+        stats,
+        retention,
+        created_at: timestamps.created_at,
+        first_event_at: timestamps.first_event_at,
+        tags,
+        time_field,
+        deleted_at,
+        limits,
+        compression,
+        flatten,
+        partition,
+        renaming_to: None,
+        load_errors,
+    };
+
+    (stream_name, metadata)
+}
+
+/// Rows of the parquet file at `key`, None if it can't be read
+async fn row_count(storage: &(impl ObjectStorage + ?Sized), key: &str) -> Option<u64> {
+    let rows = match storage.get_object(key).await {
+        Ok(body) => manifest::row_count(body).map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+
+    match rows {
+        Ok(rows) => Some(rows),
+        Err(e) => {
+            warn!("failed to read row count of {}. {}", key, e);
+            None
+        }
+    }
+}
+
+/// Add the labels field to a static schema if it's not there already. The labels
+/// of the request are added to every event, so every stream schema must have it.
+pub fn with_labels_field(mut schema: Schema) -> Schema {
+    if schema.field_with_name(utils::LABELS_FIELD).is_err() {
+        let mut fields = schema.fields().clone();
+        fields.push(Field::new(utils::LABELS_FIELD, DataType::Utf8, true));
+        schema = Schema::new_with_metadata(fields, schema.metadata().clone());
+    }
+
+    schema
+}
+
+fn parse_string(bytes: Bytes) -> Result<String, Error> {
+    String::from_utf8(bytes.to_vec()).map_err(|e| e.into())
+}
+
+/// Merge two schemas into one that can hold events of both. Fields are kept in the
+/// order of `current`, new fields from `incoming` are appended at the end.
+/// Int64 and Float64 fields are widened to Float64, any other type conflict is an error.
+pub fn merge_schemas(current: &Schema, incoming: &Schema) -> Result<Schema, Error> {
+    let mut fields: Vec<Field> = current.fields().clone();
+
+    for new_field in incoming.fields() {
+        match fields.iter_mut().find(|f| f.name() == new_field.name()) {
+            Some(field) => {
+                let data_type = merge_data_types(field, new_field)?;
+                let nullable = field.is_nullable() || new_field.is_nullable();
+                *field = Field::new(field.name(), data_type, nullable);
+            }
+            None => fields.push(new_field.clone()),
+        }
+    }
+
+    Ok(Schema::new_with_metadata(
+        fields,
+        current.metadata().clone(),
+    ))
+}
+
+/// A field whose type in an incoming schema can't be merged with its stored type
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct ConflictingField {
+    pub name: String,
+    pub stored: DataType,
+    pub incoming: DataType,
+}
+
+/// What merging an incoming schema into the stored one would do, without merging them
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct SchemaDiff {
+    /// Fields the stored schema doesn't have, that would be appended to it
+    pub added: Vec<Field>,
+    /// Fields that would fail the merge, and the events they come with
+    pub conflicting: Vec<ConflictingField>,
+}
+
+/// Compare `incoming` with the `current` schema, like `merge_schemas` would merge them
+pub fn diff_schemas(current: &Schema, incoming: &Schema) -> SchemaDiff {
+    let mut diff = SchemaDiff::default();
+
+    for new_field in incoming.fields() {
+        match current.field_with_name(new_field.name()) {
+            Ok(field) => {
+                if merge_data_types(field, new_field).is_err() {
+                    diff.conflicting.push(ConflictingField {
+                        name: new_field.name().to_owned(),
+                        stored: field.data_type().clone(),
+                        incoming: new_field.data_type().clone(),
+                    });
+                }
+            }
+            Err(_) => diff.added.push(new_field.clone()),
+        }
+    }
+
+    diff
+}
+
+fn merge_data_types(current: &Field, incoming: &Field) -> Result<DataType, Error> {
+    match (current.data_type(), incoming.data_type()) {
+        (left, right) if left == right => Ok(left.clone()),
+        (DataType::Null, other) | (other, DataType::Null) => Ok(other.clone()),
+        (DataType::Int64, DataType::Float64) | (DataType::Float64, DataType::Int64) => {
+            Ok(DataType::Float64)
+        }
+        (left, right) => Err(Error::IncompatibleField(
+            current.name().to_owned(),
+            left.clone(),
+            right.clone(),
+        )),
+    }
+}
+
+// Alert is stored as a JSON string in object storage, an empty
+// object means no alert is set for the stream.
+fn parse_alerts(bytes: Bytes) -> Result<Alerts, Error> {
+    if bytes.is_empty() {
+        return Ok(Alerts::default());
+    }
+
+    let alerts = serde_json::from_str(&parse_string(bytes)?)?;
+    validator::alert(&alerts)?;
+
+    Ok(alerts)
+}
+
+// Schema is stored as a JSON string in object storage, an empty
+// object means no event has been sent to the stream yet.
+fn parse_schema(bytes: Bytes) -> Result<Option<Schema>, Error> {
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+
+    let schema = serde_json::from_slice(&bytes)?;
+
+    Ok(Some(schema))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maplit::hashmap;
+    use rstest::*;
+    use serial_test::serial;
+    use std::collections::HashMap;
+
+    use std::fs;
+
+    use crate::localfs::LocalStorage;
+    use crate::memory::MemoryStorage;
+    use crate::storage::mock::MockStorage;
+    use crate::storage::ObjectStorageError;
+
+    #[rstest]
+    #[case::minute(PartitionGranularity::Minute, "date=2022-10-15/hour=10/minute=05/")]
+    #[case::hour(PartitionGranularity::Hour, "date=2022-10-15/hour=10/")]
+    #[case::day(PartitionGranularity::Day, "date=2022-10-15/")]
+    fn partition_prefix(#[case] granularity: PartitionGranularity, #[case] expected: &str) {
+        let time = DateTime::parse_from_rfc3339("2022-10-15T10:05:30+00:00")
+            .unwrap()
+            .into();
+
+        assert_eq!(granularity.prefix(time), expected);
+        assert_eq!(
+            granularity.truncate("stream/date=2022-10-15/hour=10/minute=05/"),
+            format!("stream/{}", expected)
+        );
+    }
+
+    #[test]
+    fn partition_defaults_to_minute() {
+        assert_eq!(
+            PartitionGranularity::default(),
+            PartitionGranularity::Minute
+        );
+        assert_eq!("hour".parse(), Ok(PartitionGranularity::Hour));
+        assert!("week".parse::<PartitionGranularity>().is_err());
+
+        let settings: StreamSettings = serde_json::from_str(r#"{"partition": "day"}"#).unwrap();
+        assert_eq!(settings.partition, Some(PartitionGranularity::Day));
+    }
+
+    #[rstest]
+    #[case::zero(0, 0, 0, 0)]
+    #[case::some(1024, 512, 2048, 10)]
+    fn update_stats(
+        #[case] size: u64,
+        #[case] compressed_size: u64,
+        #[case] prev_compressed: u64,
+        #[case] events: u64,
+    ) {
+        let mut stats = Stats {
+            size,
+            compressed_size,
+            events,
+            prev_compressed,
+            ..Default::default()
+        };
+        let time: DateTime<Utc> = DateTime::parse_from_rfc3339("2022-10-15T10:00:00+00:00")
+            .unwrap()
+            .into();
+
+        stats.update(2056, 2000);
+        stats.record_events(3, time);
+
+        assert_eq!(
+            stats,
+            Stats {
+                size: size + 2056,
+                compressed_size: prev_compressed + 2000,
+                events: events + 3,
+                last_event_at: Some(time),
+                sequence: 1,
+                prev_compressed,
+                ..Default::default()
+            }
+        )
+    }
+
+    #[rstest]
+    #[case::some(2048, 1024, 512, 1536, 512)]
+    #[case::saturate(512, 512, 1024, 0, 0)]
+    fn remove_compressed(
+        #[case] compressed_size: u64,
+        #[case] prev_compressed: u64,
+        #[case] removed: u64,
+        #[case] compressed_left: u64,
+        #[case] prev_left: u64,
+    ) {
+        let mut stats = Stats {
+            compressed_size,
+            prev_compressed,
+            ..Default::default()
+        };
+
+        stats.remove_compressed(removed);
+
+        assert_eq!(stats.compressed_size, compressed_left);
+        assert_eq!(stats.prev_compressed, prev_left);
+        assert!(!stats.is_synced());
+    }
+
+    #[test]
+    fn record_upload() {
+        let mut stats = Stats::default();
+
+        stats.record_upload("stream/date=2022-10-15/hour=10/a.parquet".to_string());
+        stats.record_upload("stream/date=2022-10-15/hour=10/b.parquet".to_string());
+
+        assert_eq!(stats.parquet_files, 2);
+        assert_eq!(
+            stats.latest_object.as_deref(),
+            Some("stream/date=2022-10-15/hour=10/b.parquet")
+        );
+        assert!(!stats.is_synced());
+    }
+
+    #[rstest]
+    #[case::grown(4096, 1024, 8192, 11264)]
+    #[case::shrunk(4096, 1024, 512, 3584)]
+    fn recalculate(
+        #[case] compressed_size: u64,
+        #[case] prev_compressed: u64,
+        #[case] stored_size: u64,
+        #[case] compressed_after: u64,
+    ) {
+        let mut stats = Stats {
+            compressed_size,
+            prev_compressed,
+            ..Default::default()
+        };
+
+        stats.recalculate(StoredObjects {
+            objects: 3,
+            size: stored_size,
+        });
+
+        assert_eq!(stats.compressed_size, compressed_after);
+        assert_eq!(stats.prev_compressed, stored_size);
+        assert_eq!(stats.parquet_files, 3);
+        assert!(!stats.is_synced());
+    }
+
+    #[test]
+    fn record_sync_failure_and_success() {
+        let mut stats = Stats::default();
+        let time: DateTime<Utc> = DateTime::parse_from_rfc3339("2022-10-15T10:00:00+00:00")
+            .unwrap()
+            .into();
+
+        stats.record_sync_failure("connection reset".to_string(), time);
+        stats.record_sync_failure("timed out".to_string(), time);
+
+        assert_eq!(stats.sync_failures, 2);
+        assert_eq!(stats.failed_syncs_in_row, 2);
+        assert_eq!(
+            stats.last_sync_error,
+            Some(SyncError {
+                time,
+                error: "timed out".to_string()
+            })
+        );
+
+        stats.record_sync_success();
+
+        // failures so far are still counted
+        assert_eq!(stats.sync_failures, 2);
+        assert_eq!(stats.failed_syncs_in_row, 0);
+        assert_eq!(stats.last_sync_error, None);
+        assert_eq!(stats.sequence, 3);
+    }
+
+    #[test]
+    fn record_events() {
+        let mut stats = Stats::default();
+        let first: DateTime<Utc> = DateTime::parse_from_rfc3339("2022-10-15T10:00:00+00:00")
+            .unwrap()
+            .into();
+        let second: DateTime<Utc> = DateTime::parse_from_rfc3339("2022-10-15T10:00:05+00:00")
+            .unwrap()
+            .into();
+
+        stats.record_events(10, second);
+        // an older timestamp never moves last_event_at backwards
+        stats.record_events(5, first);
+
+        assert_eq!(stats.events, 15);
+        assert_eq!(stats.last_event_at, Some(second));
+    }
+
+    fn time(rfc3339: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap().into()
+    }
+
+    #[test]
+    fn ingest_rate() {
+        let mut rate = IngestRate::default();
+        rate.record(time("2022-10-15T10:00:10+00:00"), 30, 3000);
+        rate.record(time("2022-10-15T10:03:00+00:00"), 10, 1000);
+        rate.record(time("2022-10-15T10:04:59+00:00"), 20, 2000);
+
+        let now = time("2022-10-15T10:04:59+00:00");
+        assert_eq!(
+            rate.rate(now, 1),
+            Rate {
+                events: 20.0,
+                bytes: 2000.0
+            }
+        );
+        assert_eq!(
+            rate.rate(now, 5),
+            Rate {
+                events: 12.0,
+                bytes: 1200.0
+            }
+        );
+        assert_eq!(
+            rate.rate(now, 15),
+            Rate {
+                events: 4.0,
+                bytes: 400.0
+            }
+        );
+
+        // the stream went quiet
+        assert_eq!(
+            rate.rate(time("2022-10-15T10:30:00+00:00"), 15),
+            Rate::default()
+        );
+    }
+
+    #[test]
+    fn ingest_rate_clock_jumps() {
+        let mut rate = IngestRate::default();
+        rate.record(time("2022-10-15T10:05:00+00:00"), 10, 100);
+        // a jump back counts towards the latest minute seen
+        rate.record(time("2022-10-15T09:50:00+00:00"), 5, 50);
+        assert_eq!(
+            rate.rate(time("2022-10-15T10:05:30+00:00"), 1),
+            Rate {
+                events: 15.0,
+                bytes: 150.0
+            }
+        );
+
+        // a jump far ahead wraps around the buffer, old minutes are dropped
+        rate.record(time("2022-10-16T10:05:00+00:00"), 1, 10);
+        assert_eq!(
+            rate.rate(time("2022-10-16T10:05:00+00:00"), 15),
+            Rate {
+                events: 1.0 / 15.0,
+                bytes: 10.0 / 15.0
+            }
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_update_stats_counts_events() {
+        clear_map();
+        STREAM_INFO
+            .add_stream("teststream".to_string(), None, Alerts::default())
+            .unwrap();
+
+        STREAM_INFO.update_stats("teststream", 100, 50, 0).unwrap();
+        assert_eq!(
+            STREAM_INFO.timestamps("teststream").unwrap().first_event_at,
+            None
+        );
+
+        STREAM_INFO.update_stats("teststream", 100, 50, 3).unwrap();
+        let first_event_at = STREAM_INFO.timestamps("teststream").unwrap().first_event_at;
+        assert!(first_event_at.is_some());
+        STREAM_INFO.update_stats("teststream", 100, 50, 2).unwrap();
+        assert_eq!(
+            STREAM_INFO.timestamps("teststream").unwrap().first_event_at,
+            first_event_at
+        );
+
+        let meta = STREAM_INFO.get("teststream").unwrap();
+        let stats = &meta.stats;
+        assert_eq!(stats.events, 5);
+        assert!(stats.last_event_at.is_some());
+    }
+
+    #[test]
+    fn restore_stats() {
+        let stats: Stats =
+            serde_json::from_str(r#"{"size": 4096, "compressed_size": 1024, "sequence": 7}"#)
+                .unwrap();
+        let mut stats = stats.restore();
+        assert_eq!(stats.prev_compressed, 1024);
+        assert!(stats.is_synced());
+
+        stats.update(100, 50);
+        assert_eq!(stats.size, 4196);
+        assert_eq!(stats.compressed_size, 1074);
+        assert_eq!(stats.sequence, 8);
+        assert!(!stats.is_synced());
+    }
+
+    #[test]
+    #[serial]
+    fn test_unsynced_stats() {
+        clear_map();
+        for stream_name in ["teststream", "otherstream"] {
+            STREAM_INFO
+                .add_stream(stream_name.to_string(), None, Alerts::default())
+                .unwrap();
+        }
+        STREAM_INFO.update_stats("teststream", 100, 50, 1).unwrap();
+
+        let unsynced = STREAM_INFO.unsynced_stats();
+        assert_eq!(unsynced.len(), 1);
+        assert_eq!(unsynced[0].0, "teststream");
+        assert_eq!(unsynced[0].1.size, 100);
+        assert_eq!(unsynced[0].1.compressed_size, 50);
+
+        STREAM_INFO
+            .set_stats_synced("teststream", unsynced[0].1.sequence)
+            .unwrap();
+        assert!(STREAM_INFO.unsynced_stats().is_empty());
+    }
+
+    #[test]
+    #[serial]
+    fn test_update_stats_sync_threshold() {
+        clear_map();
+        STREAM_INFO
+            .add_stream("teststream".to_string(), None, Alerts::default())
+            .unwrap();
+
+        for _ in 1..STATS_SYNC_THRESHOLD {
+            STREAM_INFO.update_stats("teststream", 1, 1, 1).unwrap();
+        }
+        assert!(STREAM_INFO.due_stats().is_empty());
+
+        STREAM_INFO.update_stats("teststream", 1, 1, 1).unwrap();
+        let due = STREAM_INFO.due_stats();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].1.sequence, STATS_SYNC_THRESHOLD);
+
+        STREAM_INFO
+            .set_stats_synced("teststream", STATS_SYNC_THRESHOLD)
+            .unwrap();
+        assert!(STREAM_INFO.due_stats().is_empty());
+    }
+
+    #[test]
+    #[serial]
+    fn test_update_stats_batch() {
+        clear_map();
+        for stream_name in ["teststream", "otherstream"] {
+            STREAM_INFO
+                .add_stream(stream_name.to_string(), None, Alerts::default())
+                .unwrap();
+        }
+
+        STREAM_INFO
+            .update_stats_batch(&[
+                ("teststream".to_string(), 100, 50, 1),
+                ("otherstream".to_string(), 200, 80, 2),
+                ("teststream".to_string(), 100, 60, 1),
+            ])
+            .unwrap();
+
+        let meta = STREAM_INFO.get("teststream").unwrap();
+        assert_eq!(meta.stats.size, 200);
+        assert_eq!(meta.stats.compressed_size, 60);
+        assert_eq!(meta.stats.sequence, 2);
+        assert!(meta.first_event_at.is_some());
+        drop(meta);
+        assert_eq!(STREAM_INFO.get("otherstream").unwrap().stats.size, 200);
+    }
+
+    #[test]
+    #[serial]
+    fn test_update_stats_batch_missing_streams() {
+        clear_map();
+        STREAM_INFO
+            .add_stream("teststream".to_string(), None, Alerts::default())
+            .unwrap();
+
+        let result = STREAM_INFO.update_stats_batch(&[
+            ("missingstream".to_string(), 100, 50, 1),
+            ("teststream".to_string(), 100, 50, 1),
+            ("otherstream".to_string(), 100, 50, 1),
+        ]);
+
+        match result {
+            Err(Error::StreamsMetaNotFound(missing)) => {
+                assert_eq!(missing, vec!["missingstream", "otherstream"])
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        // streams that exist are still updated
+        assert_eq!(STREAM_INFO.get("teststream").unwrap().stats.size, 100);
+    }
+
+    #[test]
+    #[serial]
+    fn test_set_schema_and_alert_keep_stats() {
+        clear_map();
+        STREAM_INFO
+            .add_stream("teststream".to_string(), None, Alerts::default())
+            .unwrap();
+
+        STREAM_INFO.update_stats("teststream", 100, 50, 1).unwrap();
+        STREAM_INFO
+            .set_schema("teststream".to_string(), Schema::empty())
+            .unwrap();
+        STREAM_INFO.update_stats("teststream", 100, 50, 1).unwrap();
+        STREAM_INFO
+            .set_alert("teststream".to_string(), sample_alerts())
+            .unwrap();
+        STREAM_INFO.update_stats("teststream", 100, 50, 1).unwrap();
+        STREAM_INFO
+            .set_schema("teststream".to_string(), Schema::empty())
+            .unwrap();
+
+        let meta = STREAM_INFO.get("teststream").unwrap();
+        assert_eq!(meta.schema.as_deref(), Some(&Schema::empty()));
+        assert_eq!(meta.alert_config, sample_alerts());
+        assert_eq!(meta.stats.size, 300);
+        assert_eq!(meta.stats.events, 3);
+    }
+
+    #[test]
+    #[serial]
+    fn test_set_on_missing_stream() {
+        clear_map();
+        assert!(matches!(
+            STREAM_INFO.set_schema("teststream".to_string(), Schema::empty()),
+            Err(Error::StreamMetaNotFound(_))
+        ));
+        assert!(matches!(
+            STREAM_INFO.set_alert("teststream".to_string(), sample_alerts()),
+            Err(Error::StreamMetaNotFound(_))
+        ));
+        assert!(!STREAM_INFO.stream_exists("teststream"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_stream_deleted_during_ingest() {
+        clear_map();
+        STREAM_INFO
+            .add_stream("teststream".to_string(), None, Alerts::default())
+            .unwrap();
+        assert!(STREAM_INFO.stream_exists("teststream"));
+
+        // the stream is deleted after ingest found it and before it is updated
+        STREAM_INFO.delete_stream("teststream").unwrap();
+
+        assert!(matches!(
+            STREAM_INFO.set_schema("teststream".to_string(), Schema::empty()),
+            Err(Error::StreamMetaNotFound(_))
+        ));
+        assert!(matches!(
+            STREAM_INFO.update_stats("teststream", 100, 10, 1),
+            Err(Error::StreamMetaNotFound(_))
+        ));
+        assert!(!STREAM_INFO.stream_exists("teststream"));
+        assert_eq!(STREAM_INFO.stream_count(), 0);
+    }
+
+    fn clear_map() {
+        STREAM_INFO.clear();
+        DELETED_STREAMS.clear();
+    }
+
+    fn snapshot() -> HashMap<String, LogStreamMetadata> {
+        STREAM_INFO
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
+    }
+
+    #[rstest]
+    #[case::nonempty_string("Hello world")]
+    #[case::empty_string("")]
+    fn test_parse_string(#[case] string: String) {
+        let bytes = Bytes::from(string);
+        assert!(parse_string(bytes).is_ok())
+    }
+
+    #[test]
+    fn test_bad_parse_string() {
+        let bad: Vec<u8> = vec![195, 40];
+        let bytes = Bytes::from(bad);
+        assert!(parse_string(bytes).is_err());
+    }
+
+    fn sample_alerts() -> Alerts {
+        serde_json::from_str(
+            r#"{
+                "alerts": [{
+                    "name": "server errors",
+                    "message": "too many server errors",
+                    "rule": { "field": "status", "contains": "500", "repeats": 5, "within": "1m" },
+                    "target": [{ "name": "slack", "server_url": "http://localhost", "api_key": "" }]
+                }]
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_alerts() {
+        let bytes = Bytes::from(serde_json::to_string(&sample_alerts()).unwrap());
+        assert_eq!(parse_alerts(bytes).unwrap(), sample_alerts());
+        assert_eq!(parse_alerts(Bytes::new()).unwrap(), Alerts::default());
+    }
+
+    #[test]
+    fn test_bad_parse_alerts() {
+        let mut alerts = sample_alerts();
+        alerts.alerts[0].target.clear();
+        let bytes = Bytes::from(serde_json::to_string(&alerts).unwrap());
+        assert!(parse_alerts(bytes).is_err());
+        assert!(parse_alerts(Bytes::from("{}")).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_set_invalid_alert() {
+        clear_map();
+        STREAM_INFO
+            .add_stream("teststream".to_string(), None, Alerts::default())
+            .unwrap();
+
+        let mut alerts = sample_alerts();
+        alerts.alerts[0].name.clear();
+        assert!(STREAM_INFO
+            .set_alert("teststream".to_string(), alerts)
+            .is_err());
+        assert!(STREAM_INFO.alert("teststream").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_empty_schema() {
+        assert_eq!(parse_schema(Bytes::new()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_parse_schema() {
+        let schema = Schema::new(vec![Field::new("a", DataType::Int64, true)]);
+        let bytes = Bytes::from(serde_json::to_string(&schema).unwrap());
+        assert_eq!(parse_schema(bytes).unwrap(), Some(schema));
+    }
+
+    #[test]
+    fn test_bad_parse_schema() {
+        let bytes = Bytes::from("not a schema");
+        assert!(parse_schema(bytes).is_err());
+    }
+
+    fn schema(fields: &[(&str, DataType)]) -> Schema {
+        Schema::new(
+            fields
+                .iter()
+                .map(|(name, data_type)| Field::new(name, data_type.clone(), true))
+                .collect(),
+        )
+    }
+
+    #[rstest]
+    #[case::same(
+        &[("a", DataType::Utf8)],
+        &[("a", DataType::Utf8)],
+        &[("a", DataType::Utf8)]
+    )]
+    #[case::added_column(
+        &[("a", DataType::Utf8)],
+        &[("b", DataType::Int64), ("a", DataType::Utf8)],
+        &[("a", DataType::Utf8), ("b", DataType::Int64)]
+    )]
+    #[case::missing_column(
+        &[("a", DataType::Utf8), ("b", DataType::Int64)],
+        &[("b", DataType::Int64)],
+        &[("a", DataType::Utf8), ("b", DataType::Int64)]
+    )]
+    #[case::int_to_float(
+        &[("a", DataType::Int64)],
+        &[("a", DataType::Float64)],
+        &[("a", DataType::Float64)]
+    )]
+    #[case::float_keeps_float(
+        &[("a", DataType::Float64)],
+        &[("a", DataType::Int64)],
+        &[("a", DataType::Float64)]
+    )]
+    fn test_merge_schemas(
+        #[case] current: &[(&str, DataType)],
+        #[case] incoming: &[(&str, DataType)],
+        #[case] merged: &[(&str, DataType)],
+    ) {
+        let left = merge_schemas(&schema(current), &schema(incoming)).unwrap();
+        assert_eq!(left, schema(merged));
+    }
+
+    #[rstest]
+    #[case::utf8_int(DataType::Utf8, DataType::Int64)]
+    #[case::bool_float(DataType::Boolean, DataType::Float64)]
+    fn test_merge_schemas_conflict(#[case] current: DataType, #[case] incoming: DataType) {
+        let current = schema(&[("a", current)]);
+        let incoming = schema(&[("a", incoming)]);
+        assert!(matches!(
+            merge_schemas(&current, &incoming),
+            Err(Error::IncompatibleField(..))
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn test_schema_is_shared() {
+        clear_map();
+        STREAM_INFO
+            .add_stream(
+                "teststream".to_string(),
+                Some(schema(&[("a", DataType::Int64)])),
+                Alerts::default(),
+            )
+            .unwrap();
+
+        let first = STREAM_INFO.schema("teststream").unwrap().unwrap();
+        let second = STREAM_INFO.schema("teststream").unwrap().unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        STREAM_INFO
+            .set_schema("teststream".to_string(), schema(&[("a", DataType::Int64)]))
+            .unwrap();
+        let third = STREAM_INFO.schema("teststream").unwrap().unwrap();
+        assert!(!Arc::ptr_eq(&first, &third));
+        // callers holding the previous schema keep it as it was
+        assert_eq!(first, third);
+    }
+
+    #[test]
+    #[serial]
+    fn test_merge_schema_does_not_modify_stream() {
+        clear_map();
+        let current = schema(&[("a", DataType::Int64)]);
+        STREAM_INFO
+            .add_stream(
+                "teststream".to_string(),
+                Some(current.clone()),
+                Alerts::default(),
+            )
+            .unwrap();
+
+        let merged = STREAM_INFO
+            .merge_schema("teststream", schema(&[("b", DataType::Utf8)]))
+            .unwrap();
+
+        assert_eq!(
+            merged,
+            schema(&[("a", DataType::Int64), ("b", DataType::Utf8)])
+        );
+        assert_eq!(
+            STREAM_INFO.schema("teststream").unwrap().as_deref(),
+            Some(&current)
+        );
+    }
+
+    #[rstest]
+    #[case::stream_schema_alert("teststream", Some(Schema::empty()), sample_alerts())]
+    #[case::stream_only("teststream", None, Alerts::default())]
+    #[serial]
+    fn test_add_stream(
+        #[case] stream_name: String,
+        #[case] schema: Option<Schema>,
+        #[case] alert_config: Alerts,
+    ) {
+        clear_map();
+        STREAM_INFO
+            .add_stream(stream_name.clone(), schema.clone(), alert_config.clone())
+            .unwrap();
+
+        let left = snapshot();
+        let created_at = left[&stream_name].created_at;
+        assert!(created_at.is_some());
+        let right = hashmap! {
+            stream_name => LogStreamMetadata {
+                schema: schema.map(Arc::new),
+                alert_config: alert_config,
+                created_at,
+                ..Default::default()
+            }
+        };
+        assert_eq!(left, right);
+    }
+
+    #[test]
+    #[serial]
+    fn test_add_stream_replaces_existing() {
+        clear_map();
+        STREAM_INFO
+            .add_stream("teststream".to_string(), None, Alerts::default())
+            .unwrap();
+        STREAM_INFO.update_stats("teststream", 100, 10, 4).unwrap();
+
+        STREAM_INFO
+            .add_stream("teststream".to_string(), None, sample_alerts())
+            .unwrap();
+
+        assert_eq!(STREAM_INFO.stream_count(), 1);
+        assert_eq!(STREAM_INFO.stats("teststream").unwrap(), Stats::default());
+        assert_eq!(
+            STREAM_INFO.alert("teststream").unwrap(),
+            sample_alerts().alerts
+        );
+    }
 
     #[rstest]
-    #[case::stream_schema_alert("teststream", "schema", "alert_config")]
-    #[case::stream_only("teststream", "", "")]
+    #[case::uppercase("TestStream")]
+    #[case::slash("test/stream")]
+    #[case::too_long(&"a".repeat(65))]
+    #[serial]
+    fn test_add_stream_invalid_name(#[case] stream_name: &str) {
+        clear_map();
+        assert!(matches!(
+            STREAM_INFO.add_stream(stream_name.to_string(), None, Alerts::default()),
+            Err(Error::InvalidStreamName(name, _)) if name == stream_name
+        ));
+        assert_eq!(STREAM_INFO.stream_count(), 0);
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_recompute_stats() {
+        clear_map();
+        STREAM_INFO
+            .add_stream("driftstream".to_string(), None, Alerts::default())
+            .unwrap();
+        let partition = "driftstream/date=2022-10-15/hour=10/minute=00";
+        let mut manifest = Manifest::default();
+        manifest.add(vec![
+            manifest::ManifestFile::new("date=2022-10-15/hour=10/minute=00/a.parquet", 100, 10)
+                .unwrap(),
+            manifest::ManifestFile::new("date=2022-10-15/hour=10/minute=00/b.parquet", 200, 20)
+                .unwrap(),
+        ]);
+        let storage = MockStorage::default()
+            .with_object(&format!("{}/a.parquet", partition), vec![0; 100])
+            .with_object(&format!("{}/b.parquet", partition), vec![0; 200])
+            .with_object(
+                "driftstream/.manifest.json",
+                serde_json::to_vec(&manifest).unwrap(),
+            );
+
+        let stats = STREAM_INFO
+            .recompute_stats("driftstream", &storage)
+            .await
+            .unwrap();
+
+        assert_eq!(stats.compressed_size, 300);
+        assert_eq!(stats.parquet_files, 2);
+        assert_eq!(stats.events, 30);
+        assert_eq!(STREAM_INFO.stats("driftstream").unwrap(), stats);
+
+        // rows of a file missing from the manifest that can't be read leave the events as they were
+        let storage = storage.with_object(&format!("{}/c.parquet", partition), vec![0; 50]);
+        let stats = STREAM_INFO
+            .recompute_stats("driftstream", &storage)
+            .await
+            .unwrap();
+
+        assert_eq!(stats.compressed_size, 350);
+        assert_eq!(stats.parquet_files, 3);
+        assert_eq!(stats.events, 30);
+
+        assert!(matches!(
+            STREAM_INFO.recompute_stats("missingstream", &storage).await,
+            Err(Error::StreamMetaNotFound(_))
+        ));
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_create_stream() {
+        clear_map();
+        let storage = MockStorage::default();
+
+        STREAM_INFO
+            .create_stream(&storage, "teststream")
+            .await
+            .unwrap();
+        STREAM_INFO.update_stats("teststream", 100, 50, 1).unwrap();
+
+        assert!(matches!(
+            STREAM_INFO.create_stream(&storage, "teststream").await,
+            Err(Error::StreamAlreadyExists(name)) if name == "teststream"
+        ));
+        // the existing stream is left untouched
+        assert_eq!(STREAM_INFO.get("teststream").unwrap().stats.size, 100);
+        assert_eq!(storage.requests(), vec!["create teststream"]);
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_create_stream_with_settings() {
+        clear_map();
+        let storage = MockStorage::default();
+        let settings = StreamSettings {
+            schema: Some(schema(&[("a", DataType::Int64)])),
+            limits: Limits {
+                max_event_size: None,
+                max_columns: Some(10),
+            },
+            compression: Some(Compression::Zstd),
+            ..Default::default()
+        };
+
+        STREAM_INFO
+            .create_stream_with(&storage, "teststream", settings)
+            .await
+            .unwrap();
+
+        // the settings are in object storage before the stream is added
+        assert_eq!(
+            storage.requests(),
+            vec![
+                "create teststream",
+                "put schema teststream",
+                "put limits teststream",
+                "put compression teststream",
+            ]
+        );
+        let static_schema = STREAM_INFO.static_schema("teststream").unwrap().unwrap();
+        assert!(static_schema.field_with_name("a").is_ok());
+        assert_eq!(STREAM_INFO.get("teststream").unwrap().schema_version, 1);
+        assert_eq!(
+            STREAM_INFO.compression("teststream").unwrap(),
+            Some(Compression::Zstd)
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_confirm_inserted() {
+        clear_map();
+        let inserted = LogStreamMetadata {
+            created_at: Some(Utc::now()),
+            ..Default::default()
+        };
+        STREAM_INFO.insert("teststream".to_string(), inserted.clone());
+
+        // stats updated right after the insert don't count as a change
+        STREAM_INFO.update_stats("teststream", 100, 50, 1).unwrap();
+        STREAM_INFO
+            .confirm_inserted("teststream", &inserted)
+            .unwrap();
+
+        // the stream was replaced, or deleted, by a concurrent request
+        STREAM_INFO
+            .add_stream("teststream".to_string(), None, sample_alerts())
+            .unwrap();
+        assert!(matches!(
+            STREAM_INFO.confirm_inserted("teststream", &inserted),
+            Err(Error::InsertFailed(name)) if name == "teststream"
+        ));
+        STREAM_INFO.remove("teststream");
+        assert!(matches!(
+            STREAM_INFO.confirm_inserted("teststream", &inserted),
+            Err(Error::InsertFailed(_))
+        ));
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_create_stream_after_delete() {
+        clear_map();
+        let storage = MockStorage::default();
+        STREAM_INFO
+            .create_stream(&storage, "teststream")
+            .await
+            .unwrap();
+        STREAM_INFO.update_stats("teststream", 100, 50, 1).unwrap();
+
+        STREAM_INFO
+            .purge_stream(&storage, "teststream")
+            .await
+            .unwrap();
+        STREAM_INFO
+            .create_stream(&storage, "teststream")
+            .await
+            .unwrap();
+
+        // the new stream starts afresh
+        assert_eq!(STREAM_INFO.summary("teststream").unwrap().stats.size, 0);
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_schema_versions() {
+        clear_map();
+        let storage = MockStorage::default();
+        STREAM_INFO
+            .create_stream(&storage, "teststream")
+            .await
+            .unwrap();
+
+        // every schema is put as the next version before it is set, like ingestion does
+        for fields in [vec![("a", DataType::Utf8)], vec![("b", DataType::Int64)]] {
+            let merged = STREAM_INFO
+                .merge_schema("teststream", schema(&fields))
+                .unwrap();
+            let version = STREAM_INFO.next_schema_version("teststream").unwrap();
+            storage
+                .put_schema_version("teststream", version, &merged)
+                .await
+                .unwrap();
+            STREAM_INFO
+                .set_schema("teststream".to_string(), merged)
+                .unwrap();
+        }
+
+        let storage: &dyn ObjectStorage = &storage;
+        let at_version =
+            move |version| STREAM_INFO.schema_at_version(storage, "teststream", version);
+        assert_eq!(
+            at_version(1).await.unwrap(),
+            schema(&[("a", DataType::Utf8)])
+        );
+        assert_eq!(
+            at_version(2).await.unwrap(),
+            schema(&[("a", DataType::Utf8), ("b", DataType::Int64)])
+        );
+        for version in [0, 3] {
+            assert!(matches!(
+                at_version(version).await,
+                Err(Error::SchemaVersionNotFound(_, v)) if v == version
+            ));
+        }
+        assert_eq!(STREAM_INFO.next_schema_version("teststream").unwrap(), 3);
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_load_keeps_schema_without_versions_as_first_version() {
+        clear_map();
+        let stream_schema = schema(&[("a", DataType::Utf8)]);
+        let storage = MemoryStorage::default().with_object(
+            "teststream/.schema",
+            serde_json::to_vec(&stream_schema).unwrap(),
+        );
+
+        STREAM_INFO.load_concurrently(&storage, 4).await.unwrap();
+
+        assert_eq!(STREAM_INFO.next_schema_version("teststream").unwrap(), 2);
+        assert_eq!(
+            storage.get_schema_version("teststream", 1).await.unwrap(),
+            stream_schema
+        );
+        assert!(storage
+            .operations()
+            .contains(&"put_object teststream/schema/v1.json".to_string()));
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_clone_stream() {
+        clear_map();
+        let storage = MockStorage::default();
+        STREAM_INFO
+            .add_stream(
+                "teststream".to_string(),
+                Some(schema(&[("a", DataType::Utf8)])),
+                sample_alerts(),
+            )
+            .unwrap();
+        STREAM_INFO.update_stats("teststream", 100, 50, 4).unwrap();
+
+        STREAM_INFO
+            .clone_stream(&storage, "teststream", "stagingstream")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            STREAM_INFO.schema("stagingstream").unwrap(),
+            STREAM_INFO.schema("teststream").unwrap()
+        );
+        assert_eq!(
+            STREAM_INFO.alert("stagingstream").unwrap(),
+            sample_alerts().alerts
+        );
+        assert_eq!(
+            STREAM_INFO.stats("stagingstream").unwrap(),
+            Stats::default()
+        );
+        // the source is left as it was
+        assert_eq!(STREAM_INFO.stats("teststream").unwrap().events, 4);
+        assert_eq!(
+            storage.requests(),
+            vec!["create stagingstream", "put schema stagingstream"]
+        );
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_clone_stream_to_taken_name() {
+        clear_map();
+        let storage = MockStorage::default();
+        for stream_name in ["teststream", "otherstream"] {
+            STREAM_INFO
+                .add_stream(stream_name.to_string(), None, sample_alerts())
+                .unwrap();
+        }
+
+        assert!(matches!(
+            STREAM_INFO
+                .clone_stream(&storage, "teststream", "otherstream")
+                .await,
+            Err(Error::StreamAlreadyExists(name)) if name == "otherstream"
+        ));
+        assert!(matches!(
+            STREAM_INFO
+                .clone_stream(&storage, "teststream", "Invalid Name")
+                .await,
+            Err(Error::InvalidStreamName(..))
+        ));
+        assert!(matches!(
+            STREAM_INFO
+                .clone_stream(&storage, "missingstream", "newstream")
+                .await,
+            Err(Error::StreamMetaNotFound(_))
+        ));
+        assert!(storage.requests().is_empty());
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_create_stream_races_with_ingest() {
+        clear_map();
+        let storage = MockStorage::default();
+        STREAM_INFO
+            .create_stream(&storage, "teststream")
+            .await
+            .unwrap();
+
+        let ingest = std::thread::spawn(|| {
+            for _ in 0..500 {
+                STREAM_INFO.update_stats("teststream", 10, 5, 1).unwrap();
+            }
+        });
+        for _ in 0..50 {
+            assert!(matches!(
+                STREAM_INFO.create_stream(&storage, "teststream").await,
+                Err(Error::StreamAlreadyExists(_))
+            ));
+        }
+        ingest.join().unwrap();
+
+        // creating the stream again never resets it
+        assert_eq!(STREAM_INFO.summary("teststream").unwrap().stats.events, 500);
+    }
+
+    #[test]
+    #[serial]
+    fn test_setting_conflicts() {
+        clear_map();
+        STREAM_INFO
+            .add_stream("teststream".to_string(), None, Alerts::default())
+            .unwrap();
+        STREAM_INFO.set_retention("teststream", 7).unwrap();
+        let tags = hashmap! { "team".to_string() => "payments".to_string() };
+        STREAM_INFO.set_tags("teststream", tags.clone()).unwrap();
+
+        let conflicts = |settings: StreamSettings| {
+            STREAM_INFO
+                .setting_conflicts("teststream", &settings)
+                .unwrap()
+        };
+
+        // settings that aren't given or match never conflict
+        assert!(conflicts(StreamSettings::default()).is_empty());
+        assert!(conflicts(StreamSettings {
+            tags: tags.clone(),
+            retention: Some(Retention { days: 7 }),
+            ..Default::default()
+        })
+        .is_empty());
+
+        assert_eq!(
+            conflicts(StreamSettings {
+                tags: hashmap! { "team".to_string() => "search".to_string() },
+                retention: Some(Retention { days: 30 }),
+                ..Default::default()
+            }),
+            vec![
+                SettingConflict {
+                    setting: "tags",
+                    existing: serde_json::json!({ "team": "payments" }),
+                    requested: serde_json::json!({ "team": "search" }),
+                },
+                SettingConflict {
+                    setting: "retention",
+                    existing: serde_json::json!({ "days": 7 }),
+                    requested: serde_json::json!({ "days": 30 }),
+                },
+            ]
+        );
+
+        STREAM_INFO
+            .set_limits(
+                "teststream",
+                Limits {
+                    max_event_size: Some(1024),
+                    max_columns: None,
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            conflicts(StreamSettings {
+                limits: Limits {
+                    max_event_size: None,
+                    max_columns: Some(10),
+                },
+                ..Default::default()
+            }),
+            vec![SettingConflict {
+                setting: "limits",
+                existing: serde_json::json!({ "max_event_size": 1024, "max_columns": null }),
+                requested: serde_json::json!({ "max_event_size": null, "max_columns": 10 }),
+            }]
+        );
+    }
+
+    fn stream_objects(stream_name: &str) -> Vec<String> {
+        (0..5)
+            .map(|i| format!("{}/date=2022-10-15/hour=10/{}.parquet", stream_name, i))
+            .collect()
+    }
+
+    #[test]
+    #[serial]
+    fn test_set_static_schema() {
+        clear_map();
+        STREAM_INFO
+            .add_stream("teststream".to_string(), None, Alerts::default())
+            .unwrap();
+        assert_eq!(STREAM_INFO.static_schema("teststream").unwrap(), None);
+
+        let settings = StreamSettings {
+            schema: Some(schema(&[("a", DataType::Int64)])),
+            ..Default::default()
+        };
+        // a stream with an inferred schema has no static schema to match
+        assert_eq!(
+            STREAM_INFO
+                .setting_conflicts("teststream", &settings)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        STREAM_INFO
+            .set_static_schema("teststream", schema(&[("a", DataType::Int64)]))
+            .unwrap();
+
+        // every event has labels, so the static schema must have them too
+        let expected = schema(&[("a", DataType::Int64), ("labels", DataType::Utf8)]);
+        assert_eq!(
+            STREAM_INFO.static_schema("teststream").unwrap().as_deref(),
+            Some(&expected)
+        );
+        assert!(STREAM_INFO
+            .setting_conflicts("teststream", &settings)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_purge_stream() {
+        clear_map();
+        let storage = MockStorage::default()
+            .with_objects(&stream_objects("teststream"))
+            .with_objects(&["otherstream/.schema"]);
+        STREAM_INFO
+            .add_stream("teststream".to_string(), None, Alerts::default())
+            .unwrap();
+
+        STREAM_INFO
+            .purge_stream(&storage, "teststream")
+            .await
+            .unwrap();
+
+        assert!(!STREAM_INFO.stream_exists("teststream"));
+        assert_eq!(storage.objects(), vec!["otherstream/.schema"]);
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_soft_delete_and_undelete_stream() {
+        clear_map();
+        let storage = MockStorage::default().with_objects(&stream_objects("teststream"));
+        STREAM_INFO
+            .add_stream("teststream".to_string(), None, Alerts::default())
+            .unwrap();
+        STREAM_INFO.update_stats("teststream", 100, 50, 1).unwrap();
+
+        STREAM_INFO
+            .soft_delete_stream(&storage, "teststream")
+            .await
+            .unwrap();
+        assert!(!STREAM_INFO.stream_exists("teststream"));
+        assert!(STREAM_INFO.list_stream_summaries().is_empty());
+        // the name stays taken and the data stays in storage
+        assert!(matches!(
+            STREAM_INFO.create_stream(&storage, "teststream").await,
+            Err(Error::StreamAlreadyExists(_))
+        ));
+        assert_eq!(storage.objects(), stream_objects("teststream"));
+
+        STREAM_INFO
+            .undelete_stream(&storage, "teststream")
+            .await
+            .unwrap();
+        let meta = STREAM_INFO.get("teststream").unwrap().clone();
+        assert_eq!(meta.deleted_at, None);
+        assert_eq!(meta.stats.size, 100);
+
+        assert!(matches!(
+            STREAM_INFO.undelete_stream(&storage, "teststream").await,
+            Err(Error::DeletedStreamNotFound(_))
+        ));
+        assert_eq!(
+            storage.requests(),
+            vec!["mark deleted teststream", "unmark deleted teststream"]
+        );
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_purge_deleted_streams() {
+        clear_map();
+        let storage = MockStorage::default().with_objects(&stream_objects("teststream"));
+        STREAM_INFO
+            .add_stream("teststream".to_string(), None, Alerts::default())
+            .unwrap();
+        STREAM_INFO
+            .soft_delete_stream(&storage, "teststream")
+            .await
+            .unwrap();
+
+        let grace_period = Duration::hours(24);
+        let now = Utc::now();
+        assert!(STREAM_INFO
+            .expired_deletions(now + Duration::hours(23), grace_period)
+            .is_empty());
+
+        // nothing is purged within the grace period
+        STREAM_INFO
+            .purge_deleted_streams(&storage, now, grace_period)
+            .await;
+        assert_eq!(storage.objects(), stream_objects("teststream"));
+
+        let later = now + Duration::hours(25);
+        assert_eq!(
+            STREAM_INFO.expired_deletions(later, grace_period),
+            vec!["teststream"]
+        );
+        STREAM_INFO
+            .purge_deleted_streams(&storage, later, grace_period)
+            .await;
+        assert!(storage.objects().is_empty());
+        assert!(STREAM_INFO
+            .expired_deletions(later, grace_period)
+            .is_empty());
+
+        // the name is free again once the stream is purged
+        STREAM_INFO
+            .create_stream(&storage, "teststream")
+            .await
+            .unwrap();
+    }
+
+    fn local_root() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("parseable-test-{}", utils::random_string()))
+    }
+
+    #[actix_web::test]
     #[serial]
-    fn test_add_stream(
-        #[case] stream_name: String,
-        #[case] schema: String,
-        #[case] alert_config: String,
-    ) {
+    async fn test_rename_stream() {
         clear_map();
+        let storage = MockStorage::default()
+            .with_objects(&stream_objects("teststream"))
+            .with_objects(&["otherstream/.schema"]);
         STREAM_INFO
-            .add_stream(stream_name.clone(), schema.clone(), alert_config.clone())
+            .add_stream("teststream".to_string(), None, sample_alerts())
             .unwrap();
+        STREAM_INFO.update_stats("teststream", 100, 10, 4).unwrap();
+        let root = local_root();
+        fs::create_dir_all(root.join("teststream")).unwrap();
+        fs::write(root.join("teststream").join("data.parquet"), b"data").unwrap();
 
-        let left = STREAM_INFO.read().unwrap().clone();
-        let right = hashmap! {
-            stream_name => LogStreamMetadata {
-                schema: schema,
-                alert_config: alert_config,
-                ..Default::default()
-            }
-        };
-        assert_eq!(left, right);
+        STREAM_INFO
+            .rename_stream(&storage, &root, "teststream", "newstream")
+            .await
+            .unwrap();
+
+        assert!(!STREAM_INFO.stream_exists("teststream"));
+        assert!(!STREAM_INFO.is_renaming("newstream"));
+        assert_eq!(STREAM_INFO.stats("newstream").unwrap().events, 4);
+        assert_eq!(
+            STREAM_INFO.alert("newstream").unwrap(),
+            sample_alerts().alerts
+        );
+        let mut objects = storage.objects();
+        objects.sort();
+        let mut expected = stream_objects("newstream");
+        expected.push("otherstream/.schema".to_string());
+        assert_eq!(objects, expected);
+        // data not synced yet is synced under the new name
+        assert!(root.join("newstream").join("data.parquet").exists());
+        assert!(!root.join("teststream").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_rename_stream_to_existing_stream() {
+        clear_map();
+        let storage = MockStorage::default().with_objects(&stream_objects("teststream"));
+        for stream_name in ["teststream", "otherstream"] {
+            STREAM_INFO
+                .add_stream(stream_name.to_string(), None, Alerts::default())
+                .unwrap();
+        }
+
+        assert!(matches!(
+            STREAM_INFO
+                .rename_stream(&storage, &local_root(), "teststream", "otherstream")
+                .await,
+            Err(Error::StreamAlreadyExists(name)) if name == "otherstream"
+        ));
+        assert!(matches!(
+            STREAM_INFO
+                .rename_stream(&storage, &local_root(), "teststream", "Invalid Name")
+                .await,
+            Err(Error::InvalidStreamName(..))
+        ));
+        assert!(!STREAM_INFO.is_renaming("teststream"));
+        assert_eq!(storage.objects(), stream_objects("teststream"));
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_rename_stream_fails_midway_and_resumes() {
+        clear_map();
+        let storage = MockStorage::default()
+            .with_objects(&stream_objects("teststream"))
+            .with_delete_limit(2);
+        STREAM_INFO
+            .add_stream("teststream".to_string(), None, Alerts::default())
+            .unwrap();
+
+        let result = STREAM_INFO
+            .rename_stream(&storage, &local_root(), "teststream", "newstream")
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(Error::Storage(ObjectStorageError::DeleteIncomplete(3)))
+        ));
+        assert!(!STREAM_INFO.is_renaming("teststream"));
+        assert!(!STREAM_INFO.stream_exists("newstream"));
+
+        // after a restart both streams are loaded, the rename can still be completed
+        let storage = MockStorage::default()
+            .with_objects(&storage.objects())
+            .with_renamed_from("newstream", "teststream");
+        STREAM_INFO
+            .add_stream("newstream".to_string(), None, Alerts::default())
+            .unwrap();
+        STREAM_INFO
+            .rename_stream(&storage, &local_root(), "teststream", "newstream")
+            .await
+            .unwrap();
+
+        assert!(!STREAM_INFO.stream_exists("teststream"));
+        let mut objects = storage.objects();
+        objects.sort();
+        assert_eq!(objects, stream_objects("newstream"));
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_purge_stream_fails_midway() {
+        clear_map();
+        let storage = MockStorage::default()
+            .with_objects(&stream_objects("teststream"))
+            .with_delete_limit(2);
+        STREAM_INFO
+            .add_stream("teststream".to_string(), None, Alerts::default())
+            .unwrap();
+
+        let result = STREAM_INFO.purge_stream(&storage, "teststream").await;
+
+        assert!(matches!(
+            result,
+            Err(Error::Storage(ObjectStorageError::DeleteIncomplete(3)))
+        ));
+        // the stream is kept so that the delete can be retried
+        assert!(STREAM_INFO.stream_exists("teststream"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_contains_stream_and_stream_count() {
+        clear_map();
+        assert!(!STREAM_INFO.contains_stream("teststream"));
+        assert_eq!(STREAM_INFO.stream_count(), 0);
+
+        for stream_name in ["teststream", "otherstream"] {
+            STREAM_INFO
+                .add_stream(stream_name.to_string(), None, Alerts::default())
+                .unwrap();
+        }
+        assert!(STREAM_INFO.contains_stream("teststream"));
+        assert!(STREAM_INFO.stream_exists("teststream"));
+        assert_eq!(STREAM_INFO.stream_count(), 2);
+
+        STREAM_INFO.delete_stream("teststream").unwrap();
+        assert!(!STREAM_INFO.contains_stream("teststream"));
+        assert!(!STREAM_INFO.stream_exists("teststream"));
+        assert!(STREAM_INFO.contains_stream("otherstream"));
+        assert_eq!(STREAM_INFO.stream_count(), 1);
     }
 
     #[rstest]
@@ -252,11 +3688,634 @@ mod tests {
     fn test_delete_stream(#[case] stream_name: String) {
         clear_map();
         STREAM_INFO
-            .add_stream(stream_name.clone(), "".to_string(), "".to_string())
+            .add_stream(stream_name.clone(), None, Alerts::default())
             .unwrap();
 
         STREAM_INFO.delete_stream(&stream_name).unwrap();
-        let map = STREAM_INFO.read().unwrap();
-        assert!(!map.contains_key(&stream_name));
+        assert!(!STREAM_INFO.stream_exists(&stream_name));
+    }
+
+    fn position(requests: &[String], request: &str) -> usize {
+        requests.iter().position(|r| r == request).unwrap()
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_load_fetches_streams_concurrently() {
+        clear_map();
+        let storage = MockStorage::default()
+            .with_stream("aslowstream", "")
+            .with_stream("bstream", "")
+            .with_stream("cstream", "")
+            .with_delay("aslowstream", std::time::Duration::from_millis(200));
+
+        STREAM_INFO.load_concurrently(&storage, 4).await.unwrap();
+
+        // the slow stream is fetched first but doesn't block the others
+        let requests = storage.requests();
+        let slow_end = position(&requests, "end aslowstream");
+        assert!(position(&requests, "start bstream") < slow_end);
+        assert!(position(&requests, "end bstream") < slow_end);
+        assert!(position(&requests, "end cstream") < slow_end);
+        assert_eq!(STREAM_INFO.stream_count(), 3);
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_load_lists_streams_in_pages() {
+        clear_map();
+        let storage = MockStorage::default()
+            .with_stream("astream", "")
+            .with_stream("bstream", "")
+            .with_stream("cstream", "")
+            .with_page_size(2);
+
+        STREAM_INFO.load_concurrently(&storage, 4).await.unwrap();
+
+        let requests = storage.requests();
+        // the first page is loaded before the second is listed
+        assert!(position(&requests, "end bstream") < position(&requests, "list streams from 2"));
+        assert!(position(&requests, "list streams from 2") < position(&requests, "start cstream"));
+        assert_eq!(STREAM_INFO.stream_count(), 3);
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_load_counts_parquet_files_without_stats() {
+        clear_map();
+        let storage = stream_objects("teststream")
+            .iter()
+            .fold(MemoryStorage::default(), |storage, key| {
+                storage.with_object(key, "")
+            })
+            .with_object("teststream/.schema", "");
+
+        STREAM_INFO.load_concurrently(&storage, 4).await.unwrap();
+
+        let stats = STREAM_INFO.stats("teststream").unwrap();
+        assert_eq!(stats.parquet_files, 5);
+        assert_eq!(stats.latest_object, None);
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_load_respects_concurrency_limit() {
+        clear_map();
+        let storage = MockStorage::default()
+            .with_stream("aslowstream", "")
+            .with_stream("bstream", "")
+            .with_delay("aslowstream", std::time::Duration::from_millis(50));
+
+        STREAM_INFO.load_concurrently(&storage, 1).await.unwrap();
+
+        let requests = storage.requests();
+        assert!(position(&requests, "end aslowstream") < position(&requests, "start bstream"));
+        assert_eq!(STREAM_INFO.stream_count(), 2);
+    }
+
+    #[test]
+    #[serial]
+    fn test_list_streams() {
+        clear_map();
+        STREAM_INFO
+            .add_stream("secondstream".to_string(), None, sample_alerts())
+            .unwrap();
+        STREAM_INFO
+            .add_stream(
+                "firststream".to_string(),
+                Some(schema(&[("a", DataType::Int64)])),
+                Alerts::default(),
+            )
+            .unwrap();
+        STREAM_INFO.set_retention("firststream", 30).unwrap();
+
+        let streams = STREAM_INFO.list_stream_summaries();
+        assert!(streams.iter().all(|stream| stream.created_at.is_some()));
+
+        assert_eq!(
+            streams,
+            vec![
+                StreamSummary {
+                    name: "firststream".to_string(),
+                    has_schema: true,
+                    static_schema: false,
+                    has_alerts: false,
+                    degraded: false,
+                    stats: Stats::default(),
+                    created_at: streams[0].created_at,
+                    first_event_at: None,
+                    rate_1m: Rate::default(),
+                    rate_5m: Rate::default(),
+                    rate_15m: Rate::default(),
+                    retention: Some(Retention { days: 30 }),
+                    tags: HashMap::new(),
+                    time_field: None,
+                    limits: Limits::default(),
+                    compression: None,
+                    flatten: Flatten::default(),
+                    partition: PartitionGranularity::default(),
+                },
+                StreamSummary {
+                    name: "secondstream".to_string(),
+                    has_schema: false,
+                    static_schema: false,
+                    has_alerts: true,
+                    degraded: false,
+                    stats: Stats::default(),
+                    created_at: streams[1].created_at,
+                    first_event_at: None,
+                    rate_1m: Rate::default(),
+                    rate_5m: Rate::default(),
+                    rate_15m: Rate::default(),
+                    retention: None,
+                    tags: HashMap::new(),
+                    time_field: None,
+                    limits: Limits::default(),
+                    compression: None,
+                    flatten: Flatten::default(),
+                    partition: PartitionGranularity::default(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_sync_failing() {
+        clear_map();
+        assert!(!STREAM_INFO.sync_failing());
+        for stream_name in ["teststream", "otherstream"] {
+            STREAM_INFO
+                .add_stream(stream_name.to_string(), None, Alerts::default())
+                .unwrap();
+        }
+        let error = ObjectStorageError::ConnectionError("connection reset".into());
+
+        for _ in 0..SYNC_FAILURES_DEGRADED {
+            STREAM_INFO.record_sync("teststream", Err(&error)).unwrap();
+        }
+        // another stream is still synced
+        assert!(!STREAM_INFO.sync_failing());
+
+        for _ in 0..SYNC_FAILURES_DEGRADED {
+            STREAM_INFO.record_sync("otherstream", Err(&error)).unwrap();
+        }
+        assert!(STREAM_INFO.sync_failing());
+        assert_eq!(
+            STREAM_INFO.stats("otherstream").unwrap().sync_failures,
+            SYNC_FAILURES_DEGRADED as u64
+        );
+
+        STREAM_INFO.record_sync("otherstream", Ok(())).unwrap();
+        assert!(!STREAM_INFO.sync_failing());
+        assert!(STREAM_INFO
+            .summary("otherstream")
+            .unwrap()
+            .stats
+            .last_sync_error
+            .is_none());
+    }
+
+    #[test]
+    #[serial]
+    fn test_total_stats() {
+        clear_map();
+        assert_eq!(STREAM_INFO.total_stats(|_| true), TotalStats::default());
+
+        for stream_name in ["firststream", "secondstream"] {
+            STREAM_INFO
+                .add_stream(stream_name.to_string(), None, Alerts::default())
+                .unwrap();
+        }
+        STREAM_INFO.update_stats("firststream", 100, 10, 4).unwrap();
+        STREAM_INFO.update_stats("secondstream", 50, 5, 1).unwrap();
+
+        assert_eq!(
+            STREAM_INFO.total_stats(|_| true),
+            TotalStats {
+                streams: 2,
+                size: 150,
+                compressed_size: 15,
+                events: 5,
+            }
+        );
+        assert_eq!(
+            STREAM_INFO.total_stats(|stream_name| stream_name == "secondstream"),
+            TotalStats {
+                streams: 1,
+                size: 50,
+                compressed_size: 5,
+                events: 1,
+            }
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_list_stream_summaries_with_stats() {
+        clear_map();
+        for stream_name in ["firststream", "secondstream"] {
+            STREAM_INFO
+                .add_stream(stream_name.to_string(), None, Alerts::default())
+                .unwrap();
+        }
+        STREAM_INFO.update_stats("firststream", 100, 40, 2).unwrap();
+        STREAM_INFO.update_stats("secondstream", 10, 5, 1).unwrap();
+        STREAM_INFO.update_stats("secondstream", 20, 10, 1).unwrap();
+
+        let stats = STREAM_INFO
+            .list_stream_summaries()
+            .into_iter()
+            .map(|stream| (stream.name, stream.stats.size, stream.stats.events))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            stats,
+            vec![
+                ("firststream".to_string(), 100, 2),
+                ("secondstream".to_string(), 30, 2),
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_load_flags_degraded_streams() {
+        clear_map();
+        let storage = MemoryStorage::default()
+            .with_object("goodstream/.schema", "")
+            .with_object("badstream/.schema", &[0xff, 0xfe, 0xfd][..]);
+
+        STREAM_INFO.load_concurrently(&storage, 4).await.unwrap();
+
+        // the stream with an invalid schema is still loaded
+        let map = snapshot();
+        assert_eq!(map.len(), 2);
+        assert!(map["goodstream"].load_errors.is_empty());
+        assert_eq!(map["badstream"].load_errors, vec![LoadError::InvalidSchema]);
+        assert_eq!(map["badstream"].schema, None);
+
+        assert_eq!(
+            STREAM_INFO.load_errors(),
+            vec![("badstream".to_string(), LoadError::InvalidSchema)]
+        );
+        assert!(STREAM_INFO.is_schema_invalid("badstream").unwrap());
+        assert!(!STREAM_INFO.is_schema_invalid("goodstream").unwrap());
+    }
+
+    // Object storage shared with another server, which puts streams to it
+    async fn shared_storage(streams: &[&str]) -> (std::path::PathBuf, LocalStorage) {
+        let root = std::env::temp_dir().join(format!("parseable-test-{}", utils::random_string()));
+        let storage = LocalStorage::new(root.clone());
+        for stream_name in streams {
+            storage
+                .put_schema(stream_name.to_string(), &Schema::empty())
+                .await
+                .unwrap();
+            storage
+                .put_metadata(stream_name, &MetadataDocument::default())
+                .await
+                .unwrap();
+        }
+        (root, storage)
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_refresh_stream() {
+        clear_map();
+        STREAM_INFO
+            .add_stream("teststream".to_string(), None, Alerts::default())
+            .unwrap();
+        STREAM_INFO.update_stats("teststream", 100, 10, 4).unwrap();
+
+        // another server set the schema and retention, and synced older stats
+        let (root, storage) = shared_storage(&["teststream"]).await;
+        let schema = Schema::new(vec![Field::new("a", DataType::Int64, true)]);
+        storage
+            .put_schema("teststream".to_string(), &schema)
+            .await
+            .unwrap();
+        storage
+            .put_retention("teststream", &Retention { days: 7 })
+            .await
+            .unwrap();
+        storage
+            .put_stats("teststream", &Stats::default())
+            .await
+            .unwrap();
+
+        let changes = STREAM_INFO
+            .refresh_stream(&storage, "teststream")
+            .await
+            .unwrap();
+        fs::remove_dir_all(root).unwrap();
+
+        let settings: Vec<_> = changes.iter().map(|change| change.setting).collect();
+        assert_eq!(settings, vec!["schema", "retention"]);
+        assert_eq!(changes[1].existing, serde_json::Value::Null);
+        assert_eq!(changes[1].refreshed, serde_json::json!({ "days": 7 }));
+        assert_eq!(
+            STREAM_INFO.schema("teststream").unwrap().as_deref(),
+            Some(&schema)
+        );
+        // stats updates that aren't synced yet are kept
+        assert_eq!(STREAM_INFO.stats("teststream").unwrap().events, 4);
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_refresh_stream_missing_from_storage() {
+        clear_map();
+        STREAM_INFO
+            .add_stream("teststream".to_string(), None, Alerts::default())
+            .unwrap();
+        let (_, storage) = shared_storage(&[]).await;
+
+        assert!(matches!(
+            STREAM_INFO.refresh_stream(&storage, "teststream").await,
+            Err(Error::SchemaNotInStore(_))
+        ));
+        assert!(matches!(
+            STREAM_INFO.refresh_stream(&storage, "missingstream").await,
+            Err(Error::StreamMetaNotFound(_))
+        ));
+        // a single stream is never removed by a refresh
+        assert!(STREAM_INFO.stream_exists("teststream"));
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_refresh_all_streams() {
+        clear_map();
+        for stream_name in ["keptstream", "gonestream"] {
+            STREAM_INFO
+                .add_stream(
+                    stream_name.to_string(),
+                    Some(Schema::empty()),
+                    Alerts::default(),
+                )
+                .unwrap();
+        }
+        let (root, storage) = shared_storage(&["keptstream", "newstream"]).await;
+        // left by a storage health check, it isn't a stream
+        storage
+            .put_object(&format!("{}probe", RESERVED_PREFIX), Bytes::new())
+            .await
+            .unwrap();
+
+        let report = STREAM_INFO.refresh_concurrently(&storage, 2).await.unwrap();
+        fs::remove_dir_all(root).unwrap();
+
+        assert_eq!(
+            report,
+            RefreshReport {
+                changed: vec![],
+                added: vec!["newstream".to_string()],
+                removed: vec!["gonestream".to_string()],
+            }
+        );
+        let mut streams: Vec<_> = STREAM_INFO.iter().map(|e| e.key().clone()).collect();
+        streams.sort();
+        assert_eq!(streams, vec!["keptstream", "newstream"]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_set_tags_and_filter() {
+        clear_map();
+        for stream_name in ["paymentsprod", "paymentsdev", "searchprod"] {
+            STREAM_INFO
+                .add_stream(stream_name.to_string(), None, Alerts::default())
+                .unwrap();
+        }
+        let tags = |team: &str, env: &str| {
+            hashmap! {
+                "team".to_string() => team.to_string(),
+                "env".to_string() => env.to_string(),
+            }
+        };
+        STREAM_INFO
+            .set_tags("paymentsprod", tags("payments", "prod"))
+            .unwrap();
+        STREAM_INFO
+            .set_tags("paymentsdev", tags("payments", "dev"))
+            .unwrap();
+        STREAM_INFO
+            .set_tags("searchprod", tags("search", "prod"))
+            .unwrap();
+        assert_eq!(
+            STREAM_INFO.tags("paymentsdev").unwrap(),
+            tags("payments", "dev")
+        );
+        assert!(STREAM_INFO.tags("missingstream").is_err());
+
+        let filter = |filters: &[(&str, &str)]| {
+            let filters = filters
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect::<Vec<_>>();
+            STREAM_INFO
+                .list_stream_summaries()
+                .into_iter()
+                .filter(|stream| stream.has_tags(&filters))
+                .map(|stream| stream.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(filter(&[]).len(), 3);
+        assert_eq!(
+            filter(&[("team", "payments")]),
+            vec!["paymentsdev", "paymentsprod"]
+        );
+        assert_eq!(
+            filter(&[("team", "payments"), ("env", "prod")]),
+            vec!["paymentsprod"]
+        );
+        assert!(filter(&[("team", "billing")]).is_empty());
+    }
+
+    #[test]
+    #[serial]
+    fn test_set_tags_errors() {
+        clear_map();
+        let tags = hashmap! { "team".to_string() => "payments".to_string() };
+        assert!(matches!(
+            STREAM_INFO.set_tags("teststream", tags),
+            Err(Error::StreamMetaNotFound(_))
+        ));
+
+        STREAM_INFO
+            .add_stream("teststream".to_string(), None, Alerts::default())
+            .unwrap();
+        let tags = hashmap! { "Team".to_string() => "payments".to_string() };
+        assert!(matches!(
+            STREAM_INFO.set_tags("teststream", tags),
+            Err(Error::InvalidTagKey(..))
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn test_set_retention() {
+        clear_map();
+        STREAM_INFO
+            .add_stream("teststream".to_string(), None, Alerts::default())
+            .unwrap();
+
+        assert!(STREAM_INFO.set_retention("teststream", 0).is_err());
+        assert_eq!(STREAM_INFO.retentions(), vec![]);
+
+        STREAM_INFO.set_retention("teststream", 7).unwrap();
+        assert_eq!(
+            STREAM_INFO.retentions(),
+            vec![("teststream".to_string(), Duration::days(7))]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_set_limits() {
+        clear_map();
+        STREAM_INFO
+            .add_stream("teststream".to_string(), None, Alerts::default())
+            .unwrap();
+
+        let invalid = Limits {
+            max_columns: Some(0),
+            ..Default::default()
+        };
+        assert!(matches!(
+            STREAM_INFO.set_limits("teststream", invalid),
+            Err(Error::InvalidLimits(_))
+        ));
+
+        let limits = Limits {
+            max_event_size: None,
+            max_columns: Some(2),
+        };
+        STREAM_INFO.set_limits("teststream", limits).unwrap();
+        assert_eq!(STREAM_INFO.limits("teststream").unwrap(), limits);
+
+        let columns = |n: usize| {
+            Schema::new(
+                (0..n)
+                    .map(|i| Field::new(&format!("field{}", i), DataType::Utf8, true))
+                    .collect(),
+            )
+        };
+        STREAM_INFO
+            .set_schema("teststream".to_string(), columns(2))
+            .unwrap();
+        assert!(matches!(
+            STREAM_INFO.set_schema("teststream".to_string(), columns(3)),
+            Err(Error::TooManyColumns(_, 3, 2))
+        ));
+        assert_eq!(
+            STREAM_INFO
+                .schema("teststream")
+                .unwrap()
+                .unwrap()
+                .fields()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_parse_limits_setting() {
+        let settings: StreamSettings =
+            serde_json::from_str(r#"{"retention":{"days":7},"max_event_size":1024}"#).unwrap();
+        assert_eq!(
+            settings.limits,
+            Limits {
+                max_event_size: Some(1024),
+                max_columns: None,
+            }
+        );
+        assert!(serde_json::from_str::<StreamSettings>("{}")
+            .unwrap()
+            .limits
+            .is_empty());
+    }
+
+    #[test]
+    #[serial]
+    fn test_panic_while_holding_entry() {
+        clear_map();
+        STREAM_INFO
+            .add_stream("teststream".to_string(), None, Alerts::default())
+            .unwrap();
+        let _ = std::thread::spawn(|| {
+            let _meta = STREAM_INFO.get_mut("teststream");
+            panic!("panic while holding the entry");
+        })
+        .join();
+
+        STREAM_INFO.update_stats("teststream", 100, 50, 1).unwrap();
+        assert_eq!(STREAM_INFO.schema("teststream").unwrap(), None);
+        assert_eq!(STREAM_INFO.list_stream_summaries().len(), 1);
+    }
+
+    #[test]
+    #[serial]
+    fn test_concurrent_update_stats() {
+        clear_map();
+        let streams = ["firststream", "secondstream"];
+        for stream_name in streams {
+            STREAM_INFO
+                .add_stream(stream_name.to_string(), None, Alerts::default())
+                .unwrap();
+        }
+
+        let handles = streams
+            .into_iter()
+            .map(|stream_name| {
+                std::thread::spawn(move || {
+                    for _ in 0..500 {
+                        STREAM_INFO.update_stats(stream_name, 10, 5, 1).unwrap();
+                        STREAM_INFO.schema(stream_name).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        for stream_name in streams {
+            let meta = STREAM_INFO.get(stream_name).unwrap();
+            let stats = &meta.stats;
+            assert_eq!(stats.size, 5000);
+            assert_eq!(stats.events, 500);
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_update_stats_many_streams_in_parallel() {
+        clear_map();
+        let handles = (0..16)
+            .map(|i| {
+                std::thread::spawn(move || {
+                    let stream_name = format!("stream{}", i);
+                    STREAM_INFO
+                        .add_stream(stream_name.clone(), None, Alerts::default())
+                        .unwrap();
+                    for _ in 0..100 {
+                        STREAM_INFO.update_stats(&stream_name, 10, 5, 2).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let streams = STREAM_INFO.list_stream_summaries();
+        assert_eq!(streams.len(), 16);
+        for stream in streams {
+            assert_eq!(stream.stats.size, 1000);
+            assert_eq!(stream.stats.events, 200);
+            assert_eq!(stream.stats.sequence, 100);
+        }
     }
 }
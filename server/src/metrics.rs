@@ -0,0 +1,255 @@
+/*
+ * Parseable Server (C) 2022 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use lazy_static::lazy_static;
+use prometheus::core::{Collector, MetricVec, MetricVecBuilder};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+
+use crate::metadata::STREAM_INFO;
+
+const METRICS_NAMESPACE: &str = "parseable";
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::new();
+    pub static ref EVENTS_INGESTED: IntCounterVec = IntCounterVec::new(
+        Opts::new("events_ingested", "Events ingested per log stream").namespace(METRICS_NAMESPACE),
+        &["stream"]
+    )
+    .expect("metric can be created");
+    pub static ref EVENTS_FAILED: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "events_failed",
+            "Events per log stream that failed to parse as JSON"
+        )
+        .namespace(METRICS_NAMESPACE),
+        &["stream"]
+    )
+    .expect("metric can be created");
+    pub static ref STORAGE_RETRIES: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "storage_retries",
+            "Object storage operations retried after a transient error"
+        )
+        .namespace(METRICS_NAMESPACE),
+        &["operation"]
+    )
+    .expect("metric can be created");
+    pub static ref STORAGE_OPERATIONS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "storage_operations",
+            "Object storage operations, with the log stream of data objects"
+        )
+        .namespace(METRICS_NAMESPACE),
+        &["operation", "stream"]
+    )
+    .expect("metric can be created");
+    pub static ref STORAGE_ERRORS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "storage_errors",
+            "Object storage operations that failed, after retries"
+        )
+        .namespace(METRICS_NAMESPACE),
+        &["operation", "stream"]
+    )
+    .expect("metric can be created");
+    pub static ref STORAGE_BYTES: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "storage_bytes",
+            "Bytes of objects put to and fetched from object storage"
+        )
+        .namespace(METRICS_NAMESPACE),
+        &["operation", "stream"]
+    )
+    .expect("metric can be created");
+    pub static ref STORAGE_DURATION: HistogramVec = HistogramVec::new(
+        HistogramOpts::new(
+            "storage_operation_duration_seconds",
+            "Time taken by object storage operations, retries included"
+        )
+        .namespace(METRICS_NAMESPACE)
+        .buckets(vec![0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0, 30.0]),
+        &["operation", "stream"]
+    )
+    .expect("metric can be created");
+    pub static ref SYNC_DURATION: Histogram = Histogram::with_opts(
+        HistogramOpts::new(
+            "sync_duration_seconds",
+            "Time taken to upload the staged files of all log streams"
+        )
+        .namespace(METRICS_NAMESPACE)
+        .buckets(vec![0.5, 1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0])
+    )
+    .expect("metric can be created");
+    static ref STORAGE_SIZE: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "storage_size",
+            "Bytes of events per log stream, as ingested"
+        )
+        .namespace(METRICS_NAMESPACE),
+        &["stream"]
+    )
+    .expect("metric can be created");
+    static ref STORAGE_COMPRESSED_SIZE: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "storage_compressed_size",
+            "Bytes of parquet files per log stream"
+        )
+        .namespace(METRICS_NAMESPACE),
+        &["stream"]
+    )
+    .expect("metric can be created");
+    static ref EVENTS_STORED: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "events_stored",
+            "Events per log stream, across server restarts"
+        )
+        .namespace(METRICS_NAMESPACE),
+        &["stream"]
+    )
+    .expect("metric can be created");
+}
+
+/// Register all metrics, before the server starts. Registering twice fails.
+pub fn register() -> prometheus::Result<()> {
+    REGISTRY.register(Box::new(EVENTS_INGESTED.clone()))?;
+    REGISTRY.register(Box::new(EVENTS_FAILED.clone()))?;
+    REGISTRY.register(Box::new(STORAGE_RETRIES.clone()))?;
+    REGISTRY.register(Box::new(STORAGE_OPERATIONS.clone()))?;
+    REGISTRY.register(Box::new(STORAGE_ERRORS.clone()))?;
+    REGISTRY.register(Box::new(STORAGE_BYTES.clone()))?;
+    REGISTRY.register(Box::new(STORAGE_DURATION.clone()))?;
+    REGISTRY.register(Box::new(SYNC_DURATION.clone()))?;
+    REGISTRY.register(Box::new(STORAGE_SIZE.clone()))?;
+    REGISTRY.register(Box::new(STORAGE_COMPRESSED_SIZE.clone()))?;
+    REGISTRY.register(Box::new(EVENTS_STORED.clone()))?;
+
+    Ok(())
+}
+
+/// Returns all metrics in the Prometheus text format. Gauges are set from the
+/// stats of every log stream at the time of the scrape.
+pub fn gather() -> prometheus::Result<String> {
+    // streams deleted since the last scrape must not be reported anymore
+    STORAGE_SIZE.reset();
+    STORAGE_COMPRESSED_SIZE.reset();
+    EVENTS_STORED.reset();
+    remove_deleted_streams(&STORAGE_OPERATIONS);
+    remove_deleted_streams(&STORAGE_ERRORS);
+    remove_deleted_streams(&STORAGE_BYTES);
+    remove_deleted_streams(&STORAGE_DURATION);
+
+    for entry in STREAM_INFO.iter() {
+        let stream_name = entry.key().as_str();
+        let stats = &entry.value().stats;
+        STORAGE_SIZE
+            .with_label_values(&[stream_name])
+            .set(stats.size as i64);
+        STORAGE_COMPRESSED_SIZE
+            .with_label_values(&[stream_name])
+            .set(stats.compressed_size as i64);
+        EVENTS_STORED
+            .with_label_values(&[stream_name])
+            .set(stats.events as i64);
+    }
+
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer)?;
+
+    Ok(String::from_utf8(buffer).expect("text format is utf-8"))
+}
+
+// Storage operations are labelled with (operation, stream). Counts of the data objects
+// of deleted streams are dropped, they are never added to again.
+fn remove_deleted_streams<T: MetricVecBuilder>(metric: &MetricVec<T>) {
+    let mut deleted = Vec::new();
+    for family in metric.collect() {
+        for counted in family.get_metric() {
+            let label = |name| {
+                counted
+                    .get_label()
+                    .iter()
+                    .find(|label| label.get_name() == name)
+                    .map(|label| label.get_value().to_string())
+                    .unwrap_or_default()
+            };
+            let stream_name = label("stream");
+            if !stream_name.is_empty() && !STREAM_INFO.stream_exists(&stream_name) {
+                deleted.push((label("operation"), stream_name));
+            }
+        }
+    }
+
+    for (operation, stream_name) in deleted {
+        let _ = metric.remove_label_values(&[&operation, &stream_name]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serial_test::serial;
+
+    use super::{gather, register, EVENTS_INGESTED, STORAGE_OPERATIONS};
+    use crate::alerts::Alerts;
+    use crate::metadata::STREAM_INFO;
+
+    #[test]
+    #[serial]
+    fn gather_stream_metrics() {
+        register().unwrap();
+        STREAM_INFO
+            .add_stream("metricstream".to_string(), None, Alerts::default())
+            .unwrap();
+        STREAM_INFO
+            .update_stats("metricstream", 100, 10, 4)
+            .unwrap();
+        EVENTS_INGESTED
+            .with_label_values(&["metricstream"])
+            .inc_by(4);
+        STORAGE_OPERATIONS
+            .with_label_values(&["upload_file", "metricstream"])
+            .inc();
+
+        let metrics = gather().unwrap();
+        STREAM_INFO.delete_stream("metricstream").unwrap();
+
+        for line in [
+            r#"parseable_storage_size{stream="metricstream"} 100"#,
+            r#"parseable_storage_compressed_size{stream="metricstream"} 10"#,
+            r#"parseable_events_stored{stream="metricstream"} 4"#,
+            r#"parseable_events_ingested{stream="metricstream"} 4"#,
+            r#"parseable_storage_operations{operation="upload_file",stream="metricstream"} 1"#,
+        ] {
+            assert!(
+                metrics.lines().any(|l| l == line),
+                "{} in {}",
+                line,
+                metrics
+            );
+        }
+
+        // deleted streams are gone with the next scrape
+        let metrics = gather().unwrap();
+        assert!(!metrics.contains(r#"parseable_storage_size{stream="metricstream"}"#));
+        assert!(!metrics.contains(
+            r#"parseable_storage_operations{operation="upload_file",stream="metricstream"}"#
+        ));
+    }
+}
@@ -0,0 +1,250 @@
+/*
+ * Parseable Server (C) 2022 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use serde::{Deserialize, Serialize};
+
+use crate::metadata::Stats;
+use crate::storage::{ObjectStorage, ObjectStorageError};
+use crate::Error;
+
+/// Version of the layout of stream metadata in object storage this server reads and writes
+pub const CURRENT_VERSION: u32 = 2;
+
+/// Version of the layout of the metadata of a stream, put to object storage as
+/// `.metadata.json`. Streams put by servers from before the document have no
+/// document, they have version 1.
+///
+/// - version 1: a schema and alerts
+/// - version 2: also stats with the time of the last event and lifecycle timestamps
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetadataDocument {
+    pub version: u32,
+}
+
+impl Default for MetadataDocument {
+    fn default() -> Self {
+        Self {
+            version: CURRENT_VERSION,
+        }
+    }
+}
+
+/// Bring the metadata of the stream in object storage to the current version, one
+/// version at a time. Metadata of an unknown version is left untouched and fails
+/// with `UnsupportedMetadataVersion`, as it can't be read correctly.
+pub async fn migrate(storage: &dyn ObjectStorage, stream_name: &str) -> Result<(), Error> {
+    let mut version = match storage.get_metadata(stream_name).await {
+        Ok(document) => document.version,
+        Err(ObjectStorageError::NoSuchKey(_)) => 1,
+        Err(e) => return Err(e.into()),
+    };
+
+    if version == CURRENT_VERSION {
+        return Ok(());
+    }
+
+    while version < CURRENT_VERSION {
+        match version {
+            1 => v1_to_v2(storage, stream_name).await?,
+            _ => break,
+        }
+        version += 1;
+    }
+
+    if version != CURRENT_VERSION {
+        return Err(Error::UnsupportedMetadataVersion(
+            stream_name.to_owned(),
+            version,
+            CURRENT_VERSION,
+        ));
+    }
+
+    // the document is put last, so that an interrupted migration is run again
+    storage
+        .put_metadata(stream_name, &MetadataDocument::default())
+        .await?;
+
+    Ok(())
+}
+
+// Version 1 streams have no lifecycle timestamps and their stats don't have the
+// time of the last event. The earliest and latest data partitions are the closest
+// approximations.
+async fn v1_to_v2(
+    storage: &dyn ObjectStorage,
+    stream_name: &str,
+) -> Result<(), ObjectStorageError> {
+    let mut timestamps = match storage.get_timestamps(stream_name).await {
+        Ok(timestamps) => timestamps,
+        Err(ObjectStorageError::NoSuchKey(_)) => Default::default(),
+        Err(e) => return Err(e),
+    };
+    if timestamps.created_at.is_none() || timestamps.first_event_at.is_none() {
+        if let Some(earliest) = storage.earliest_event_time(stream_name).await? {
+            timestamps.created_at = timestamps.created_at.or(Some(earliest));
+            timestamps.first_event_at = timestamps.first_event_at.or(Some(earliest));
+            storage.put_timestamps(stream_name, &timestamps).await?;
+        }
+    }
+
+    // stats are only put to storage after the first stats sync
+    let mut stats = match storage.get_stats(stream_name).await {
+        Ok(stats) => stats,
+        Err(ObjectStorageError::NoSuchKey(_)) => Stats::default(),
+        Err(e) => return Err(e),
+    };
+    if stats.last_event_at.is_none() {
+        if let Some(latest) = storage.latest_event_time(stream_name).await? {
+            stats.last_event_at = Some(latest);
+            storage.put_stats(stream_name, &stats).await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use super::{migrate, MetadataDocument, CURRENT_VERSION};
+    use crate::localfs::LocalStorage;
+    use crate::storage::ObjectStorage;
+    use crate::{utils, Error};
+
+    fn time(rfc3339: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap().into()
+    }
+
+    // Write the objects of a stream as put by an older server version
+    fn fixture(objects: &[(&str, &str)]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("parseable-test-{}", utils::random_string()));
+        for (key, body) in objects {
+            let path = root.join(key);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, body).unwrap();
+        }
+        root
+    }
+
+    fn read(root: &Path, key: &str) -> String {
+        fs::read_to_string(root.join(key)).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn migrate_v1() {
+        let root = fixture(&[
+            ("stream/.schema", r#"{"fields":[],"metadata":{}}"#),
+            ("stream/.alert.json", r#"{"alerts":[]}"#),
+            ("stream/.stats.json", r#"{"size":100,"compressed_size":10}"#),
+            ("stream/date=2022-10-14/hour=10/minute=05/a.parquet", "data"),
+            ("stream/date=2022-10-15/hour=08/minute=00/b.parquet", "data"),
+        ]);
+        let storage = LocalStorage::new(root.clone());
+
+        migrate(&storage, "stream").await.unwrap();
+
+        assert_eq!(
+            storage.get_metadata("stream").await.unwrap(),
+            MetadataDocument {
+                version: CURRENT_VERSION
+            }
+        );
+        let timestamps = storage.get_timestamps("stream").await.unwrap();
+        assert_eq!(
+            timestamps.created_at,
+            Some(time("2022-10-14T10:05:00+00:00"))
+        );
+        assert_eq!(
+            timestamps.first_event_at,
+            Some(time("2022-10-14T10:05:00+00:00"))
+        );
+        let stats = storage.get_stats("stream").await.unwrap();
+        assert_eq!(stats.size, 100);
+        assert_eq!(stats.last_event_at, Some(time("2022-10-15T08:00:00+00:00")));
+        // the blobs of version 1 are kept as they are
+        assert_eq!(
+            read(&root, "stream/.schema"),
+            r#"{"fields":[],"metadata":{}}"#
+        );
+        assert_eq!(read(&root, "stream/.alert.json"), r#"{"alerts":[]}"#);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn migrate_v1_without_data() {
+        let root = fixture(&[("stream/.schema", "")]);
+        let storage = LocalStorage::new(root.clone());
+
+        migrate(&storage, "stream").await.unwrap();
+
+        assert_eq!(
+            storage.get_metadata("stream").await.unwrap().version,
+            CURRENT_VERSION
+        );
+        assert!(!root.join("stream/.timestamps.json").exists());
+        assert!(!root.join("stream/.stats.json").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn migrate_v2() {
+        let stats = r#"{"size":100,"compressed_size":10,"events":5,"last_event_at":"2022-10-15T09:00:00Z","sequence":3}"#;
+        let timestamps = r#"{"created_at":"2022-10-14T09:00:00Z","first_event_at":null}"#;
+        let root = fixture(&[
+            ("stream/.metadata.json", r#"{"version":2}"#),
+            ("stream/.schema", ""),
+            ("stream/.stats.json", stats),
+            ("stream/.timestamps.json", timestamps),
+            ("stream/date=2022-10-14/hour=10/minute=05/a.parquet", "data"),
+        ]);
+        let storage = LocalStorage::new(root.clone());
+
+        migrate(&storage, "stream").await.unwrap();
+
+        // metadata of the current version is left untouched
+        assert_eq!(read(&root, "stream/.metadata.json"), r#"{"version":2}"#);
+        assert_eq!(read(&root, "stream/.stats.json"), stats);
+        assert_eq!(read(&root, "stream/.timestamps.json"), timestamps);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn migrate_unknown_version() {
+        let root = fixture(&[
+            (
+                "stream/.metadata.json",
+                r#"{"version":3,"layout":"unknown"}"#,
+            ),
+            ("stream/.schema", ""),
+        ]);
+        let storage = LocalStorage::new(root.clone());
+
+        assert!(matches!(
+            migrate(&storage, "stream").await,
+            Err(Error::UnsupportedMetadataVersion(_, 3, CURRENT_VERSION))
+        ));
+        assert_eq!(
+            read(&root, "stream/.metadata.json"),
+            r#"{"version":3,"layout":"unknown"}"#
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -17,19 +17,42 @@
  */
 
 use crossterm::style::Stylize;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use structopt::StructOpt;
 
+use crate::auth::Permissions;
+#[cfg(feature = "azure")]
+use crate::azure::AzureConfig;
 use crate::banner;
+use crate::gcs::GcsConfig;
+use crate::instrumented::InstrumentedStorage;
+use crate::localfs::LocalStorageConfig;
+#[cfg(feature = "memory")]
+use crate::memory::MemoryStorageConfig;
+use crate::metadata::Compression;
+use crate::retry::{RetryPolicy, RetryingStorage};
 use crate::s3::S3Config;
-use crate::storage::{ObjectStorage, ObjectStorageError};
+use crate::storage::{self, ObjectStorage, ObjectStorageError};
 
 lazy_static::lazy_static! {
     #[derive(Debug)]
     pub static ref CONFIG: Arc<Config> = {
-        let storage = Box::new(S3Config::from_args());
-        Arc::new(Config::new(storage))
+        let parseable = Opt::from_args();
+        let storage: Box<dyn StorageOpt> = match parseable.storage_backend {
+            #[cfg(feature = "memory")]
+            _ if parseable.demo => Box::new(MemoryStorageConfig::new(&parseable.local_disk_path)),
+            StorageBackend::S3 => Box::new(S3Config::from_args()),
+            StorageBackend::Gcs => Box::new(GcsConfig::from_args()),
+            StorageBackend::Local => Box::new(LocalStorageConfig::from_args()),
+            #[cfg(feature = "azure")]
+            StorageBackend::Azure => Box::new(AzureConfig::from_args()),
+            #[cfg(feature = "memory")]
+            StorageBackend::Memory => Box::new(MemoryStorageConfig::new(&parseable.local_disk_path)),
+        };
+        Arc::new(Config::new(parseable, storage))
     };
 }
 
@@ -43,6 +66,7 @@ pub trait StorageOpt: Sync + Send {
     fn endpoint_url(&self) -> &str;
     fn warning(&self);
     fn is_default_url(&self) -> bool;
+    fn object_storage(&self) -> Box<dyn ObjectStorage>;
 }
 
 pub struct Config {
@@ -51,11 +75,21 @@ pub struct Config {
 }
 
 impl Config {
-    fn new(storage: Box<dyn StorageOpt>) -> Config {
-        Config {
-            parseable: Opt::from_args(),
-            storage,
-        }
+    fn new(parseable: Opt, storage: Box<dyn StorageOpt>) -> Config {
+        Config { parseable, storage }
+    }
+
+    /// Client for the object storage backend selected at startup, retrying
+    /// operations that fail with transient errors and recording metrics of all
+    pub fn object_storage(&self) -> Box<dyn ObjectStorage> {
+        let policy = RetryPolicy {
+            max_attempts: self.parseable.storage_max_attempts,
+            base_delay: Duration::from_millis(self.parseable.storage_retry_delay),
+        };
+        Box::new(InstrumentedStorage::new(RetryingStorage::new(
+            self.storage.object_storage(),
+            policy,
+        )))
     }
 
     pub fn print(&self) {
@@ -68,12 +102,25 @@ impl Config {
     }
 
     pub fn validate(&self) {
+        if cfg!(not(feature = "memory")) && CONFIG.parseable.demo {
+            panic!("--demo needs the server to be built with the memory feature");
+        }
+        if CONFIG.parseable.storage_max_attempts == 0 {
+            panic!("storage_max_attempts (P_STORAGE_MAX_ATTEMPTS) must be 1 or more");
+        }
         if CONFIG.parseable.upload_interval < 60 {
             panic!("object storage upload_interval (P_STORAGE_UPLOAD_INTERVAL) must be 60 seconds or more");
         }
+        // buffered events must be written before the data file is moved for upload
+        if CONFIG.parseable.flush_interval >= storage::LOCAL_SYNC_INTERVAL {
+            panic!(
+                "flush_interval (P_FLUSH_INTERVAL) must be less than {} seconds",
+                storage::LOCAL_SYNC_INTERVAL
+            );
+        }
     }
 
-    pub async fn validate_storage(&self, storage: &impl ObjectStorage) {
+    pub async fn validate_storage(&self, storage: &dyn ObjectStorage) {
         match storage.check().await {
             Ok(_) => (),
             Err(ObjectStorageError::NoSuchBucket(name)) => panic!(
@@ -81,6 +128,11 @@ impl Config {
                 bucket = name,
                 url = self.storage.endpoint_url()
             ),
+            Err(ObjectStorageError::AccessDenied(name)) => panic!(
+                "Could not start because access to bucket {bucket} on {url} was denied. Please check the credentials and their permissions on the bucket",
+                bucket = name,
+                url = self.storage.endpoint_url()
+            ),
             Err(ObjectStorageError::ConnectionError(inner)) => panic!(
                 "Failed to connect to the Object Storage Service on {url}\nCaused by: {cause}",
                 url = self.storage.endpoint_url(),
@@ -88,6 +140,28 @@ impl Config {
             ),
             Err(error) => { panic!("{error}") } 
         }
+
+        if self.parseable.skip_storage_check {
+            return;
+        }
+        match storage.check_write().await {
+            Ok(_) => (),
+            Err(ObjectStorageError::AccessDenied(name)) => panic!(
+                "Could not start because bucket {bucket} on {url} can be read but not written to. Please allow the credentials to write to it, or start with --skip-storage-check if they are meant to be read only",
+                bucket = name,
+                url = self.storage.endpoint_url()
+            ),
+            Err(ObjectStorageError::ConnectionError(inner)) => panic!(
+                "Failed to connect to the Object Storage Service on {url}\nCaused by: {cause}",
+                url = self.storage.endpoint_url(),
+                cause = inner
+            ),
+            Err(error) => panic!(
+                "Could not start because writing a probe object to {url} failed\nCaused by: {cause}",
+                url = self.storage.endpoint_url(),
+                cause = error
+            ),
+        }
     }
 
     fn status_info(&self, scheme: &str) {
@@ -183,11 +257,119 @@ pub struct Opt {
     #[structopt(long, env = "P_LOCAL_STORAGE", default_value = "./data")]
     pub local_disk_path: String,
 
+    /// The object storage platform used to store log streams,
+    /// one of `s3`, `gcs`, `local`, `azure` or `memory`. Defaults to s3.
+    #[structopt(long, env = "P_STORAGE_BACKEND", default_value = "s3")]
+    pub storage_backend: StorageBackend,
+
     /// Optional interval after which server would upload uncommited data to
     /// remote object storage platform. Defaults to 1min.
     #[structopt(long, env = "P_STORAGE_UPLOAD_INTERVAL", default_value = "60")]
     pub upload_interval: u64,
 
+    /// Optional interval after which server would persist stats of
+    /// all log streams to remote object storage platform. Defaults to 1min.
+    #[structopt(long, env = "P_STATS_SYNC_INTERVAL", default_value = "60")]
+    pub stats_sync_interval: u64,
+
+    /// Optional number of log streams whose metadata is fetched from object
+    /// storage at the same time during server start up. Defaults to 16.
+    #[structopt(long, env = "P_LOAD_CONCURRENCY", default_value = "16")]
+    pub load_concurrency: usize,
+
+    /// Optional number of log streams whose staged files are uploaded to object
+    /// storage at the same time during a sync. Defaults to 8.
+    #[structopt(long, env = "P_SYNC_CONCURRENCY", default_value = "8")]
+    pub sync_concurrency: usize,
+
+    /// Skip writing, reading back and deleting a probe object in object storage
+    /// at startup, for credentials that may only read. Reachability is still checked.
+    #[structopt(long)]
+    pub skip_storage_check: bool,
+
+    /// Run a demo server keeping log streams in memory, nothing is written to
+    /// object storage and all log streams are lost when the server stops.
+    #[structopt(long)]
+    pub demo: bool,
+
+    /// Optional limit in bytes on the size of an ingestion request body, as sent.
+    /// Larger requests are rejected with 413 Payload Too Large. Defaults to 100 KiB.
+    #[structopt(long, env = "P_MAX_INGEST_BODY_BYTES", default_value = "102400")]
+    pub max_ingest_body_bytes: usize,
+
+    /// Optional limit in bytes on the size of a gzip compressed event body
+    /// after decompression. Defaults to 10 MiB.
+    #[structopt(long, env = "P_MAX_DECOMPRESSED_SIZE", default_value = "10485760")]
+    pub max_decompressed_size: usize,
+
+    /// Optional time in hours that a deleted log stream can be restored for,
+    /// before its data is removed from remote object storage. Defaults to 24 hours.
+    #[structopt(long, env = "P_DELETE_GRACE_PERIOD", default_value = "24")]
+    pub delete_grace_period: u64,
+
+    /// Optional time in days that events of log streams partitioned by their own
+    /// time can arrive late, older events are rejected. Defaults to 30 days.
+    #[structopt(long, env = "P_MAX_EVENT_LATENESS", default_value = "30")]
+    pub max_event_lateness: u32,
+
+    /// Optional limit in bytes on the size of a single event, after flattening,
+    /// for log streams that don't set their own. Defaults to 1 MiB.
+    #[structopt(long, env = "P_MAX_EVENT_SIZE", default_value = "1048576")]
+    pub max_event_size: usize,
+
+    /// Optional limit on the number of columns of a log stream, and so of the
+    /// fields of a single event, for log streams that don't set their own. Defaults to 250.
+    #[structopt(long, env = "P_MAX_COLUMNS", default_value = "250")]
+    pub max_columns: usize,
+
+    /// Optional size in bytes of the events of a log stream buffered in memory,
+    /// after which they are written to its parquet file. Defaults to 4 MiB.
+    #[structopt(long, env = "P_FLUSH_SIZE", default_value = "4194304")]
+    pub flush_size: u64,
+
+    /// Optional interval in seconds after which events buffered in memory are
+    /// written to parquet files, whatever their size. Defaults to 10s.
+    #[structopt(long, env = "P_FLUSH_INTERVAL", default_value = "10")]
+    pub flush_interval: u64,
+
+    /// Optional time in seconds the server waits on shutdown, for in-flight
+    /// requests to finish and then for buffered events and stats to be
+    /// written to object storage. Defaults to 30s.
+    #[structopt(long, env = "P_SHUTDOWN_TIMEOUT", default_value = "30")]
+    pub shutdown_timeout: u64,
+
+    /// Optional number of attempts at an object storage operation that fails with a
+    /// transient error, like a timeout or a 5xx response. Defaults to 3.
+    #[structopt(long, env = "P_STORAGE_MAX_ATTEMPTS", default_value = "3")]
+    pub storage_max_attempts: u32,
+
+    /// Optional delay in milliseconds before the first retry of an object storage
+    /// operation. It doubles with every further retry and is randomized. Defaults to 200ms.
+    #[structopt(long, env = "P_STORAGE_RETRY_DELAY", default_value = "200")]
+    pub storage_retry_delay: u64,
+
+    /// Optional codec parquet files are compressed with, for log streams that
+    /// don't set their own. One of `snappy`, `zstd`, `gzip` or `uncompressed`.
+    /// Defaults to uncompressed.
+    #[structopt(long, env = "P_PARQUET_COMPRESSION", default_value = "uncompressed")]
+    pub parquet_compression: Compression,
+
+    /// Optional size in bytes that small parquet files are merged into by
+    /// compaction, files this size or larger are left as they are. Defaults to 256 MiB.
+    #[structopt(long, env = "P_COMPACTION_TARGET_SIZE", default_value = "268435456")]
+    pub compaction_target_size: u64,
+
+    /// Optional timeout in seconds for delivering a triggered alert to
+    /// one of its targets. Defaults to 10 sec.
+    #[structopt(long, env = "P_ALERT_TIMEOUT", default_value = "10")]
+    pub alert_timeout: u64,
+
+    /// Optional time in seconds after an alert fires that it doesn't fire again,
+    /// for alerts that don't set their own. Matches meanwhile are counted and sent
+    /// with the next notification. Defaults to 60 sec.
+    #[structopt(long, env = "P_ALERT_COOLDOWN", default_value = "60")]
+    pub alert_cooldown: u64,
+
     /// Optional username to enable basic auth on the server
     #[structopt(long, env = USERNAME_ENV, default_value = DEFAULT_USERNAME)]
     pub username: String,
@@ -195,6 +377,17 @@ pub struct Opt {
     /// Optional password to enable basic auth on the server
     #[structopt(long, env = PASSOWRD_ENV, default_value = DEFAULT_PASSWORD)]
     pub password: String,
+
+    /// Optional comma separated API keys, requests with any of them in an
+    /// `Authorization: Bearer <key>` header are let through as well
+    #[structopt(long, env = "P_API_KEYS", use_delimiter = true)]
+    pub api_keys: Vec<String>,
+
+    /// Optional JSON object limiting API keys to log streams and actions, like
+    /// `{"<key>": {"streams": ["app"], "actions": ["read", "write"]}}`. Keys
+    /// left out may take any action on any log stream.
+    #[structopt(long, env = "P_API_KEY_PERMISSIONS", default_value = "{}")]
+    pub api_key_permissions: Permissions,
 }
 
 impl Opt {
@@ -206,6 +399,18 @@ impl Opt {
         format!("{}/{}", self.local_disk_path, stream_name)
     }
 
+    /// Directory parquet files are merged in by compaction, hidden so that it isn't
+    /// taken for a log stream
+    pub fn compaction_staging_path(&self) -> PathBuf {
+        Path::new(&self.local_disk_path).join(".compaction")
+    }
+
+    /// Directory staged files that can't be read are moved to on start up, hidden
+    /// so that it isn't taken for a log stream
+    pub fn quarantine_path(&self) -> PathBuf {
+        Path::new(&self.local_disk_path).join(".quarantine")
+    }
+
     pub fn get_scheme(&self) -> String {
         if self.tls_cert_path.is_some() && self.tls_key_path.is_some() {
             return "https".to_string();
@@ -214,3 +419,34 @@ impl Opt {
         "http".to_string()
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageBackend {
+    S3,
+    Gcs,
+    Local,
+    #[cfg(feature = "azure")]
+    Azure,
+    #[cfg(feature = "memory")]
+    Memory,
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "s3" => Ok(StorageBackend::S3),
+            "gcs" => Ok(StorageBackend::Gcs),
+            "local" => Ok(StorageBackend::Local),
+            #[cfg(feature = "azure")]
+            "azure" => Ok(StorageBackend::Azure),
+            #[cfg(feature = "memory")]
+            "memory" => Ok(StorageBackend::Memory),
+            _ => Err(format!(
+                "unknown storage backend {}, expected s3, gcs, local, azure or memory",
+                s
+            )),
+        }
+    }
+}
@@ -17,17 +17,19 @@
  */
 
 use chrono::{DateTime, Utc};
+use datafusion::arrow::datatypes::SchemaRef;
 use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::datasource::file_format::parquet::ParquetFormat;
 use datafusion::datasource::listing::ListingOptions;
+use datafusion::error::DataFusionError;
 use datafusion::prelude::*;
 use serde_json::Value;
+use std::ops::Range;
 use std::sync::Arc;
 
+use crate::metadata;
 use crate::option::CONFIG;
-use crate::storage;
 use crate::storage::ObjectStorage;
-use crate::utils::TimePeriod;
 use crate::validator;
 use crate::Error;
 
@@ -39,6 +41,29 @@ fn get_value<'a>(value: &'a Value, key: &'static str) -> Result<&'a str, Error>
         .ok_or(Error::JsonQuery(key))
 }
 
+/// Run `sql` on the events of `stream_name` received in `time_range`. The query must
+/// only select from `stream_name`, and only the partitions within the time range are read.
+pub async fn run_sql(
+    storage: &dyn ObjectStorage,
+    stream_name: &str,
+    sql: &str,
+    time_range: Range<DateTime<Utc>>,
+) -> Result<Vec<RecordBatch>, Error> {
+    let query = validator::query(
+        sql,
+        &time_range.start.to_rfc3339(),
+        &time_range.end.to_rfc3339(),
+    )?;
+    if query.stream_name != stream_name {
+        return Err(Error::QueryStreamMismatch(
+            stream_name.to_owned(),
+            query.stream_name,
+        ));
+    }
+
+    query.execute(storage).await
+}
+
 // Query holds all values relevant to a query for a single log stream
 pub struct Query {
     pub query: String,
@@ -59,15 +84,18 @@ impl Query {
         validator::query(query, start_time, end_time)
     }
 
-    /// Return prefixes, each per day/hour/minutes as necessary
-    pub fn get_prefixes(&self) -> Vec<String> {
-        TimePeriod::new(self.start, self.end, storage::OBJECT_STORE_DATA_GRANULARITY)
-            .generate_prefixes(&self.stream_name)
+    /// Schema of the log stream, the tables of its parquet files are registered with it
+    /// instead of one inferred from the files, which may predate changes to the schema.
+    pub fn stored_schema(&self) -> Option<SchemaRef> {
+        metadata::STREAM_INFO
+            .schema(&self.stream_name)
+            .ok()
+            .flatten()
     }
 
     /// Execute query on object storage(and if necessary on cache as well) with given stream information
     /// TODO: find a way to query all selected parquet files together in a single context.
-    pub async fn execute(&self, storage: &impl ObjectStorage) -> Result<Vec<RecordBatch>, Error> {
+    pub async fn execute(&self, storage: &dyn ObjectStorage) -> Result<Vec<RecordBatch>, Error> {
         let mut results = vec![];
         storage.query(self, &mut results).await?;
 
@@ -81,6 +109,18 @@ impl Query {
     }
 
     async fn execute_on_cache(&self, results: &mut Vec<RecordBatch>) -> Result<(), Error> {
+        let path = CONFIG.parseable.get_cache_path(&self.stream_name);
+        self.execute_on_dir(&path, results)
+            .await
+            .map_err(Error::DataFusion)
+    }
+
+    /// Execute query on the parquet files in a local directory
+    pub async fn execute_on_dir(
+        &self,
+        path: &str,
+        results: &mut Vec<RecordBatch>,
+    ) -> Result<(), DataFusionError> {
         let ctx = SessionContext::new();
         let file_format = ParquetFormat::default().with_enable_pruning(true);
 
@@ -94,15 +134,15 @@ impl Query {
 
         ctx.register_listing_table(
             &self.stream_name,
-            CONFIG.parseable.get_cache_path(&self.stream_name).as_str(),
+            path,
             listing_options,
-            None,
+            self.stored_schema(),
         )
         .await?;
 
         // execute the query and collect results
         let df = ctx.sql(self.query.as_str()).await?;
-        results.extend(df.collect().await.map_err(Error::DataFusion)?);
+        results.extend(df.collect().await?);
 
         Ok(())
     }
@@ -110,10 +150,27 @@ impl Query {
 
 #[cfg(test)]
 mod tests {
-    use super::Query;
+    use super::{run_sql, Query};
+    use chrono::{DateTime, Utc};
+    use datafusion::arrow::array::StringArray;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use parquet::arrow::arrow_writer::ArrowWriter;
     use rstest::*;
     use serde_json::Value;
+    use serial_test::serial;
+    use std::fs;
+    use std::path::Path;
     use std::str::FromStr;
+    use std::sync::Arc;
+
+    use crate::alerts::Alerts;
+    use crate::localfs::LocalStorage;
+    use crate::metadata::STREAM_INFO;
+    use crate::storage::mock::MockStorage;
+    use crate::storage::{ObjectStorage, OBJECT_STORE_DATA_GRANULARITY};
+    use crate::utils::TimePeriod;
+    use crate::{utils, validator, Error};
 
     #[rstest]
     #[case(
@@ -136,8 +193,92 @@ mod tests {
         let query = Value::from_str(prefix).unwrap();
         let query = Query::parse(query).unwrap();
         assert_eq!(&query.stream_name, "stream_name");
-        let prefixes = query.get_prefixes();
+        let prefixes = TimePeriod::new(query.start, query.end, OBJECT_STORE_DATA_GRANULARITY)
+            .generate_prefixes(&query.stream_name);
         let left = prefixes.iter().map(String::as_str).collect::<Vec<&str>>();
         assert_eq!(left.as_slice(), right);
     }
+
+    fn time(rfc3339: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap().into()
+    }
+
+    fn fixture_schema() -> Schema {
+        Schema::new(vec![Field::new("level", DataType::Utf8, true)])
+    }
+
+    fn write_fixture(root: &Path, prefix: &str, levels: &[&str]) {
+        let dir = root.join(prefix);
+        fs::create_dir_all(&dir).unwrap();
+
+        let schema = Arc::new(fixture_schema());
+        let rb = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(StringArray::from(levels.to_vec()))],
+        )
+        .unwrap();
+        let file = fs::File::create(dir.join("data.parquet")).unwrap();
+        let mut writer = ArrowWriter::try_new(file, schema, None).unwrap();
+        writer.write(&rb).unwrap();
+        writer.close().unwrap();
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn query_fixture_partitions() {
+        let root = std::env::temp_dir().join(format!("parseable-test-{}", utils::random_string()));
+        write_fixture(
+            &root,
+            "fixture/date=2022-10-15/hour=10/minute=00/",
+            &["info", "error"],
+        );
+        write_fixture(
+            &root,
+            "fixture/date=2022-10-15/hour=10/minute=01/",
+            &["error"],
+        );
+        // outside of the time range of the query
+        write_fixture(
+            &root,
+            "fixture/date=2022-10-15/hour=10/minute=02/",
+            &["error"],
+        );
+        STREAM_INFO
+            .add_stream(
+                "fixture".to_string(),
+                Some(fixture_schema()),
+                Alerts::default(),
+            )
+            .unwrap();
+
+        let query = validator::query(
+            "SELECT * FROM fixture WHERE level = 'error'",
+            "2022-10-15T10:00:00+00:00",
+            "2022-10-15T10:02:00+00:00",
+        )
+        .unwrap();
+        let mut results = vec![];
+        let result = LocalStorage::new(root.clone())
+            .query(&query, &mut results)
+            .await;
+        STREAM_INFO.delete_stream("fixture").unwrap();
+        fs::remove_dir_all(root).unwrap();
+
+        result.unwrap();
+        let rows: usize = results.iter().map(RecordBatch::num_rows).sum();
+        assert_eq!(rows, 2);
+    }
+
+    #[actix_web::test]
+    async fn run_sql_on_other_stream() {
+        let result = run_sql(
+            &MockStorage::default(),
+            "fixture",
+            "SELECT * FROM otherstream",
+            time("2022-10-15T10:00:00+00:00")..time("2022-10-15T10:02:00+00:00"),
+        )
+        .await;
+
+        assert!(matches!(result, Err(Error::QueryStreamMismatch(..))));
+    }
 }
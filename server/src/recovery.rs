@@ -0,0 +1,343 @@
+/*
+ * Parseable Server (C) 2022 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use parquet::arrow::{ArrowReader, ParquetFileArrowReader};
+use parquet::file::reader::SerializedFileReader;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use walkdir::WalkDir;
+
+use crate::manifest::{self, ManifestFile};
+use crate::metadata::STREAM_INFO;
+use crate::storage::{self, ObjectStorage, DATA_FILE};
+use crate::utils;
+use crate::Error;
+
+/// Staged files of a previous run, counted by what became of them
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Recovered {
+    pub uploaded: u64,
+    pub quarantined: u64,
+    /// Files that failed to upload, they are left to the next sync
+    pub failed: u64,
+}
+
+/// Upload the parquet files left in `staging_dir` by a server that was stopped before it
+/// synced them, e.g. because it was killed. Runs on start up before events are accepted,
+/// so that no data file is written to while it is uploaded. Files that can't be read are
+/// moved to `quarantine_dir` instead, and counted in the stats of their stream.
+pub async fn recover(
+    storage: &(impl ObjectStorage + ?Sized),
+    staging_dir: &Path,
+    quarantine_dir: &Path,
+    now: DateTime<Utc>,
+) -> Recovered {
+    let mut recovered = Recovered::default();
+    let dirs = match fs::read_dir(staging_dir) {
+        Ok(dirs) => dirs,
+        // nothing was staged yet
+        Err(_) => return recovered,
+    };
+
+    for dir in dirs.filter_map(Result::ok) {
+        let stream_name = dir.file_name().to_string_lossy().into_owned();
+        // hidden dirs, like the compaction staging dir, aren't log streams
+        if stream_name.starts_with('.') || !dir.path().is_dir() {
+            continue;
+        }
+        if !STREAM_INFO.stream_exists(&stream_name) {
+            warn!(
+                "staged files of unknown log stream {} are left as is",
+                stream_name
+            );
+            continue;
+        }
+
+        let stream = recover_stream(
+            storage,
+            &stream_name,
+            &dir.path(),
+            &quarantine_dir.join(&stream_name),
+            now,
+        )
+        .await;
+        if stream == Recovered::default() {
+            continue;
+        }
+        info!(
+            "recovered staged files of log stream {}. {:?}",
+            stream_name, stream
+        );
+
+        let stats = STREAM_INFO.stats(&stream_name).map_err(|e| e.to_string());
+        match stats {
+            Ok(stats) => {
+                if let Err(e) = storage.sync_stream_stats(&stream_name, &stats).await {
+                    warn!("failed to put stats of log stream {}. {:?}", stream_name, e);
+                }
+            }
+            Err(e) => warn!("failed to get stats of log stream {}. {}", stream_name, e),
+        }
+
+        recovered.uploaded += stream.uploaded;
+        recovered.quarantined += stream.quarantined;
+        recovered.failed += stream.failed;
+    }
+
+    recovered
+}
+
+async fn recover_stream(
+    storage: &(impl ObjectStorage + ?Sized),
+    stream_name: &str,
+    dir: &Path,
+    quarantine_dir: &Path,
+    now: DateTime<Utc>,
+) -> Recovered {
+    let mut recovered = Recovered::default();
+    let files = staged_files(dir, stream_name, now);
+
+    let mut synced = Vec::new();
+    for (index, (path, key)) in files.iter().enumerate() {
+        if let Err(e) = check_readable(path) {
+            warn!("staged file {} can't be read. {}", path.display(), e);
+            match quarantine(path, quarantine_dir) {
+                Ok(()) => {
+                    recovered.quarantined += 1;
+                    if let Err(e) = STREAM_INFO.record_quarantine(stream_name) {
+                        warn!("failed to record quarantined file. {:?}", e);
+                    }
+                }
+                Err(e) => {
+                    warn!("failed to quarantine {}. {}", path.display(), e);
+                    recovered.failed += 1;
+                }
+            }
+            continue;
+        }
+
+        let local_path = path.to_string_lossy();
+        // read before the upload, the staged file is removed once uploaded
+        let entry = key
+            .strip_prefix(&format!("{}/", stream_name))
+            .and_then(|key| ManifestFile::of_file(key, path).ok().flatten());
+        if let Err(e) = storage::upload_verified(storage, stream_name, key, &local_path).await {
+            log::error!("failed to upload {} due to error [{}]", key, e);
+            if let Err(e) = STREAM_INFO.record_sync(stream_name, Err(&e)) {
+                warn!("failed to record sync failure. {:?}", e);
+            }
+            // the rest is left to the next sync
+            recovered.failed += (files.len() - index) as u64;
+            break;
+        }
+
+        recovered.uploaded += 1;
+        synced.extend(entry);
+        if let Err(e) = STREAM_INFO.record_upload(stream_name, key.clone()) {
+            warn!("failed to record upload of {}. {:?}", key, e);
+        }
+    }
+
+    if !synced.is_empty() {
+        manifest::update(storage, stream_name, |manifest| manifest.add(synced)).await;
+    }
+
+    recovered
+}
+
+/// Staged parquet files of the stream in `dir`, along with the keys they are uploaded to
+fn staged_files(dir: &Path, stream_name: &str, now: DateTime<Utc>) -> Vec<(PathBuf, String)> {
+    let mut files = Vec::new();
+
+    // files moved to tmp are already named after their partition
+    for file in WalkDir::new(dir.join("tmp"))
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|file| file.file_type().is_file())
+    {
+        let name = file.file_name().to_string_lossy();
+        if let Some(name) = name.strip_suffix(".parquet") {
+            let key = format!("{}/{}.parquet", stream_name, name.replace('.', "/"));
+            files.push((file.path().to_path_buf(), key));
+        }
+    }
+
+    // the data file of the stream was written to until the server stopped,
+    // data files of events partitioned by their own time are named after the partition
+    for file in fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+    {
+        let name = file.file_name().to_string_lossy().into_owned();
+        let partition = match name.strip_suffix(DATA_FILE) {
+            Some("") => {
+                let written_at = file
+                    .metadata()
+                    .and_then(|metadata| metadata.modified())
+                    .map_or(now, DateTime::<Utc>::from);
+                STREAM_INFO
+                    .partition(stream_name)
+                    .unwrap_or_default()
+                    .prefix(written_at)
+            }
+            Some(local_uri) if local_uri.starts_with("date=") => local_uri.replace('.', "/"),
+            _ => continue,
+        };
+        let key = format!(
+            "{}/{}{}.parquet",
+            stream_name,
+            partition,
+            utils::random_string()
+        );
+        files.push((file.path(), key));
+    }
+
+    files
+}
+
+// All of the file is read, not just its footer, as any part of it may be damaged
+fn check_readable(path: &Path) -> Result<(), Error> {
+    let mut reader =
+        ParquetFileArrowReader::new(Arc::new(SerializedFileReader::new(fs::File::open(path)?)?));
+    for rb in reader.get_record_reader(2048)? {
+        rb?;
+    }
+
+    Ok(())
+}
+
+fn quarantine(path: &Path, quarantine_dir: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(quarantine_dir)?;
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    // data files of different runs have the same name
+    fs::rename(
+        path,
+        quarantine_dir.join(format!("{}.{}", utils::random_string(), name)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::StringArray;
+    use arrow::datatypes::{DataType, Field, Schema};
+    use arrow::record_batch::RecordBatch;
+    use parquet::arrow::arrow_writer::ArrowWriter;
+    use serial_test::serial;
+
+    use crate::alerts::Alerts;
+    use crate::storage::mock::MockStorage;
+
+    fn write_parquet(path: &Path) {
+        let schema = Arc::new(Schema::new(vec![Field::new("level", DataType::Utf8, true)]));
+        let rb = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(StringArray::from(vec!["info", "error"]))],
+        )
+        .unwrap();
+
+        let mut writer =
+            ArrowWriter::try_new(fs::File::create(path).unwrap(), schema, None).unwrap();
+        writer.write(&rb).unwrap();
+        writer.close().unwrap();
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn staged_files_of_killed_server_are_uploaded() {
+        let dir = std::env::temp_dir().join(format!("parseable-test-{}", utils::random_string()));
+        let staging_dir = dir.join("staging");
+        let quarantine_dir = dir.join("quarantine");
+        let stream_dir = staging_dir.join("recoverstream");
+        fs::create_dir_all(stream_dir.join("tmp")).unwrap();
+        // moved to tmp by the local sync, not uploaded yet
+        write_parquet(
+            &stream_dir
+                .join("tmp")
+                .join("date=2022-10-15.hour=10.minute=05.abc.parquet"),
+        );
+        // still being written to
+        write_parquet(&stream_dir.join(DATA_FILE));
+        // cut short while it was written
+        fs::write(
+            stream_dir.join(format!("date=2022-10-15.hour=11.minute=00.{}", DATA_FILE)),
+            b"PAR1 events",
+        )
+        .unwrap();
+        STREAM_INFO
+            .add_stream("recoverstream".to_string(), None, Alerts::default())
+            .unwrap();
+        let storage = MockStorage::default();
+
+        let recovered = recover(&storage, &staging_dir, &quarantine_dir, Utc::now()).await;
+        let stats = STREAM_INFO.stats("recoverstream").unwrap();
+        STREAM_INFO.delete_stream("recoverstream").unwrap();
+
+        assert_eq!(
+            recovered,
+            Recovered {
+                uploaded: 2,
+                quarantined: 1,
+                failed: 0,
+            }
+        );
+        let objects = storage.objects();
+        assert!(objects
+            .contains(&"recoverstream/date=2022-10-15/hour=10/minute=05/abc.parquet".to_string()));
+        assert_eq!(
+            objects
+                .iter()
+                .filter(|key| key.starts_with("recoverstream/date=") && key.ends_with(".parquet"))
+                .count(),
+            2
+        );
+        assert_eq!(stats.parquet_files, 2);
+        assert_eq!(stats.quarantined_files, 1);
+        assert!(staged_files(&stream_dir, "recoverstream", Utc::now()).is_empty());
+        assert_eq!(
+            fs::read_dir(quarantine_dir.join("recoverstream"))
+                .unwrap()
+                .count(),
+            1
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn staged_files_of_unknown_stream_are_left() {
+        let dir = std::env::temp_dir().join(format!("parseable-test-{}", utils::random_string()));
+        let stream_dir = dir.join("unknownstream");
+        fs::create_dir_all(&stream_dir).unwrap();
+        write_parquet(&stream_dir.join(DATA_FILE));
+        let storage = MockStorage::default();
+
+        let recovered = recover(&storage, &dir, &dir.join(".quarantine"), Utc::now()).await;
+
+        assert_eq!(recovered, Recovered::default());
+        assert!(storage.objects().is_empty());
+        assert!(stream_dir.join(DATA_FILE).exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -17,12 +17,12 @@
  */
 
 use actix_web::http::StatusCode;
-use actix_web::{error, web, HttpResponse, HttpResponseBuilder, Responder};
+use actix_web::{error, HttpResponse, HttpResponseBuilder};
 use datafusion::arrow::json;
 use datafusion::arrow::record_batch::RecordBatch;
 use derive_more::{Display, Error};
 
-use crate::storage;
+use crate::metadata::StreamSummary;
 
 pub struct ServerResponse {
     pub code: StatusCode,
@@ -39,8 +39,8 @@ impl ServerResponse {
     }
 }
 
-pub fn list_response(body: Vec<storage::LogStream>) -> impl Responder {
-    web::Json(body)
+pub fn list_response(body: Vec<StreamSummary>) -> HttpResponse {
+    HttpResponse::Ok().json(body)
 }
 
 pub struct QueryResponse {
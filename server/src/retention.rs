@@ -0,0 +1,182 @@
+/*
+ * Parseable Server (C) 2022 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use chrono::{Duration, NaiveDate, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::manifest;
+use crate::metadata::STREAM_INFO;
+use crate::storage::{ObjectStorage, ObjectStorageError};
+
+/// Interval in seconds between two runs of the retention job
+pub const RETENTION_INTERVAL: u32 = 60 * 60;
+
+/// Retention period of a log stream as put to object storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Retention {
+    pub days: u32,
+}
+
+impl Retention {
+    pub fn duration(&self) -> Duration {
+        Duration::days(self.days as i64)
+    }
+}
+
+impl From<Duration> for Retention {
+    fn from(duration: Duration) -> Self {
+        Self {
+            days: duration.num_days().max(0) as u32,
+        }
+    }
+}
+
+/// Delete data of all log streams that is older than their retention period.
+/// Streams without a retention period keep all of their data.
+pub async fn enforce(storage: &dyn ObjectStorage) {
+    for (stream_name, retention) in STREAM_INFO.retentions() {
+        if let Err(e) = enforce_stream(storage, &stream_name, retention).await {
+            warn!(
+                "failed to enforce retention of log stream {}. {:?}",
+                stream_name, e
+            );
+        }
+    }
+}
+
+async fn enforce_stream(
+    storage: &dyn ObjectStorage,
+    stream_name: &str,
+    retention: Duration,
+) -> Result<(), ObjectStorageError> {
+    let today = Utc::now().naive_utc().date();
+    let prefix = format!("{}/", stream_name);
+
+    let mut objects = 0;
+    let mut size = 0;
+    for dir in storage.list_dirs(&prefix).await? {
+        if !is_expired(&dir, today, retention) {
+            continue;
+        }
+
+        let deleted = match storage.delete_prefix(&format!("{}{}/", prefix, dir)).await {
+            Ok(deleted) => deleted,
+            // objects that were deleted still come off the stats, the rest
+            // are left for the next run
+            Err(ObjectStorageError::PartialDelete(deleted)) => {
+                remove_compressed(stream_name, deleted.size);
+                return Err(ObjectStorageError::PartialDelete(deleted));
+            }
+            Err(e) => return Err(e),
+        };
+        objects += deleted.objects;
+        size += deleted.size;
+        let expired = format!("{}/", dir);
+        manifest::update(storage, stream_name, |manifest| {
+            manifest.remove_prefix(&expired)
+        })
+        .await;
+
+        // update stats after each partition, so a failure later on
+        // doesn't leave them out of step with what is in storage
+        remove_compressed(stream_name, deleted.size);
+    }
+
+    if objects > 0 {
+        info!(
+            "removed {} objects ({} bytes) of log stream {} older than {} days",
+            objects,
+            size,
+            stream_name,
+            retention.num_days()
+        );
+    }
+
+    Ok(())
+}
+
+fn remove_compressed(stream_name: &str, size: u64) {
+    if let Err(e) = STREAM_INFO.remove_compressed(stream_name, size) {
+        warn!("failed to update stats after enforcing retention. {:?}", e);
+    }
+}
+
+/// Whether the date partition `dir` (e.g. `date=2022-10-15`) is older than the
+/// retention period. Today's partition is never expired, whatever the retention.
+fn is_expired(dir: &str, today: NaiveDate, retention: Duration) -> bool {
+    let date = match dir
+        .strip_prefix("date=")
+        .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok())
+    {
+        Some(date) => date,
+        None => return false,
+    };
+
+    date < today && date < today - retention
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    use crate::storage::mock::MockStorage;
+    use crate::storage::DeletedObjects;
+
+    #[rstest]
+    #[case::older("date=2022-09-14", 30, true)]
+    #[case::at_cutoff("date=2022-09-15", 30, false)]
+    #[case::recent("date=2022-10-14", 30, false)]
+    #[case::yesterday_zero_days("date=2022-10-14", 0, true)]
+    #[case::today_zero_days("date=2022-10-15", 0, false)]
+    #[case::not_a_date("hour=10", 0, false)]
+    #[case::invalid_date("date=2022-13-01", 0, false)]
+    fn expired_partitions(#[case] dir: &str, #[case] days: i64, #[case] expired: bool) {
+        let today = NaiveDate::from_ymd_opt(2022, 10, 15).unwrap();
+        assert_eq!(is_expired(dir, today, Duration::days(days)), expired);
+    }
+
+    #[actix_web::test]
+    async fn partial_delete_is_reported() {
+        let storage = MockStorage::default()
+            .with_objects(&[
+                "retainedstream/date=2000-01-01/hour=10/minute=00/a.parquet",
+                "retainedstream/date=2000-01-01/hour=10/minute=00/b.parquet",
+            ])
+            .with_delete_limit(1);
+
+        let result = enforce_stream(&storage, "retainedstream", Duration::days(1)).await;
+
+        assert!(matches!(
+            result,
+            Err(ObjectStorageError::PartialDelete(DeletedObjects {
+                objects: 1,
+                failed: 1,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn retention_roundtrip() {
+        let retention: Retention = serde_json::from_str(r#"{"days": 30}"#).unwrap();
+        assert_eq!(retention.duration(), Duration::days(30));
+        assert_eq!(Retention::from(retention.duration()), retention);
+    }
+}
@@ -0,0 +1,555 @@
+/*
+ * Parseable Server (C) 2022 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use arrow::datatypes::Schema;
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use datafusion::arrow::record_batch::RecordBatch;
+use log::warn;
+use rand::Rng;
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+
+use crate::alerts::Alerts;
+use crate::metadata::{
+    Compression, Flatten, Limits, PartitionGranularity, Stats, StreamTimestamps,
+};
+use crate::metrics;
+use crate::migration::MetadataDocument;
+use crate::query::Query;
+use crate::retention::Retention;
+use crate::storage::{
+    DeletedObjects, LogStream, ObjectMeta, ObjectStorage, ObjectStorageError, ParquetFile,
+    StoredObjects, StreamsPage,
+};
+
+/// Retries are never further apart than this, however many there are
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Attempts at an operation, the first one included
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubling with every further retry
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Delay before the `retry`th retry, counting from 1. Randomized between half
+    /// and all of the exponential delay, so that clients that failed together
+    /// don't retry together.
+    fn delay(&self, retry: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry - 1))
+            .min(MAX_RETRY_DELAY);
+        let millis = delay.as_millis() as u64;
+
+        Duration::from_millis(rand::thread_rng().gen_range(millis / 2..=millis))
+    }
+}
+
+/// Errors that may not happen again. Missing objects, denied access and
+/// invalid requests fail the same way every time and are not retried.
+fn is_transient(error: &ObjectStorageError) -> bool {
+    matches!(
+        error,
+        ObjectStorageError::ConnectionError(_) | ObjectStorageError::Unavailable(_)
+    )
+}
+
+/// Object storage that retries operations failing with transient errors. Operations
+/// are retried as a whole: puts and uploads write the whole object again, and copies
+/// and deletes of a prefix start over, so a retry never leaves an object half written.
+pub struct RetryingStorage {
+    inner: Box<dyn ObjectStorage>,
+    policy: RetryPolicy,
+}
+
+impl RetryingStorage {
+    pub fn new(inner: Box<dyn ObjectStorage>, policy: RetryPolicy) -> Self {
+        Self { inner, policy }
+    }
+
+    async fn retry<T, F, Fut>(&self, operation: &str, attempt: F) -> Result<T, ObjectStorageError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, ObjectStorageError>>,
+    {
+        let mut retries = 0;
+        loop {
+            // the error isn't kept across the wait, it can't be sent between threads
+            match attempt().await {
+                Ok(value) => return Ok(value),
+                Err(e) if is_transient(&e) && retries + 1 < self.policy.max_attempts => {
+                    warn!("object storage {} failed, retrying. {}", operation, e)
+                }
+                Err(e) => return Err(e),
+            }
+
+            retries += 1;
+            metrics::STORAGE_RETRIES
+                .with_label_values(&[operation])
+                .inc();
+            actix_web::rt::time::sleep(self.policy.delay(retries)).await;
+        }
+    }
+}
+
+#[async_trait]
+impl ObjectStorage for RetryingStorage {
+    async fn check(&self) -> Result<(), ObjectStorageError> {
+        self.retry("check", || self.inner.check()).await
+    }
+
+    async fn put_schema(
+        &self,
+        stream_name: String,
+        schema: &Schema,
+    ) -> Result<(), ObjectStorageError> {
+        self.retry("put_schema", || {
+            self.inner.put_schema(stream_name.clone(), schema)
+        })
+        .await
+    }
+
+    async fn create_stream(&self, stream_name: &str) -> Result<(), ObjectStorageError> {
+        self.retry("create_stream", || self.inner.create_stream(stream_name))
+            .await
+    }
+
+    async fn create_alert(
+        &self,
+        stream_name: &str,
+        alerts: &Alerts,
+    ) -> Result<(), ObjectStorageError> {
+        self.retry("create_alert", || {
+            self.inner.create_alert(stream_name, alerts)
+        })
+        .await
+    }
+
+    async fn get_schema(&self, stream_name: &str) -> Result<Bytes, ObjectStorageError> {
+        self.retry("get_schema", || self.inner.get_schema(stream_name))
+            .await
+    }
+
+    async fn get_schema_tagged(
+        &self,
+        stream_name: &str,
+    ) -> Result<Option<(Bytes, String)>, ObjectStorageError> {
+        self.retry("get_schema_tagged", || {
+            self.inner.get_schema_tagged(stream_name)
+        })
+        .await
+    }
+
+    async fn put_schema_if(
+        &self,
+        stream_name: &str,
+        schema: &Schema,
+        tag: Option<&str>,
+    ) -> Result<(), ObjectStorageError> {
+        self.retry("put_schema_if", || {
+            self.inner.put_schema_if(stream_name, schema, tag)
+        })
+        .await
+    }
+
+    async fn get_alert(&self, stream_name: &str) -> Result<Bytes, ObjectStorageError> {
+        self.retry("get_alert", || self.inner.get_alert(stream_name))
+            .await
+    }
+
+    async fn get_stats(&self, stream_name: &str) -> Result<Stats, ObjectStorageError> {
+        self.retry("get_stats", || self.inner.get_stats(stream_name))
+            .await
+    }
+
+    async fn put_stats(&self, stream_name: &str, stats: &Stats) -> Result<(), ObjectStorageError> {
+        self.retry("put_stats", || self.inner.put_stats(stream_name, stats))
+            .await
+    }
+
+    async fn put_retention(
+        &self,
+        stream_name: &str,
+        retention: &Retention,
+    ) -> Result<(), ObjectStorageError> {
+        self.retry("put_retention", || {
+            self.inner.put_retention(stream_name, retention)
+        })
+        .await
+    }
+
+    async fn get_retention(&self, stream_name: &str) -> Result<Retention, ObjectStorageError> {
+        self.retry("get_retention", || self.inner.get_retention(stream_name))
+            .await
+    }
+
+    async fn put_tags(
+        &self,
+        stream_name: &str,
+        tags: &HashMap<String, String>,
+    ) -> Result<(), ObjectStorageError> {
+        self.retry("put_tags", || self.inner.put_tags(stream_name, tags))
+            .await
+    }
+
+    async fn get_tags(
+        &self,
+        stream_name: &str,
+    ) -> Result<HashMap<String, String>, ObjectStorageError> {
+        self.retry("get_tags", || self.inner.get_tags(stream_name))
+            .await
+    }
+
+    async fn put_static_schema(
+        &self,
+        stream_name: &str,
+        static_schema: bool,
+    ) -> Result<(), ObjectStorageError> {
+        self.retry("put_static_schema", || {
+            self.inner.put_static_schema(stream_name, static_schema)
+        })
+        .await
+    }
+
+    async fn get_static_schema(&self, stream_name: &str) -> Result<bool, ObjectStorageError> {
+        self.retry("get_static_schema", || {
+            self.inner.get_static_schema(stream_name)
+        })
+        .await
+    }
+
+    async fn put_deleted_at(
+        &self,
+        stream_name: &str,
+        deleted_at: Option<DateTime<Utc>>,
+    ) -> Result<(), ObjectStorageError> {
+        self.retry("put_deleted_at", || {
+            self.inner.put_deleted_at(stream_name, deleted_at)
+        })
+        .await
+    }
+
+    async fn get_deleted_at(
+        &self,
+        stream_name: &str,
+    ) -> Result<Option<DateTime<Utc>>, ObjectStorageError> {
+        self.retry("get_deleted_at", || self.inner.get_deleted_at(stream_name))
+            .await
+    }
+
+    async fn put_renamed_from(
+        &self,
+        stream_name: &str,
+        renamed_from: &str,
+    ) -> Result<(), ObjectStorageError> {
+        self.retry("put_renamed_from", || {
+            self.inner.put_renamed_from(stream_name, renamed_from)
+        })
+        .await
+    }
+
+    async fn get_renamed_from(&self, stream_name: &str) -> Result<String, ObjectStorageError> {
+        self.retry("get_renamed_from", || {
+            self.inner.get_renamed_from(stream_name)
+        })
+        .await
+    }
+
+    async fn put_time_field(
+        &self,
+        stream_name: &str,
+        time_field: &str,
+    ) -> Result<(), ObjectStorageError> {
+        self.retry("put_time_field", || {
+            self.inner.put_time_field(stream_name, time_field)
+        })
+        .await
+    }
+
+    async fn get_time_field(&self, stream_name: &str) -> Result<String, ObjectStorageError> {
+        self.retry("get_time_field", || self.inner.get_time_field(stream_name))
+            .await
+    }
+
+    async fn put_limits(
+        &self,
+        stream_name: &str,
+        limits: &Limits,
+    ) -> Result<(), ObjectStorageError> {
+        self.retry("put_limits", || self.inner.put_limits(stream_name, limits))
+            .await
+    }
+
+    async fn get_limits(&self, stream_name: &str) -> Result<Limits, ObjectStorageError> {
+        self.retry("get_limits", || self.inner.get_limits(stream_name))
+            .await
+    }
+
+    async fn put_compression(
+        &self,
+        stream_name: &str,
+        compression: Compression,
+    ) -> Result<(), ObjectStorageError> {
+        self.retry("put_compression", || {
+            self.inner.put_compression(stream_name, compression)
+        })
+        .await
+    }
+
+    async fn get_compression(&self, stream_name: &str) -> Result<Compression, ObjectStorageError> {
+        self.retry("get_compression", || {
+            self.inner.get_compression(stream_name)
+        })
+        .await
+    }
+
+    async fn put_flatten(
+        &self,
+        stream_name: &str,
+        flatten: Flatten,
+    ) -> Result<(), ObjectStorageError> {
+        self.retry("put_flatten", || {
+            self.inner.put_flatten(stream_name, flatten)
+        })
+        .await
+    }
+
+    async fn get_flatten(&self, stream_name: &str) -> Result<Flatten, ObjectStorageError> {
+        self.retry("get_flatten", || self.inner.get_flatten(stream_name))
+            .await
+    }
+
+    async fn put_partition(
+        &self,
+        stream_name: &str,
+        partition: PartitionGranularity,
+    ) -> Result<(), ObjectStorageError> {
+        self.retry("put_partition", || {
+            self.inner.put_partition(stream_name, partition)
+        })
+        .await
+    }
+
+    async fn get_partition(
+        &self,
+        stream_name: &str,
+    ) -> Result<PartitionGranularity, ObjectStorageError> {
+        self.retry("get_partition", || self.inner.get_partition(stream_name))
+            .await
+    }
+
+    async fn put_timestamps(
+        &self,
+        stream_name: &str,
+        timestamps: &StreamTimestamps,
+    ) -> Result<(), ObjectStorageError> {
+        self.retry("put_timestamps", || {
+            self.inner.put_timestamps(stream_name, timestamps)
+        })
+        .await
+    }
+
+    async fn get_timestamps(
+        &self,
+        stream_name: &str,
+    ) -> Result<StreamTimestamps, ObjectStorageError> {
+        self.retry("get_timestamps", || self.inner.get_timestamps(stream_name))
+            .await
+    }
+
+    async fn put_metadata(
+        &self,
+        stream_name: &str,
+        document: &MetadataDocument,
+    ) -> Result<(), ObjectStorageError> {
+        self.retry("put_metadata", || {
+            self.inner.put_metadata(stream_name, document)
+        })
+        .await
+    }
+
+    async fn get_metadata(
+        &self,
+        stream_name: &str,
+    ) -> Result<MetadataDocument, ObjectStorageError> {
+        self.retry("get_metadata", || self.inner.get_metadata(stream_name))
+            .await
+    }
+
+    async fn list_streams(&self) -> Result<Vec<LogStream>, ObjectStorageError> {
+        self.retry("list_streams", || self.inner.list_streams())
+            .await
+    }
+
+    async fn list_streams_paged(
+        &self,
+        continuation_token: Option<&str>,
+        limit: usize,
+    ) -> Result<StreamsPage, ObjectStorageError> {
+        self.retry("list_streams_paged", || {
+            self.inner.list_streams_paged(continuation_token, limit)
+        })
+        .await
+    }
+
+    async fn list_dirs(&self, prefix: &str) -> Result<Vec<String>, ObjectStorageError> {
+        self.retry("list_dirs", || self.inner.list_dirs(prefix))
+            .await
+    }
+
+    async fn put_object(&self, key: &str, body: Bytes) -> Result<(), ObjectStorageError> {
+        self.retry("put_object", || self.inner.put_object(key, body.clone()))
+            .await
+    }
+
+    async fn get_object(&self, key: &str) -> Result<Bytes, ObjectStorageError> {
+        self.retry("get_object", || self.inner.get_object(key))
+            .await
+    }
+
+    async fn upload_file(&self, key: &str, path: &str) -> Result<(), ObjectStorageError> {
+        self.retry("upload_file", || self.inner.upload_file(key, path))
+            .await
+    }
+
+    async fn object_meta(&self, key: &str) -> Result<ObjectMeta, ObjectStorageError> {
+        self.retry("object_meta", || self.inner.object_meta(key))
+            .await
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> Result<DeletedObjects, ObjectStorageError> {
+        self.retry("delete_prefix", || self.inner.delete_prefix(prefix))
+            .await
+    }
+
+    async fn copy_prefix(&self, from: &str, to: &str) -> Result<u64, ObjectStorageError> {
+        self.retry("copy_prefix", || self.inner.copy_prefix(from, to))
+            .await
+    }
+
+    async fn count_objects(&self, prefix: &str) -> Result<u64, ObjectStorageError> {
+        self.retry("count_objects", || self.inner.count_objects(prefix))
+            .await
+    }
+
+    async fn parquet_objects(&self, prefix: &str) -> Result<StoredObjects, ObjectStorageError> {
+        self.retry("parquet_objects", || self.inner.parquet_objects(prefix))
+            .await
+    }
+
+    async fn list_parquet_files(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<ParquetFile>, ObjectStorageError> {
+        self.retry("list_parquet_files", || {
+            self.inner.list_parquet_files(prefix)
+        })
+        .await
+    }
+
+    // results of a failed attempt may already be in `results`, so queries aren't retried
+    async fn query(
+        &self,
+        query: &Query,
+        results: &mut Vec<RecordBatch>,
+    ) -> Result<(), ObjectStorageError> {
+        self.inner.query(query, results).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::mock::MockStorage;
+    use crate::utils;
+
+    fn retrying(mock: MockStorage, max_attempts: u32) -> RetryingStorage {
+        let policy = RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(1),
+        };
+        RetryingStorage::new(Box::new(mock), policy)
+    }
+
+    #[test]
+    fn delay_grows_and_is_capped() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(200),
+        };
+
+        let first = policy.delay(1);
+        assert!(first >= Duration::from_millis(100) && first <= Duration::from_millis(200));
+        let third = policy.delay(3);
+        assert!(third >= Duration::from_millis(400) && third <= Duration::from_millis(800));
+        assert!(policy.delay(20) <= MAX_RETRY_DELAY);
+    }
+
+    #[actix_web::test]
+    async fn upload_succeeds_after_transient_failures() {
+        let retries = metrics::STORAGE_RETRIES.with_label_values(&["upload_file"]);
+        let before = retries.get();
+        let storage = retrying(MockStorage::default().with_transient_failures(2), 3);
+        let path = std::env::temp_dir().join(format!("parseable-test-{}", utils::random_string()));
+        std::fs::write(&path, b"PAR1").unwrap();
+
+        storage
+            .upload_file("stream/a.parquet", path.to_str().unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(retries.get() - before, 2);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn gives_up_after_max_attempts() {
+        let storage = retrying(
+            MockStorage::default()
+                .with_stream("stream", "{}")
+                .with_transient_failures(3),
+            3,
+        );
+
+        assert!(matches!(
+            storage.get_schema("stream").await,
+            Err(ObjectStorageError::ConnectionError(_))
+        ));
+        // the next attempt gets through
+        assert!(storage.get_schema("stream").await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn permanent_errors_are_not_retried() {
+        let retries = metrics::STORAGE_RETRIES.with_label_values(&["get_alert"]);
+        let before = retries.get();
+        let storage = retrying(MockStorage::default(), 3);
+
+        assert!(matches!(
+            storage.get_alert("stream").await,
+            Err(ObjectStorageError::NoSuchKey(_))
+        ));
+        assert_eq!(retries.get(), before);
+    }
+}
@@ -1,28 +1,46 @@
+use arrow::datatypes::Schema;
 use async_trait::async_trait;
+use aws_sdk_s3::client::fluent_builders::PutObject;
 use aws_sdk_s3::error::{HeadBucketError, HeadBucketErrorKind};
-use aws_sdk_s3::model::{Delete, ObjectIdentifier};
+use aws_sdk_s3::model::{
+    CompletedMultipartUpload, CompletedPart, Delete, ObjectIdentifier, ServerSideEncryption,
+};
 use aws_sdk_s3::types::{ByteStream, SdkError};
 use aws_sdk_s3::Error as AwsSdkError;
 use aws_sdk_s3::{Client, Credentials, Endpoint, Region};
 use aws_types::credentials::SharedCredentialsProvider;
 use bytes::Bytes;
+use chrono::{DateTime, Utc};
 use crossterm::style::Stylize;
 use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::datasource::listing::{ListingTable, ListingTableConfig};
 use datafusion::prelude::SessionContext;
 use datafusion_objectstore_s3::object_store::s3::S3FileSystem;
 use http::Uri;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::io::{Read, Seek, SeekFrom};
 use std::iter::Iterator;
+use std::ops::Range;
+use std::str::FromStr;
 use std::sync::Arc;
 use structopt::StructOpt;
+use tokio::sync::Semaphore;
 use tokio_stream::StreamExt;
 
-use crate::metadata::Stats;
+use crate::alerts::Alerts;
+use crate::metadata::{
+    Compression, Flatten, Limits, PartitionGranularity, Stats, StreamTimestamps,
+};
+use crate::migration::MetadataDocument;
 use crate::option::{StorageOpt, CONFIG};
 use crate::query::Query;
-use crate::storage::{LogStream, ObjectStorage, ObjectStorageError};
+use crate::retention::Retention;
+use crate::storage::{
+    self, DeletedObjects, LogStream, ObjectMeta, ObjectStorage, ObjectStorageError, ParquetFile,
+    StoredObjects, StreamsPage,
+};
+use crate::validator;
 
 // Default object storage currently is DO Spaces bucket
 // Any user who starts the Parseable server with default configuration
@@ -32,9 +50,18 @@ const DEFAULT_S3_REGION: &str = "sgp1";
 const DEFAULT_S3_BUCKET: &str = "parseable";
 const DEFAULT_S3_ACCESS_KEY: &str = "DO00KWGMX3M4ABBBFPCZ";
 const DEFAULT_S3_SECRET_KEY: &str = "5CqfjVsIPBjZxTOz51Bxod3Cd0FWkMLC3/vTwRavaaQ";
+// Region requests to other S3 compatible endpoints are signed for, when none is given.
+// MinIO and Ceph accept it unless configured with a region of their own.
+const FALLBACK_S3_REGION: &str = "us-east-1";
 
 const S3_URL_ENV_VAR: &str = "P_S3_URL";
 
+// S3 takes parts of at least 5 MiB, other than the last one
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+// Requests deleting a batch of keys, keys that fail are tried again with the next one
+const DELETE_ATTEMPTS: u32 = 3;
+
 lazy_static::lazy_static! {
     #[derive(Debug)]
     pub static ref S3_CONFIG: Arc<S3Config> = Arc::new(S3Config::from_args());
@@ -55,13 +82,131 @@ pub struct S3Config {
     #[structopt(long, env = "P_S3_SECRET_KEY", default_value = DEFAULT_S3_SECRET_KEY)]
     pub s3_secret_key: String,
 
-    /// The region for AWS S3 or compatible object storage platform
-    #[structopt(long, env = "P_S3_REGION", default_value = DEFAULT_S3_REGION)]
-    pub s3_default_region: String,
+    /// The region for AWS S3 or compatible object storage platform.
+    /// Optional for compatible platforms, defaults to us-east-1 for them
+    #[structopt(long, env = "P_S3_REGION")]
+    pub s3_default_region: Option<String>,
 
     /// The AWS S3 or compatible object storage bucket to be used for storage
     #[structopt(long, env = "P_S3_BUCKET", default_value = DEFAULT_S3_BUCKET)]
     pub s3_bucket_name: String,
+
+    /// Optional size in MiB of the parts of multipart uploads. Files larger than
+    /// this are uploaded in parts, at least 5 MiB each. Defaults to 16 MiB.
+    #[structopt(long, env = "P_S3_PART_SIZE", default_value = "16")]
+    pub s3_part_size: u64,
+
+    /// Optional server side encryption of objects put to the bucket, one of
+    /// `aes256` or `aws:kms`. Objects aren't encrypted by the server by default.
+    #[structopt(long, env = "P_S3_SSE")]
+    pub s3_sse: Option<Sse>,
+
+    /// Optional id of the KMS key objects are encrypted with, when `s3_sse` is
+    /// `aws:kms`. Defaults to the AWS managed key of the account.
+    #[structopt(long, env = "P_S3_KMS_KEY_ID")]
+    pub s3_kms_key_id: Option<String>,
+
+    /// Optional number of keys listed in a page, at most 1000. Defaults to 1000.
+    #[structopt(long, env = "P_S3_LIST_PAGE_SIZE", default_value = "1000")]
+    pub s3_list_page_size: i32,
+
+    /// Optional number of parts of a multipart upload uploaded at once. Defaults to 4.
+    #[structopt(long, env = "P_S3_UPLOAD_CONCURRENCY", default_value = "4")]
+    pub s3_upload_concurrency: usize,
+
+    /// Optional prefix of every key of this deployment, so that several deployments
+    /// can share a bucket. Each of them needs its own prefix then, an unprefixed
+    /// deployment would list the others' prefixes as its log streams.
+    #[structopt(long, env = "P_STORAGE_PREFIX")]
+    pub storage_prefix: Option<String>,
+
+    /// Move the log streams of an unprefixed deployment under `storage_prefix` and
+    /// exit. Stop the deployment before, and start it with the prefix afterwards.
+    #[structopt(long)]
+    pub migrate_to_storage_prefix: bool,
+}
+
+impl S3Config {
+    fn region(&self) -> &str {
+        match &self.s3_default_region {
+            Some(region) => region,
+            None if self.is_default_url() => DEFAULT_S3_REGION,
+            None => FALLBACK_S3_REGION,
+        }
+    }
+
+    fn server_side_encryption(&self) -> Option<ServerSideEncryption> {
+        self.s3_sse.map(|sse| match sse {
+            Sse::Aes256 => ServerSideEncryption::Aes256,
+            Sse::Kms => ServerSideEncryption::AwsKms,
+        })
+    }
+
+    fn part_size(&self) -> u64 {
+        (self.s3_part_size * 1024 * 1024).max(MIN_PART_SIZE)
+    }
+}
+
+/// Server side encryption of objects put to S3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sse {
+    /// Keys managed by S3
+    Aes256,
+    /// Keys managed by AWS KMS
+    Kms,
+}
+
+impl FromStr for Sse {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aes256" => Ok(Sse::Aes256),
+            "aws:kms" => Ok(Sse::Kms),
+            _ => Err(format!(
+                "unknown server side encryption {}, expected aes256 or aws:kms",
+                s
+            )),
+        }
+    }
+}
+
+/// Byte ranges of the parts a file of `size` bytes is uploaded in
+fn part_ranges(size: u64, part_size: u64) -> Vec<Range<u64>> {
+    (0..size)
+        .step_by(part_size as usize)
+        .map(|start| start..(start + part_size).min(size))
+        .collect()
+}
+
+fn read_range(path: &str, range: Range<u64>) -> std::io::Result<Vec<u8>> {
+    let mut file = fs::File::open(path)?;
+    file.seek(SeekFrom::Start(range.start))?;
+    let mut buf = vec![0; (range.end - range.start) as usize];
+    file.read_exact(&mut buf)?;
+
+    Ok(buf)
+}
+
+/// Value of the Content-MD5 header of a request with `body`
+fn content_md5(body: &[u8]) -> String {
+    base64::encode(md5::compute(body).0)
+}
+
+/// MD5 hash of an object from its ETag, `None` if the ETag isn't one, as for
+/// objects uploaded in parts
+fn md5_from_etag(etag: &str) -> Option<[u8; 16]> {
+    let hex = etag.trim_matches('"');
+    if hex.len() != 32 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut md5 = [0; 16];
+    for (i, byte) in md5.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+
+    Some(md5)
 }
 
 impl StorageOpt for S3Config {
@@ -77,6 +222,10 @@ impl StorageOpt for S3Config {
         self.s3_endpoint_url == DEFAULT_S3_URL
     }
 
+    fn object_storage(&self) -> Box<dyn ObjectStorage> {
+        Box::new(S3::new())
+    }
+
     fn warning(&self) {
         if self.is_default_url() {
             eprintln!(
@@ -106,7 +255,9 @@ impl S3Options {
     fn new() -> Self {
         let uri = S3_CONFIG.s3_endpoint_url.parse::<Uri>().unwrap();
         let endpoint = Endpoint::immutable(uri);
-        let region = Region::new(&S3_CONFIG.s3_default_region);
+        // the endpoint is used as is, so requests are path-style, with the bucket
+        // in the path. That's what MinIO and Ceph expect by default
+        let region = Region::new(S3_CONFIG.region().to_string());
         let creds = Credentials::new(
             &S3_CONFIG.s3_access_key_id,
             &S3_CONFIG.s3_secret_key,
@@ -123,13 +274,35 @@ impl S3Options {
     }
 }
 
+/// The prefix of every key, as `prefix/`, or empty without one
+fn key_prefix(storage_prefix: Option<&str>) -> Result<String, String> {
+    match storage_prefix.map(|prefix| prefix.trim_matches('/')) {
+        None | Some("") => Ok(String::new()),
+        Some(prefix) => validator::stream_name(prefix)
+            .map(|_| format!("{}/", prefix))
+            .map_err(|e| e.to_string()),
+    }
+}
+
 pub struct S3 {
     options: S3Options,
     client: aws_sdk_s3::Client,
+    prefix: String,
 }
 
 impl S3 {
     pub fn new() -> Self {
+        let prefix = key_prefix(S3_CONFIG.storage_prefix.as_deref()).unwrap_or_else(|e| {
+            panic!("invalid storage prefix (P_STORAGE_PREFIX). {}", e);
+        });
+
+        Self::with_prefix(prefix)
+    }
+
+    fn with_prefix(prefix: String) -> Self {
+        if S3_CONFIG.s3_kms_key_id.is_some() && S3_CONFIG.s3_sse != Some(Sse::Kms) {
+            panic!("a KMS key id (P_S3_KMS_KEY_ID) is only used with P_S3_SSE=aws:kms");
+        }
         let options = S3Options::new();
         let config = aws_sdk_s3::Config::builder()
             .region(options.region.clone())
@@ -139,15 +312,36 @@ impl S3 {
 
         let client = Client::from_conf(config);
 
-        Self { options, client }
+        Self {
+            options,
+            client,
+            prefix,
+        }
+    }
+
+    /// Key in the bucket of `key`, under the storage prefix
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
     }
 
-    async fn _put_schema(&self, stream_name: String, body: String) -> Result<(), AwsSdkError> {
-        let _resp = self
-            .client
+    /// Key of this deployment of `key` in the bucket
+    fn strip<'a>(&self, key: &'a str) -> &'a str {
+        key.strip_prefix(&self.prefix).unwrap_or(key)
+    }
+
+    /// Request putting an object into the bucket, encrypted as configured
+    fn put_request(&self) -> PutObject {
+        self.client
             .put_object()
             .bucket(&S3_CONFIG.s3_bucket_name)
-            .key(format!("{}/.schema", stream_name))
+            .set_server_side_encryption(S3_CONFIG.server_side_encryption())
+            .set_ssekms_key_id(S3_CONFIG.s3_kms_key_id.clone())
+    }
+
+    async fn _put_schema(&self, stream_name: String, body: String) -> Result<(), AwsSdkError> {
+        let _resp = self
+            .put_request()
+            .key(self.key(&format!("{}/.schema", stream_name)))
             .body(body.into_bytes().into())
             .send()
             .await?;
@@ -157,10 +351,8 @@ impl S3 {
 
     async fn _create_stream(&self, stream_name: &str) -> Result<(), AwsSdkError> {
         let _resp = self
-            .client
-            .put_object()
-            .bucket(&S3_CONFIG.s3_bucket_name)
-            .key(format!("{}/.schema", stream_name))
+            .put_request()
+            .key(self.key(&format!("{}/.schema", stream_name)))
             .send()
             .await?;
         // Prefix created on S3, now create the directory in
@@ -169,42 +361,44 @@ impl S3 {
         Ok(())
     }
 
-    async fn _delete_stream(&self, stream_name: &str) -> Result<(), AwsSdkError> {
-        let mut pages = self
-            .client
-            .list_objects_v2()
-            .bucket(&S3_CONFIG.s3_bucket_name)
-            .prefix(stream_name)
-            .into_paginator()
-            .send();
+    async fn _create_alert(&self, stream_name: &str, body: String) -> Result<(), AwsSdkError> {
+        let _resp = self
+            .put_request()
+            .key(self.key(&format!("{}/.alert.json", stream_name)))
+            .body(body.into_bytes().into())
+            .send()
+            .await?;
 
-        let mut delete_objects: Vec<ObjectIdentifier> = vec![];
-        while let Some(page) = pages.next().await {
-            let page = page?;
-            for obj in page.contents.unwrap() {
-                let obj_id = ObjectIdentifier::builder().set_key(obj.key).build();
-                delete_objects.push(obj_id);
-            }
-        }
+        Ok(())
+    }
 
-        let delete = Delete::builder().set_objects(Some(delete_objects)).build();
+    async fn _get_schema(&self, stream_name: &str) -> Result<Bytes, AwsSdkError> {
+        self._get(stream_name, "schema").await
+    }
 
-        self.client
-            .delete_objects()
-            .bucket(&S3_CONFIG.s3_bucket_name)
-            .delete(delete)
+    async fn _alert_exists(&self, stream_name: &str) -> Result<Bytes, AwsSdkError> {
+        self._get(stream_name, "alert.json").await
+    }
+
+    async fn _get_stats(&self, stream_name: &str) -> Result<Bytes, AwsSdkError> {
+        self._get(stream_name, "stats.json").await
+    }
+
+    async fn _put_stats(&self, stream_name: &str, body: String) -> Result<(), AwsSdkError> {
+        let _resp = self
+            .put_request()
+            .key(self.key(&format!("{}/.stats.json", stream_name)))
+            .body(body.into_bytes().into())
             .send()
             .await?;
 
         Ok(())
     }
 
-    async fn _create_alert(&self, stream_name: &str, body: String) -> Result<(), AwsSdkError> {
+    async fn _put_retention(&self, stream_name: &str, body: String) -> Result<(), AwsSdkError> {
         let _resp = self
-            .client
-            .put_object()
-            .bucket(&S3_CONFIG.s3_bucket_name)
-            .key(format!("{}/.alert.json", stream_name))
+            .put_request()
+            .key(self.key(&format!("{}/.retention.json", stream_name)))
             .body(body.into_bytes().into())
             .send()
             .await?;
@@ -212,16 +406,125 @@ impl S3 {
         Ok(())
     }
 
-    async fn _get_schema(&self, stream_name: &str) -> Result<Bytes, AwsSdkError> {
-        self._get(stream_name, "schema").await
+    async fn _put_tags(&self, stream_name: &str, body: String) -> Result<(), AwsSdkError> {
+        let _resp = self
+            .put_request()
+            .key(self.key(&format!("{}/.tags.json", stream_name)))
+            .body(body.into_bytes().into())
+            .send()
+            .await?;
+
+        Ok(())
     }
 
-    async fn _alert_exists(&self, stream_name: &str) -> Result<Bytes, AwsSdkError> {
-        self._get(stream_name, "alert.json").await
+    async fn _put_static_schema(&self, stream_name: &str, body: String) -> Result<(), AwsSdkError> {
+        let _resp = self
+            .put_request()
+            .key(self.key(&format!("{}/.static_schema.json", stream_name)))
+            .body(body.into_bytes().into())
+            .send()
+            .await?;
+
+        Ok(())
     }
 
-    async fn _get_stats(&self, stream_name: &str) -> Result<Bytes, AwsSdkError> {
-        self._get(stream_name, "stats.json").await
+    async fn _put_deleted_at(&self, stream_name: &str, body: String) -> Result<(), AwsSdkError> {
+        let _resp = self
+            .put_request()
+            .key(self.key(&format!("{}/.deleted.json", stream_name)))
+            .body(body.into_bytes().into())
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn _put_renamed_from(&self, stream_name: &str, body: String) -> Result<(), AwsSdkError> {
+        let _resp = self
+            .put_request()
+            .key(self.key(&format!("{}/.renamed_from.json", stream_name)))
+            .body(body.into_bytes().into())
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn _put_time_field(&self, stream_name: &str, body: String) -> Result<(), AwsSdkError> {
+        let _resp = self
+            .put_request()
+            .key(self.key(&format!("{}/.time_field.json", stream_name)))
+            .body(body.into_bytes().into())
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn _put_limits(&self, stream_name: &str, body: String) -> Result<(), AwsSdkError> {
+        let _resp = self
+            .put_request()
+            .key(self.key(&format!("{}/.limits.json", stream_name)))
+            .body(body.into_bytes().into())
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn _put_compression(&self, stream_name: &str, body: String) -> Result<(), AwsSdkError> {
+        let _resp = self
+            .put_request()
+            .key(self.key(&format!("{}/.compression.json", stream_name)))
+            .body(body.into_bytes().into())
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn _put_flatten(&self, stream_name: &str, body: String) -> Result<(), AwsSdkError> {
+        let _resp = self
+            .put_request()
+            .key(self.key(&format!("{}/.flatten.json", stream_name)))
+            .body(body.into_bytes().into())
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn _put_partition(&self, stream_name: &str, body: String) -> Result<(), AwsSdkError> {
+        let _resp = self
+            .put_request()
+            .key(self.key(&format!("{}/.partition.json", stream_name)))
+            .body(body.into_bytes().into())
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn _put_metadata(&self, stream_name: &str, body: String) -> Result<(), AwsSdkError> {
+        let _resp = self
+            .put_request()
+            .key(self.key(&format!("{}/.metadata.json", stream_name)))
+            .body(body.into_bytes().into())
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn _put_timestamps(&self, stream_name: &str, body: String) -> Result<(), AwsSdkError> {
+        let _resp = self
+            .put_request()
+            .key(self.key(&format!("{}/.timestamps.json", stream_name)))
+            .body(body.into_bytes().into())
+            .send()
+            .await?;
+
+        Ok(())
     }
 
     async fn _get(&self, stream_name: &str, resource: &str) -> Result<Bytes, AwsSdkError> {
@@ -229,7 +532,7 @@ impl S3 {
             .client
             .get_object()
             .bucket(&S3_CONFIG.s3_bucket_name)
-            .key(format!("{}/.{}", stream_name, resource))
+            .key(self.key(&format!("{}/.{}", stream_name, resource)))
             .send()
             .await?;
         let body = resp.body.collect().await;
@@ -237,61 +540,378 @@ impl S3 {
         Ok(body_bytes)
     }
 
-    async fn prefix_exists(&self, prefix: &str) -> Result<bool, AwsSdkError> {
-        // TODO check if head object is faster compared to
-        // list objects
+    async fn _list_streams_paged(
+        &self,
+        continuation_token: Option<&str>,
+        limit: usize,
+    ) -> Result<StreamsPage, AwsSdkError> {
         let resp = self
             .client
             .list_objects_v2()
             .bucket(&S3_CONFIG.s3_bucket_name)
-            .prefix(prefix)
-            .max_keys(1)
+            .prefix(&self.prefix)
+            .delimiter("/")
+            .max_keys(limit.max(1) as i32)
+            .set_continuation_token(continuation_token.map(str::to_string))
             .send()
             .await?;
 
-        let result = resp.contents.is_some();
+        let streams = resp
+            .common_prefixes()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|prefix| prefix.prefix())
+            .map(|prefix| LogStream {
+                name: self.strip(prefix).trim_end_matches('/').to_string(),
+            })
+            .collect();
+
+        Ok(StreamsPage {
+            streams,
+            next_token: resp.next_continuation_token().map(str::to_string),
+        })
+    }
+
+    async fn _list_dirs(&self, prefix: &str) -> Result<Vec<String>, AwsSdkError> {
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&S3_CONFIG.s3_bucket_name)
+            .prefix(self.key(prefix))
+            .delimiter("/")
+            .max_keys(S3_CONFIG.s3_list_page_size)
+            .into_paginator()
+            .send();
 
-        Ok(result)
+        let mut dirs = Vec::new();
+        while let Some(page) = pages.next().await {
+            let page = page?;
+            for common_prefix in page.common_prefixes().unwrap_or_default() {
+                if let Some(dir) = common_prefix.prefix() {
+                    let dir = dir
+                        .trim_start_matches(&self.key(prefix))
+                        .trim_end_matches('/');
+                    dirs.push(dir.to_string());
+                }
+            }
+        }
+
+        Ok(dirs)
     }
 
-    async fn _list_streams(&self) -> Result<Vec<LogStream>, AwsSdkError> {
-        let resp = self
+    async fn _delete_prefix(&self, prefix: &str) -> Result<DeletedObjects, AwsSdkError> {
+        let mut pages = self
             .client
             .list_objects_v2()
             .bucket(&S3_CONFIG.s3_bucket_name)
-            .send()
-            .await?;
-        let body = resp.contents().unwrap_or_default();
-        // make a set of unique prefixes at the root level
-        let mut hs = HashSet::<String>::new();
-        for logstream in body {
-            let name = logstream.key().unwrap_or_default().to_string();
-            let tokens = name.split('/').collect::<Vec<&str>>();
-            hs.insert(tokens[0].to_string());
+            .prefix(self.key(prefix))
+            .max_keys(S3_CONFIG.s3_list_page_size)
+            .into_paginator()
+            .send();
+
+        let mut deleted = DeletedObjects::default();
+        // a page holds at most 1000 keys, which is also the
+        // limit of keys that can be deleted in a single request
+        while let Some(page) = pages.next().await {
+            let page = page?;
+            let objects: HashMap<String, u64> = page
+                .contents
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|obj| Some((obj.key?, obj.size.max(0) as u64)))
+                .collect();
+
+            // carry on with the next batch whatever fails in this one,
+            // so that as little as possible is left behind
+            let batch = self._delete_objects(prefix, objects).await;
+            deleted.objects += batch.objects;
+            deleted.size += batch.size;
+            deleted.failed += batch.failed;
         }
-        // transform that hashset to a vector before returning
-        let mut streams = Vec::new();
-        for v in hs {
-            streams.push(LogStream { name: v });
+
+        Ok(deleted)
+    }
+
+    /// Delete `objects`, keys along with their sizes, in a single request. Keys that fail
+    /// to be deleted are tried again, up to `DELETE_ATTEMPTS` times in all.
+    async fn _delete_objects(
+        &self,
+        prefix: &str,
+        mut objects: HashMap<String, u64>,
+    ) -> DeletedObjects {
+        let mut deleted = DeletedObjects::default();
+
+        for attempt in 1..=DELETE_ATTEMPTS {
+            if objects.is_empty() {
+                break;
+            }
+
+            let ids = objects
+                .keys()
+                .map(|key| ObjectIdentifier::builder().key(key).build())
+                .collect();
+            let resp = self
+                .client
+                .delete_objects()
+                .bucket(&S3_CONFIG.s3_bucket_name)
+                .delete(Delete::builder().set_objects(Some(ids)).build())
+                .send()
+                .await;
+
+            let failed: HashSet<String> = match resp {
+                Ok(output) => output
+                    .errors
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|error| {
+                        log::warn!(
+                            "failed to delete object {:?}, attempt {}. {:?}",
+                            error.key,
+                            attempt,
+                            error.message
+                        );
+                        error.key
+                    })
+                    .collect(),
+                Err(e) => {
+                    log::warn!(
+                        "failed to delete objects under {}, attempt {}. {:?}",
+                        prefix,
+                        attempt,
+                        e
+                    );
+                    objects.keys().cloned().collect()
+                }
+            };
+
+            for (_, size) in objects.iter().filter(|(key, _)| !failed.contains(*key)) {
+                deleted.objects += 1;
+                deleted.size += size;
+            }
+            objects.retain(|key, _| failed.contains(key));
         }
+        deleted.failed = objects.len() as u64;
 
-        Ok(streams)
+        deleted
     }
 
-    async fn _upload_file(&self, key: &str, path: &str) -> Result<(), AwsSdkError> {
-        let body = ByteStream::from_path(path).await.unwrap();
-        let resp = self
+    async fn _copy_prefix(&self, from: &str, to: &str) -> Result<u64, AwsSdkError> {
+        let (from, to) = (self.key(from), self.key(to));
+        let mut pages = self
             .client
-            .put_object()
+            .list_objects_v2()
             .bucket(&S3_CONFIG.s3_bucket_name)
+            .prefix(&from)
+            .max_keys(S3_CONFIG.s3_list_page_size)
+            .into_paginator()
+            .send();
+
+        let mut copied = 0;
+        while let Some(page) = pages.next().await {
+            for obj in page?.contents.unwrap_or_default() {
+                let key = obj.key.unwrap_or_default();
+                let target = format!("{}{}", to, key.strip_prefix(&from).unwrap_or(&key));
+                // objects are copied within the bucket, without downloading them
+                let _resp = self
+                    .client
+                    .copy_object()
+                    .bucket(&S3_CONFIG.s3_bucket_name)
+                    .copy_source(format!("{}/{}", S3_CONFIG.s3_bucket_name, key))
+                    // copies are encrypted like new objects, not like their source
+                    .set_server_side_encryption(S3_CONFIG.server_side_encryption())
+                    .set_ssekms_key_id(S3_CONFIG.s3_kms_key_id.clone())
+                    .key(target)
+                    .send()
+                    .await?;
+                copied += 1;
+            }
+        }
+
+        Ok(copied)
+    }
+
+    async fn _count_objects(&self, prefix: &str) -> Result<u64, AwsSdkError> {
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&S3_CONFIG.s3_bucket_name)
+            .prefix(self.key(prefix))
+            .max_keys(S3_CONFIG.s3_list_page_size)
+            .into_paginator()
+            .send();
+
+        let mut objects = 0;
+        while let Some(page) = pages.next().await {
+            objects += page?.contents.map_or(0, |contents| contents.len() as u64);
+        }
+
+        Ok(objects)
+    }
+
+    async fn _parquet_objects(&self, prefix: &str) -> Result<StoredObjects, AwsSdkError> {
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&S3_CONFIG.s3_bucket_name)
+            .prefix(self.key(prefix))
+            .max_keys(S3_CONFIG.s3_list_page_size)
+            .into_paginator()
+            .send();
+
+        let mut stored = StoredObjects::default();
+        while let Some(page) = pages.next().await {
+            for obj in page?.contents.unwrap_or_default() {
+                if obj.key.map_or(false, |key| key.ends_with(".parquet")) {
+                    stored.objects += 1;
+                    stored.size += obj.size.max(0) as u64;
+                }
+            }
+        }
+
+        Ok(stored)
+    }
+
+    async fn _list_parquet_files(&self, prefix: &str) -> Result<Vec<ParquetFile>, AwsSdkError> {
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&S3_CONFIG.s3_bucket_name)
+            .prefix(self.key(prefix))
+            .max_keys(S3_CONFIG.s3_list_page_size)
+            .into_paginator()
+            .send();
+
+        let mut files = Vec::new();
+        while let Some(page) = pages.next().await {
+            for obj in page?.contents.unwrap_or_default() {
+                if let Some(key) = obj.key.filter(|key| key.ends_with(".parquet")) {
+                    files.push(ParquetFile {
+                        key: self.strip(&key).to_string(),
+                        size: obj.size.max(0) as u64,
+                    });
+                }
+            }
+        }
+
+        Ok(files)
+    }
+
+    async fn _upload_file(&self, key: &str, path: &str) -> Result<(), AwsSdkError> {
+        let key = &self.key(key);
+        let size = fs::metadata(path)
+            .map_err(|e| AwsSdkError::Unhandled(Box::new(e)))?
+            .len();
+        if size > S3_CONFIG.part_size() {
+            return self._multipart_upload(key, path, size).await;
+        }
+
+        // S3 rejects a body that doesn't match its Content-MD5
+        let body = fs::read(path).map_err(|e| AwsSdkError::Unhandled(Box::new(e)))?;
+        let resp = self
+            .put_request()
             .key(key)
-            .body(body)
+            .content_md5(content_md5(&body))
+            .body(ByteStream::from(body))
             .send()
             .await?;
         log::trace!("{:?}", resp);
 
         Ok(())
     }
+
+    // Uploads of large files are split, so a dropped connection costs a part, not the
+    // whole transfer. Failed uploads are aborted, S3 bills for the parts kept otherwise.
+    async fn _multipart_upload(&self, key: &str, path: &str, size: u64) -> Result<(), AwsSdkError> {
+        let upload = self
+            .client
+            .create_multipart_upload()
+            .bucket(&S3_CONFIG.s3_bucket_name)
+            .key(key)
+            .set_server_side_encryption(S3_CONFIG.server_side_encryption())
+            .set_ssekms_key_id(S3_CONFIG.s3_kms_key_id.clone())
+            .send()
+            .await?;
+        let upload_id = upload.upload_id().unwrap_or_default();
+
+        let result = match self._upload_parts(key, path, size, upload_id).await {
+            Ok(parts) => self
+                .client
+                .complete_multipart_upload()
+                .bucket(&S3_CONFIG.s3_bucket_name)
+                .key(key)
+                .upload_id(upload_id)
+                .multipart_upload(
+                    CompletedMultipartUpload::builder()
+                        .set_parts(Some(parts))
+                        .build(),
+                )
+                .send()
+                .await
+                .map(|_| ())
+                .map_err(AwsSdkError::from),
+            Err(e) => Err(e),
+        };
+
+        if result.is_err() {
+            if let Err(e) = self
+                .client
+                .abort_multipart_upload()
+                .bucket(&S3_CONFIG.s3_bucket_name)
+                .key(key)
+                .upload_id(upload_id)
+                .send()
+                .await
+            {
+                log::warn!("failed to abort multipart upload of {}. {}", key, e);
+            }
+        }
+
+        result
+    }
+
+    async fn _upload_parts(
+        &self,
+        key: &str,
+        path: &str,
+        size: u64,
+        upload_id: &str,
+    ) -> Result<Vec<CompletedPart>, AwsSdkError> {
+        // parts are read once a permit is taken, so at most that many are in memory
+        let permits = Semaphore::new(S3_CONFIG.s3_upload_concurrency.max(1));
+        let parts = part_ranges(size, S3_CONFIG.part_size())
+            .into_iter()
+            .enumerate()
+            .map(|(index, range)| {
+                let permits = &permits;
+                async move {
+                    let _permit = permits.acquire().await.expect("semaphore is never closed");
+                    let part_number = index as i32 + 1;
+                    let body =
+                        read_range(path, range).map_err(|e| AwsSdkError::Unhandled(Box::new(e)))?;
+                    let resp = self
+                        .client
+                        .upload_part()
+                        .bucket(&S3_CONFIG.s3_bucket_name)
+                        .key(key)
+                        .upload_id(upload_id)
+                        .part_number(part_number)
+                        .content_md5(content_md5(&body))
+                        .body(ByteStream::from(body))
+                        .send()
+                        .await?;
+
+                    Ok::<_, AwsSdkError>(
+                        CompletedPart::builder()
+                            .set_e_tag(resp.e_tag().map(str::to_string))
+                            .part_number(part_number)
+                            .build(),
+                    )
+                }
+            });
+
+        // the first failure drops the parts still uploading
+        futures::future::try_join_all(parts).await
+    }
 }
 
 #[async_trait]
@@ -309,8 +929,9 @@ impl ObjectStorage for S3 {
     async fn put_schema(
         &self,
         stream_name: String,
-        body: String,
+        schema: &Schema,
     ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_string(schema)?;
         self._put_schema(stream_name, body).await?;
 
         Ok(())
@@ -322,17 +943,12 @@ impl ObjectStorage for S3 {
         Ok(())
     }
 
-    async fn delete_stream(&self, stream_name: &str) -> Result<(), ObjectStorageError> {
-        self._delete_stream(stream_name).await?;
-
-        Ok(())
-    }
-
     async fn create_alert(
         &self,
         stream_name: &str,
-        body: String,
+        alerts: &Alerts,
     ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_string(alerts)?;
         self._create_alert(stream_name, body).await?;
 
         Ok(())
@@ -344,6 +960,75 @@ impl ObjectStorage for S3 {
         Ok(body_bytes)
     }
 
+    async fn get_schema_tagged(
+        &self,
+        stream_name: &str,
+    ) -> Result<Option<(Bytes, String)>, ObjectStorageError> {
+        let resp = match self
+            .client
+            .get_object()
+            .bucket(&S3_CONFIG.s3_bucket_name)
+            .key(self.key(&format!("{}/.schema", stream_name)))
+            .send()
+            .await
+            .map_err(AwsSdkError::from)
+        {
+            Ok(resp) => resp,
+            Err(AwsSdkError::NoSuchKey(_)) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let etag = resp.e_tag().unwrap_or_default().to_string();
+        let body = resp
+            .body
+            .collect()
+            .await
+            .map_err(|e| ObjectStorageError::UnhandledError(e.into()))?;
+
+        Ok(Some((body.into_bytes(), etag)))
+    }
+
+    // See https://docs.aws.amazon.com/AmazonS3/latest/userguide/conditional-requests.html
+    async fn put_schema_if(
+        &self,
+        stream_name: &str,
+        schema: &Schema,
+        tag: Option<&str>,
+    ) -> Result<(), ObjectStorageError> {
+        let key = format!("{}/.schema", stream_name);
+        let body = serde_json::to_string(schema)?;
+        let mut put = self
+            .put_request()
+            .key(self.key(&key))
+            .body(body.into_bytes().into())
+            .customize()
+            .await
+            .map_err(AwsSdkError::from)?;
+        // the SDK has no setters for the conditions, they are set on the request
+        let (name, value) = match tag {
+            Some(tag) => (http::header::IF_MATCH, tag),
+            None => (http::header::IF_NONE_MATCH, "*"),
+        };
+        put.request_mut().headers_mut().insert(
+            name,
+            http::HeaderValue::from_str(value)
+                .map_err(|e| ObjectStorageError::UnhandledError(e.into()))?,
+        );
+
+        match put.send().await {
+            Ok(_) => Ok(()),
+            // concurrent conditional puts of the same key may fail with a conflict
+            Err(SdkError::ServiceError { raw, .. })
+                if matches!(
+                    raw.http().status(),
+                    http::StatusCode::PRECONDITION_FAILED | http::StatusCode::CONFLICT
+                ) =>
+            {
+                Err(ObjectStorageError::PreconditionFailed(key))
+            }
+            Err(e) => Err(AwsSdkError::from(e).into()),
+        }
+    }
+
     async fn get_alert(&self, stream_name: &str) -> Result<Bytes, ObjectStorageError> {
         let body_bytes = self._alert_exists(stream_name).await?;
 
@@ -356,10 +1041,297 @@ impl ObjectStorage for S3 {
         Ok(stats)
     }
 
+    async fn put_stats(&self, stream_name: &str, stats: &Stats) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_string(stats)?;
+        self._put_stats(stream_name, body).await?;
+
+        Ok(())
+    }
+
+    async fn put_retention(
+        &self,
+        stream_name: &str,
+        retention: &Retention,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_string(retention)?;
+        self._put_retention(stream_name, body).await?;
+
+        Ok(())
+    }
+
+    async fn get_retention(&self, stream_name: &str) -> Result<Retention, ObjectStorageError> {
+        let retention = serde_json::from_slice(&self._get(stream_name, "retention.json").await?)?;
+
+        Ok(retention)
+    }
+
+    async fn put_tags(
+        &self,
+        stream_name: &str,
+        tags: &HashMap<String, String>,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_string(tags)?;
+        self._put_tags(stream_name, body).await?;
+
+        Ok(())
+    }
+
+    async fn get_tags(
+        &self,
+        stream_name: &str,
+    ) -> Result<HashMap<String, String>, ObjectStorageError> {
+        let tags = serde_json::from_slice(&self._get(stream_name, "tags.json").await?)?;
+
+        Ok(tags)
+    }
+
+    async fn put_static_schema(
+        &self,
+        stream_name: &str,
+        static_schema: bool,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_string(&static_schema)?;
+        self._put_static_schema(stream_name, body).await?;
+
+        Ok(())
+    }
+
+    async fn get_static_schema(&self, stream_name: &str) -> Result<bool, ObjectStorageError> {
+        let static_schema =
+            serde_json::from_slice(&self._get(stream_name, "static_schema.json").await?)?;
+
+        Ok(static_schema)
+    }
+
+    async fn put_deleted_at(
+        &self,
+        stream_name: &str,
+        deleted_at: Option<DateTime<Utc>>,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_string(&deleted_at)?;
+        self._put_deleted_at(stream_name, body).await?;
+
+        Ok(())
+    }
+
+    async fn get_deleted_at(
+        &self,
+        stream_name: &str,
+    ) -> Result<Option<DateTime<Utc>>, ObjectStorageError> {
+        let deleted_at = serde_json::from_slice(&self._get(stream_name, "deleted.json").await?)?;
+
+        Ok(deleted_at)
+    }
+
+    async fn put_renamed_from(
+        &self,
+        stream_name: &str,
+        renamed_from: &str,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_string(renamed_from)?;
+        self._put_renamed_from(stream_name, body).await?;
+
+        Ok(())
+    }
+
+    async fn get_renamed_from(&self, stream_name: &str) -> Result<String, ObjectStorageError> {
+        let renamed_from =
+            serde_json::from_slice(&self._get(stream_name, "renamed_from.json").await?)?;
+
+        Ok(renamed_from)
+    }
+
+    async fn put_time_field(
+        &self,
+        stream_name: &str,
+        time_field: &str,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_string(time_field)?;
+        self._put_time_field(stream_name, body).await?;
+
+        Ok(())
+    }
+
+    async fn get_time_field(&self, stream_name: &str) -> Result<String, ObjectStorageError> {
+        let time_field = serde_json::from_slice(&self._get(stream_name, "time_field.json").await?)?;
+
+        Ok(time_field)
+    }
+
+    async fn put_limits(
+        &self,
+        stream_name: &str,
+        limits: &Limits,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_string(limits)?;
+        self._put_limits(stream_name, body).await?;
+
+        Ok(())
+    }
+
+    async fn get_limits(&self, stream_name: &str) -> Result<Limits, ObjectStorageError> {
+        let limits = serde_json::from_slice(&self._get(stream_name, "limits.json").await?)?;
+
+        Ok(limits)
+    }
+
+    async fn put_compression(
+        &self,
+        stream_name: &str,
+        compression: Compression,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_string(&compression)?;
+        self._put_compression(stream_name, body).await?;
+
+        Ok(())
+    }
+
+    async fn get_compression(&self, stream_name: &str) -> Result<Compression, ObjectStorageError> {
+        let compression =
+            serde_json::from_slice(&self._get(stream_name, "compression.json").await?)?;
+
+        Ok(compression)
+    }
+
+    async fn put_flatten(
+        &self,
+        stream_name: &str,
+        flatten: Flatten,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_string(&flatten)?;
+        self._put_flatten(stream_name, body).await?;
+
+        Ok(())
+    }
+
+    async fn get_flatten(&self, stream_name: &str) -> Result<Flatten, ObjectStorageError> {
+        let flatten = serde_json::from_slice(&self._get(stream_name, "flatten.json").await?)?;
+
+        Ok(flatten)
+    }
+
+    async fn put_partition(
+        &self,
+        stream_name: &str,
+        partition: PartitionGranularity,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_string(&partition)?;
+        self._put_partition(stream_name, body).await?;
+
+        Ok(())
+    }
+
+    async fn get_partition(
+        &self,
+        stream_name: &str,
+    ) -> Result<PartitionGranularity, ObjectStorageError> {
+        let partition = serde_json::from_slice(&self._get(stream_name, "partition.json").await?)?;
+
+        Ok(partition)
+    }
+
+    async fn put_metadata(
+        &self,
+        stream_name: &str,
+        document: &MetadataDocument,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_string(document)?;
+        self._put_metadata(stream_name, body).await?;
+
+        Ok(())
+    }
+
+    async fn get_metadata(
+        &self,
+        stream_name: &str,
+    ) -> Result<MetadataDocument, ObjectStorageError> {
+        let document = serde_json::from_slice(&self._get(stream_name, "metadata.json").await?)?;
+
+        Ok(document)
+    }
+
+    async fn put_timestamps(
+        &self,
+        stream_name: &str,
+        timestamps: &StreamTimestamps,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_string(timestamps)?;
+        self._put_timestamps(stream_name, body).await?;
+
+        Ok(())
+    }
+
+    async fn get_timestamps(
+        &self,
+        stream_name: &str,
+    ) -> Result<StreamTimestamps, ObjectStorageError> {
+        let timestamps = serde_json::from_slice(&self._get(stream_name, "timestamps.json").await?)?;
+
+        Ok(timestamps)
+    }
+
     async fn list_streams(&self) -> Result<Vec<LogStream>, ObjectStorageError> {
-        let streams = self._list_streams().await?;
+        // a page holds at most 1000 streams, there may be more
+        storage::list_all_streams(self, S3_CONFIG.s3_list_page_size as usize).await
+    }
+
+    async fn list_streams_paged(
+        &self,
+        continuation_token: Option<&str>,
+        limit: usize,
+    ) -> Result<StreamsPage, ObjectStorageError> {
+        let page = self._list_streams_paged(continuation_token, limit).await?;
 
-        Ok(streams)
+        Ok(page)
+    }
+
+    async fn list_dirs(&self, prefix: &str) -> Result<Vec<String>, ObjectStorageError> {
+        let dirs = self._list_dirs(prefix).await?;
+
+        Ok(dirs)
+    }
+
+    async fn put_object(&self, key: &str, body: Bytes) -> Result<(), ObjectStorageError> {
+        let result = self
+            .put_request()
+            .key(self.key(key))
+            .body(ByteStream::from(body))
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            // the bucket may still be readable, as with read only credentials
+            Err(SdkError::ServiceError { raw, .. })
+                if raw.http().status() == http::StatusCode::FORBIDDEN =>
+            {
+                Err(ObjectStorageError::AccessDenied(
+                    S3_CONFIG.bucket_name().to_string(),
+                ))
+            }
+            Err(SdkError::DispatchFailure(err)) => {
+                Err(ObjectStorageError::ConnectionError(err.into()))
+            }
+            Err(err) => Err(AwsSdkError::from(err).into()),
+        }
+    }
+
+    async fn get_object(&self, key: &str) -> Result<Bytes, ObjectStorageError> {
+        let resp = self
+            .client
+            .get_object()
+            .bucket(&S3_CONFIG.s3_bucket_name)
+            .key(self.key(key))
+            .send()
+            .await
+            .map_err(AwsSdkError::from)?;
+        let body = resp
+            .body
+            .collect()
+            .await
+            .map_err(|e| ObjectStorageError::UnhandledError(e.into()))?;
+
+        Ok(body.into_bytes())
     }
 
     async fn upload_file(&self, key: &str, path: &str) -> Result<(), ObjectStorageError> {
@@ -368,6 +1340,59 @@ impl ObjectStorage for S3 {
         Ok(())
     }
 
+    async fn object_meta(&self, key: &str) -> Result<ObjectMeta, ObjectStorageError> {
+        let resp = self
+            .client
+            .head_object()
+            .bucket(&S3_CONFIG.s3_bucket_name)
+            .key(self.key(key))
+            .send()
+            .await
+            .map_err(AwsSdkError::from)?;
+
+        // the ETag is only the MD5 hash of objects put whole, without KMS encryption
+        let md5 = match S3_CONFIG.s3_sse {
+            Some(Sse::Kms) => None,
+            _ => resp.e_tag().and_then(md5_from_etag),
+        };
+
+        Ok(ObjectMeta {
+            size: resp.content_length().max(0) as u64,
+            md5,
+        })
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> Result<DeletedObjects, ObjectStorageError> {
+        let deleted = self._delete_prefix(prefix).await?;
+
+        deleted.complete()
+    }
+
+    async fn copy_prefix(&self, from: &str, to: &str) -> Result<u64, ObjectStorageError> {
+        let copied = self._copy_prefix(from, to).await?;
+
+        Ok(copied)
+    }
+
+    async fn count_objects(&self, prefix: &str) -> Result<u64, ObjectStorageError> {
+        let objects = self._count_objects(prefix).await?;
+
+        Ok(objects)
+    }
+
+    async fn parquet_objects(&self, prefix: &str) -> Result<StoredObjects, ObjectStorageError> {
+        let stored = self._parquet_objects(prefix).await?;
+
+        Ok(stored)
+    }
+
+    async fn list_parquet_files(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<ParquetFile>, ObjectStorageError> {
+        Ok(self._list_parquet_files(prefix).await?)
+    }
+
     async fn query(
         &self,
         query: &Query,
@@ -385,17 +1410,20 @@ impl ObjectStorage for S3 {
             .await,
         );
 
-        for prefix in query.get_prefixes() {
+        for prefix in self
+            .query_partitions(&query.stream_name, query.start, query.end)
+            .await?
+        {
             let ctx = SessionContext::new();
-            let path = format!("s3://{}/{}", &S3_CONFIG.s3_bucket_name, prefix);
-
-            if !self.prefix_exists(&prefix).await? {
-                continue;
-            }
+            let path = format!("s3://{}/{}", &S3_CONFIG.s3_bucket_name, self.key(&prefix));
 
             let config = ListingTableConfig::new(s3_file_system.clone(), &path)
                 .infer()
                 .await?;
+            let config = match query.stored_schema() {
+                Some(schema) => config.with_schema(schema),
+                None => config,
+            };
 
             let table = ListingTable::try_new(config)?;
             ctx.register_table(query.stream_name.as_str(), Arc::new(table))?;
@@ -409,9 +1437,55 @@ impl ObjectStorage for S3 {
     }
 }
 
+/// Move the log streams of an unprefixed deployment under `storage_prefix`. Objects of a
+/// stream are copied before the originals are deleted, so the migration can be run again
+/// if it fails part way. Returns the names of the log streams moved.
+pub async fn migrate_to_storage_prefix() -> Result<Vec<String>, ObjectStorageError> {
+    let prefix = key_prefix(S3_CONFIG.storage_prefix.as_deref())
+        .map_err(|e| ObjectStorageError::UnhandledError(e.into()))?;
+    if prefix.is_empty() {
+        return Err(ObjectStorageError::UnhandledError(
+            "a storage prefix (P_STORAGE_PREFIX) is needed to migrate to".into(),
+        ));
+    }
+
+    let unprefixed = S3::with_prefix(String::new());
+    let mut moved = Vec::new();
+    for stream in unprefixed.list_streams().await? {
+        let name = stream.name;
+        // prefixes of deployments sharing the bucket have no schema of their own
+        if validator::stream_name(&name).is_err()
+            || unprefixed
+                .object_meta(&format!("{}/.schema", name))
+                .await
+                .is_err()
+        {
+            continue;
+        }
+
+        let from = format!("{}/", name);
+        let copied = unprefixed
+            .copy_prefix(&from, &format!("{}{}", prefix, from))
+            .await?;
+        unprefixed.delete_prefix(&from).await?;
+        log::info!(
+            "moved {} objects of log stream {} under {}",
+            copied,
+            name,
+            prefix
+        );
+        moved.push(name);
+    }
+
+    Ok(moved)
+}
+
 impl From<AwsSdkError> for ObjectStorageError {
     fn from(error: AwsSdkError) -> Self {
-        ObjectStorageError::UnhandledError(error.into())
+        match error {
+            AwsSdkError::NoSuchKey(e) => ObjectStorageError::NoSuchKey(e.to_string()),
+            error => ObjectStorageError::UnhandledError(error.into()),
+        }
     }
 }
 
@@ -426,6 +1500,11 @@ impl From<SdkError<HeadBucketError>> for ObjectStorageError {
                     },
                 ..
             } => ObjectStorageError::NoSuchBucket(S3_CONFIG.bucket_name().to_string()),
+            SdkError::ServiceError { raw, .. }
+                if raw.http().status() == http::StatusCode::FORBIDDEN =>
+            {
+                ObjectStorageError::AccessDenied(S3_CONFIG.bucket_name().to_string())
+            }
             SdkError::DispatchFailure(err) => ObjectStorageError::ConnectionError(err.into()),
             SdkError::TimeoutError(err) => ObjectStorageError::ConnectionError(err),
             err => ObjectStorageError::UnhandledError(err.into()),
@@ -438,3 +1517,51 @@ impl From<serde_json::Error> for ObjectStorageError {
         ObjectStorageError::UnhandledError(error.into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{key_prefix, md5_from_etag, part_ranges, Sse};
+    use rstest::*;
+
+    #[rstest]
+    #[case("aes256", Ok(Sse::Aes256))]
+    #[case("aws:kms", Ok(Sse::Kms))]
+    #[case("kms", Err(()))]
+    fn parse_server_side_encryption(#[case] sse: &str, #[case] expected: Result<Sse, ()>) {
+        assert_eq!(sse.parse::<Sse>().map_err(|_| ()), expected);
+    }
+
+    #[rstest]
+    #[case(10, 4, vec![0..4, 4..8, 8..10])]
+    #[case(8, 4, vec![0..4, 4..8])]
+    #[case(3, 4, vec![0..3])]
+    fn split_into_parts(
+        #[case] size: u64,
+        #[case] part_size: u64,
+        #[case] expected: Vec<std::ops::Range<u64>>,
+    ) {
+        assert_eq!(part_ranges(size, part_size), expected);
+    }
+
+    #[rstest]
+    #[case("\"d33e0ce8df4048e3f39614067369d863\"", Some(md5::compute("parseable").0))]
+    #[case("\"d33e0ce8df4048e3f39614067369d863-2\"", None)]
+    #[case("\"d33e0ce8df4048e3f39614067369d8zz\"", None)]
+    fn md5_of_etag(#[case] etag: &str, #[case] expected: Option<[u8; 16]>) {
+        assert_eq!(md5_from_etag(etag), expected);
+    }
+
+    #[rstest]
+    #[case(None, Ok(""))]
+    #[case(Some(""), Ok(""))]
+    #[case(Some("staging"), Ok("staging/"))]
+    #[case(Some("/staging/"), Ok("staging/"))]
+    #[case(Some("staging/eu"), Err(()))]
+    #[case(Some("meta"), Err(()))]
+    fn storage_key_prefix(#[case] prefix: Option<&str>, #[case] expected: Result<&str, ()>) {
+        assert_eq!(
+            key_prefix(prefix).map_err(|_| ()),
+            expected.map(str::to_string)
+        );
+    }
+}
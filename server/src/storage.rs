@@ -16,22 +16,34 @@
  *
  */
 
-use crate::metadata::Stats;
+use crate::alerts::Alerts;
+use crate::manifest::{self, ManifestFile};
+use crate::metadata::{
+    self, Compression, Flatten, Limits, PartitionGranularity, Stats, StreamTimestamps, STREAM_INFO,
+};
+use crate::metrics;
+use crate::migration::MetadataDocument;
 use crate::option::CONFIG;
 use crate::query::Query;
+use crate::retention::Retention;
 use crate::utils;
 
+use arrow::datatypes::Schema;
 use async_trait::async_trait;
 use bytes::Bytes;
-use chrono::{Duration, Timelike, Utc};
+use chrono::{DateTime, Duration, Utc};
 use datafusion::arrow::record_batch::RecordBatch;
-use serde::Serialize;
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
 
+use std::collections::HashMap;
 use std::fmt::Debug;
 use std::fs;
 use std::io;
 use std::iter::Iterator;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tracing::Instrument;
 
 extern crate walkdir;
 use walkdir::WalkDir;
@@ -44,25 +56,242 @@ pub const LOCAL_SYNC_INTERVAL: u64 = 60;
 /// used for storage. Defaults to 1 min.
 pub const OBJECT_STORE_DATA_GRANULARITY: u32 = (LOCAL_SYNC_INTERVAL as u32) / 60;
 
+/// Local file that events are written to before they are synced. Events partitioned
+/// by their own time are written to a file per partition, named like
+/// `date=2022-10-15.hour=10.minute=30.data.parquet`.
+pub const DATA_FILE: &str = "data.parquet";
+
+/// Prefix of objects parseable keeps for itself, no log stream can be named `meta`
+pub const RESERVED_PREFIX: &str = "meta/";
+const PROBE_PREFIX: &str = "meta/probe/";
+const PROBE_BODY: &[u8] = b"parseable";
+/// Uploads of a staged file tried in a sync until one matches the file
+const CORRUPTED_UPLOAD_ATTEMPTS: u32 = 3;
+/// Puts of a schema tried until no other server changed it in between
+const SCHEMA_PUT_ATTEMPTS: u32 = 5;
+
 #[async_trait]
-pub trait ObjectStorage: Sync + 'static {
+pub trait ObjectStorage: Send + Sync + 'static {
     async fn check(&self) -> Result<(), ObjectStorageError>;
-    async fn put_schema(&self, stream_name: String, body: String)
-        -> Result<(), ObjectStorageError>;
+    async fn put_schema(
+        &self,
+        stream_name: String,
+        schema: &Schema,
+    ) -> Result<(), ObjectStorageError>;
     async fn create_stream(&self, stream_name: &str) -> Result<(), ObjectStorageError>;
-    async fn delete_stream(&self, stream_name: &str) -> Result<(), ObjectStorageError>;
-    async fn create_alert(&self, stream_name: &str, body: String)
-        -> Result<(), ObjectStorageError>;
+    async fn create_alert(
+        &self,
+        stream_name: &str,
+        alerts: &Alerts,
+    ) -> Result<(), ObjectStorageError>;
     async fn get_schema(&self, stream_name: &str) -> Result<Bytes, ObjectStorageError>;
+    /// Schema of the stream along with the tag of its current version, e.g. the ETag,
+    /// None if the stream has no schema object
+    async fn get_schema_tagged(
+        &self,
+        stream_name: &str,
+    ) -> Result<Option<(Bytes, String)>, ObjectStorageError>;
+    /// Put the schema only if the schema object is still at version `tag`, or doesn't
+    /// exist if `tag` is None. Fails with `PreconditionFailed` if it was changed.
+    async fn put_schema_if(
+        &self,
+        stream_name: &str,
+        schema: &Schema,
+        tag: Option<&str>,
+    ) -> Result<(), ObjectStorageError>;
     async fn get_alert(&self, stream_name: &str) -> Result<Bytes, ObjectStorageError>;
     async fn get_stats(&self, stream_name: &str) -> Result<Stats, ObjectStorageError>;
+    async fn put_stats(&self, stream_name: &str, stats: &Stats) -> Result<(), ObjectStorageError>;
+    async fn put_retention(
+        &self,
+        stream_name: &str,
+        retention: &Retention,
+    ) -> Result<(), ObjectStorageError>;
+    async fn get_retention(&self, stream_name: &str) -> Result<Retention, ObjectStorageError>;
+    async fn put_tags(
+        &self,
+        stream_name: &str,
+        tags: &HashMap<String, String>,
+    ) -> Result<(), ObjectStorageError>;
+    async fn get_tags(
+        &self,
+        stream_name: &str,
+    ) -> Result<HashMap<String, String>, ObjectStorageError>;
+    async fn put_static_schema(
+        &self,
+        stream_name: &str,
+        static_schema: bool,
+    ) -> Result<(), ObjectStorageError>;
+    async fn get_static_schema(&self, stream_name: &str) -> Result<bool, ObjectStorageError>;
+    /// Mark the stream as deleted at `deleted_at`, or restore it if `None`
+    async fn put_deleted_at(
+        &self,
+        stream_name: &str,
+        deleted_at: Option<DateTime<Utc>>,
+    ) -> Result<(), ObjectStorageError>;
+    async fn get_deleted_at(
+        &self,
+        stream_name: &str,
+    ) -> Result<Option<DateTime<Utc>>, ObjectStorageError>;
+    /// Mark the stream as the target of renaming the stream `renamed_from`
+    async fn put_renamed_from(
+        &self,
+        stream_name: &str,
+        renamed_from: &str,
+    ) -> Result<(), ObjectStorageError>;
+    async fn get_renamed_from(&self, stream_name: &str) -> Result<String, ObjectStorageError>;
+    async fn put_time_field(
+        &self,
+        stream_name: &str,
+        time_field: &str,
+    ) -> Result<(), ObjectStorageError>;
+    async fn get_time_field(&self, stream_name: &str) -> Result<String, ObjectStorageError>;
+    async fn put_limits(
+        &self,
+        stream_name: &str,
+        limits: &Limits,
+    ) -> Result<(), ObjectStorageError>;
+    async fn get_limits(&self, stream_name: &str) -> Result<Limits, ObjectStorageError>;
+    async fn put_compression(
+        &self,
+        stream_name: &str,
+        compression: Compression,
+    ) -> Result<(), ObjectStorageError>;
+    async fn get_compression(&self, stream_name: &str) -> Result<Compression, ObjectStorageError>;
+    async fn put_flatten(
+        &self,
+        stream_name: &str,
+        flatten: Flatten,
+    ) -> Result<(), ObjectStorageError>;
+    async fn get_flatten(&self, stream_name: &str) -> Result<Flatten, ObjectStorageError>;
+    async fn put_partition(
+        &self,
+        stream_name: &str,
+        partition: PartitionGranularity,
+    ) -> Result<(), ObjectStorageError>;
+    async fn get_partition(
+        &self,
+        stream_name: &str,
+    ) -> Result<PartitionGranularity, ObjectStorageError>;
+    async fn put_timestamps(
+        &self,
+        stream_name: &str,
+        timestamps: &StreamTimestamps,
+    ) -> Result<(), ObjectStorageError>;
+    async fn get_timestamps(
+        &self,
+        stream_name: &str,
+    ) -> Result<StreamTimestamps, ObjectStorageError>;
+    /// Put the document with the version of the layout of the metadata of the stream
+    async fn put_metadata(
+        &self,
+        stream_name: &str,
+        document: &MetadataDocument,
+    ) -> Result<(), ObjectStorageError>;
+    async fn get_metadata(&self, stream_name: &str)
+        -> Result<MetadataDocument, ObjectStorageError>;
     async fn list_streams(&self) -> Result<Vec<LogStream>, ObjectStorageError>;
+    /// List up to `limit` streams, following the page `continuation_token` came with.
+    /// Backends that can't list in pages list all streams and return the ones after
+    /// the token, which is then the name of the last stream of the previous page.
+    async fn list_streams_paged(
+        &self,
+        continuation_token: Option<&str>,
+        limit: usize,
+    ) -> Result<StreamsPage, ObjectStorageError> {
+        let mut streams = self.list_streams().await?;
+        streams.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut streams = streams
+            .into_iter()
+            .filter(|stream| continuation_token.map_or(true, |after| stream.name.as_str() > after))
+            .take(limit.max(1) + 1)
+            .collect::<Vec<_>>();
+
+        let next_token = if streams.len() > limit.max(1) {
+            streams.pop();
+            streams.last().map(|stream| stream.name.clone())
+        } else {
+            None
+        };
+
+        Ok(StreamsPage {
+            streams,
+            next_token,
+        })
+    }
+    /// List names of the directories directly under `prefix`, e.g. `date=2022-10-15`
+    /// for `prefix` = `stream_name/`.
+    async fn list_dirs(&self, prefix: &str) -> Result<Vec<String>, ObjectStorageError>;
+    /// Put `body` as the object at `key`, replacing the object if there is one
+    async fn put_object(&self, key: &str, body: Bytes) -> Result<(), ObjectStorageError>;
+    async fn get_object(&self, key: &str) -> Result<Bytes, ObjectStorageError>;
     async fn upload_file(&self, key: &str, path: &str) -> Result<(), ObjectStorageError>;
+    /// Size of the object at `key`, and its MD5 hash if the object storage keeps one
+    async fn object_meta(&self, key: &str) -> Result<ObjectMeta, ObjectStorageError>;
+    /// Delete all objects under `prefix`. The delete goes on past objects that fail to be
+    /// removed, then errors with `PartialDelete` holding the counts if any did.
+    async fn delete_prefix(&self, prefix: &str) -> Result<DeletedObjects, ObjectStorageError>;
+    /// Copy all objects under `from` to the same keys under `to`, objects that already
+    /// exist are overwritten. Returns the number of objects copied.
+    async fn copy_prefix(&self, from: &str, to: &str) -> Result<u64, ObjectStorageError>;
+    /// Number of objects under `prefix`
+    async fn count_objects(&self, prefix: &str) -> Result<u64, ObjectStorageError>;
+    /// Number and total size of the parquet files under `prefix`
+    async fn parquet_objects(&self, prefix: &str) -> Result<StoredObjects, ObjectStorageError>;
+    /// Keys and sizes of the parquet files under `prefix`
+    async fn list_parquet_files(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<ParquetFile>, ObjectStorageError>;
     async fn query(
         &self,
         query: &Query,
         results: &mut Vec<RecordBatch>,
     ) -> Result<(), ObjectStorageError>;
+
+    /// Put `schema` as version `version` of the schema of the stream. Past versions are
+    /// kept, so that data written before the schema changed can be read with its schema.
+    async fn put_schema_version(
+        &self,
+        stream_name: &str,
+        version: u32,
+        schema: &Schema,
+    ) -> Result<(), ObjectStorageError> {
+        let body = serde_json::to_vec(schema)?;
+        self.put_object(&schema_version_key(stream_name, version), body.into())
+            .await
+    }
+
+    async fn get_schema_version(
+        &self,
+        stream_name: &str,
+        version: u32,
+    ) -> Result<Schema, ObjectStorageError> {
+        let body = self
+            .get_object(&schema_version_key(stream_name, version))
+            .await?;
+
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Write, read back and delete a small object under the reserved `meta/` prefix.
+    /// `check` only tells the bucket can be read, this tells it can be written too.
+    async fn check_write(&self) -> Result<(), ObjectStorageError> {
+        let key = format!("{}probe-{}", PROBE_PREFIX, utils::random_string());
+        self.put_object(&key, Bytes::from_static(PROBE_BODY))
+            .await?;
+        let body = self.get_object(&key).await?;
+        self.delete_prefix(&key).await?;
+
+        if body != PROBE_BODY {
+            return Err(ObjectStorageError::UnhandledError(
+                format!("object {} read back differs from what was written", key).into(),
+            ));
+        }
+
+        Ok(())
+    }
+
     fn local_sync(&self) -> io::Result<()> {
         // If the local data path doesn't exist yet, return early.
         // This method will be called again after next ticker interval
@@ -79,6 +308,14 @@ pub trait ObjectStorage: Sync + 'static {
             let path = entry.into_os_string().into_string().unwrap();
             let init_sync = StorageSync::new(path);
 
+            if let Err(e) = init_sync.move_partition_files_to_tmp() {
+                log::error!(
+                    "Error moving partitioned parquet files in path {} due to error [{}]",
+                    init_sync.path,
+                    e
+                );
+            }
+
             // if data.parquet file not present, skip this stream
             if !init_sync.parquet_path_exists() {
                 continue;
@@ -108,51 +345,522 @@ pub trait ObjectStorage: Sync + 'static {
     }
 
     async fn s3_sync(&self) -> Result<(), ObjectStorageError> {
-        if !Path::new(&CONFIG.parseable.local_disk_path).exists() {
-            return Ok(());
+        let started = Instant::now();
+        let result = sync_streams(
+            self,
+            Path::new(&CONFIG.parseable.local_disk_path),
+            CONFIG.parseable.sync_concurrency,
+        )
+        .await;
+        metrics::SYNC_DURATION.observe(started.elapsed().as_secs_f64());
+
+        result
+    }
+
+    /// Delete all objects of the stream, then list its prefix to confirm that none are left.
+    /// A delete that fails halfway may still have removed some objects, so the error is
+    /// only logged and whatever remains is reported instead.
+    async fn delete_stream(&self, stream_name: &str) -> Result<(), ObjectStorageError> {
+        let prefix = format!("{}/", stream_name);
+
+        if let Err(e) = self.delete_prefix(&prefix).await {
+            log::warn!("failed to delete log stream {}. {:?}", stream_name, e);
         }
 
-        let entries = fs::read_dir(&CONFIG.parseable.local_disk_path)?
-            .map(|res| res.map(|e| e.path()))
-            .collect::<Result<Vec<_>, io::Error>>()?;
+        match self.count_objects(&prefix).await? {
+            0 => Ok(()),
+            remaining => Err(ObjectStorageError::DeleteIncomplete(remaining)),
+        }
+    }
 
-        for entry in entries {
-            let path = entry.into_os_string().into_string().unwrap();
-            let init_sync = StorageSync::new(path);
+    /// Move all objects of the stream `from` to the stream `to`. The target is marked before
+    /// anything is copied and the mark is only removed once no object of `from` is left, so
+    /// that a rename that failed halfway can be run again.
+    async fn rename_stream(&self, from: &str, to: &str) -> Result<(), ObjectStorageError> {
+        self.put_renamed_from(to, from).await?;
+        self.copy_prefix(&format!("{}/", from), &format!("{}/", to))
+            .await?;
+        self.delete_stream(from).await?;
+        self.delete_prefix(&format!("{}/.renamed_from.json", to))
+            .await?;
 
-            let dir = init_sync.get_dir_name();
+        Ok(())
+    }
+
+    /// Prefixes of the partitions of the stream that hold events between `start` and `end`,
+    /// e.g. `stream_name/date=2022-10-15/hour=10/`. Queries only list the objects under
+    /// these, instead of all objects of the stream.
+    async fn list_partitions_in_range(
+        &self,
+        stream_name: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<String>, ObjectStorageError> {
+        // prefixes are generated by minute, streams partitioned by hour or day only
+        // have the coarser levels
+        let granularity = STREAM_INFO.partition(stream_name).unwrap_or_default();
+        let mut prefixes: Vec<String> =
+            utils::TimePeriod::new(start, end, OBJECT_STORE_DATA_GRANULARITY)
+                .generate_prefixes(stream_name)
+                .iter()
+                .map(|prefix| granularity.truncate(prefix))
+                .collect();
+        prefixes.dedup();
 
-            for file in WalkDir::new(&format!("{}/tmp", &dir.dir_name_local))
+        // each prefix is checked against the dirs of its parent, listed once per parent
+        let mut dirs: HashMap<String, Vec<String>> = HashMap::new();
+        let mut partitions = Vec::new();
+        for prefix in prefixes {
+            let (parent, dir) = match prefix.trim_end_matches('/').rsplit_once('/') {
+                Some((parent, dir)) => (format!("{}/", parent), dir.to_owned()),
+                None => {
+                    partitions.push(prefix);
+                    continue;
+                }
+            };
+
+            if !dirs.contains_key(&parent) {
+                let listed = self.list_dirs(&parent).await?;
+                dirs.insert(parent.clone(), listed);
+            }
+            if dirs[&parent].contains(&dir) {
+                partitions.push(prefix);
+            }
+        }
+
+        Ok(partitions)
+    }
+
+    /// Prefixes of the partitions of the stream that hold events between `start` and `end`,
+    /// found in the manifest of the stream. Listed instead if it has none or it is stale.
+    async fn query_partitions(
+        &self,
+        stream_name: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<String>, ObjectStorageError> {
+        let loaded = manifest::load(self, stream_name)
+            .await
+            .map_err(|e| e.to_string());
+        match loaded {
+            Ok(Some(manifest)) => return Ok(manifest.partitions_in_range(stream_name, start, end)),
+            Ok(None) => {}
+            Err(e) => log::warn!(
+                "failed to load manifest of log stream {}, listing it instead. {}",
+                stream_name,
+                e
+            ),
+        }
+
+        self.list_partitions_in_range(stream_name, start, end).await
+    }
+
+    /// Start time of the latest data partition of the stream in object storage.
+    async fn latest_event_time(
+        &self,
+        stream_name: &str,
+    ) -> Result<Option<DateTime<Utc>>, ObjectStorageError> {
+        self.partition_time(stream_name, true).await
+    }
+
+    /// Start time of the earliest data partition of the stream in object storage.
+    async fn earliest_event_time(
+        &self,
+        stream_name: &str,
+    ) -> Result<Option<DateTime<Utc>>, ObjectStorageError> {
+        self.partition_time(stream_name, false).await
+    }
+
+    /// Start time of the latest or earliest data partition of the stream.
+    async fn partition_time(
+        &self,
+        stream_name: &str,
+        latest: bool,
+    ) -> Result<Option<DateTime<Utc>>, ObjectStorageError> {
+        let mut prefix = format!("{}/", stream_name);
+        let mut partitions = Vec::with_capacity(3);
+
+        for key in ["date=", "hour=", "minute="] {
+            let dirs = self
+                .list_dirs(&prefix)
+                .await?
                 .into_iter()
-                .filter_map(|file| file.ok())
+                .filter(|dir| dir.starts_with(key));
+            let edge = if latest { dirs.max() } else { dirs.min() };
+
+            match edge {
+                Some(dir) => {
+                    prefix = format!("{}{}/", prefix, dir);
+                    partitions.push(dir);
+                }
+                None => break,
+            }
+        }
+
+        Ok(utils::partition_to_time(&partitions))
+    }
+
+    async fn stats_sync(&self) -> Result<(), ObjectStorageError> {
+        // stats are cloned so that the lock on STREAM_INFO is not held
+        // while talking to object storage
+        for (stream_name, stats) in STREAM_INFO.unsynced_stats() {
+            self.sync_stream_stats(&stream_name, &stats).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Put stats of the streams that piled up enough updates since they were last put,
+    /// without waiting for the periodic stats sync.
+    async fn due_stats_sync(&self) -> Result<(), ObjectStorageError> {
+        for (stream_name, stats) in STREAM_INFO.due_stats() {
+            self.sync_stream_stats(&stream_name, &stats).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Put lifecycle timestamps of a stream, as currently in STREAM_INFO, to object storage.
+    async fn sync_timestamps(&self, stream_name: &str) -> Result<(), ObjectStorageError> {
+        // nothing to put if the stream was deleted meanwhile
+        if let Ok(timestamps) = STREAM_INFO.timestamps(stream_name) {
+            self.put_timestamps(stream_name, &timestamps).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Put stats of a stream to object storage, unless a newer copy is already there.
+    /// This happens when the put of stats that were due races with the periodic stats sync.
+    async fn sync_stream_stats(
+        &self,
+        stream_name: &str,
+        stats: &Stats,
+    ) -> Result<(), ObjectStorageError> {
+        let is_stale = matches!(
+            self.get_stats(stream_name).await,
+            Ok(stored) if stored.sequence > stats.sequence
+        );
+
+        if !is_stale {
+            self.put_stats(stream_name, stats).await?;
+        }
+
+        if let Err(e) = STREAM_INFO.set_stats_synced(stream_name, stats.sequence) {
+            log::warn!("failed to mark stats as synced. {:?}", e);
+        }
+
+        Ok(())
+    }
+}
+
+/// Upload the staged parquet files of the streams in `local_path`, up to `concurrency`
+/// streams at a time, so that a slow or failing stream doesn't hold up the others.
+/// The files of a stream are uploaded one at a time, a failed upload skips the rest of them.
+pub async fn sync_streams(
+    storage: &(impl ObjectStorage + ?Sized),
+    local_path: &Path,
+    concurrency: usize,
+) -> Result<(), ObjectStorageError> {
+    if !local_path.exists() {
+        return Ok(());
+    }
+
+    let entries = fs::read_dir(local_path)?
+        .map(|res| res.map(|e| e.path()))
+        .collect::<Result<Vec<_>, io::Error>>()?;
+
+    let mut syncs = stream::iter(entries)
+        .map(|entry| sync_stream(storage, entry))
+        .buffer_unordered(concurrency.max(1));
+
+    let mut failed = Vec::new();
+    while let Some((stream_name, synced)) = syncs.next().await {
+        if !synced {
+            failed.push(stream_name);
+        }
+    }
+
+    if !failed.is_empty() {
+        failed.sort();
+        return Err(ObjectStorageError::UnhandledError(
+            format!("failed to sync log streams {}", failed.join(", ")).into(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Upload the files staged in the `tmp` dir of the stream at `dir`. Returns the name
+/// of the stream, and whether all of its files were uploaded.
+async fn sync_stream(storage: &(impl ObjectStorage + ?Sized), dir: PathBuf) -> (String, bool) {
+    let stream_name = dir
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let mut uploaded = false;
+    let mut failed = false;
+    let mut synced = Vec::new();
+    for file in WalkDir::new(dir.join("tmp"))
+        .into_iter()
+        .filter_map(|file| file.ok())
+    {
+        if file.metadata().unwrap().is_file() {
+            let file_local = format!("{}", file.path().display());
+            // files are named after their partition, like `date=2022-10-15.hour=10.minute=30.<random>.parquet`
+            let file_name = file.file_name().to_string_lossy();
+            let f_new_path = format!(
+                "{}/{}",
+                stream_name,
+                file_name.replace('.', "/").replace("/parquet", ".parquet")
+            );
+            let span = tracing::info_span!(
+                "upload_file",
+                key = %f_new_path,
+                bytes = file.metadata().map_or(0, |metadata| metadata.len())
+            );
+            // read before the upload, the staged file is removed once uploaded
+            let entry = f_new_path
+                .strip_prefix(&format!("{}/", stream_name))
+                .and_then(|key| ManifestFile::of_file(key, &file_local).ok().flatten());
+            if let Err(e) = upload_verified(storage, &stream_name, &f_new_path, &file_local)
+                .instrument(span)
+                .await
             {
-                if file.metadata().unwrap().is_file() {
-                    let file_local = format!("{}", file.path().display());
-                    let file_s3 = file_local.replace("/tmp", "");
-                    let final_s3_path =
-                        file_s3.replace(&format!("{}/", CONFIG.parseable.local_disk_path), "");
-                    let f_path = str::replace(&final_s3_path, ".", "/");
-                    let f_new_path = f_path.replace("/parquet", ".parquet");
-                    let _put_parquet_file = self.upload_file(&f_new_path, &file_local).await?;
-                    if let Err(e) = dir.delete_parquet_file(file_local.clone()) {
-                        log::error!(
-                            "Error deleting parquet file in path {} due to error [{}]",
-                            file_local,
-                            e
-                        );
-                    }
+                log::error!("failed to upload {} due to error [{}]", f_new_path, e);
+                if let Err(e) = STREAM_INFO.record_sync(&stream_name, Err(&e)) {
+                    log::warn!("failed to record sync failure. {:?}", e);
+                }
+                failed = true;
+                break;
+            }
+            uploaded = true;
+            match entry {
+                Some(entry) => synced.push(entry),
+                None => log::warn!("failed to read {} for the manifest", f_new_path),
+            }
+            // persisted to object storage with the next stats sync
+            if let Err(e) = STREAM_INFO.record_upload(&stream_name, f_new_path.clone()) {
+                log::warn!("failed to record upload of {}. {:?}", f_new_path, e);
+            }
+        }
+    }
+
+    if !synced.is_empty() {
+        manifest::update(storage, &stream_name, |manifest| manifest.add(synced)).await;
+    }
+
+    if uploaded && !failed {
+        if let Err(e) = STREAM_INFO.record_sync(&stream_name, Ok(())) {
+            log::warn!("failed to record sync. {:?}", e);
+        }
+    }
+
+    (stream_name, !failed)
+}
+
+/// Put `schema` merged with the schema in object storage. Servers sharing a bucket may
+/// put the schema of a stream at the same time, so it is only put if the stored schema
+/// wasn't changed since it was read, and merged with the new one again otherwise.
+/// Returns the schema that was put.
+pub async fn put_schema_merged(
+    storage: &(impl ObjectStorage + ?Sized),
+    stream_name: &str,
+    schema: &Schema,
+) -> Result<Schema, crate::Error> {
+    for _ in 0..SCHEMA_PUT_ATTEMPTS {
+        let (merged, tag) = match storage.get_schema_tagged(stream_name).await? {
+            // streams are created with an empty schema object
+            Some((body, tag)) if !body.is_empty() => {
+                let stored: Schema = serde_json::from_slice(&body)?;
+                (metadata::merge_schemas(&stored, schema)?, Some(tag))
+            }
+            Some((_, tag)) => (schema.clone(), Some(tag)),
+            // the stream was deleted, the put is conditional on its schema object
+            // so that a stream deleted meanwhile isn't brought back
+            None => return Err(crate::Error::StreamMetaNotFound(stream_name.to_owned())),
+        };
+
+        match storage
+            .put_schema_if(stream_name, &merged, tag.as_deref())
+            .await
+        {
+            Ok(()) => return Ok(merged),
+            Err(ObjectStorageError::PreconditionFailed(key)) => {
+                log::info!("{} was changed by another server, merging again", key)
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Err(crate::Error::SchemaConflict(
+        stream_name.to_owned(),
+        SCHEMA_PUT_ATTEMPTS,
+    ))
+}
+
+/// Key of version `version` of the schema of the stream
+fn schema_version_key(stream_name: &str, version: u32) -> String {
+    format!("{}/schema/v{}.json", stream_name, version)
+}
+
+/// List all streams, a page of up to `page_size` streams at a time, for backends
+/// that can only list in pages.
+pub async fn list_all_streams(
+    storage: &dyn ObjectStorage,
+    page_size: usize,
+) -> Result<Vec<LogStream>, ObjectStorageError> {
+    let mut streams = Vec::new();
+    let mut continuation_token: Option<String> = None;
+    loop {
+        let page = storage
+            .list_streams_paged(continuation_token.as_deref(), page_size)
+            .await?;
+        streams.extend(page.streams);
+
+        match page.next_token {
+            Some(token) => continuation_token = Some(token),
+            None => break,
+        }
+    }
+
+    Ok(streams)
+}
+
+/// Upload the staged parquet file at `path` to `key` and delete the staged file once
+/// the object is found to match it. A mismatching upload is counted in the stats of the
+/// stream and retried, the staged file is kept for the next sync if none matches.
+pub async fn upload_verified(
+    storage: &(impl ObjectStorage + ?Sized),
+    stream_name: &str,
+    key: &str,
+    path: &str,
+) -> Result<(), ObjectStorageError> {
+    let digest = FileDigest::of_file(path)?;
+
+    let mut attempt = 1;
+    loop {
+        storage.upload_file(key, path).await?;
+        let meta = storage.object_meta(key).await?;
+        match digest.verify(key, &meta) {
+            Ok(()) => break,
+            Err(e) => {
+                log::warn!("upload {} of {}. {}", attempt, path, e);
+                if let Err(e) = STREAM_INFO.record_corrupted_upload(stream_name) {
+                    log::warn!("failed to record corrupted upload. {:?}", e);
+                }
+                if attempt >= CORRUPTED_UPLOAD_ATTEMPTS {
+                    return Err(e);
                 }
+                attempt += 1;
             }
         }
+    }
+
+    if let Err(e) = fs::remove_file(path) {
+        log::error!(
+            "Error deleting parquet file in path {} due to error [{}]",
+            path,
+            e
+        );
+    }
+
+    Ok(())
+}
+
+/// Size and MD5 hash of a staged file, to check the object uploaded from it against
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileDigest {
+    pub size: u64,
+    pub md5: [u8; 16],
+}
+
+impl FileDigest {
+    pub fn of_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = fs::File::open(path)?;
+        let mut context = md5::Context::new();
+        let size = io::copy(&mut file, &mut context)?;
+
+        Ok(Self {
+            size,
+            md5: context.compute().0,
+        })
+    }
+
+    /// Check the uploaded object against the file, its hash only if the object storage
+    /// returned one
+    pub fn verify(&self, key: &str, meta: &ObjectMeta) -> Result<(), ObjectStorageError> {
+        if meta.size != self.size {
+            return Err(ObjectStorageError::Corrupted(
+                key.to_string(),
+                format!("{} bytes stored, {} bytes staged", meta.size, self.size),
+            ));
+        }
+        if matches!(meta.md5, Some(md5) if md5 != self.md5) {
+            return Err(ObjectStorageError::Corrupted(
+                key.to_string(),
+                "MD5 hash differs".to_string(),
+            ));
+        }
+
         Ok(())
     }
 }
 
-#[derive(Serialize)]
+/// Size in bytes of an object and its MD5 hash, object storages don't keep one for
+/// every object, e.g. S3 for objects uploaded in parts or encrypted with KMS
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectMeta {
+    pub size: u64,
+    pub md5: Option<[u8; 16]>,
+}
+
+/// Streams listed in a page, with the token of the next page if there is one
+#[derive(Debug)]
+pub struct StreamsPage {
+    pub streams: Vec<LogStream>,
+    pub next_token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
 pub struct LogStream {
     pub name: String,
 }
 
+/// Number and total size in bytes of objects in object storage
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct StoredObjects {
+    pub objects: u64,
+    pub size: u64,
+}
+
+/// A parquet file in object storage
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParquetFile {
+    pub key: String,
+    pub size: u64,
+}
+
+/// Number and total size in bytes of objects removed from object storage, and the
+/// number of objects that failed to be removed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DeletedObjects {
+    pub objects: u64,
+    pub size: u64,
+    pub failed: u64,
+}
+
+impl DeletedObjects {
+    /// The counts, or a `PartialDelete` error holding them if any object failed
+    pub fn complete(self) -> Result<Self, ObjectStorageError> {
+        match self.failed {
+            0 => Ok(self),
+            _ => Err(ObjectStorageError::PartialDelete(self)),
+        }
+    }
+}
+
 #[derive(Debug)]
 struct DirName {
     dir_name_tmp_local: String,
@@ -172,10 +880,6 @@ impl DirName {
     fn create_dir_name_tmp(&self) -> io::Result<()> {
         fs::create_dir_all(&self.dir_name_tmp_local)
     }
-
-    fn delete_parquet_file(&self, path: String) -> io::Result<()> {
-        fs::remove_file(path)
-    }
 }
 
 struct StorageSync {
@@ -192,23 +896,55 @@ impl StorageSync {
     }
 
     fn parquet_path_exists(&self) -> bool {
-        let new_parquet_path = format!("{}/data.parquet", &self.path);
+        let new_parquet_path = format!("{}/{}", &self.path, DATA_FILE);
 
         Path::new(&new_parquet_path).exists()
     }
 
+    // Files of events partitioned by their own time are moved to the tmp dir
+    // named after their partition, instead of the time of the sync.
+    fn move_partition_files_to_tmp(&self) -> io::Result<()> {
+        if !Path::new(&self.path).is_dir() {
+            return Ok(());
+        }
+
+        let dir_name_tmp_local = format!("{}/tmp", self.path);
+        for file in fs::read_dir(&self.path)? {
+            let file = file?;
+            let file_name = file.file_name().to_string_lossy().into_owned();
+            let local_uri = match file_name.strip_suffix(DATA_FILE) {
+                Some(local_uri) if local_uri.starts_with("date=") => local_uri,
+                _ => continue,
+            };
+
+            fs::create_dir_all(&dir_name_tmp_local)?;
+            fs::rename(
+                file.path(),
+                format!(
+                    "{}/{}{}.parquet",
+                    dir_name_tmp_local,
+                    local_uri,
+                    utils::random_string()
+                ),
+            )?;
+        }
+
+        Ok(())
+    }
+
     fn get_dir_name(&self) -> DirName {
         let local_path = format!("{}/", CONFIG.parseable.local_disk_path);
         let _storage_path = format!("{}/", CONFIG.storage.bucket_name());
         let stream_name = self.path.replace(&local_path, "");
-        let parquet_path = format!("{}/data.parquet", self.path);
+        let parquet_path = format!("{}/{}", self.path, DATA_FILE);
         // subtract OBJECT_STORE_DATA_GRANULARITY from current time here,
         // this is because, when we're creating this file
         // the data in the file is from OBJECT_STORE_DATA_GRANULARITY time ago.
         let time = self.time - Duration::minutes(OBJECT_STORE_DATA_GRANULARITY as i64);
-        let uri = utils::date_to_prefix(time.date())
-            + &utils::hour_to_prefix(time.hour())
-            + &utils::minute_to_prefix(time.minute(), OBJECT_STORE_DATA_GRANULARITY).unwrap();
+        let uri = STREAM_INFO
+            .partition(&stream_name)
+            .unwrap_or_default()
+            .prefix(time);
 
         let local_uri = str::replace(&uri, "/", ".");
 
@@ -237,14 +973,31 @@ impl StorageSync {
 pub enum ObjectStorageError {
     #[error("Bucket {0} not found")]
     NoSuchBucket(String),
+    #[error("Access denied to bucket {0}")]
+    AccessDenied(String),
+    #[error("Object {0} not found")]
+    NoSuchKey(String),
+    #[error("Invalid object key {0}")]
+    InvalidKey(String),
     #[error("Connection Error: {0}")]
     ConnectionError(Box<dyn std::error::Error>),
+    /// The object storage is throttling requests or failed to handle them, trying again may work
+    #[error("Object storage unavailable: {0}")]
+    Unavailable(Box<dyn std::error::Error>),
     #[error("IO Error: {0}")]
     IoError(#[from] std::io::Error),
     #[error("DataFusion Error: {0}")]
     DataFusionError(#[from] datafusion::error::DataFusionError),
     #[error("Unhandled Error: {0}")]
     UnhandledError(Box<dyn std::error::Error>),
+    #[error("Delete incomplete: {0} objects remain in object storage")]
+    DeleteIncomplete(u64),
+    #[error("Delete incomplete: {} objects deleted, {} failed", .0.objects, .0.failed)]
+    PartialDelete(DeletedObjects),
+    #[error("Uploaded object {0} does not match the staged file: {1}")]
+    Corrupted(String, String),
+    #[error("Object {0} was changed since it was read")]
+    PreconditionFailed(String),
 }
 
 impl From<ObjectStorageError> for crate::error::Error {
@@ -252,3 +1005,887 @@ impl From<ObjectStorageError> for crate::error::Error {
         crate::error::Error::Storage(e)
     }
 }
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    /// In memory object storage for tests. Records when fetching the schema of
+    /// a stream starts and ends and when a stream is created or its schema is put,
+    /// so tests can check ordering.
+    #[derive(Default)]
+    pub struct MockStorage {
+        schemas: HashMap<String, Bytes>,
+        delays: HashMap<String, Duration>,
+        requests: Mutex<Vec<String>>,
+        objects: Mutex<Vec<String>>,
+        /// Source stream of each stream marked as the target of a rename
+        renames: Mutex<HashMap<String, String>>,
+        /// Number of objects deleted before the rest fail to be deleted
+        delete_limit: Option<usize>,
+        /// Number of schema fetches and uploads left to fail with a connection error
+        transient_failures: Mutex<u32>,
+        check_delay: Option<Duration>,
+        /// Most streams listed in a page, whatever the limit asked for
+        page_size: Option<usize>,
+        bodies: Mutex<HashMap<String, Bytes>>,
+        /// Deny writes of objects
+        read_only: bool,
+        /// Number of uploads left to store truncated
+        mangled_uploads: Mutex<u32>,
+        upload_delay: Option<Duration>,
+        /// Number of conditional schema puts left to fail, as if another server put it
+        schema_conflicts: Mutex<u32>,
+        /// Purge streams right after their schema is first fetched
+        purge_on_schema_fetch: bool,
+        purged: Mutex<HashSet<String>>,
+    }
+
+    impl MockStorage {
+        pub fn with_stream(mut self, stream_name: &str, schema: impl Into<Bytes>) -> Self {
+            self.schemas.insert(stream_name.to_string(), schema.into());
+            self
+        }
+
+        /// Delay fetching the schema of the stream
+        pub fn with_delay(mut self, stream_name: &str, delay: Duration) -> Self {
+            self.delays.insert(stream_name.to_string(), delay);
+            self
+        }
+
+        /// Add objects with the given keys
+        pub fn with_objects<S: ToString>(self, keys: &[S]) -> Self {
+            self.objects
+                .lock()
+                .unwrap()
+                .extend(keys.iter().map(|key| key.to_string()));
+            self
+        }
+
+        /// Add an object with the given key and body
+        pub fn with_object(self, key: &str, body: impl Into<Bytes>) -> Self {
+            self.bodies
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), body.into());
+            self.with_objects(&[key])
+        }
+
+        /// Mark `stream_name` as the target of an incomplete rename of `renamed_from`
+        pub fn with_renamed_from(self, stream_name: &str, renamed_from: &str) -> Self {
+            self.renames
+                .lock()
+                .unwrap()
+                .insert(stream_name.to_string(), renamed_from.to_string());
+            self.with_objects(&[format!("{}/.renamed_from.json", stream_name)])
+        }
+
+        /// Fail deletes once `limit` objects have been deleted
+        pub fn with_delete_limit(mut self, limit: usize) -> Self {
+            self.delete_limit = Some(limit);
+            self
+        }
+
+        /// Fail the next `failures` schema fetches and uploads with a connection error
+        pub fn with_transient_failures(self, failures: u32) -> Self {
+            *self.transient_failures.lock().unwrap() = failures;
+            self
+        }
+
+        /// Delay checks
+        pub fn with_check_delay(mut self, delay: Duration) -> Self {
+            self.check_delay = Some(delay);
+            self
+        }
+
+        /// List at most `page_size` streams a page
+        pub fn with_page_size(mut self, page_size: usize) -> Self {
+            self.page_size = Some(page_size);
+            self
+        }
+
+        /// Deny putting objects, like credentials that may only read
+        pub fn with_read_only(mut self) -> Self {
+            self.read_only = true;
+            self
+        }
+
+        /// Store the next `uploads` uploaded files truncated, like a transfer cut short
+        pub fn with_mangled_uploads(self, uploads: u32) -> Self {
+            *self.mangled_uploads.lock().unwrap() = uploads;
+            self
+        }
+
+        /// Fail the next `puts` conditional puts of a schema
+        pub fn with_schema_conflicts(self, puts: u32) -> Self {
+            *self.schema_conflicts.lock().unwrap() = puts;
+            self
+        }
+
+        /// Purge a stream, from STREAM_INFO along with its schema object, right after
+        /// its schema is first fetched, like a delete racing with an event that
+        /// changes the schema
+        pub fn with_purge_on_schema_fetch(mut self) -> Self {
+            self.purge_on_schema_fetch = true;
+            self
+        }
+
+        /// Delay every upload of a file
+        pub fn with_upload_delay(mut self, delay: Duration) -> Self {
+            self.upload_delay = Some(delay);
+            self
+        }
+
+        pub fn objects(&self) -> Vec<String> {
+            self.objects.lock().unwrap().clone()
+        }
+
+        pub fn requests(&self) -> Vec<String> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        fn record(&self, request: String) {
+            self.requests.lock().unwrap().push(request);
+        }
+
+        fn add_object(&self, key: &str, body: Bytes) {
+            let mut objects = self.objects.lock().unwrap();
+            if !objects.iter().any(|existing| existing == key) {
+                objects.push(key.to_string());
+            }
+            self.bodies.lock().unwrap().insert(key.to_string(), body);
+        }
+
+        fn fail_transiently(&self) -> Result<(), ObjectStorageError> {
+            let mut failures = self.transient_failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(ObjectStorageError::ConnectionError(
+                    "connection reset".into(),
+                ));
+            }
+
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl ObjectStorage for MockStorage {
+        async fn check(&self) -> Result<(), ObjectStorageError> {
+            if let Some(delay) = self.check_delay {
+                actix_web::rt::time::sleep(delay).await;
+            }
+            Ok(())
+        }
+
+        async fn put_schema(
+            &self,
+            stream_name: String,
+            _schema: &Schema,
+        ) -> Result<(), ObjectStorageError> {
+            self.record(format!("put schema {}", stream_name));
+            Ok(())
+        }
+
+        async fn get_schema_tagged(
+            &self,
+            stream_name: &str,
+        ) -> Result<Option<(Bytes, String)>, ObjectStorageError> {
+            let mut purged = self.purged.lock().unwrap();
+            let schema = match purged.contains(stream_name) {
+                true => None,
+                false => self
+                    .schemas
+                    .get(stream_name)
+                    .map(|schema| (schema.clone(), "mock".to_string())),
+            };
+            if self.purge_on_schema_fetch && purged.insert(stream_name.to_owned()) {
+                STREAM_INFO.delete_stream(stream_name).unwrap_or(());
+            }
+            Ok(schema)
+        }
+
+        async fn put_schema_if(
+            &self,
+            stream_name: &str,
+            _schema: &Schema,
+            tag: Option<&str>,
+        ) -> Result<(), ObjectStorageError> {
+            self.record(format!("put schema {}", stream_name));
+            // the schema object is gone, so it isn't at `tag` anymore
+            if tag.is_some() && self.purged.lock().unwrap().contains(stream_name) {
+                return Err(ObjectStorageError::PreconditionFailed(format!(
+                    "{}/.schema",
+                    stream_name
+                )));
+            }
+            let mut conflicts = self.schema_conflicts.lock().unwrap();
+            if *conflicts > 0 {
+                *conflicts -= 1;
+                return Err(ObjectStorageError::PreconditionFailed(format!(
+                    "{}/.schema",
+                    stream_name
+                )));
+            }
+
+            Ok(())
+        }
+
+        async fn create_stream(&self, stream_name: &str) -> Result<(), ObjectStorageError> {
+            self.record(format!("create {}", stream_name));
+            Ok(())
+        }
+
+        async fn create_alert(
+            &self,
+            _stream_name: &str,
+            _alerts: &Alerts,
+        ) -> Result<(), ObjectStorageError> {
+            Ok(())
+        }
+
+        async fn get_schema(&self, stream_name: &str) -> Result<Bytes, ObjectStorageError> {
+            self.record(format!("start {}", stream_name));
+            if let Some(delay) = self.delays.get(stream_name) {
+                actix_web::rt::time::sleep(*delay).await;
+            }
+            self.record(format!("end {}", stream_name));
+            self.fail_transiently()?;
+
+            self.schemas
+                .get(stream_name)
+                .cloned()
+                .ok_or_else(|| ObjectStorageError::NoSuchKey(format!("{}/.schema", stream_name)))
+        }
+
+        async fn get_alert(&self, stream_name: &str) -> Result<Bytes, ObjectStorageError> {
+            Err(ObjectStorageError::NoSuchKey(format!(
+                "{}/.alert.json",
+                stream_name
+            )))
+        }
+
+        async fn get_stats(&self, stream_name: &str) -> Result<Stats, ObjectStorageError> {
+            Err(ObjectStorageError::NoSuchKey(format!(
+                "{}/.stats.json",
+                stream_name
+            )))
+        }
+
+        async fn put_stats(
+            &self,
+            _stream_name: &str,
+            _stats: &Stats,
+        ) -> Result<(), ObjectStorageError> {
+            Ok(())
+        }
+
+        async fn put_retention(
+            &self,
+            _stream_name: &str,
+            _retention: &Retention,
+        ) -> Result<(), ObjectStorageError> {
+            Ok(())
+        }
+
+        async fn get_retention(&self, stream_name: &str) -> Result<Retention, ObjectStorageError> {
+            Err(ObjectStorageError::NoSuchKey(format!(
+                "{}/.retention.json",
+                stream_name
+            )))
+        }
+
+        async fn put_tags(
+            &self,
+            _stream_name: &str,
+            _tags: &HashMap<String, String>,
+        ) -> Result<(), ObjectStorageError> {
+            Ok(())
+        }
+
+        async fn get_tags(
+            &self,
+            stream_name: &str,
+        ) -> Result<HashMap<String, String>, ObjectStorageError> {
+            Err(ObjectStorageError::NoSuchKey(format!(
+                "{}/.tags.json",
+                stream_name
+            )))
+        }
+
+        async fn put_static_schema(
+            &self,
+            _stream_name: &str,
+            _static_schema: bool,
+        ) -> Result<(), ObjectStorageError> {
+            Ok(())
+        }
+
+        async fn get_static_schema(&self, stream_name: &str) -> Result<bool, ObjectStorageError> {
+            Err(ObjectStorageError::NoSuchKey(format!(
+                "{}/.static_schema.json",
+                stream_name
+            )))
+        }
+
+        async fn put_deleted_at(
+            &self,
+            stream_name: &str,
+            deleted_at: Option<DateTime<Utc>>,
+        ) -> Result<(), ObjectStorageError> {
+            match deleted_at {
+                Some(_) => self.record(format!("mark deleted {}", stream_name)),
+                None => self.record(format!("unmark deleted {}", stream_name)),
+            }
+            Ok(())
+        }
+
+        async fn get_deleted_at(
+            &self,
+            stream_name: &str,
+        ) -> Result<Option<DateTime<Utc>>, ObjectStorageError> {
+            Err(ObjectStorageError::NoSuchKey(format!(
+                "{}/.deleted.json",
+                stream_name
+            )))
+        }
+
+        async fn put_renamed_from(
+            &self,
+            stream_name: &str,
+            renamed_from: &str,
+        ) -> Result<(), ObjectStorageError> {
+            self.record(format!(
+                "mark {} renamed from {}",
+                stream_name, renamed_from
+            ));
+            self.renames
+                .lock()
+                .unwrap()
+                .insert(stream_name.to_string(), renamed_from.to_string());
+            self.objects
+                .lock()
+                .unwrap()
+                .push(format!("{}/.renamed_from.json", stream_name));
+            Ok(())
+        }
+
+        async fn get_renamed_from(&self, stream_name: &str) -> Result<String, ObjectStorageError> {
+            let key = format!("{}/.renamed_from.json", stream_name);
+            if !self.objects.lock().unwrap().contains(&key) {
+                return Err(ObjectStorageError::NoSuchKey(key));
+            }
+
+            self.renames
+                .lock()
+                .unwrap()
+                .get(stream_name)
+                .cloned()
+                .ok_or(ObjectStorageError::NoSuchKey(key))
+        }
+
+        async fn put_time_field(
+            &self,
+            _stream_name: &str,
+            _time_field: &str,
+        ) -> Result<(), ObjectStorageError> {
+            Ok(())
+        }
+
+        async fn get_time_field(&self, stream_name: &str) -> Result<String, ObjectStorageError> {
+            Err(ObjectStorageError::NoSuchKey(format!(
+                "{}/.time_field.json",
+                stream_name
+            )))
+        }
+
+        async fn put_limits(
+            &self,
+            stream_name: &str,
+            _limits: &Limits,
+        ) -> Result<(), ObjectStorageError> {
+            self.record(format!("put limits {}", stream_name));
+            Ok(())
+        }
+
+        async fn get_limits(&self, stream_name: &str) -> Result<Limits, ObjectStorageError> {
+            Err(ObjectStorageError::NoSuchKey(format!(
+                "{}/.limits.json",
+                stream_name
+            )))
+        }
+
+        async fn put_compression(
+            &self,
+            stream_name: &str,
+            _compression: Compression,
+        ) -> Result<(), ObjectStorageError> {
+            self.record(format!("put compression {}", stream_name));
+            Ok(())
+        }
+
+        async fn get_compression(
+            &self,
+            stream_name: &str,
+        ) -> Result<Compression, ObjectStorageError> {
+            Err(ObjectStorageError::NoSuchKey(format!(
+                "{}/.compression.json",
+                stream_name
+            )))
+        }
+
+        async fn put_flatten(
+            &self,
+            stream_name: &str,
+            _flatten: Flatten,
+        ) -> Result<(), ObjectStorageError> {
+            self.record(format!("put flatten {}", stream_name));
+            Ok(())
+        }
+
+        async fn get_flatten(&self, stream_name: &str) -> Result<Flatten, ObjectStorageError> {
+            Err(ObjectStorageError::NoSuchKey(format!(
+                "{}/.flatten.json",
+                stream_name
+            )))
+        }
+
+        async fn put_partition(
+            &self,
+            stream_name: &str,
+            _partition: PartitionGranularity,
+        ) -> Result<(), ObjectStorageError> {
+            self.record(format!("put partition {}", stream_name));
+            Ok(())
+        }
+
+        async fn get_partition(
+            &self,
+            stream_name: &str,
+        ) -> Result<PartitionGranularity, ObjectStorageError> {
+            Err(ObjectStorageError::NoSuchKey(format!(
+                "{}/.partition.json",
+                stream_name
+            )))
+        }
+
+        async fn put_timestamps(
+            &self,
+            _stream_name: &str,
+            _timestamps: &StreamTimestamps,
+        ) -> Result<(), ObjectStorageError> {
+            Ok(())
+        }
+
+        async fn get_timestamps(
+            &self,
+            stream_name: &str,
+        ) -> Result<StreamTimestamps, ObjectStorageError> {
+            Err(ObjectStorageError::NoSuchKey(format!(
+                "{}/.timestamps.json",
+                stream_name
+            )))
+        }
+
+        async fn put_metadata(
+            &self,
+            _stream_name: &str,
+            _document: &MetadataDocument,
+        ) -> Result<(), ObjectStorageError> {
+            Ok(())
+        }
+
+        async fn get_metadata(
+            &self,
+            stream_name: &str,
+        ) -> Result<MetadataDocument, ObjectStorageError> {
+            Err(ObjectStorageError::NoSuchKey(format!(
+                "{}/.metadata.json",
+                stream_name
+            )))
+        }
+
+        async fn list_streams(&self) -> Result<Vec<LogStream>, ObjectStorageError> {
+            let mut names = self.schemas.keys().cloned().collect::<Vec<_>>();
+            names.sort();
+
+            Ok(names.into_iter().map(|name| LogStream { name }).collect())
+        }
+
+        async fn list_streams_paged(
+            &self,
+            continuation_token: Option<&str>,
+            limit: usize,
+        ) -> Result<StreamsPage, ObjectStorageError> {
+            self.record(format!(
+                "list streams from {}",
+                continuation_token.unwrap_or("start")
+            ));
+            let mut names = self.schemas.keys().cloned().collect::<Vec<_>>();
+            names.sort();
+
+            // the token is the index of the first stream of the page
+            let start = continuation_token.map_or(0, |token| token.parse().unwrap());
+            let limit = self
+                .page_size
+                .map_or(limit, |page_size| page_size.min(limit));
+            let end = (start + limit).min(names.len());
+
+            Ok(StreamsPage {
+                streams: names[start..end]
+                    .iter()
+                    .map(|name| LogStream { name: name.clone() })
+                    .collect(),
+                next_token: (end < names.len()).then(|| end.to_string()),
+            })
+        }
+
+        async fn list_dirs(&self, prefix: &str) -> Result<Vec<String>, ObjectStorageError> {
+            let mut dirs: Vec<String> = self
+                .objects()
+                .iter()
+                .filter_map(|key| key.strip_prefix(prefix)?.split_once('/'))
+                .map(|(dir, _)| dir.to_string())
+                .collect();
+            dirs.sort();
+            dirs.dedup();
+
+            Ok(dirs)
+        }
+
+        async fn put_object(&self, key: &str, body: Bytes) -> Result<(), ObjectStorageError> {
+            if self.read_only {
+                return Err(ObjectStorageError::AccessDenied("mock".to_string()));
+            }
+            self.add_object(key, body);
+            Ok(())
+        }
+
+        async fn get_object(&self, key: &str) -> Result<Bytes, ObjectStorageError> {
+            self.bodies
+                .lock()
+                .unwrap()
+                .get(key)
+                .cloned()
+                .ok_or_else(|| ObjectStorageError::NoSuchKey(key.to_string()))
+        }
+
+        async fn upload_file(&self, key: &str, path: &str) -> Result<(), ObjectStorageError> {
+            self.record(format!("upload {}", key));
+            self.fail_transiently()?;
+            if let Some(delay) = self.upload_delay {
+                actix_web::rt::time::sleep(delay).await;
+            }
+
+            let mut body = fs::read(path)?;
+            let mut mangled = self.mangled_uploads.lock().unwrap();
+            if *mangled > 0 {
+                *mangled -= 1;
+                body.truncate(body.len() / 2);
+            }
+            drop(mangled);
+            self.add_object(key, body.into());
+            Ok(())
+        }
+
+        async fn object_meta(&self, key: &str) -> Result<ObjectMeta, ObjectStorageError> {
+            let body = self.get_object(key).await?;
+            Ok(ObjectMeta {
+                size: body.len() as u64,
+                md5: Some(md5::compute(&body).0),
+            })
+        }
+
+        async fn delete_prefix(&self, prefix: &str) -> Result<DeletedObjects, ObjectStorageError> {
+            let mut objects = self.objects.lock().unwrap();
+            let mut deleted = DeletedObjects::default();
+
+            while let Some(index) = objects.iter().position(|key| key.starts_with(prefix)) {
+                if Some(deleted.objects as usize) == self.delete_limit {
+                    deleted.failed =
+                        objects.iter().filter(|key| key.starts_with(prefix)).count() as u64;
+                    break;
+                }
+                let key = objects.remove(index);
+                self.bodies.lock().unwrap().remove(&key);
+                deleted.objects += 1;
+            }
+
+            deleted.complete()
+        }
+
+        async fn copy_prefix(&self, from: &str, to: &str) -> Result<u64, ObjectStorageError> {
+            let mut objects = self.objects.lock().unwrap();
+            let copies = objects
+                .iter()
+                .filter_map(|key| key.strip_prefix(from))
+                .map(|rest| format!("{}{}", to, rest))
+                .collect::<Vec<_>>();
+
+            let copied = copies.len() as u64;
+            for key in copies {
+                if !objects.contains(&key) {
+                    objects.push(key);
+                }
+            }
+
+            Ok(copied)
+        }
+
+        async fn count_objects(&self, prefix: &str) -> Result<u64, ObjectStorageError> {
+            let objects = self.objects.lock().unwrap();
+            Ok(objects.iter().filter(|key| key.starts_with(prefix)).count() as u64)
+        }
+
+        async fn parquet_objects(&self, prefix: &str) -> Result<StoredObjects, ObjectStorageError> {
+            let objects = self.objects.lock().unwrap();
+            let parquet = objects
+                .iter()
+                .filter(|key| key.starts_with(prefix) && key.ends_with(".parquet"))
+                .count() as u64;
+
+            // objects of the mock have no content
+            Ok(StoredObjects {
+                objects: parquet,
+                size: 0,
+            })
+        }
+
+        async fn list_parquet_files(
+            &self,
+            prefix: &str,
+        ) -> Result<Vec<ParquetFile>, ObjectStorageError> {
+            let objects = self.objects.lock().unwrap();
+            let bodies = self.bodies.lock().unwrap();
+            Ok(objects
+                .iter()
+                .filter(|key| key.starts_with(prefix) && key.ends_with(".parquet"))
+                .map(|key| ParquetFile {
+                    key: key.clone(),
+                    size: bodies.get(key).map_or(0, |body| body.len() as u64),
+                })
+                .collect())
+        }
+
+        async fn query(
+            &self,
+            _query: &Query,
+            _results: &mut Vec<RecordBatch>,
+        ) -> Result<(), ObjectStorageError> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockStorage;
+    use super::*;
+    use serial_test::serial;
+
+    #[actix_web::test]
+    async fn list_all_streams_across_pages() {
+        let mut storage = MockStorage::default().with_page_size(1000);
+        for i in 0..1500 {
+            storage = storage.with_stream(&format!("stream{:04}", i), "");
+        }
+
+        let streams = list_all_streams(&storage, 1000).await.unwrap();
+
+        assert_eq!(streams.len(), 1500);
+        assert_eq!(streams[1499].name, "stream1499");
+        assert_eq!(
+            storage.requests(),
+            vec!["list streams from start", "list streams from 1000"]
+        );
+    }
+
+    #[actix_web::test]
+    async fn check_write_leaves_no_probe() {
+        let storage = MockStorage::default();
+
+        storage.check_write().await.unwrap();
+        assert!(storage.objects().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn check_write_with_read_only_access() {
+        let storage = MockStorage::default().with_read_only();
+
+        assert!(storage.check().await.is_ok());
+        assert!(matches!(
+            storage.check_write().await,
+            Err(ObjectStorageError::AccessDenied(_))
+        ));
+    }
+
+    fn staged_file(dir: &Path) -> String {
+        fs::create_dir_all(dir).unwrap();
+        let path = dir.join("data.parquet");
+        fs::write(&path, b"PAR1 events PAR1").unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn corrupted_upload_keeps_staged_file() {
+        let dir = std::env::temp_dir().join(format!("parseable-test-{}", utils::random_string()));
+        let path = staged_file(&dir);
+        STREAM_INFO
+            .add_stream("corruptedstream".to_string(), None, Alerts::default())
+            .unwrap();
+        let storage = MockStorage::default().with_mangled_uploads(CORRUPTED_UPLOAD_ATTEMPTS);
+
+        let result = upload_verified(&storage, "corruptedstream", "stream/a.parquet", &path).await;
+
+        assert!(matches!(result, Err(ObjectStorageError::Corrupted(..))));
+        assert!(Path::new(&path).exists());
+        assert_eq!(storage.requests().len(), CORRUPTED_UPLOAD_ATTEMPTS as usize);
+        assert_eq!(
+            STREAM_INFO
+                .stats("corruptedstream")
+                .unwrap()
+                .corrupted_uploads,
+            CORRUPTED_UPLOAD_ATTEMPTS as u64
+        );
+
+        // the staged file is uploaded again with the next sync
+        upload_verified(&storage, "corruptedstream", "stream/a.parquet", &path)
+            .await
+            .unwrap();
+        assert!(!Path::new(&path).exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn corrupted_upload_is_retried() {
+        let dir = std::env::temp_dir().join(format!("parseable-test-{}", utils::random_string()));
+        let path = staged_file(&dir);
+        let storage = MockStorage::default().with_mangled_uploads(1);
+
+        upload_verified(&storage, "stream", "stream/a.parquet", &path)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            storage.requests(),
+            vec!["upload stream/a.parquet", "upload stream/a.parquet"]
+        );
+        assert!(!Path::new(&path).exists());
+        assert_eq!(
+            storage.get_object("stream/a.parquet").await.unwrap(),
+            Bytes::from_static(b"PAR1 events PAR1")
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn schema_of(fields: &[&str]) -> Schema {
+        Schema::new(
+            fields
+                .iter()
+                .map(|name| {
+                    arrow::datatypes::Field::new(name, arrow::datatypes::DataType::Utf8, true)
+                })
+                .collect(),
+        )
+    }
+
+    #[actix_web::test]
+    async fn schema_put_by_other_server_is_merged() {
+        let root = std::env::temp_dir().join(format!("parseable-test-{}", utils::random_string()));
+        let storage = crate::localfs::LocalStorage::new(root.clone());
+        // another server put its schema first
+        storage
+            .put_schema("sharedstream".to_string(), &schema_of(&["a"]))
+            .await
+            .unwrap();
+
+        let merged = put_schema_merged(&storage, "sharedstream", &schema_of(&["b"]))
+            .await
+            .unwrap();
+
+        assert_eq!(merged, schema_of(&["a", "b"]));
+        let body = storage.get_schema("sharedstream").await.unwrap();
+        assert_eq!(serde_json::from_slice::<Schema>(&body).unwrap(), merged);
+
+        // neither of two servers putting at the same time loses its columns
+        let (first, second) = futures::join!(
+            put_schema_merged(&storage, "sharedstream", &schema_of(&["c"])),
+            put_schema_merged(&storage, "sharedstream", &schema_of(&["d"])),
+        );
+        first.unwrap();
+        second.unwrap();
+        let body = storage.get_schema("sharedstream").await.unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Schema>(&body).unwrap(),
+            schema_of(&["a", "b", "c", "d"])
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn schema_put_gives_up_on_conflicts() {
+        let storage = MockStorage::default()
+            .with_stream("sharedstream", "")
+            .with_schema_conflicts(SCHEMA_PUT_ATTEMPTS - 1);
+        put_schema_merged(&storage, "sharedstream", &schema_of(&["a"]))
+            .await
+            .unwrap();
+        assert_eq!(storage.requests().len(), SCHEMA_PUT_ATTEMPTS as usize);
+
+        let storage = MockStorage::default()
+            .with_stream("sharedstream", "")
+            .with_schema_conflicts(SCHEMA_PUT_ATTEMPTS);
+        let result = put_schema_merged(&storage, "sharedstream", &schema_of(&["a"])).await;
+        assert!(matches!(
+            result,
+            Err(crate::Error::SchemaConflict(stream, SCHEMA_PUT_ATTEMPTS)) if stream == "sharedstream"
+        ));
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn streams_are_synced_concurrently() {
+        let dir = std::env::temp_dir().join(format!("parseable-test-{}", utils::random_string()));
+        let streams = ["syncstream1", "syncstream2", "syncstream3", "syncstream4"];
+        for stream_name in streams {
+            let tmp = dir.join(stream_name).join("tmp");
+            fs::create_dir_all(&tmp).unwrap();
+            fs::write(
+                tmp.join("date=2022-10-15.hour=10.minute=05.abc.parquet"),
+                b"PAR1 events PAR1",
+            )
+            .unwrap();
+            STREAM_INFO
+                .add_stream(stream_name.to_string(), None, Alerts::default())
+                .unwrap();
+        }
+        let delay = std::time::Duration::from_millis(200);
+        let storage = MockStorage::default().with_upload_delay(delay);
+
+        let started = Instant::now();
+        sync_streams(&storage, &dir, streams.len()).await.unwrap();
+        let elapsed = started.elapsed();
+
+        // one upload after another would take a delay per stream
+        assert!(elapsed < delay * 2, "{:?}", elapsed);
+        for stream_name in streams {
+            let key = format!(
+                "{}/date=2022-10-15/hour=10/minute=05/abc.parquet",
+                stream_name
+            );
+            assert!(storage.objects().contains(&key));
+            // every upload is accounted to its own stream
+            assert_eq!(STREAM_INFO.stats(stream_name).unwrap().parquet_files, 1);
+            STREAM_INFO.delete_stream(stream_name).unwrap();
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
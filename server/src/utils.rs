@@ -18,31 +18,218 @@
 
 use actix_web::web;
 use actix_web::HttpRequest;
-use chrono::{Date, DateTime, Timelike, Utc};
+use chrono::{Date, DateTime, NaiveDate, TimeZone, Timelike, Utc};
+use flate2::read::GzDecoder;
 use rand::{distributions::Alphanumeric, Rng};
-use serde_json::{json, Value};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
 use std::collections::HashMap;
+use std::io::Read;
 
+use crate::metadata::Flatten;
 use crate::Error;
 
 const META_LABEL: &str = "x-p-meta";
 
+/// Field that the labels of a request are added to every event in
+pub const LABELS_FIELD: &str = "labels";
+
 pub fn flatten_json_body(
     body: web::Json<serde_json::Value>,
     labels: Option<String>,
+    flatten: Flatten,
 ) -> Result<String, Error> {
     let mut collector_labels = HashMap::new();
 
-    collector_labels.insert("labels".to_string(), labels.unwrap());
+    collector_labels.insert(LABELS_FIELD.to_string(), labels.unwrap());
 
-    let mut flat_value: Value = json!({});
     let new_body = merge(&body, &collector_labels);
-    flatten_json::flatten(&new_body, &mut flat_value, None, true, Some("_")).unwrap();
+    let flat_value = match &new_body {
+        Value::Object(fields) if flatten.flatten => {
+            let mut flat = Map::new();
+            flatten_dotted(fields, "", flatten.flatten_arrays, &mut flat);
+            Value::Object(flat)
+        }
+        _ => {
+            let mut flat_value: Value = json!({});
+            flatten_json::flatten(&new_body, &mut flat_value, None, true, Some("_")).unwrap();
+            flat_value
+        }
+    };
     let flattened = serde_json::to_string(&flat_value)?;
 
     Ok(flattened)
 }
 
+// Fields of nested objects are named by their dotted path under `prefix`. Arrays are
+// flattened by the index of their elements with `arrays`, kept as a JSON string otherwise.
+fn flatten_dotted(
+    fields: &Map<String, Value>,
+    prefix: &str,
+    arrays: bool,
+    flat: &mut Map<String, Value>,
+) {
+    for (name, value) in fields {
+        let key = if prefix.is_empty() {
+            name.clone()
+        } else {
+            format!("{}.{}", prefix, name)
+        };
+
+        match value {
+            Value::Object(fields) => flatten_dotted(fields, &key, arrays, flat),
+            Value::Array(items) if arrays => {
+                let items = items
+                    .iter()
+                    .enumerate()
+                    .map(|(index, item)| (index.to_string(), item.clone()))
+                    .collect();
+                flatten_dotted(&items, &key, arrays, flat);
+            }
+            Value::Array(_) => {
+                flat.insert(key, Value::String(value.to_string()));
+            }
+            _ => {
+                flat.insert(key, value.clone());
+            }
+        }
+    }
+}
+
+/// A line of a newline delimited JSON body that isn't a valid event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LineError {
+    /// Line number, counting from 1
+    pub line: usize,
+    pub error: String,
+}
+
+/// Flatten every line of a newline delimited JSON body into an event, as
+/// `(line number, event)`. Blank lines are skipped, lines that aren't JSON
+/// objects are returned as errors instead of failing the whole body.
+pub fn flatten_ndjson_body(
+    body: &[u8],
+    labels: Option<String>,
+    flatten: Flatten,
+) -> (Vec<(usize, String)>, Vec<LineError>) {
+    let mut events = Vec::new();
+    let mut errors = Vec::new();
+
+    for (index, line) in body.split(|byte| *byte == b'\n').enumerate() {
+        let line_number = index + 1;
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+
+        let error = match serde_json::from_slice::<Value>(line) {
+            Ok(value) if value.is_object() => {
+                match flatten_json_body(web::Json(value), labels.clone(), flatten) {
+                    Ok(event) => {
+                        events.push((line_number, event));
+                        continue;
+                    }
+                    Err(e) => e.to_string(),
+                }
+            }
+            Ok(_) => "event is not a JSON object".to_string(),
+            Err(e) => e.to_string(),
+        };
+
+        errors.push(LineError {
+            line: line_number,
+            error,
+        });
+    }
+
+    (events, errors)
+}
+
+/// Turn every row of a CSV body into an event, as `(line number, event)`.
+/// The first row holds the field names. Values are strings, or with
+/// `detect_numbers` numbers where they parse as one, and empty values are
+/// left out of the event. Rows with a different number of values than
+/// there are field names are returned as errors.
+pub fn flatten_csv_body(
+    body: &[u8],
+    labels: Option<String>,
+    detect_numbers: bool,
+) -> (Vec<(usize, String)>, Vec<LineError>) {
+    let mut events = Vec::new();
+    let mut errors = Vec::new();
+
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(body);
+    let headers = match reader.headers() {
+        Ok(headers) => headers.clone(),
+        Err(e) => {
+            errors.push(LineError {
+                line: 1,
+                error: e.to_string(),
+            });
+            return (events, errors);
+        }
+    };
+
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                errors.push(LineError {
+                    line: e.position().map_or(0, |position| position.line() as usize),
+                    error: e.to_string(),
+                });
+                continue;
+            }
+        };
+        let line_number = record
+            .position()
+            .map_or(0, |position| position.line() as usize);
+        if record.len() != headers.len() {
+            errors.push(LineError {
+                line: line_number,
+                error: format!(
+                    "row has {} values, expected {}",
+                    record.len(),
+                    headers.len()
+                ),
+            });
+            continue;
+        }
+
+        let event = headers
+            .iter()
+            .zip(record.iter())
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(field, value)| (field.to_string(), csv_value(value, detect_numbers)))
+            .collect::<serde_json::Map<_, _>>();
+        match flatten_json_body(web::Json(Value::Object(event)), labels.clone()) {
+            Ok(event) => events.push((line_number, event)),
+            Err(e) => errors.push(LineError {
+                line: line_number,
+                error: e.to_string(),
+            }),
+        }
+    }
+
+    (events, errors)
+}
+
+fn csv_value(value: &str, detect_numbers: bool) -> Value {
+    if detect_numbers {
+        if let Ok(number) = value.parse::<i64>() {
+            return Value::from(number);
+        }
+        if let Some(number) = value
+            .parse::<f64>()
+            .ok()
+            .and_then(serde_json::Number::from_f64)
+        {
+            return Value::Number(number);
+        }
+    }
+
+    Value::String(value.to_string())
+}
+
 fn merge(v: &Value, fields: &HashMap<String, String>) -> Value {
     match v {
         Value::Object(m) => {
@@ -67,6 +254,21 @@ fn merge(v: &Value, fields: &HashMap<String, String>) -> Value {
     }
 }
 
+/// Decompress a gzip encoded body, failing once more than `limit` bytes come out
+/// of it instead of decompressing it completely.
+pub fn decompress_gzip(body: &[u8], limit: usize) -> Result<Vec<u8>, Error> {
+    let mut decompressed = Vec::new();
+    GzDecoder::new(body)
+        .take(limit as u64 + 1)
+        .read_to_end(&mut decompressed)?;
+
+    if decompressed.len() > limit {
+        return Err(Error::DecompressedTooLarge(limit));
+    }
+
+    Ok(decompressed)
+}
+
 pub fn random_string() -> String {
     rand::thread_rng()
         .sample_iter(&Alphanumeric)
@@ -108,6 +310,32 @@ pub fn minute_to_prefix(minute: u32, data_granularity: u32) -> Option<String> {
     ))
 }
 
+/// Prefix of the data partition that `time` falls into,
+/// e.g. `date=2022-10-15/hour=10/minute=30/`
+pub fn time_to_prefix(time: DateTime<Utc>, data_granularity: u32) -> String {
+    date_to_prefix(time.date())
+        + &hour_to_prefix(time.hour())
+        + &minute_to_prefix(time.minute(), data_granularity).unwrap()
+}
+
+/// Convert partition directory names, e.g. `["date=2022-10-15", "hour=10", "minute=30"]`
+/// to the start time of the partition. Hour and minute default to 0 if missing.
+pub fn partition_to_time(partitions: &[String]) -> Option<DateTime<Utc>> {
+    let value = |index: usize, key: &str| -> Option<String> {
+        match partitions.get(index) {
+            Some(dir) => dir.strip_prefix(key).map(str::to_owned),
+            None => Some("00".to_string()),
+        }
+    };
+
+    let date = NaiveDate::parse_from_str(&value(0, "date=")?, "%Y-%m-%d").ok()?;
+    let hour = value(1, "hour=")?.parse().ok()?;
+    // minute slots span a range for granularity larger than a minute, e.g. "10-19"
+    let minute = value(2, "minute=")?.split('-').next()?.parse().ok()?;
+
+    Some(Utc.from_utc_datetime(&date.and_hms_opt(hour, minute, 0)?))
+}
+
 /// collect labels passed from http headers
 /// format: labels = "app=k8s, cloud=gcp"
 pub fn collect_labels(req: &HttpRequest) -> Option<String> {
@@ -281,10 +509,17 @@ impl TimePeriod {
 
 #[cfg(test)]
 mod tests {
-    use chrono::DateTime;
+    use chrono::{DateTime, Utc};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
     use rstest::*;
+    use std::io::Write;
 
-    use super::TimePeriod;
+    use super::{
+        decompress_gzip, flatten_csv_body, flatten_json_body, flatten_ndjson_body,
+        partition_to_time, TimePeriod,
+    };
+    use crate::metadata::Flatten;
 
     fn time_period_from_str(start: &str, end: &str) -> TimePeriod {
         TimePeriod::new(
@@ -360,4 +595,139 @@ mod tests {
         let left = prefixes.iter().map(String::as_str).collect::<Vec<&str>>();
         assert_eq!(left.as_slice(), right);
     }
+
+    #[rstest]
+    #[case::minute(&["date=2022-06-11", "hour=16", "minute=30"], Some("2022-06-11T16:30:00+00:00"))]
+    #[case::minute_slot(&["date=2022-06-11", "hour=16", "minute=30-39"], Some("2022-06-11T16:30:00+00:00"))]
+    #[case::date_only(&["date=2022-06-11"], Some("2022-06-11T00:00:00+00:00"))]
+    #[case::bad_date(&["date=2022-13-11"], None)]
+    #[case::bad_key(&["date=2022-06-11", "minute=16"], None)]
+    #[case::empty(&[], None)]
+    fn partition_time(#[case] partitions: &[&str], #[case] right: Option<&str>) {
+        let partitions = partitions.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        let right: Option<DateTime<Utc>> =
+            right.map(|time| DateTime::parse_from_rfc3339(time).unwrap().into());
+        assert_eq!(partition_to_time(&partitions), right);
+    }
+
+    #[test]
+    fn ndjson_body() {
+        let body = b"{\"a\": 1}\n\n{\"b\": {\"c\": \"x\"}}\r\nnot json\n[1, 2]\n{\"d\": true}";
+        let (events, errors) =
+            flatten_ndjson_body(body, Some("host=a".to_string()), Flatten::default());
+
+        assert_eq!(
+            events,
+            vec![
+                (1, r#"{"a":1,"labels":"host=a"}"#.to_string()),
+                (3, r#"{"b_c":"x","labels":"host=a"}"#.to_string()),
+                (6, r#"{"d":true,"labels":"host=a"}"#.to_string()),
+            ]
+        );
+        assert_eq!(
+            errors.iter().map(|e| e.line).collect::<Vec<_>>(),
+            vec![4, 5]
+        );
+        assert_eq!(errors[1].error, "event is not a JSON object");
+    }
+
+    #[rstest]
+    #[case(
+        Flatten::default(),
+        r#"{"level":"info","user":{"id":7,"name":"x"}}"#,
+        r#"{"labels":"","level":"info","user_id":7,"user_name":"x"}"#
+    )]
+    #[case(
+        Flatten { flatten: true, flatten_arrays: false },
+        r#"{"level":"info","user":{"id":7,"name":"x"}}"#,
+        r#"{"labels":"","level":"info","user.id":7,"user.name":"x"}"#
+    )]
+    #[case(
+        Flatten { flatten: true, flatten_arrays: false },
+        r#"{"user":{"tags":["a",{"b":1}]}}"#,
+        r#"{"labels":"","user.tags":"[\"a\",{\"b\":1}]"}"#
+    )]
+    #[case(
+        Flatten { flatten: true, flatten_arrays: true },
+        r#"{"user":{"tags":["a",{"b":1}]}}"#,
+        r#"{"labels":"","user.tags.0":"a","user.tags.1.b":1}"#
+    )]
+    fn nested_event(#[case] flatten: Flatten, #[case] event: &str, #[case] expected: &str) {
+        let event = serde_json::from_str(event).unwrap();
+
+        let flattened =
+            flatten_json_body(actix_web::web::Json(event), Some(String::new()), flatten).unwrap();
+        assert_eq!(flattened, expected);
+    }
+
+    #[test]
+    fn csv_body() {
+        let body = b"host,status,latency\nweb-1,200,1.5\nweb-2,,12\n";
+
+        let (events, errors) = flatten_csv_body(body, Some("env=prod".to_string()), false);
+        assert!(errors.is_empty());
+        assert_eq!(
+            events,
+            vec![
+                (
+                    2,
+                    r#"{"host":"web-1","labels":"env=prod","latency":"1.5","status":"200"}"#
+                        .to_string()
+                ),
+                (
+                    3,
+                    r#"{"host":"web-2","labels":"env=prod","latency":"12"}"#.to_string()
+                ),
+            ]
+        );
+
+        let (events, _) = flatten_csv_body(body, Some(String::new()), true);
+        assert_eq!(
+            events[0].1,
+            r#"{"host":"web-1","labels":"","latency":1.5,"status":200}"#
+        );
+    }
+
+    #[test]
+    fn csv_body_with_ragged_row() {
+        let body = b"host,status\nweb-1,200\nweb-2\nweb-3,500,extra\nweb-4,404\n";
+
+        let (events, errors) = flatten_csv_body(body, Some(String::new()), false);
+
+        assert_eq!(
+            events.iter().map(|(line, _)| *line).collect::<Vec<_>>(),
+            vec![2, 5]
+        );
+        assert_eq!(
+            errors,
+            vec![
+                super::LineError {
+                    line: 3,
+                    error: "row has 1 values, expected 2".to_string()
+                },
+                super::LineError {
+                    line: 4,
+                    error: "row has 3 values, expected 2".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn gzip_body() {
+        let body = br#"{"a": 1}"#.repeat(100);
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&body).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        assert_eq!(decompress_gzip(&compressed, body.len()).unwrap(), body);
+        assert!(matches!(
+            decompress_gzip(&compressed, body.len() - 1),
+            Err(crate::Error::DecompressedTooLarge(_))
+        ));
+        assert!(matches!(
+            decompress_gzip(&body, body.len()),
+            Err(crate::Error::Io(_))
+        ));
+    }
 }
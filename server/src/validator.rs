@@ -17,54 +17,28 @@
  */
 
 use chrono::{DateTime, Utc};
-use serde_derive::Deserialize;
-use serde_derive::Serialize;
 
+use serde_json::Value;
+use std::collections::HashMap;
+
+use crate::alerts::{Alerts, Operator, Rule};
+use crate::metadata::Limits;
 use crate::query::Query;
 use crate::Error;
 
+const MIN_STREAM_NAME_LEN: usize = 3;
+const MAX_STREAM_NAME_LEN: usize = 64;
+
+// names of top level prefixes used by parseable itself
+const RESERVED_NAMES: &[&str] = &["meta"];
+
 // TODO: add more sql keywords here in lower case
 const DENIED_NAMES: &[&str] = &[
     "select", "from", "where", "group", "by", "order", "limit", "offset", "join", "and",
 ];
 
-#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct Alerts {
-    pub alerts: Vec<Alert>,
-}
-
-#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct Alert {
-    pub name: String,
-    pub message: String,
-    pub rule: Rule,
-    pub target: Vec<Target>,
-}
-
-#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct Rule {
-    pub field: String,
-    pub contains: String,
-    pub repeats: u32,
-    pub within: String,
-}
-
-#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct Target {
-    pub name: String,
-    #[serde(rename = "server_url")]
-    pub server_url: String,
-    #[serde(rename = "api_key")]
-    pub api_key: String,
-}
-
-pub fn alert(body: String) -> Result<(), Error> {
-    let alerts: Alerts = serde_json::from_str(body.as_str())?;
-    for alert in alerts.alerts {
+pub fn alert(alerts: &Alerts) -> Result<(), Error> {
+    for alert in &alerts.alerts {
         if alert.name.is_empty() {
             return Err(Error::InvalidAlert(
                 "alert name cannot be empty".to_string(),
@@ -75,53 +49,151 @@ pub fn alert(body: String) -> Result<(), Error> {
                 "alert message cannot be empty".to_string(),
             ));
         }
-        if alert.rule.contains.is_empty() {
-            return Err(Error::InvalidAlert("rule.contains must be set".to_string()));
+        if alert.rules.is_empty() {
+            return Err(Error::InvalidAlert(
+                "alert must have at least one rule".to_string(),
+            ));
         }
-        if alert.rule.field.is_empty() {
-            return Err(Error::InvalidAlert("rule.field must be set".to_string()));
+        for (index, rule) in alert.rules.iter().enumerate() {
+            rule_of_alert(index, rule)?;
         }
-        if alert.rule.within.is_empty() {
-            return Err(Error::InvalidAlert("rule.within must be set".to_string()));
+        // a second rule with the same operator on a field is redundant, or can't
+        // match along with the first
+        for (index, rule) in alert.rules.iter().enumerate() {
+            if let Some(first) = alert.rules[..index]
+                .iter()
+                .position(|other| other.field == rule.field && other.operator == rule.operator)
+            {
+                return Err(Error::InvalidAlert(format!(
+                    "rules[{}] and rules[{}] both apply operator {} to field {}",
+                    first, index, rule.operator, rule.field
+                )));
+            }
         }
-        if alert.rule.repeats == 0 {
+        if alert.target.is_empty() {
             return Err(Error::InvalidAlert(
-                "rule.repeats can't be set to 0".to_string(),
+                "alert must have at least one target".to_string(),
             ));
         }
-        if alert.target.is_empty() {
+        if alert
+            .target
+            .iter()
+            .any(|target| target.server_url.is_empty())
+        {
             return Err(Error::InvalidAlert(
-                "alert must have at least one target".to_string(),
+                "target.server_url must be set".to_string(),
             ));
         }
     }
     Ok(())
 }
 
+fn rule_of_alert(index: usize, rule: &Rule) -> Result<(), Error> {
+    let invalid = |reason: String| Err(Error::InvalidAlert(format!("rules[{}].{}", index, reason)));
+
+    if rule.field.is_empty() {
+        return invalid("field must be set".to_string());
+    }
+    match (rule.operator, &rule.value) {
+        (op, value) if op.is_numeric() && !value.is_number() => {
+            return invalid(format!("value must be a number for operator {}", op));
+        }
+        (Operator::Contains, value) if value.as_str().unwrap_or_default().is_empty() => {
+            return invalid("value must be a non empty string for operator contains".to_string());
+        }
+        (_, Value::Null) => return invalid("value must be set".to_string()),
+        _ => {}
+    }
+    if rule.within.is_empty() {
+        return invalid("within must be set".to_string());
+    }
+    if rule.repeats == 0 {
+        return invalid("repeats can't be set to 0".to_string());
+    }
+
+    Ok(())
+}
+
 pub fn stream_name(str_name: &str) -> Result<(), Error> {
-    if str_name.is_empty() {
-        return Err(Error::EmptyName);
+    let invalid = |reason| Err(Error::InvalidStreamName(str_name.to_owned(), reason));
+
+    if let Err(reason) = name(str_name) {
+        return invalid(reason);
     }
 
-    if str_name.chars().all(char::is_numeric) {
-        return Err(Error::NameNumericOnly(str_name.to_owned()));
+    if RESERVED_NAMES.contains(&str_name) {
+        return invalid("name is reserved for internal use");
     }
 
-    if str_name.chars().next().unwrap().is_numeric() {
-        return Err(Error::NameCantStartWithNumber(str_name.to_owned()));
+    if DENIED_NAMES.contains(&str_name) {
+        return invalid("name cannot be a sql keyword");
     }
 
-    for c in str_name.chars() {
+    Ok(())
+}
+
+/// Tag keys follow the same rules as stream names, values can be any string.
+pub fn tags(tags: &HashMap<String, String>) -> Result<(), Error> {
+    for key in tags.keys() {
+        name(key).map_err(|reason| Error::InvalidTagKey(key.to_owned(), reason))?;
+    }
+
+    Ok(())
+}
+
+fn name(name: &str) -> Result<(), &'static str> {
+    if name.is_empty() {
+        return Err("name cannot be empty");
+    }
+
+    if !(MIN_STREAM_NAME_LEN..=MAX_STREAM_NAME_LEN).contains(&name.len()) {
+        return Err("name must be between 3 and 64 characters long");
+    }
+
+    // only allow a single spelling of a name, so that no two
+    // log streams can end up at the same object storage prefix
+    for c in name.chars() {
         match c {
-            ' ' => return Err(Error::NameWhiteSpace(str_name.to_owned())),
-            c if !c.is_alphanumeric() => return Err(Error::NameSpecialChar(str_name.to_owned())),
-            c if c.is_ascii_uppercase() => return Err(Error::NameUpperCase(str_name.to_owned())),
-            _ => {}
+            ' ' => return Err("name cannot contain spaces"),
+            c if c.is_ascii_uppercase() => return Err("name cannot contain uppercase characters"),
+            'a'..='z' | '0'..='9' | '-' | '_' => {}
+            _ => {
+                return Err(
+                    "name can only contain lowercase letters, numbers, hyphens and underscores",
+                )
+            }
         }
     }
 
-    if DENIED_NAMES.contains(&str_name) {
-        return Err(Error::SQLKeyword(str_name.to_owned()));
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        return Err("name cannot start with a number");
+    }
+
+    if !name.starts_with(|c: char| c.is_ascii_lowercase()) {
+        return Err("name must start with a letter");
+    }
+
+    Ok(())
+}
+
+pub fn retention(days: u32) -> Result<(), Error> {
+    if days == 0 {
+        return Err(Error::InvalidRetention(days));
+    }
+
+    Ok(())
+}
+
+pub fn limits(limits: &Limits) -> Result<(), Error> {
+    if limits.max_event_size == Some(0) {
+        return Err(Error::InvalidLimits(
+            "max_event_size must be at least 1 byte".to_string(),
+        ));
+    }
+    if limits.max_columns == Some(0) {
+        return Err(Error::InvalidLimits(
+            "max_columns must be at least 1 column".to_string(),
+        ));
     }
 
     Ok(())
@@ -172,3 +244,115 @@ pub fn query(query: &str, start_time: &str, end_time: &str) -> Result<Query, Err
         query: query.to_string(),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use rstest::*;
+
+    use maplit::hashmap;
+
+    use super::{alert, retention, stream_name, tags};
+    use crate::alerts::Alerts;
+
+    #[rstest]
+    #[case::simple("teststream")]
+    #[case::digits("stream01")]
+    #[case::hyphen("test-stream")]
+    #[case::underscore("test_stream")]
+    #[case::min_length("abc")]
+    #[case::max_length(&"a".repeat(64))]
+    fn valid_stream_name(#[case] name: &str) {
+        assert!(stream_name(name).is_ok());
+    }
+
+    #[rstest]
+    #[case::empty("")]
+    #[case::too_short("ab")]
+    #[case::too_long(&"a".repeat(65))]
+    #[case::uppercase("TestStream")]
+    #[case::space("test stream")]
+    #[case::special_char("stream!")]
+    #[case::slash("test/stream")]
+    #[case::path_traversal("../../etc")]
+    #[case::trailing_slash("teststream/")]
+    #[case::dot("test.stream")]
+    #[case::unicode("stréam")]
+    #[case::unicode_digit("stream١")]
+    #[case::numeric_only("12345")]
+    #[case::starts_with_number("1stream")]
+    #[case::starts_with_hyphen("-stream")]
+    #[case::starts_with_underscore("_stream")]
+    #[case::reserved("meta")]
+    #[case::sql_keyword("select")]
+    fn invalid_stream_name(#[case] name: &str) {
+        assert!(matches!(
+            stream_name(name),
+            Err(crate::Error::InvalidStreamName(..))
+        ));
+    }
+
+    #[rstest]
+    #[case::simple("team", true)]
+    #[case::hyphen("cost-center", true)]
+    #[case::uppercase("Team", false)]
+    #[case::colon("team:payments", false)]
+    #[case::too_short("ab", false)]
+    fn tag_key(#[case] key: &str, #[case] valid: bool) {
+        let stream_tags = hashmap! { key.to_string() => "payments".to_string() };
+        assert_eq!(tags(&stream_tags).is_ok(), valid);
+    }
+
+    #[rstest]
+    #[case::zero(0, false)]
+    #[case::one(1, true)]
+    #[case::month(30, true)]
+    fn retention_days(#[case] days: u32, #[case] valid: bool) {
+        assert_eq!(retention(days).is_ok(), valid);
+    }
+
+    // rules as (field, operator, value)
+    fn alerts(rules: &[(&str, &str, serde_json::Value)]) -> Alerts {
+        let rules = rules
+            .iter()
+            .map(|(field, operator, value)| {
+                serde_json::json!({
+                    "field": field, "operator": operator, "value": value,
+                    "repeats": 1, "within": "1m"
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::from_value(serde_json::json!({
+            "alerts": [{
+                "name": "errors",
+                "message": "errors",
+                "rules": rules,
+                "grouping": "allOf",
+                "target": [{ "name": "slack", "server_url": "http://localhost", "api_key": "" }]
+            }]
+        }))
+        .unwrap()
+    }
+
+    #[rstest]
+    #[case::distinct_fields(&[("code", ">=", 500.into()), ("latency", ">", 1.into())], None)]
+    #[case::range_on_field(&[("latency", ">", 1.into()), ("latency", "<", 5.into())], None)]
+    #[case::no_rules(&[], Some("alert must have at least one rule"))]
+    #[case::same_operator_on_field(
+        &[("code", ">=", 500.into()), ("latency", ">", 1.into()), ("code", ">=", 400.into())],
+        Some("rules[0] and rules[2] both apply operator >= to field code")
+    )]
+    #[case::bad_second_rule(
+        &[("code", ">=", 500.into()), ("latency", ">", "1s".into())],
+        Some("rules[1].value must be a number for operator >")
+    )]
+    fn grouped_alert_rules(
+        #[case] rules: &[(&str, &str, serde_json::Value)],
+        #[case] error: Option<&str>,
+    ) {
+        let result = alert(&alerts(rules)).map_err(|e| e.to_string());
+        match error {
+            None => assert!(result.is_ok()),
+            Some(error) => assert!(result.unwrap_err().contains(error)),
+        }
+    }
+}